@@ -7,4 +7,8 @@ pub fn encode(data: &str) -> String {
 pub fn decode(data: &str) -> Result<String, base64::DecodeError> {
     let decoded = STANDARD.decode(data.as_bytes())?;
     Ok(String::from_utf8(decoded).unwrap())
-}
\ No newline at end of file
+}
+
+pub fn decode_bytes(data: &str) -> Result<Vec<u8>, base64::DecodeError> {
+    STANDARD.decode(data.as_bytes())
+}
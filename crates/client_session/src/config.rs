@@ -0,0 +1,89 @@
+use std::{sync::Arc, time::Duration};
+
+use relay::RelayClient;
+use request_parser::ParseMode;
+
+use crate::{dead_letter::DeadLetterCapture, extension::ExtensionRegistry, metrics::MetricsCollector, tls_limit::TlsHandshakeLimiter};
+
+#[derive(Debug, Clone)]
+pub struct SessionConfig {
+    // the name the server introduces itself with in the greeting and the EHLO reply
+    pub hostname: String,
+    // reject MAIL FROM addresses that do not belong to the authenticated user
+    pub enforce_sender_ownership: bool,
+    // AUTH is only accepted on an encrypted connection
+    pub require_tls: bool,
+    // MAIL FROM before AUTH gets 530, as required for a submission server
+    pub require_auth: bool,
+    // accepted commands per second, DATA content is not counted
+    pub command_rate: f64,
+    // commands that may be sent at once before the rate applies
+    pub command_burst: u32,
+    // refused commands in a row after which the session is closed with 421
+    pub max_bad_commands: usize,
+    // longest line read in bytes, CRLF included; the AUTH continuation line has the same limit.
+    // Commands over MAX_COMMAND_LINE are refused by the parser even when this is larger.
+    pub max_line_length: usize,
+    // a MAIL FROM announcing a larger SIZE, or a larger message after DATA or BDAT, is refused with 552
+    pub max_message_size: usize,
+    // longest line of the header section of a DATA message in bytes, CRLF excluded; the message
+    // is read to its end and refused with 552 when one is longer
+    pub max_header_line_length: usize,
+    // the session is closed with 421 at the first command after this, however active the client
+    // is; the idle timeout of the connection starts over with every command
+    pub max_session_duration: Duration,
+    // how long reading one command line may take in all, a client trickling its bytes in keeps
+    // each read within the connection timeout but still gets 421
+    pub command_deadline: Duration,
+    // the same for the message of DATA, or for one BDAT chunk; a timeout closes with 451
+    pub data_deadline: Duration,
+    // how long the end of DATA waits for the delivery backend before replying 451
+    pub delivery_timeout: Duration,
+    // Lenient also accepts "MAIL FROM :", meant for testing by hand
+    pub parse_mode: ParseMode,
+    // charsets of RFC 2047 encoded subjects that are decoded before storing, empty disables decoding
+    pub accepted_charsets: Vec<String>,
+    // domains whose mailboxes are stored locally, RCPT TO to them and to addresses without a
+    // domain is checked against the mail database; when empty every domain is local
+    pub local_domains: Vec<String>,
+    // with local domains set, RCPT TO to other domains from an authenticated user is accepted and
+    // the message relayed through it; otherwise they are refused with 550 Relaying denied
+    pub relay: Option<Arc<RelayClient>>,
+    // shared by every session cloned from this config, STARTTLS beyond the limit gets 454
+    pub tls_handshakes: Arc<TlsHandshakeLimiter>,
+    // shared like tls_handshakes, so the server sees the statistics of all sessions
+    pub metrics: Arc<MetricsCollector>,
+    // advertised after the built-in EHLO keywords, asked about commands the parser rejects as unrecognized
+    pub extensions: ExtensionRegistry,
+    // when set, messages refused after DATA or BDAT are written out for debugging
+    pub dead_letters: Option<Arc<DeadLetterCapture>>,
+}
+
+impl Default for SessionConfig {
+    fn default() -> Self {
+        Self {
+            hostname: "localhost".to_string(),
+            enforce_sender_ownership: true,
+            require_tls: true,
+            require_auth: true,
+            command_rate: 10.0,
+            command_burst: 50,
+            max_bad_commands: 10,
+            max_line_length: 512,
+            max_message_size: 10 * 1024 * 1024,
+            max_header_line_length: 998,
+            max_session_duration: Duration::from_secs(600),
+            command_deadline: Duration::from_secs(300),
+            data_deadline: Duration::from_secs(600),
+            delivery_timeout: Duration::from_secs(30),
+            parse_mode: ParseMode::Strict,
+            accepted_charsets: crate::encoded_word::default_charsets(),
+            local_domains: Vec::new(),
+            relay: None,
+            tls_handshakes: Arc::new(TlsHandshakeLimiter::new(16)),
+            metrics: Arc::new(MetricsCollector::default()),
+            extensions: ExtensionRegistry::default(),
+            dead_letters: None,
+        }
+    }
+}
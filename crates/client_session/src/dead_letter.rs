@@ -0,0 +1,49 @@
+use std::{fs, io, path::PathBuf};
+
+use chrono::Local;
+
+use crate::{envelope::Envelope, received::next_message_id};
+
+const FILE_PREFIX: &str = "dead-letter-";
+
+// Writes refused messages to a directory, for finding out what a misbehaving client sent.
+// The files hold the whole envelope and body as received, personal data and any credentials
+// in the message included: the directory should be readable by the server's user only, and
+// the captures removed once the problem is understood. Nothing is written by default.
+#[derive(Debug)]
+pub struct DeadLetterCapture {
+    directory: PathBuf,
+    // captures stop once the directory holds this many, old ones are never deleted
+    max_files: usize,
+    // the body is cut after this many bytes
+    max_size: usize,
+}
+
+impl DeadLetterCapture {
+    pub fn new(directory: PathBuf, max_files: usize, max_size: usize) -> Self {
+        Self { directory, max_files, max_size }
+    }
+
+    // Returns the file written, None when the directory is full
+    pub fn capture(&self, reply: &str, envelope: &Envelope, data: &[u8]) -> io::Result<Option<PathBuf>> {
+        fs::create_dir_all(&self.directory)?;
+        let captured = fs::read_dir(&self.directory)?
+            .filter_map(Result::ok)
+            .filter(|entry| entry.file_name().to_string_lossy().starts_with(FILE_PREFIX))
+            .count();
+        if captured >= self.max_files {
+            return Ok(None);
+        }
+
+        let sender = envelope.mail_from.as_ref().map(ToString::to_string).unwrap_or_default();
+        let recipients: Vec<String> = envelope.recipient_names().iter().map(|name| format!("<{}>", name)).collect();
+        let mut content = format!("X-Dead-Letter-Reply: {}\r\nX-Envelope-From: <{}>\r\nX-Envelope-To: {}\r\n",
+            reply.trim_end(), sender, recipients.join(", ")).into_bytes();
+        content.extend_from_slice(&data[..data.len().min(self.max_size)]);
+
+        let name = format!("{}{}-{}.eml", FILE_PREFIX, Local::now().format("%Y%m%dT%H%M%S"), next_message_id());
+        let path = self.directory.join(name);
+        fs::write(&path, content)?;
+        Ok(Some(path))
+    }
+}
@@ -0,0 +1,271 @@
+use std::{collections::BTreeMap, sync::{atomic::{AtomicU8, Ordering}, Arc, Mutex}, time::Duration};
+
+use async_std::{channel::{self, Receiver}, future::timeout, task};
+use mail_database::{IMailDB, MailError};
+use relay::{error::RelayError, RelayClient};
+
+use crate::{encoded_word, envelope::{EmailAddress, Envelope}};
+
+// The mail database of a session, shared with the DatabaseDelivery backend
+pub type SharedMailDB = Arc<Mutex<Box<dyn IMailDB + Send>>>;
+
+// Runs a query on the blocking thread pool of async-std. Sessions share few executor threads,
+// one waiting for a slow database would stall every other session queued on it.
+pub(crate) async fn query_db<T, F>(db_connection: &SharedMailDB, query: F) -> T
+where
+    F: FnOnce(&mut dyn IMailDB) -> T + Send + 'static,
+    T: Send + 'static,
+{
+    let db_connection = db_connection.clone();
+    task::spawn_blocking(move || {
+        let mut db_connection = db_connection.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        query(db_connection.as_mut())
+    }).await
+}
+
+#[derive(Debug, Clone)]
+pub enum DeliveryError {
+    // the message may be accepted if the client retries later (451)
+    Transient(String),
+    // the message will never be accepted (550)
+    Permanent(String),
+}
+
+impl DeliveryError {
+    pub fn is_transient(&self) -> bool {
+        matches!(self, DeliveryError::Transient(_))
+    }
+}
+
+impl std::fmt::Display for DeliveryError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            DeliveryError::Transient(reason) => write!(f, "Temporary delivery failure: {}", reason),
+            DeliveryError::Permanent(reason) => write!(f, "Permanent delivery failure: {}", reason),
+        }
+    }
+}
+
+impl std::error::Error for DeliveryError {}
+
+impl From<MailError> for DeliveryError {
+    fn from(err: MailError) -> Self {
+        if err.is_transient() {
+            DeliveryError::Transient(err.to_string())
+        } else {
+            DeliveryError::Permanent(err.to_string())
+        }
+    }
+}
+
+impl From<RelayError> for DeliveryError {
+    fn from(err: RelayError) -> Self {
+        if err.is_permanent() {
+            DeliveryError::Permanent(err.to_string())
+        } else {
+            DeliveryError::Transient(err.to_string())
+        }
+    }
+}
+
+// Receives every message accepted at the end of DATA. The session commits its mail
+// transaction when delivery succeeds and rolls it back otherwise. Success is reported as
+// the number of recipients that got the message, which may be less than all of them.
+pub trait DeliveryBackend {
+    fn deliver(&self, envelope: &Envelope, data: &[u8]) -> Result<usize, DeliveryError>;
+}
+
+const RUNNING: u8 = 0;
+const FINISHING: u8 = 1;
+const ABANDONED: u8 = 2;
+
+// A delivery running on its own thread, so that a hung backend cannot hold the session.
+// The thread commits the mail transaction when the delivery succeeds, unless the session
+// has stopped waiting for it; it then rolls the transaction back, whatever the outcome.
+pub(crate) struct PendingDelivery {
+    state: Arc<AtomicU8>,
+    receiver: Receiver<Result<usize, DeliveryError>>,
+}
+
+impl PendingDelivery {
+    pub fn spawn(delivery: Arc<dyn DeliveryBackend + Send + Sync>, db_connection: SharedMailDB,
+        envelope: Envelope, data: Vec<u8>) -> std::io::Result<Self> {
+        let state = Arc::new(AtomicU8::new(RUNNING));
+        let (sender, receiver) = channel::bounded(1);
+        let thread_state = state.clone();
+        std::thread::Builder::new().name("delivery".to_string()).spawn(move || {
+            let result = delivery.deliver(&envelope, &data);
+            let mut db_connection = db_connection.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+            if thread_state.compare_exchange(RUNNING, FINISHING, Ordering::AcqRel, Ordering::Acquire).is_err() {
+                if let Err(err) = db_connection.rollback_transaction() {
+                    logger::warn!("Could not roll back abandoned mail transaction: {}", err);
+                }
+                return;
+            }
+            let result = result.and_then(|delivered| {
+                db_connection.commit_transaction()?;
+                Ok(delivered)
+            });
+            let _ = sender.try_send(result);
+        })?;
+        Ok(Self { state, receiver })
+    }
+
+    // None when the delivery was abandoned. Once the thread has started to commit,
+    // the session waits for it past the timeout, the client must not be told otherwise.
+    pub async fn wait(&self, limit: Duration) -> Option<Result<usize, DeliveryError>> {
+        let result = match timeout(limit, self.receiver.recv()).await {
+            Ok(result) => result,
+            Err(_) if self.abandon() => return None,
+            Err(_) => self.receiver.recv().await,
+        };
+        Some(result.unwrap_or_else(|_| Err(DeliveryError::Transient("delivery thread stopped".to_string()))))
+    }
+
+    fn abandon(&self) -> bool {
+        self.state.compare_exchange(RUNNING, ABANDONED, Ordering::AcqRel, Ordering::Acquire).is_ok()
+    }
+
+    // The thread of an abandoned delivery has rolled back its transaction
+    pub fn is_finished(&self) -> bool {
+        self.receiver.is_closed()
+    }
+}
+
+// Decides whether a recipient is a mailbox of this server. Addresses without a domain
+// name a local user directly, the others must belong to one of the local domains; without
+// local domains every domain is local.
+pub struct LocalRecipients {
+    db_connection: SharedMailDB,
+    local_domains: Vec<String>,
+}
+
+impl LocalRecipients {
+    pub fn new(db_connection: SharedMailDB, local_domains: Vec<String>) -> Self {
+        Self { db_connection, local_domains }
+    }
+
+    pub fn is_local_domain(&self, addr: &EmailAddress) -> bool {
+        match &addr.domain {
+            Some(domain) => self.local_domains.is_empty()
+                || self.local_domains.iter().any(|local| local.eq_ignore_ascii_case(domain)),
+            None => true,
+        }
+    }
+
+    pub fn is_local_recipient(&mut self, addr: &EmailAddress) -> Result<bool, MailError> {
+        if !self.is_local_domain(addr) {
+            return Ok(false);
+        }
+        let mut db_connection = self.db_connection.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        db_connection.user_exists(&addr.local_part)
+    }
+
+    // The name the recipient is stored under in the mail database
+    pub fn mailbox_name(&self, addr: &EmailAddress) -> String {
+        if addr.domain.is_some() && self.is_local_domain(addr) {
+            addr.local_part.clone()
+        } else {
+            addr.to_string()
+        }
+    }
+}
+
+// Whether each recipient got the message
+type DeliveryResults = Vec<(String, Result<(), DeliveryError>)>;
+
+// Stores messages in the mail database of the session, as the logged in user. With a relay
+// client, recipients outside the local domains get the message over SMTP instead.
+pub struct DatabaseDelivery {
+    recipients: LocalRecipients,
+    accepted_charsets: Vec<String>,
+    relay: Option<Arc<RelayClient>>,
+}
+
+impl DatabaseDelivery {
+    pub fn new(db_connection: SharedMailDB, accepted_charsets: Vec<String>, local_domains: Vec<String>,
+        relay: Option<Arc<RelayClient>>) -> Self {
+        Self {
+            recipients: LocalRecipients::new(db_connection, local_domains),
+            accepted_charsets,
+            relay,
+        }
+    }
+
+    // The body is stored as received, only the subject is decoded for display
+    fn store(&self, envelope: &Envelope, recipients: &[&EmailAddress], data: &str)
+        -> Result<DeliveryResults, DeliveryError> {
+        let subject = &data.lines()
+                        .find(|x| x.starts_with("Subject: "))
+                        .unwrap_or("Subject: No Subject")[9..];
+        let subject = encoded_word::decode_header(subject, &self.accepted_charsets);
+
+        let recipients: Vec<String> = recipients.iter()
+            .map(|recipient| self.recipients.mailbox_name(recipient))
+            .collect();
+        let mut db_connection = self.recipients.db_connection.lock()
+            .map_err(|_| DeliveryError::Transient("mail database lock poisoned".to_string()))?;
+        let sender = envelope.mail_from.as_ref().map(|sender| self.recipients.mailbox_name(sender));
+        db_connection.set_envelope_sender(sender.as_deref());
+        db_connection.set_message_id(envelope.message_id.as_deref());
+        let results = db_connection.deliver_to_recipients(
+                recipients.iter().map(|x| &x[..]).collect(),
+                &subject,
+                data
+            )?;
+        Ok(results.into_iter()
+            .map(|(recipient, result)| (recipient, result.map(|_| ()).map_err(DeliveryError::from)))
+            .collect())
+    }
+
+    // One SMTP transaction per domain, its recipients share the outcome
+    fn relay(relay: &RelayClient, envelope: &Envelope, recipients: &[&EmailAddress], data: &str)
+        -> DeliveryResults {
+        let sender = envelope.mail_from.as_ref().map(EmailAddress::to_string).unwrap_or_default();
+        let mut domains: BTreeMap<&str, Vec<String>> = BTreeMap::new();
+        for recipient in recipients {
+            domains.entry(recipient.domain.as_deref().unwrap_or_default()).or_default().push(recipient.to_string());
+        }
+        let mut results = Vec::new();
+        for (domain, recipients) in domains {
+            let result = task::block_on(relay.deliver(domain, &sender, &recipients, data)).map_err(DeliveryError::from);
+            results.extend(recipients.into_iter().map(|recipient| (recipient, result.clone())));
+        }
+        results
+    }
+}
+
+impl DeliveryBackend for DatabaseDelivery {
+    // Local recipients are stored first, a database failure then stops the delivery before
+    // anything was sent out
+    fn deliver(&self, envelope: &Envelope, data: &[u8]) -> Result<usize, DeliveryError> {
+        let data = String::from_utf8_lossy(data);
+        let (local, remote): (Vec<&EmailAddress>, Vec<&EmailAddress>) = envelope.recipients.iter()
+            .partition(|recipient| self.relay.is_none() || self.recipients.is_local_domain(recipient));
+        let mut results = match local.is_empty() {
+            true => Vec::new(),
+            false => self.store(envelope, &local, &data)?,
+        };
+        if let Some(relay) = &self.relay {
+            if !remote.is_empty() {
+                results.extend(Self::relay(relay, envelope, &remote, &data));
+            }
+        }
+
+        let mut delivered = 0;
+        let mut first_error = None;
+        for (recipient, result) in results {
+            match result {
+                Ok(_) => delivered += 1,
+                Err(err) => {
+                    logger::warn!("Could not deliver to {}: {}", recipient, err);
+                    first_error.get_or_insert(err);
+                },
+            }
+        }
+        match first_error {
+            Some(err) if delivered == 0 => Err(err),
+            _ => Ok(delivered),
+        }
+    }
+}
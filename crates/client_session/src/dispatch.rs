@@ -0,0 +1,158 @@
+use request_parser::RequestType;
+
+use crate::ClientState;
+
+// The verb of a request, without its argument
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Command {
+    Ehlo,
+    Helo,
+    StartTls,
+    Auth,
+    Register,
+    MailFrom,
+    RcptTo,
+    Data,
+    Quit,
+    Help,
+    Noop,
+    Rset,
+    Vrfy,
+    Expn,
+    Bdat,
+}
+
+impl Command {
+    pub fn of(request: &RequestType) -> Self {
+        match request {
+            RequestType::EHLO(_) => Command::Ehlo,
+            RequestType::HELO(_) => Command::Helo,
+            RequestType::STARTTLS => Command::StartTls,
+            RequestType::AUTH { .. } => Command::Auth,
+            RequestType::REGISTER(_) => Command::Register,
+            RequestType::MAIL_FROM(_) => Command::MailFrom,
+            RequestType::RCPT_TO(_) => Command::RcptTo,
+            RequestType::DATA => Command::Data,
+            RequestType::QUIT => Command::Quit,
+            RequestType::HELP => Command::Help,
+            RequestType::NOOP => Command::Noop,
+            RequestType::RSET => Command::Rset,
+            RequestType::VRFY(_) => Command::Vrfy,
+            RequestType::EXPN(_) => Command::Expn,
+            RequestType::BDAT { .. } => Command::Bdat,
+        }
+    }
+}
+
+// The session method that executes a command, see ClientSession::handle
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Handler {
+    Ehlo,
+    Helo,
+    StartTls,
+    Auth,
+    Register,
+    // MAIL FROM before AUTH, refused with 530 when authentication is required
+    UnauthenticatedMailFrom,
+    MailFrom,
+    RcptTo,
+    Data,
+    Quit,
+    Help,
+    Noop,
+    Rset,
+    Vrfy,
+    Expn,
+    Bdat,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Route {
+    Handle(Handler),
+    // the command is not accepted in this state, the reply is sent as is
+    Reject(&'static str),
+}
+
+const ERROR: &str = "500 Error\r\n";
+const BAD_SEQUENCE: &str = "503 Bad sequence of commands\r\n";
+
+const ANY_STATE: &[ClientState] = &[
+    ClientState::Connected,
+    ClientState::Ehlo,
+    ClientState::StartTLS,
+    ClientState::Auth,
+    ClientState::MailFrom,
+    ClientState::RcptTo,
+    ClientState::Chunking,
+    ClientState::Data,
+    ClientState::Quit,
+];
+
+// Every state once the client has introduced itself
+const AFTER_EHLO: &[ClientState] = &[
+    ClientState::Ehlo,
+    ClientState::StartTLS,
+    ClientState::Auth,
+    ClientState::MailFrom,
+    ClientState::RcptTo,
+    ClientState::Chunking,
+    ClientState::Data,
+];
+
+// Every (state, command) pair the session accepts, or rejects with something else than 500
+const DISPATCH_TABLE: &[(&[ClientState], Command, Route)] = &[
+    (ANY_STATE, Command::Ehlo, Route::Handle(Handler::Ehlo)),
+    (ANY_STATE, Command::Helo, Route::Handle(Handler::Helo)),
+    (ANY_STATE, Command::Quit, Route::Handle(Handler::Quit)),
+    (ANY_STATE, Command::Help, Route::Handle(Handler::Help)),
+    (ANY_STATE, Command::Noop, Route::Handle(Handler::Noop)),
+    (ANY_STATE, Command::Rset, Route::Handle(Handler::Rset)),
+    (AFTER_EHLO, Command::Vrfy, Route::Handle(Handler::Vrfy)),
+    (AFTER_EHLO, Command::Expn, Route::Handle(Handler::Expn)),
+    (&[ClientState::Ehlo], Command::StartTls, Route::Handle(Handler::StartTls)),
+    (&[ClientState::Ehlo, ClientState::StartTLS], Command::Auth, Route::Handle(Handler::Auth)),
+    (&[ClientState::Ehlo, ClientState::StartTLS], Command::Register, Route::Handle(Handler::Register)),
+    (&[ClientState::Ehlo, ClientState::StartTLS], Command::MailFrom, Route::Handle(Handler::UnauthenticatedMailFrom)),
+    (&[ClientState::Auth, ClientState::Data], Command::MailFrom, Route::Handle(Handler::MailFrom)),
+    // valid commands, but a mail transaction has to be started first
+    (&[ClientState::Auth], Command::RcptTo, Route::Reject(BAD_SEQUENCE)),
+    (&[ClientState::Auth], Command::Data, Route::Reject(BAD_SEQUENCE)),
+    (&[ClientState::MailFrom, ClientState::RcptTo], Command::RcptTo, Route::Handle(Handler::RcptTo)),
+    (&[ClientState::RcptTo], Command::Data, Route::Handle(Handler::Data)),
+    // without a transaction the chunk is still read and then refused, see handle_bdat
+    (&[ClientState::Auth, ClientState::RcptTo, ClientState::Chunking, ClientState::Data], Command::Bdat, Route::Handle(Handler::Bdat)),
+    // DATA cannot finish a message started with BDAT
+    (&[ClientState::Chunking], Command::Data, Route::Reject(BAD_SEQUENCE)),
+];
+
+pub fn route(state: ClientState, command: Command) -> Route {
+    DISPATCH_TABLE.iter()
+        .find(|(states, table_command, _)| *table_command == command && states.contains(&state))
+        .map(|(_, _, route)| *route)
+        .unwrap_or(Route::Reject(ERROR))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn route_test() {
+        assert_eq!(route(ClientState::RcptTo, Command::Data), Route::Handle(Handler::Data));
+        assert_eq!(route(ClientState::Auth, Command::MailFrom), Route::Handle(Handler::MailFrom));
+        assert_eq!(route(ClientState::Ehlo, Command::MailFrom), Route::Handle(Handler::UnauthenticatedMailFrom));
+        assert_eq!(route(ClientState::Auth, Command::Data), Route::Reject(BAD_SEQUENCE));
+        assert_eq!(route(ClientState::MailFrom, Command::Data), Route::Reject(ERROR));
+        assert_eq!(route(ClientState::Connected, Command::MailFrom), Route::Reject(ERROR));
+        assert_eq!(route(ClientState::RcptTo, Command::Vrfy), Route::Handle(Handler::Vrfy));
+        assert_eq!(route(ClientState::Connected, Command::Vrfy), Route::Reject(ERROR));
+    }
+
+    #[test]
+    fn commands_accepted_in_any_state_test() {
+        for state in ANY_STATE {
+            assert_eq!(route(*state, Command::Noop), Route::Handle(Handler::Noop));
+            assert_eq!(route(*state, Command::Ehlo), Route::Handle(Handler::Ehlo));
+        }
+    }
+}
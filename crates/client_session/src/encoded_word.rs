@@ -0,0 +1,131 @@
+// RFC 2047 encoded-words (`=?charset?B|Q?text?=`) as used in Subject and other display headers
+
+// Charsets we are able to convert to UTF-8
+const SUPPORTED_CHARSETS: [&str; 4] = ["utf-8", "us-ascii", "iso-8859-1", "latin1"];
+
+// Decodes every encoded-word in `header` whose charset is listed in `accepted_charsets`.
+// Words with other charsets or a malformed body are kept verbatim.
+pub fn decode_header(header: &str, accepted_charsets: &[String]) -> String {
+    let mut decoded = String::new();
+    let mut rest = header;
+    let mut previous_was_word = false;
+
+    while let Some(start) = rest.find("=?") {
+        let (before, candidate) = rest.split_at(start);
+        match parse_encoded_word(candidate, accepted_charsets) {
+            Some((text, length)) => {
+                // whitespace between two adjacent encoded-words is not displayed
+                if !(previous_was_word && before.trim().is_empty()) {
+                    decoded.push_str(before);
+                }
+                decoded.push_str(&text);
+                rest = &candidate[length..];
+                previous_was_word = true;
+            },
+            None => {
+                decoded.push_str(before);
+                decoded.push_str("=?");
+                rest = &candidate[2..];
+                previous_was_word = false;
+            }
+        }
+    }
+    decoded.push_str(rest);
+    decoded
+}
+
+// Returns the decoded text and the length of the encoded-word at the start of `input`
+fn parse_encoded_word(input: &str, accepted_charsets: &[String]) -> Option<(String, usize)> {
+    let inner = input.strip_prefix("=?")?;
+    let mut parts = inner.splitn(3, '?');
+    let charset = parts.next()?;
+    let encoding = parts.next()?;
+    let remainder = parts.next()?;
+    let text_end = remainder.find("?=")?;
+    let text = &remainder[..text_end];
+
+    if text.contains(char::is_whitespace) {
+        return None;
+    }
+    // RFC 2231 allows a language suffix, e.g. "UTF-8*en"
+    let charset = charset.split('*').next()?.to_ascii_lowercase();
+    if !accepted_charsets.iter().any(|accepted| accepted.eq_ignore_ascii_case(&charset)) {
+        return None;
+    }
+
+    let bytes = match encoding {
+        "B" | "b" => base64::decode_bytes(text).ok()?,
+        "Q" | "q" => decode_q(text)?,
+        _ => return None,
+    };
+    let length = input.len() - remainder.len() + text_end + 2;
+    Some((to_utf8(&charset, bytes)?, length))
+}
+
+fn decode_q(text: &str) -> Option<Vec<u8>> {
+    let mut bytes = Vec::with_capacity(text.len());
+    let mut chars = text.bytes();
+    while let Some(byte) = chars.next() {
+        match byte {
+            b'_' => bytes.push(b' '),
+            b'=' => {
+                let hex = [chars.next()?, chars.next()?];
+                bytes.push(u8::from_str_radix(std::str::from_utf8(&hex).ok()?, 16).ok()?);
+            },
+            _ => bytes.push(byte),
+        }
+    }
+    Some(bytes)
+}
+
+fn to_utf8(charset: &str, bytes: Vec<u8>) -> Option<String> {
+    match charset {
+        "utf-8" => String::from_utf8(bytes).ok(),
+        "us-ascii" if bytes.is_ascii() => String::from_utf8(bytes).ok(),
+        // ISO-8859-1 maps every byte to the code point of the same value
+        "iso-8859-1" | "latin1" => Some(bytes.into_iter().map(char::from).collect()),
+        _ => None,
+    }
+}
+
+pub fn default_charsets() -> Vec<String> {
+    SUPPORTED_CHARSETS.iter().map(|charset| charset.to_string()).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decode_base64_word_test() {
+        let subject = "=?UTF-8?B?0J/RgNC40LLRltGC?=";
+        assert_eq!(decode_header(subject, &default_charsets()), "Привіт");
+    }
+
+    #[test]
+    fn decode_quoted_printable_word_test() {
+        let subject = "=?ISO-8859-1?Q?Caf=E9_au_lait?=";
+        assert_eq!(decode_header(subject, &default_charsets()), "Café au lait");
+
+        let subject = "=?utf-8?q?Caf=C3=A9?=";
+        assert_eq!(decode_header(subject, &default_charsets()), "Café");
+    }
+
+    #[test]
+    fn decode_mixed_header_test() {
+        // whitespace between adjacent encoded-words is dropped, plain text is kept
+        let subject = "Re: =?UTF-8?Q?a?= =?UTF-8?Q?b?= and more";
+        assert_eq!(decode_header(subject, &default_charsets()), "Re: ab and more");
+    }
+
+    #[test]
+    fn keep_unaccepted_or_malformed_words_test() {
+        let accepted = vec!["utf-8".to_string()];
+        let subject = "=?ISO-8859-1?Q?Caf=E9?=";
+        assert_eq!(decode_header(subject, &accepted), subject);
+
+        assert_eq!(decode_header("=?UTF-8?X?abc?=", &accepted), "=?UTF-8?X?abc?=");
+        assert_eq!(decode_header("=?UTF-8?B?abc", &accepted), "=?UTF-8?B?abc");
+        assert_eq!(decode_header("plain subject", &accepted), "plain subject");
+    }
+}
@@ -0,0 +1,99 @@
+pub use request_parser::{DsnNotify, DsnReturn};
+
+// A mailbox from MAIL FROM or RCPT TO. Local users may be addressed by their
+// bare user name, so the domain is optional.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EmailAddress {
+    pub local_part: String,
+    pub domain: Option<String>,
+}
+
+impl EmailAddress {
+    // Splits at the last '@'; both parts must be non-empty and free of whitespace
+    pub fn parse(address: &str) -> Option<EmailAddress> {
+        if address.is_empty() || address.chars().any(char::is_whitespace) {
+            return None;
+        }
+        match address.rsplit_once('@') {
+            Some((local_part, domain)) if !local_part.is_empty() && !domain.is_empty() => Some(EmailAddress {
+                local_part: local_part.to_string(),
+                domain: Some(domain.to_string()),
+            }),
+            Some(_) => None,
+            None => Some(EmailAddress {
+                local_part: address.to_string(),
+                domain: None,
+            }),
+        }
+    }
+}
+
+impl std::fmt::Display for EmailAddress {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match &self.domain {
+            Some(domain) => write!(f, "{}@{}", self.local_part, domain),
+            None => write!(f, "{}", self.local_part),
+        }
+    }
+}
+
+// ESMTP parameters given with MAIL FROM
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct MailParams {
+    pub size: Option<usize>,
+    pub ret: Option<DsnReturn>,
+    pub envid: Option<String>,
+    pub params: Vec<(String, String)>,
+}
+
+// The state of one mail transaction, built up from MAIL FROM to DATA
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Envelope {
+    pub mail_from: Option<EmailAddress>,
+    pub recipients: Vec<EmailAddress>,
+    // the NOTIFY parameter given with each of the recipients, in the same order;
+    // nothing generates delivery status notifications from it yet
+    pub notify: Vec<Option<DsnNotify>>,
+    pub params: MailParams,
+    // given when the message data is complete, named in the Received header and the reply
+    pub message_id: Option<String>,
+}
+
+impl Envelope {
+    pub fn recipient_names(&self) -> Vec<String> {
+        self.recipients.iter().map(EmailAddress::to_string).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_address_test() {
+        assert_eq!(EmailAddress::parse("user@example.com"), Some(EmailAddress {
+            local_part: "user".to_string(),
+            domain: Some("example.com".to_string()),
+        }));
+        assert_eq!(EmailAddress::parse("user"), Some(EmailAddress {
+            local_part: "user".to_string(),
+            domain: None,
+        }));
+        assert_eq!(EmailAddress::parse("\"a@b\"@example.com").unwrap().local_part, "\"a@b\"");
+    }
+
+    #[test]
+    fn parse_invalid_address_test() {
+        assert_eq!(EmailAddress::parse(""), None);
+        assert_eq!(EmailAddress::parse("@example.com"), None);
+        assert_eq!(EmailAddress::parse("user@"), None);
+        assert_eq!(EmailAddress::parse("us er@example.com"), None);
+    }
+
+    #[test]
+    fn display_round_trip_test() {
+        for address in ["user@example.com", "user"] {
+            assert_eq!(EmailAddress::parse(address).unwrap().to_string(), address);
+        }
+    }
+}
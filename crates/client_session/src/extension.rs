@@ -0,0 +1,56 @@
+use std::{net::IpAddr, sync::Arc};
+
+// What an extension may know about the session it runs in
+#[derive(Debug, Clone)]
+pub struct ExtensionContext<'a> {
+    // empty before EHLO or HELO
+    pub ehlo_domain: &'a str,
+    // empty until AUTH succeeded
+    pub logged_user: &'a str,
+    pub encrypted: bool,
+    pub peer: Option<IpAddr>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ExtensionReply {
+    pub code: u16,
+    // without an enhanced status code, the session adds one when the client sent EHLO
+    pub text: String,
+}
+
+// An ESMTP extension added from outside the crate, like XFORWARD for a trusted proxy.
+// It only sees the command lines the parser does not recognize.
+pub trait SmtpExtension: Send + Sync {
+    // The EHLO keyword with its parameters, None advertises nothing
+    fn keyword(&self) -> Option<String>;
+
+    // The command line without CRLF; None leaves it to the next extension,
+    // and when no extension takes it the client gets 500
+    fn handle(&self, command: &str, context: &ExtensionContext) -> Option<ExtensionReply>;
+}
+
+// The extensions of every session cloned from a SessionConfig, consulted in registration order
+#[derive(Clone, Default)]
+pub struct ExtensionRegistry {
+    extensions: Vec<Arc<dyn SmtpExtension>>,
+}
+
+impl ExtensionRegistry {
+    pub fn register(&mut self, extension: Arc<dyn SmtpExtension>) {
+        self.extensions.push(extension);
+    }
+
+    pub fn keywords(&self) -> Vec<String> {
+        self.extensions.iter().filter_map(|extension| extension.keyword()).collect()
+    }
+
+    pub fn handle(&self, command: &str, context: &ExtensionContext) -> Option<ExtensionReply> {
+        self.extensions.iter().find_map(|extension| extension.handle(command, context))
+    }
+}
+
+impl std::fmt::Debug for ExtensionRegistry {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_list().entries(self.keywords()).finish()
+    }
+}
@@ -1,14 +1,40 @@
 use logger_proc_macro::log;
-use smart_stream::AsyncStream;
-use request_parser::RequestType;
-use async_native_tls::TlsAcceptor;
+use smart_stream::{error::SmartStreamError, AsyncStream, TlsAcceptor};
+use request_parser::{AuthMechanism, MailFrom, ParseError, RcptTo, RequestType};
 use mail_database::{IMailDB, PgMailDB};
-use base64::decode;
+use std::{sync::{Arc, Mutex}, time::Instant};
+use base64::{decode, decode_bytes};
+use chrono::Local;
 
 pub mod error;
 use error::ClientSessionError;
 
-#[derive(Debug)]
+pub mod config;
+pub use config::SessionConfig;
+pub use request_parser::ParseMode;
+
+pub mod delivery;
+use delivery::{query_db, DatabaseDelivery, DeliveryBackend, DeliveryError, LocalRecipients, PendingDelivery, SharedMailDB};
+
+pub mod dead_letter;
+pub mod envelope;
+use envelope::{EmailAddress, Envelope, MailParams};
+
+pub mod extension;
+use extension::{ExtensionContext, ExtensionReply};
+
+pub mod metrics;
+mod encoded_word;
+mod received;
+mod dispatch;
+mod rate_limit;
+mod status;
+pub mod tls_limit;
+use rate_limit::TokenBucket;
+use received::{next_message_id, received_header, ReceivedFrom};
+use dispatch::{Command, Handler, Route};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 enum ClientState {
     Connected,
     Ehlo,
@@ -16,283 +42,895 @@ enum ClientState {
     Auth,
     MailFrom,
     RcptTo,
+    // BDAT chunks without LAST have been received
+    Chunking,
     Data,
     Quit,
 }
 
-#[derive(Default, Debug)]
+#[derive(Default, Debug, Clone)]
 pub struct SessionData {
     logged_user: String,
-    pub mail_from: String,
-    pub rcpt_to: Vec<String>,
+    // the client's name from the last EHLO or HELO
+    pub ehlo_domain: String,
+    pub envelope: Envelope,
     pub data: String,
+    // the BDAT chunks received so far
+    pub chunks: Vec<u8>,
+    // total bytes read from the client during the whole connection
+    pub bytes_received: usize,
+}
+
+impl SessionData {
+    // Forgets the current mail transaction, keeping the login and connection counters
+    fn reset_transaction(&mut self) {
+        self.envelope = Envelope::default();
+        self.data.clear();
+        self.chunks.clear();
+    }
+}
+
+// Why the message content after DATA could not be read
+enum DataError {
+    Timeout,
+    // the client closed the connection, or it broke, before the terminating dot
+    Closed,
+    // the whole message was read, the bytes are kept for the dead letter capture
+    HeaderLineTooLong(Vec<u8>),
+    // the whole message was read, only the bytes up to the size limit are kept
+    TooLarge(Vec<u8>),
 }
 
 pub struct ClientSession {
     current_state: ClientState,
     connection: Option<AsyncStream>,
     connection_data: SessionData,
-    tls_acceptor: TlsAcceptor,
-    db_connection: PgMailDB,
+    // None when the server runs without TLS
+    tls_acceptor: Option<TlsAcceptor>,
+    db_connection: SharedMailDB,
+    delivery: Arc<dyn DeliveryBackend + Send + Sync>,
+    // a delivery that timed out, its thread may still hold the mail transaction
+    abandoned_delivery: Option<PendingDelivery>,
+    local_recipients: LocalRecipients,
+    config: SessionConfig,
+    transaction_open: bool,
+    // when MAIL FROM of the current transaction was accepted
+    transaction_started: Option<Instant>,
+    session_started: Instant,
+    command_limiter: TokenBucket,
+    // refused commands since the last one that was handled
+    bad_commands: usize,
+    // the client greeted with EHLO, so replies carry RFC 3463 codes
+    enhanced_status_codes: bool,
+    // the command being answered, a 250 to MAIL FROM and to RCPT TO have different enhanced codes
+    answering: Option<Command>,
+    // replies held back while more pipelined commands are buffered, sent in one write
+    queued_replies: Vec<u8>,
 }
 
 impl ClientSession {
     #[log(debug)]
-    pub fn new(connection: AsyncStream, tls_acceptor: &TlsAcceptor, connection_string: &str)
+    pub fn new(connection: AsyncStream, tls_acceptor: Option<&TlsAcceptor>, connection_string: &str, config: SessionConfig)
     -> Result<Self, ClientSessionError> {
         let mut pg = PgMailDB::new("localhost".to_string());
         pg.connect(connection_string)?;
         
-        Ok(Self {
+        Ok(Self::with_database(connection, tls_acceptor, Box::new(pg), config))
+    }
+
+    #[log(debug)]
+    pub fn with_database(connection: AsyncStream, tls_acceptor: Option<&TlsAcceptor>,
+        db_connection: Box<dyn IMailDB + Send>, config: SessionConfig) -> Self {
+        let db_connection: SharedMailDB = Arc::new(Mutex::new(db_connection));
+        Self {
             current_state: ClientState::Connected,
             connection: Some(connection),
             connection_data: SessionData::default(),
-            tls_acceptor: tls_acceptor.clone(),
-            db_connection: pg,
-        })
+            tls_acceptor: tls_acceptor.cloned(),
+            delivery: Arc::new(DatabaseDelivery::new(db_connection.clone(), config.accepted_charsets.clone(),
+                config.local_domains.clone(), config.relay.clone())),
+            abandoned_delivery: None,
+            local_recipients: LocalRecipients::new(db_connection.clone(), config.local_domains.clone()),
+            db_connection,
+            command_limiter: TokenBucket::new(config.command_rate, config.command_burst),
+            config,
+            transaction_open: false,
+            transaction_started: None,
+            session_started: Instant::now(),
+            bad_commands: 0,
+            enhanced_status_codes: false,
+            answering: None,
+            queued_replies: Vec::new(),
+        }
+    }
+
+    // Replaces the default delivery into the session's mail database
+    pub fn with_delivery_backend(mut self, delivery: Box<dyn DeliveryBackend + Send + Sync>) -> Self {
+        self.delivery = Arc::from(delivery);
+        self
     }
 
     #[log(trace)]
     async fn handle_new_request(&mut self) -> Result<(), ClientSessionError> {
         let connection = self.connection.as_mut().ok_or(ClientSessionError::ClosedConnection)?;
-        let raw_request = connection.read_until("\r\n").await?;
-        let request = RequestType::parse(&raw_request);
+        connection.set_deadline(Some(Instant::now() + self.config.command_deadline));
+        let raw_request = match connection.read_bytes_until_limited(b"\r\n", self.config.max_line_length).await {
+            Ok(raw_request) => raw_request,
+            Err(SmartStreamError::LineTooLong(_)) => return self.refuse_command(b"500 Line too long\r\n").await,
+            Err(SmartStreamError::Timeout(_)) => {
+                let reply = Self::reply_line(421, &format!("{} Timeout waiting for command, closing connection", self.config.hostname));
+                return self.close_with_reply(reply.as_bytes()).await;
+            },
+            Err(err) => return Err(err.into()),
+        };
+        self.connection_data.bytes_received += raw_request.len();
+        // a client that keeps sending NOOP is never idle, but still cannot hold the connection forever
+        if self.session_started.elapsed() >= self.config.max_session_duration {
+            let reply = Self::reply_line(421, &format!("{} Session time limit exceeded, closing connection", self.config.hostname));
+            return self.close_with_reply(reply.as_bytes()).await;
+        }
+        let Some(raw_request) = Self::decode_command_line(&raw_request) else {
+            return self.refuse_command(b"500 Command contains invalid characters\r\n").await;
+        };
+        let request = RequestType::parse_with_mode(&raw_request, self.config.parse_mode);
+        self.answering = request.as_ref().ok().map(Command::of);
 
         match request {
+            Ok(_) if !self.command_limiter.try_acquire() => {
+                self.close_with_reply(b"421 Too many commands, closing connection\r\n").await?;
+            },
             Ok(request) => {
-                // commands that can be executed in any state
-                if self.handle_if_loose(&request).await? {
-                    return Ok(());
-                }
-            
-                match self.current_state {
-                    ClientState::Connected => { self.handle_following_connected(&request).await?; },
-                    ClientState::Ehlo => { self.handle_following_ehlo(&request).await?; },
-                    ClientState::StartTLS => { self.handle_following_starttls(&request).await?; },
-                    ClientState::Auth => { self.handle_following_auth(&request).await?; },
-                    ClientState::MailFrom => { self.handle_following_mail_from(&request).await?; },
-                    ClientState::RcptTo => { self.handle_following_rcpt_to(&request).await?; },
-                    ClientState::Data => { self.handle_following_data(&request).await?; },
-                    ClientState::Quit => { self.handle_following_quit(&request).await?; },
+                match dispatch::route(self.current_state, Command::of(&request)) {
+                    Route::Handle(handler) => {
+                        self.bad_commands = 0;
+                        self.handle(handler, &request).await?;
+                    },
+                    Route::Reject(reply) => { self.refuse_command(reply.as_bytes()).await?; },
                 }
             },
+            // the core does not know the command, a registered extension may
+            Err(ParseError::Unrecognized) => match self.extension_reply(&raw_request) {
+                Some(_) if !self.command_limiter.try_acquire() => {
+                    self.close_with_reply(b"421 Too many commands, closing connection\r\n").await?;
+                },
+                Some(reply) => {
+                    self.bad_commands = 0;
+                    self.write_reply(reply.code, &reply.text).await?;
+                },
+                None => self.refuse_command(b"500 Syntax error, command unrecognized\r\n").await?,
+            },
             Err(err) => {
-                connection.write(format!("500 Error {}\r\n", err).as_bytes()).await?;
+                let reply = match err {
+                    // parse takes a line without CRLF as complete, so Incomplete does not happen here
+                    ParseError::Unrecognized | ParseError::Incomplete => "500 Syntax error, command unrecognized\r\n".to_string(),
+                    ParseError::LineTooLong(_) => "500 Line too long\r\n".to_string(),
+                    ParseError::NotImplemented(_) => "502 Command not implemented\r\n".to_string(),
+                    ParseError::InvalidArgument(_) => Self::reply_line(501, &format!("Syntax error in parameters or arguments: {}", err)),
+                };
+                self.refuse_command(reply.as_bytes()).await?;
             }
         }
         Ok(())
     }
-    
+
+    fn extension_reply(&self, raw_request: &str) -> Option<ExtensionReply> {
+        let connection = self.connection.as_ref()?;
+        let context = ExtensionContext {
+            ehlo_domain: &self.connection_data.ehlo_domain,
+            logged_user: &self.connection_data.logged_user,
+            encrypted: connection.is_encrypted(),
+            peer: connection.peer_addr().map(|addr| addr.ip()),
+        };
+        self.config.extensions.handle(raw_request.trim_end_matches("\r\n"), &context)
+    }
+
+    // Replies to a command that could not be parsed or is not accepted in this state. Like
+    // smtpd_hard_error_limit of Postfix, max_bad_commands of them in a row end the session.
+    async fn refuse_command(&mut self, reply: &[u8]) -> Result<(), ClientSessionError> {
+        self.bad_commands += 1;
+        if self.bad_commands >= self.config.max_bad_commands {
+            let reply = Self::reply_line(421, &format!("{} Too many errors, closing connection", self.config.hostname));
+            return self.close_with_reply(reply.as_bytes()).await;
+        }
+        self.reply(reply).await
+    }
+
+    // The verb must be 7-bit ASCII, invalid UTF-8 in the arguments is replaced
+    fn decode_command_line(raw_request: &[u8]) -> Option<String> {
+        let verb = raw_request.trim_ascii_start().split(|byte| *byte == b' ').next().unwrap_or_default();
+        if !verb.is_ascii() {
+            return None;
+        }
+        Some(String::from_utf8_lossy(raw_request).into_owned())
+    }
+
+    // Runs the handler the dispatch table chose for the request
+    #[log(trace)]
+    async fn handle(&mut self, handler: Handler, request: &RequestType) -> Result<(), ClientSessionError> {
+        match (handler, request) {
+            (Handler::Ehlo, RequestType::EHLO(domain)) => self.handle_ehlo(domain, true).await,
+            (Handler::Helo, RequestType::HELO(domain)) => self.handle_ehlo(domain, false).await,
+            (Handler::StartTls, _) => self.handle_starttls().await,
+            (Handler::Auth, RequestType::AUTH { mechanism, initial_response }) =>
+                self.handle_auth(*mechanism, initial_response.as_deref()).await,
+            (Handler::Register, _) => self.handle_register().await,
+            (Handler::UnauthenticatedMailFrom, RequestType::MAIL_FROM(mail_from)) =>
+                self.handle_unauthenticated_mail_from(mail_from).await,
+            (Handler::MailFrom, RequestType::MAIL_FROM(mail_from)) => self.handle_mail_from(mail_from).await,
+            (Handler::RcptTo, RequestType::RCPT_TO(rcpt_to)) => self.handle_rcpt_to(rcpt_to).await,
+            (Handler::Data, _) => self.handle_data().await,
+            (Handler::Bdat, RequestType::BDAT { size, last }) => self.handle_bdat(*size, *last).await,
+            (Handler::Quit, _) => self.close_with_reply(b"221 OK\r\n").await,
+            (Handler::Help, _) => self.reply(b"214 OK\r\n").await,
+            (Handler::Noop, _) => self.reply(b"250 OK\r\n").await,
+            (Handler::Rset, _) => self.handle_rset().await,
+            (Handler::Vrfy, RequestType::VRFY(address)) => self.handle_vrfy(address).await,
+            // there are no mailing lists to expand
+            (Handler::Expn, _) => self.reply(b"252 Cannot VRFY user, but will accept message\r\n").await,
+            (handler, request) => unreachable!("{:?} cannot handle {:?}", handler, request),
+        }
+    }
+
+    async fn reply(&mut self, reply: &[u8]) -> Result<(), ClientSessionError> {
+        let reply = self.with_enhanced_code(reply);
+        self.send_reply(&reply).await
+    }
+
+    // With PIPELINING the client may send a group of commands at once. Their replies are
+    // queued until no complete command is left in the buffer, then written together. A 3xx
+    // reply, like the 354 of DATA, goes out at once since the client waits for it.
+    async fn send_reply(&mut self, reply: &[u8]) -> Result<(), ClientSessionError> {
+        self.queued_replies.extend_from_slice(reply);
+        let connection = self.connection.as_mut().ok_or(ClientSessionError::ClosedConnection)?;
+        if reply.starts_with(b"3") || !connection.pending_contains(b"\r\n") {
+            connection.write(&std::mem::take(&mut self.queued_replies)).await?;
+        }
+        Ok(())
+    }
+
+    // Every reply but the greeting and the EHLO capabilities passes through here
+    fn with_enhanced_code(&self, reply: &[u8]) -> Vec<u8> {
+        self.enhanced_status_codes.then(|| status::enhance(reply, self.answering))
+            .flatten()
+            .unwrap_or_else(|| reply.to_vec())
+    }
+
+    async fn write_reply(&mut self, code: u16, text: &str) -> Result<(), ClientSessionError> {
+        self.reply(Self::reply_line(code, text).as_bytes()).await
+    }
+
+    // A single line reply. Text that comes from the client or the database may contain CR or LF,
+    // which are replaced so it cannot end the reply early and make up another one.
+    fn reply_line(code: u16, text: &str) -> String {
+        let text: String = text.chars()
+            .map(|c| if c == '\r' || c == '\n' { ' ' } else { c })
+            .collect();
+        format!("{} {}\r\n", code, text)
+    }
+
     #[log(trace)]
     pub async fn run(&mut self) -> Result<(), ClientSessionError> {
+        let metrics = self.config.metrics.clone();
+        let _active = metrics.session_started();
         let connection = self.connection.as_mut().ok_or(ClientSessionError::ClosedConnection)?;
-        connection.write(b"220 SMTP server ready\r\n").await?;
+        connection.write(Self::reply_line(220, &format!("{} SMTP server ready", self.config.hostname)).as_bytes()).await?;
+        let mut result = Ok(());
         while let Some(connection) = &self.connection {
             if !connection.is_open() {
                 break;
             }
-            self.handle_new_request().await?;
+            result = self.handle_new_request().await;
+            if result.is_err() {
+                break;
+            }
         }
-        Ok(())
+        logger::info!("Session finished: {} bytes received", self.connection_data.bytes_received);
+        // a session that failed still counts what it read
+        metrics.record_bytes_received(self.connection_data.bytes_received);
+        result
+    }
+
+    pub fn session_data(&self) -> &SessionData {
+        &self.connection_data
     }
 
+    // EHLO resets the mail transaction, but not the TLS and authentication state. HELO does the
+    // same, but its reply lists no extensions and later replies keep the plain RFC 821 codes.
     #[log(trace)]
-    async fn handle_following_connected(&mut self, _request: &RequestType) -> Result<(), ClientSessionError> {
+    async fn handle_ehlo(&mut self, domain: &str, extended: bool) -> Result<(), ClientSessionError> {
         let connection = self.connection.as_mut().ok_or(ClientSessionError::ClosedConnection)?;
-        connection.write(b"500 Error\r\n").await?;
+        self.connection_data.ehlo_domain = domain.to_string();
+        self.enhanced_status_codes = extended;
+        let encrypted = connection.is_encrypted();
+        let authenticated = !self.connection_data.logged_user.is_empty();
+        let offer_starttls = self.tls_acceptor.is_some() && !encrypted;
+        let offer_auth = !authenticated && (encrypted || !self.config.require_tls);
+        let reply = if extended {
+            Self::ehlo_reply(&self.config, offer_starttls, offer_auth)
+        } else {
+            Self::reply_line(250, &format!("{} greets you", self.config.hostname))
+        };
+        self.send_reply(reply.as_bytes()).await?;
+        self.rollback_mail_transaction().await;
+        self.current_state = if authenticated {
+            ClientState::Auth
+        } else if encrypted {
+            ClientState::StartTLS
+        } else {
+            ClientState::Ehlo
+        };
+        self.connection_data.reset_transaction();
+        Ok(())
+    }
+
+    #[log(trace)]
+    async fn handle_rset(&mut self) -> Result<(), ClientSessionError> {
+        self.reply(b"250 OK\r\n").await?;
+        self.rollback_mail_transaction().await;
+        self.current_state = ClientState::Connected;
+        self.connection_data = SessionData {
+            bytes_received: self.connection_data.bytes_received,
+            ..Default::default()
+        };
         Ok(())
     }
 
     #[log(trace)]
-    async fn handle_following_ehlo(&mut self, request: &RequestType) -> Result<(), ClientSessionError> {
+    async fn handle_starttls(&mut self) -> Result<(), ClientSessionError> {
+        let Some(tls_acceptor) = self.tls_acceptor.clone() else {
+            return self.reply(b"502 Command not implemented\r\n").await;
+        };
+        // Commands sent with STARTTLS in plaintext would otherwise be taken as sent after the
+        // handshake, letting a man in the middle inject them into the encrypted session
         let connection = self.connection.as_mut().ok_or(ClientSessionError::ClosedConnection)?;
-        match request {
-            RequestType::STARTTLS => {
-                connection.write(b"220 Ready to start TLS\r\n").await?;
-                self.current_state = ClientState::StartTLS;
+        if connection.has_pending() {
+            let discarded = connection.discard_pending();
+            logger::warn!("Refused STARTTLS followed by {} pipelined bytes", discarded);
+            return self.reply(b"503 Bad sequence of commands\r\n").await;
+        }
+        let Some(_permit) = self.config.tls_handshakes.try_acquire() else {
+            return self.reply(b"454 TLS temporarily unavailable\r\n").await;
+        };
+        self.reply(b"220 Ready to start TLS\r\n").await?;
+        self.current_state = ClientState::StartTLS;
 
-                connection.accept_tls(&self.tls_acceptor).await?;
-            },
-            _ => {
-                connection.write(b"500 Error\r\n").await?;
-            }
+        let connection = self.connection.as_mut().ok_or(ClientSessionError::ClosedConnection)?;
+        connection.accept_tls(&tls_acceptor).await?;
+        if let Some(tls_info) = connection.tls_info() {
+            logger::info!("TLS established with {}", tls_info);
         }
         Ok(())
     }
 
+    // AUTH and REGISTER are accepted on a plain connection only if TLS is not required
+    fn plaintext_auth_refused(&self) -> bool {
+        self.config.require_tls && !self.connection.as_ref().is_some_and(AsyncStream::is_encrypted)
+    }
+
+    // Only the mechanisms listed in the EHLO reply are accepted
     #[log(trace)]
-    async fn handle_following_starttls(&mut self, request: &RequestType) -> Result<(), ClientSessionError> {
-        let connection = self.connection.as_mut().ok_or(ClientSessionError::ClosedConnection)?;
-        match request {
-            RequestType::AUTH_PLAIN(cred_string) => {
-                match decode(cred_string) {
-                    Ok(cred) => {
-                        let cred: Vec<&str> = cred.split("\0").collect();
-                        let user = cred[1];
-                        let pass = cred[2];
-                        if self.db_connection.login(user, pass).is_ok() {
-                            self.current_state = ClientState::Auth;
-                            self.connection_data.logged_user = user.to_string();
-                            connection.write(b"235 OK\r\n").await?;
-                        } else {
-                            connection.write(b"500 Error user not found\r\n").await?;
-                        }
-                    },
-                    Err(_) => {
-                        connection.write(b"500 Error could not decode credentials\r\n").await?;
-                    }
-                }
-                self.current_state = ClientState::Auth;
-            },
-            RequestType::REGISTER(_) => {
-                self.current_state = ClientState::Auth;
-                connection.write(b"235 OK\r\n").await?;
-            },
-            _ => {
-                connection.write(b"500 Error\r\n").await?; 
-            }
+    async fn handle_auth(&mut self, mechanism: AuthMechanism, initial_response: Option<&str>) -> Result<(), ClientSessionError> {
+        if self.plaintext_auth_refused() {
+            return self.reply(b"530 Must issue a STARTTLS command first\r\n").await;
+        }
+        match mechanism {
+            AuthMechanism::Plain => self.handle_auth_plain(initial_response).await,
+            AuthMechanism::Login => self.handle_auth_login(initial_response).await,
+            AuthMechanism::CramMd5 => self.reply(b"504 Unrecognized authentication type\r\n").await,
         }
-        Ok(())
     }
 
     #[log(trace)]
-    async fn handle_following_auth(&mut self, request: &RequestType) -> Result<(), ClientSessionError> {
-        let connection = self.connection.as_mut().ok_or(ClientSessionError::ClosedConnection)?;
-        match request {
-            RequestType::MAIL_FROM(_) => {
-                self.current_state = ClientState::MailFrom;
-                connection.write(b"250 OK\r\n").await?;
+    async fn handle_auth_plain(&mut self, initial_response: Option<&str>) -> Result<(), ClientSessionError> {
+        let cred_string = match initial_response {
+            Some(cred_string) => cred_string.to_string(),
+            // no initial response, the credentials follow on a continuation line
+            None => match self.read_auth_response(b"334 \r\n").await? {
+                Some(response) => response,
+                None => return Ok(()),
             },
-            _ => {
-                connection.write(b"500 Error\r\n").await?;
-            },
-            
+        };
+        let Ok(cred) = decode(&cred_string) else {
+            return self.reply(b"500 Error could not decode credentials\r\n").await;
+        };
+        let [_, user, pass] = cred.split('\0').collect::<Vec<&str>>()[..] else {
+            return self.reply(b"501 Error malformed credentials\r\n").await;
+        };
+        let (user, pass) = (user.to_string(), pass.to_string());
+        let login = query_db(&self.db_connection, {
+            let user = user.clone();
+            move |db| db.login(&user, &pass)
+        }).await;
+        if login.is_ok() {
+            self.current_state = ClientState::Auth;
+            self.connection_data.logged_user = user;
+            self.reply(b"235 OK\r\n").await
+        } else {
+            self.reply(b"535 Authentication credentials invalid\r\n").await
         }
-        Ok(())
     }
 
+    // The user name and the password are requested one after the other, each base64 encoded;
+    // a user name sent along with AUTH LOGIN skips the first challenge
     #[log(trace)]
-    async fn handle_following_mail_from(&mut self, request: &RequestType) -> Result<(), ClientSessionError> {
-        let connection = self.connection.as_mut().ok_or(ClientSessionError::ClosedConnection)?;
-        match request {
-            RequestType::RCPT_TO(rcpt_to) => {
-                self.connection_data.rcpt_to.push(rcpt_to.clone());
-                self.current_state = ClientState::RcptTo;
-                connection.write(b"250 OK\r\n").await?;
+    async fn handle_auth_login(&mut self, initial_user: Option<&str>) -> Result<(), ClientSessionError> {
+        let user = match initial_user {
+            Some(user) => user.to_string(),
+            None => match self.read_auth_response(b"334 VXNlcm5hbWU6\r\n").await? {
+                Some(response) => response,
+                None => return Ok(()),
             },
-            _ => {
-                connection.write(b"500 Error\r\n").await?;
-            }
+        };
+        let Some(pass) = self.read_auth_response(b"334 UGFzc3dvcmQ6\r\n").await? else {
+            return Ok(());
+        };
+        let decode_utf8 = |encoded: &str| decode_bytes(encoded).ok().and_then(|bytes| String::from_utf8(bytes).ok());
+        let (Some(user), Some(pass)) = (decode_utf8(&user), decode_utf8(&pass)) else {
+            return self.reply(b"501 Error could not decode credentials\r\n").await;
+        };
+        let login = query_db(&self.db_connection, {
+            let user = user.clone();
+            move |db| db.login(&user, &pass)
+        }).await;
+        if login.is_ok() {
+            self.current_state = ClientState::Auth;
+            self.connection_data.logged_user = user;
+            self.reply(b"235 OK\r\n").await
+        } else {
+            self.reply(b"535 Authentication credentials invalid\r\n").await
         }
-        Ok(())
     }
 
-    #[log(trace)]
-    async fn handle_following_rcpt_to(&mut self, request: &RequestType) -> Result<(), ClientSessionError> {
+    // Sends a SASL challenge and reads the client's response line. None means the exchange
+    // is over and its reply already sent: the client canceled with "*", or the line was
+    // empty or too long.
+    async fn read_auth_response(&mut self, challenge: &[u8]) -> Result<Option<String>, ClientSessionError> {
+        self.reply(challenge).await?;
         let connection = self.connection.as_mut().ok_or(ClientSessionError::ClosedConnection)?;
-        match request {
-            RequestType::RCPT_TO(rcpt_to) => {
-                self.connection_data.rcpt_to.push(rcpt_to.clone());
-                self.current_state = ClientState::RcptTo;
-                connection.write(b"250 OK\r\n").await?;
+        connection.set_deadline(Some(Instant::now() + self.config.command_deadline));
+        let response = match connection.read_bytes_until_limited(b"\r\n", self.config.max_line_length).await {
+            Ok(response) => String::from_utf8_lossy(&response).into_owned(),
+            Err(SmartStreamError::LineTooLong(_)) => {
+                self.reply(b"500 Line too long\r\n").await?;
+                return Ok(None);
             },
-            RequestType::DATA => {
-                connection.write(b"354 End data with <CR><LF>.<CR><LF>\r\n").await?; 
-                let result = Self::read_data_until_dot(connection).await;
-
-                match result {
-                    Ok(data) => {
-                        self.connection_data.data = data;
-                        self.current_state = ClientState::Data;
-                        connection.write(b"250 OK\r\n").await?;
-  
-                        let subject = &self.connection_data.data.lines()
-                                                .find(|x| x.starts_with("Subject: "))
-                                                .unwrap_or("Subject: No Subject")[9..];
-
-                        self.db_connection.insert_multiple_emails(
-                                self.connection_data.rcpt_to.iter().map(|x| &x[..]).collect(), 
-                                subject, 
-                                &self.connection_data.data
-                            )?;
-                    },
-                    Err(err) => {
-                        connection.write([b"500 Error\r\n", err.as_bytes()].concat().as_ref()).await?;
-                    }
-                } 
+            Err(err) => return Err(err.into()),
+        };
+        self.connection_data.bytes_received += response.len();
+        match response.trim_end_matches(['\r', '\n']) {
+            "*" => {
+                self.reply(b"501 Authentication canceled\r\n").await?;
+                Ok(None)
             },
-            _ => {
-                connection.write(b"500 Error\r\n").await?;
-            }
+            "" => {
+                self.reply(b"501 Empty authentication response\r\n").await?;
+                Ok(None)
+            },
+            response => Ok(Some(response.to_string())),
         }
-        Ok(())
     }
 
     #[log(trace)]
-    async fn handle_following_data(&mut self, request: &RequestType) -> Result<(), ClientSessionError> {
-        let connection = self.connection.as_mut().ok_or(ClientSessionError::ClosedConnection)?;
-        match request {
-            RequestType::MAIL_FROM(mail_from) => {
-                self.current_state = ClientState::MailFrom;
-                self.connection_data = SessionData::default();
-                self.connection_data.rcpt_to.push(mail_from.clone());
-                connection.write(b"250 OK\r\n").await?;
+    async fn handle_register(&mut self) -> Result<(), ClientSessionError> {
+        if self.plaintext_auth_refused() {
+            return self.reply(b"530 Must issue a STARTTLS command first\r\n").await;
+        }
+        self.current_state = ClientState::Auth;
+        self.reply(b"235 OK\r\n").await
+    }
+
+    // Starts a new mail transaction, a message accepted before is forgotten. The null sender
+    // <> of bounces claims no address, so it is not checked against the authenticated user.
+    #[log(trace)]
+    async fn handle_mail_from(&mut self, mail_from: &MailFrom) -> Result<(), ClientSessionError> {
+        // a new transaction would share the database connection with the abandoned one
+        if self.abandoned_delivery.as_ref().is_some_and(|pending| !pending.is_finished()) {
+            return self.reply(b"451 Requested action aborted: previous message still being processed\r\n").await;
+        }
+        self.abandoned_delivery = None;
+        self.connection_data.reset_transaction();
+        self.current_state = ClientState::Auth;
+        let address = match mail_from.address.as_str() {
+            "" => None,
+            address => match EmailAddress::parse(address) {
+                Some(address) => Some(address),
+                None => return self.reply(b"501 Syntax error in parameters or arguments: invalid sender address\r\n").await,
             },
-            _ => {
-                connection.write(b"500 Error\r\n").await?;
+        };
+        if mail_from.size.is_some_and(|size| size > self.config.max_message_size) {
+            return self.reply(b"552 Message size exceeds fixed maximum message size\r\n").await;
+        }
+        // without required authentication an anonymous sender owns no address to check
+        let anonymous = !self.config.require_auth && self.connection_data.logged_user.is_empty();
+        if self.config.enforce_sender_ownership && !anonymous && address.is_some()
+            && !Self::is_sender_owned_by(&mail_from.address, &self.connection_data.logged_user) {
+            let text = format!("Sender address not owned by authenticated user {}", self.connection_data.logged_user);
+            return self.write_reply(553, &text).await;
+        }
+        query_db(&self.db_connection, |db| db.begin_transaction()).await?;
+        self.transaction_open = true;
+        self.transaction_started = Some(Instant::now());
+        self.connection_data.envelope.mail_from = address;
+        self.connection_data.envelope.params = MailParams {
+            size: mail_from.size,
+            ret: mail_from.ret,
+            envid: mail_from.envid.clone(),
+            params: mail_from.params.clone(),
+        };
+        self.current_state = ClientState::MailFrom;
+        self.reply(b"250 OK\r\n").await
+    }
+
+    // MAIL FROM before AUTH, accepted only when the server does not require authentication
+    #[log(trace)]
+    async fn handle_unauthenticated_mail_from(&mut self, mail_from: &MailFrom) -> Result<(), ClientSessionError> {
+        if self.config.require_auth {
+            return self.reply(b"530 Authentication required\r\n").await;
+        }
+        self.handle_mail_from(mail_from).await
+    }
+
+    // The sender is owned by the user if it is the user's address itself,
+    // or if the user name has no domain part and matches the address local part.
+    fn is_sender_owned_by(mail_from: &str, user: &str) -> bool {
+        if user.is_empty() {
+            return false;
+        }
+        if mail_from.eq_ignore_ascii_case(user) {
+            return true;
+        }
+        match mail_from.split_once('@') {
+            Some((local_part, _)) if !user.contains('@') => local_part.eq_ignore_ascii_case(user),
+            _ => false,
+        }
+    }
+
+    // Shared by the MailFrom and RcptTo states, which both accept further recipients
+    #[log(trace)]
+    async fn handle_rcpt_to(&mut self, rcpt_to: &RcptTo) -> Result<(), ClientSessionError> {
+        let Some(address) = EmailAddress::parse(&rcpt_to.address) else {
+            return self.reply(b"501 Syntax error in parameters or arguments: invalid recipient address\r\n").await;
+        };
+        // unknown local users are refused here, before the client sends the message, and only
+        // authenticated users may relay, or the server would be an open relay
+        let reply: Option<&[u8]> = if !self.local_recipients.is_local_domain(&address) {
+            match self.config.relay.is_some() && !self.connection_data.logged_user.is_empty() {
+                true => None,
+                false => Some(b"550 Relaying denied\r\n"),
+            }
+        } else {
+            let local_part = address.local_part.clone();
+            match query_db(&self.db_connection, move |db| db.user_exists(&local_part)).await {
+                Ok(true) => None,
+                Ok(false) => Some(b"550 No such user here\r\n"),
+                Err(err) => {
+                    logger::error!("Could not look up recipient: {}", err);
+                    Some(b"451 Requested action aborted: local error in processing\r\n")
+                },
             }
+        };
+        if let Some(reply) = reply {
+            return self.reply(reply).await;
         }
-        Ok(())
+        self.connection_data.envelope.recipients.push(address);
+        self.connection_data.envelope.notify.push(rcpt_to.notify);
+        self.current_state = ClientState::RcptTo;
+        self.reply(b"250 OK\r\n").await
     }
 
+    // Only tells whether a local mailbox exists, the session state is left as it is
     #[log(trace)]
-    async fn handle_following_quit(&mut self, _request: &RequestType) -> Result<(), ClientSessionError> {
-        unreachable!("Should not accept any commands after QUIT");
+    async fn handle_vrfy(&mut self, address: &str) -> Result<(), ClientSessionError> {
+        let address = address.trim();
+        let address = address.strip_prefix('<').and_then(|arg| arg.strip_suffix('>')).unwrap_or(address);
+        let Some(address) = EmailAddress::parse(address) else {
+            return self.reply(b"501 Syntax error in parameters or arguments: invalid address\r\n").await;
+        };
+        let known = if self.local_recipients.is_local_domain(&address) {
+            let local_part = address.local_part.clone();
+            query_db(&self.db_connection, move |db| db.user_exists(&local_part)).await
+        } else {
+            Ok(false)
+        };
+        match known {
+            Ok(true) => self.write_reply(250, &format!("<{}>", address)).await,
+            Ok(false) => self.reply(b"550 No such user here\r\n").await,
+            Err(err) => {
+                logger::error!("Could not look up user: {}", err);
+                self.reply(b"451 Requested action aborted: local error in processing\r\n").await
+            },
+        }
     }
 
     #[log(trace)]
-    async fn handle_if_loose(&mut self, request: &RequestType) -> Result<bool, ClientSessionError> {
+    async fn handle_data(&mut self) -> Result<(), ClientSessionError> {
+        self.reply(b"354 End data with <CR><LF>.<CR><LF>\r\n").await?;
         let connection = self.connection.as_mut().ok_or(ClientSessionError::ClosedConnection)?;
-        match request {
-            RequestType::EHLO(_) => {
-                self.current_state = ClientState::Ehlo;
-                self.connection_data = SessionData::default();
-                connection.write(b"250 OK\r\n").await?;
+        connection.set_deadline(Some(Instant::now() + self.config.data_deadline));
+        let result = Self::read_data_until_dot(connection, &mut self.connection_data.bytes_received,
+            self.config.max_message_size, self.config.max_header_line_length).await;
+
+        match result {
+            Ok(data) => self.accept_message(&data).await?,
+            // the client stopped sending in the middle of the message
+            Err(DataError::Timeout) => {
+                self.close_with_reply(b"451 Timeout waiting for data from client\r\n").await?;
             },
-            RequestType::QUIT => {
+            // nobody is left to reply to, the partial message is dropped with its transaction
+            Err(DataError::Closed) => {
+                logger::info!("Connection closed during DATA, discarding the partial message");
+                self.abort_transaction().await;
                 self.current_state = ClientState::Quit;
-                connection.write(b"221 OK\r\n").await?;
-                self.connection.take();
-                self.db_connection.disconnect();
+                self.disconnect_db().await;
+                self.connection = None;
             },
-            RequestType::HELP => {
-                connection.write(b"214 OK\r\n").await?;
+            Err(DataError::HeaderLineTooLong(data)) => {
+                let reply: &[u8] = b"552 Message header line too long\r\n";
+                self.capture_dead_letter(reply, &data);
+                self.reply(reply).await?;
+                self.abort_transaction().await;
             },
-            RequestType::NOOP => {
-                connection.write(b"250 OK\r\n").await?;
+            Err(DataError::TooLarge(data)) => {
+                let reply: &[u8] = b"552 Message size exceeds fixed maximum message size\r\n";
+                self.capture_dead_letter(reply, &data);
+                self.reply(reply).await?;
+                self.abort_transaction().await;
+            },
+        }
+        Ok(())
+    }
+
+    // Reads exactly `size` bytes, the message is complete with the LAST chunk. A chunk
+    // outside of a mail transaction, or one that grows the message beyond max_message_size,
+    // is still read so that the next command is found, and then refused.
+    #[log(trace)]
+    async fn handle_bdat(&mut self, size: usize, last: bool) -> Result<(), ClientSessionError> {
+        const READ_PIECE: usize = 64 * 1024;
+        let connection = self.connection.as_mut().ok_or(ClientSessionError::ClosedConnection)?;
+        let refusal: Option<&[u8]> = if matches!(self.current_state, ClientState::Auth | ClientState::Data) {
+            Some(b"503 Bad sequence of commands\r\n")
+        } else if self.connection_data.chunks.len() + size > self.config.max_message_size {
+            Some(b"552 Message size exceeds fixed maximum message size\r\n")
+        } else {
+            None
+        };
+        connection.set_deadline(Some(Instant::now() + self.config.data_deadline));
+        let mut remaining = size;
+        while remaining > 0 {
+            let piece = match connection.read_exact(remaining.min(READ_PIECE)).await {
+                Ok(piece) => piece,
+                Err(SmartStreamError::Timeout(_)) => {
+                    return self.close_with_reply(b"451 Timeout waiting for data from client\r\n").await;
+                },
+                Err(err) => return Err(err.into()),
+            };
+            remaining -= piece.len();
+            self.connection_data.bytes_received += piece.len();
+            if refusal.is_none() {
+                self.connection_data.chunks.extend_from_slice(&piece);
+            }
+        }
+
+        if let Some(reply) = refusal {
+            if self.transaction_open {
+                self.capture_dead_letter(reply, &self.connection_data.chunks);
+            }
+            self.reply(reply).await?;
+            self.abort_transaction().await;
+        } else if last {
+            let data = String::from_utf8_lossy(&std::mem::take(&mut self.connection_data.chunks)).into_owned();
+            self.accept_message(&data).await?;
+        } else {
+            self.current_state = ClientState::Chunking;
+            let text = format!("{} octets received", size);
+            self.write_reply(250, &text).await?;
+        }
+        Ok(())
+    }
+
+    // Stores a complete message of DATA or BDAT behind its Received header
+    async fn accept_message(&mut self, data: &str) -> Result<(), ClientSessionError> {
+        let connection = self.connection.as_mut().ok_or(ClientSessionError::ClosedConnection)?;
+        let from = ReceivedFrom {
+            ehlo_domain: &self.connection_data.ehlo_domain,
+            extended: self.enhanced_status_codes,
+            peer: connection.peer_addr().map(|addr| addr.ip()),
+            encrypted: connection.is_encrypted(),
+            authenticated: !self.connection_data.logged_user.is_empty(),
+        };
+        let message_id = next_message_id();
+        let header = received_header(&from, &self.config.hostname, &message_id, Local::now());
+        self.connection_data.data = header + data;
+        self.connection_data.envelope.message_id = Some(message_id.clone());
+        let stored = self.deliver_mail().await;
+        match stored {
+            Ok(delivered) => {
+                self.config.metrics.record_message_accepted();
+                self.transaction_open = false;
+                self.current_state = ClientState::Data;
+                let recipients = self.connection_data.envelope.recipients.len();
+                self.reply(Self::delivered_reply(delivered, recipients, &message_id).as_bytes()).await?;
             },
-            RequestType::RSET => {  
-                self.current_state = ClientState::Connected;
-                self.connection_data = SessionData::default();
-                connection.write(b"250 OK\r\n").await?;
+            Err(err) => {
+                logger::error!("Could not store mail: {}", err);
+                let reply = Self::storage_failure_reply(&err);
+                self.capture_dead_letter(reply, self.connection_data.data.as_bytes());
+                self.reply(reply).await?;
+                self.abort_transaction().await;
+            }
+        }
+        if let Some(started) = self.transaction_started.take() {
+            self.config.metrics.record_message_latency(started.elapsed());
+        }
+        Ok(())
+    }
+
+    // Hands the message to the delivery backend and commits the mail transaction on success.
+    // A delivery that outlasts delivery_timeout is abandoned and reported as a transient failure.
+    async fn deliver_mail(&mut self) -> Result<usize, DeliveryError> {
+        let pending = PendingDelivery::spawn(self.delivery.clone(), self.db_connection.clone(),
+                self.connection_data.envelope.clone(), self.connection_data.data.as_bytes().to_vec())
+            .map_err(|err| DeliveryError::Transient(format!("could not start delivery: {}", err)))?;
+        match pending.wait(self.config.delivery_timeout).await {
+            Some(result) => result,
+            None => {
+                // the delivery thread rolls the transaction back once the backend returns
+                self.transaction_open = false;
+                self.abandoned_delivery = Some(pending);
+                Err(DeliveryError::Transient(format!("no result after {:?}", self.config.delivery_timeout)))
             },
-            _ => {
-                return Ok(false);
+        }
+    }
+
+    // Keeps a copy of a refused message when the server is configured to
+    fn capture_dead_letter(&self, reply: &[u8], data: &[u8]) {
+        let Some(dead_letters) = &self.config.dead_letters else {
+            return;
+        };
+        match dead_letters.capture(&String::from_utf8_lossy(reply), &self.connection_data.envelope, data) {
+            Ok(Some(path)) => logger::info!("Refused message captured in {}", path.display()),
+            Ok(None) => logger::warn!("Dead letter directory is full, the refused message is not captured"),
+            Err(err) => logger::error!("Could not capture the refused message: {}", err),
+        }
+    }
+
+    // Names the message id, and tells the client when only some recipients got the message
+    fn delivered_reply(delivered: usize, recipients: usize, message_id: &str) -> String {
+        if delivered < recipients {
+            Self::reply_line(250, &format!("OK: queued as {}, delivered to {} of {} recipients", message_id, delivered, recipients))
+        } else {
+            Self::reply_line(250, &format!("OK: queued as {}", message_id))
+        }
+    }
+
+    // Client-safe reply for a failed DATA, the error itself is only logged
+    fn storage_failure_reply(err: &DeliveryError) -> &'static [u8] {
+        if err.is_transient() {
+            b"451 Requested action aborted: local error in processing\r\n"
+        } else {
+            b"550 Requested action not taken: mailbox unavailable\r\n"
+        }
+    }
+
+    // The EHLO keywords, without the greeting line; a new extension is one more entry
+    pub fn capabilities(config: &SessionConfig, offer_starttls: bool, offer_auth: bool) -> Vec<String> {
+        let capabilities = [
+            (offer_starttls, "STARTTLS".to_string()),
+            (offer_auth, "AUTH PLAIN LOGIN".to_string()),
+            (true, format!("SIZE {}", config.max_message_size)),
+            (true, "8BITMIME".to_string()),
+            (true, "PIPELINING".to_string()),
+            (true, "CHUNKING".to_string()),
+            (true, "DSN".to_string()),
+            (true, "ENHANCEDSTATUSCODES".to_string()),
+            (true, "HELP".to_string()),
+        ];
+        capabilities.into_iter()
+            .filter_map(|(offered, keyword)| offered.then_some(keyword))
+            .chain(config.extensions.keywords())
+            .collect()
+    }
+
+    // STARTTLS is offered only on a plain connection of a TLS enabled server, AUTH only
+    // once encrypted (unless TLS is not required) and until the client has authenticated
+    fn ehlo_reply(config: &SessionConfig, offer_starttls: bool, offer_auth: bool) -> String {
+        let capabilities = Self::capabilities(config, offer_starttls, offer_auth);
+        let mut reply = format!("250-{} greets you\r\n", config.hostname);
+        for (i, capability) in capabilities.iter().enumerate() {
+            let separator = if i + 1 == capabilities.len() { ' ' } else { '-' };
+            reply.push_str(&format!("250{}{}\r\n", separator, capability));
+        }
+        reply
+    }
+
+    // Every failed MAIL FROM, DATA or BDAT ends the mail transaction, so the next MAIL FROM
+    // starts clean without a RSET. The login is kept. A refused RCPT TO is the exception: the
+    // recipient is not added, and the client may go on with the others.
+    async fn abort_transaction(&mut self) {
+        self.rollback_mail_transaction().await;
+        self.current_state = ClientState::Auth;
+        self.connection_data.reset_transaction();
+    }
+
+    // Sends the final reply and ends the session, an unfinished mail transaction is discarded
+    async fn close_with_reply(&mut self, reply: &[u8]) -> Result<(), ClientSessionError> {
+        let mut replies = std::mem::take(&mut self.queued_replies);
+        replies.extend(self.with_enhanced_code(reply));
+        self.current_state = ClientState::Quit;
+        self.rollback_mail_transaction().await;
+        self.disconnect_db().await;
+        if let Some(mut connection) = self.connection.take() {
+            connection.write(&replies).await?;
+        }
+        Ok(())
+    }
+
+    // Discards everything stored since MAIL FROM if the mail transaction was not completed
+    async fn rollback_mail_transaction(&mut self) {
+        self.transaction_started = None;
+        if self.transaction_open {
+            self.transaction_open = false;
+            if let Err(err) = query_db(&self.db_connection, |db| db.rollback_transaction()).await {
+                logger::warn!("Could not roll back mail transaction: {}", err);
             }
         }
-        Ok(true)
     }
 
-    #[log(debug)]
-    async fn read_data_until_dot(stream: &mut AsyncStream) -> Result<String, String> {
-        const MAX_SIZE: usize = 1024 * 1024 * 2;
-        let data = stream.read_until("\r\n.\r\n").await
-            .map_err(|_| "Error on read")?;
+    // An abandoned delivery may hold the database until its backend returns; its thread then
+    // drops the last reference, which closes the connection without the session waiting for it
+    async fn disconnect_db(&mut self) {
+        if self.abandoned_delivery.as_ref().is_some_and(|pending| !pending.is_finished()) {
+            return;
+        }
+        query_db(&self.db_connection, |db| db.disconnect()).await;
+    }
 
-        if data.len() > MAX_SIZE {
-            return Err("Data size is too big".into());
+    // Reads whole lines, so the terminating dot is only recognized alone on its line. The CRLF
+    // before the dot of an empty message is the one that ended the DATA command. The message is
+    // returned without the terminator and with the leading dot of stuffed lines removed (RFC 5321
+    // 4.5.2). The header section ends with the first empty line, until then no line may exceed
+    // max_header_line. A message beyond max_size is still read up to its terminator, so that
+    // the rest of it is not taken for commands.
+    #[log(debug)]
+    async fn read_data_until_dot(stream: &mut AsyncStream, bytes_received: &mut usize, max_size: usize,
+        max_header_line: usize) -> Result<String, DataError> {
+        let mut data = Vec::new();
+        let mut in_header = true;
+        let mut header_line_too_long = false;
+        let mut too_large = false;
+        loop {
+            // a line longer than the whole message allows is dropped up to its CRLF
+            let line = match stream.read_bytes_until_limited(b"\r\n", max_size).await {
+                Ok(line) => line,
+                Err(SmartStreamError::LineTooLong(_)) => {
+                    too_large = true;
+                    continue;
+                },
+                Err(SmartStreamError::Timeout(_)) => return Err(DataError::Timeout),
+                Err(_) => return Err(DataError::Closed),
+            };
+            *bytes_received += line.len();
+            if line == b".\r\n" {
+                break;
+            }
+            if in_header {
+                in_header = line != b"\r\n";
+                header_line_too_long |= line.len().saturating_sub(2) > max_header_line;
+            }
+            let line = line.strip_prefix(b".").unwrap_or(&line);
+            too_large |= data.len() + line.len() > max_size;
+            if !too_large {
+                data.extend_from_slice(line);
+            }
         }
-        
-        Ok(data)
+        if too_large {
+            return Err(DataError::TooLarge(data));
+        }
+        if header_line_too_long {
+            return Err(DataError::HeaderLineTooLong(data));
+        }
+
+        // 8BITMIME is offered, a body that is not UTF-8 is kept like BDAT keeps it
+        Ok(String::from_utf8_lossy(&data).into_owned())
     }
 }
@@ -0,0 +1,94 @@
+use std::{sync::{atomic::{AtomicU64, AtomicUsize, Ordering}, Mutex}, time::Duration};
+
+// Running statistics of a duration, cheap enough to update on every message
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct LatencyStats {
+    pub count: u64,
+    pub total: Duration,
+    pub max: Duration,
+}
+
+impl LatencyStats {
+    fn record(&mut self, latency: Duration) {
+        self.count += 1;
+        self.total += latency;
+        self.max = self.max.max(latency);
+    }
+
+    fn merge(&mut self, other: &LatencyStats) {
+        self.count += other.count;
+        self.total += other.total;
+        self.max = self.max.max(other.max);
+    }
+
+    pub fn average(&self) -> Option<Duration> {
+        (self.count > 0).then(|| self.total / self.count as u32)
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct MetricsSnapshot {
+    // from MAIL FROM to the final reply of DATA, failed deliveries included
+    pub message_latency: LatencyStats,
+    pub active_sessions: usize,
+    // of finished sessions
+    pub bytes_received: u64,
+    // messages stored or handed to the delivery backend
+    pub messages_accepted: u64,
+}
+
+// Collects statistics of all sessions sharing it through their SessionConfig
+#[derive(Debug, Default)]
+pub struct MetricsCollector {
+    message_latency: Mutex<LatencyStats>,
+    active_sessions: AtomicUsize,
+    bytes_received: AtomicU64,
+    messages_accepted: AtomicU64,
+}
+
+impl MetricsCollector {
+    pub fn record_message_latency(&self, latency: Duration) {
+        self.message_latency.lock().unwrap_or_else(|poisoned| poisoned.into_inner()).record(latency);
+    }
+
+    pub fn record_bytes_received(&self, bytes: usize) {
+        self.bytes_received.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    pub fn record_message_accepted(&self) {
+        self.messages_accepted.fetch_add(1, Ordering::Relaxed);
+    }
+
+    // Adds the cumulative totals of a snapshot taken by a previous process,
+    // its active sessions are gone and not counted
+    pub fn restore(&self, totals: &MetricsSnapshot) {
+        self.message_latency.lock().unwrap_or_else(|poisoned| poisoned.into_inner()).merge(&totals.message_latency);
+        self.bytes_received.fetch_add(totals.bytes_received, Ordering::Relaxed);
+        self.messages_accepted.fetch_add(totals.messages_accepted, Ordering::Relaxed);
+    }
+
+    // Counts the session as active until the returned guard is dropped
+    pub fn session_started(&self) -> ActiveSession<'_> {
+        self.active_sessions.fetch_add(1, Ordering::Relaxed);
+        ActiveSession { metrics: self }
+    }
+
+    pub fn snapshot(&self) -> MetricsSnapshot {
+        MetricsSnapshot {
+            message_latency: *self.message_latency.lock().unwrap_or_else(|poisoned| poisoned.into_inner()),
+            active_sessions: self.active_sessions.load(Ordering::Relaxed),
+            bytes_received: self.bytes_received.load(Ordering::Relaxed),
+            messages_accepted: self.messages_accepted.load(Ordering::Relaxed),
+        }
+    }
+}
+
+pub struct ActiveSession<'a> {
+    metrics: &'a MetricsCollector,
+}
+
+impl Drop for ActiveSession<'_> {
+    fn drop(&mut self) {
+        self.metrics.active_sessions.fetch_sub(1, Ordering::Relaxed);
+    }
+}
@@ -0,0 +1,84 @@
+use std::{
+    net::IpAddr,
+    sync::atomic::{AtomicU32, Ordering},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use chrono::{DateTime, Local};
+
+// What the Received header says about the client and its session
+pub struct ReceivedFrom<'a> {
+    pub ehlo_domain: &'a str,
+    // greeted with EHLO rather than HELO
+    pub extended: bool,
+    pub peer: Option<IpAddr>,
+    pub encrypted: bool,
+    pub authenticated: bool,
+}
+
+// Unique within this process: the start time in seconds followed by a counter
+pub fn next_message_id() -> String {
+    static COUNTER: AtomicU32 = AtomicU32::new(0);
+    let seconds = SystemTime::now().duration_since(UNIX_EPOCH).map(|time| time.as_secs()).unwrap_or(0);
+    format!("{:X}{:06X}", seconds, COUNTER.fetch_add(1, Ordering::Relaxed))
+}
+
+// The trace header of RFC 5321 section 4.4, folded so no line gets too long.
+// The protocol follows RFC 3848: SMTP after HELO, otherwise ESMTP, plus S when encrypted
+// and A when authenticated.
+pub fn received_header(from: &ReceivedFrom, hostname: &str, id: &str, date: DateTime<Local>) -> String {
+    let peer = match from.peer {
+        Some(ip) => format!(" ([{}])", ip),
+        None => String::new(),
+    };
+    let protocol = match (from.encrypted, from.authenticated) {
+        _ if !from.extended => "SMTP",
+        (false, false) => "ESMTP",
+        (true, false) => "ESMTPS",
+        (false, true) => "ESMTPA",
+        (true, true) => "ESMTPSA",
+    };
+    format!("Received: from {}{}\r\n\tby {} with {} id {};\r\n\t{}\r\n",
+        from.ehlo_domain, peer, hostname, protocol, id, date.format("%a, %d %b %Y %H:%M:%S %z"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    #[test]
+    fn received_header_test() {
+        let from = ReceivedFrom {
+            ehlo_domain: "client.example.com",
+            extended: true,
+            peer: Some("192.0.2.7".parse().unwrap()),
+            encrypted: true,
+            authenticated: true,
+        };
+        let date = Local.with_ymd_and_hms(2024, 3, 5, 14, 7, 9).unwrap();
+        let header = received_header(&from, "mx.example.org", "ABC123", date);
+        let expected_date = date.format("%a, %d %b %Y %H:%M:%S %z").to_string();
+        assert_eq!(header, format!("Received: from client.example.com ([192.0.2.7])\r\n\
+            \tby mx.example.org with ESMTPSA id ABC123;\r\n\t{}\r\n", expected_date));
+        assert!(expected_date.starts_with("Tue, 05 Mar 2024 14:07:09 "));
+    }
+
+    #[test]
+    fn received_header_after_helo_test() {
+        let from = ReceivedFrom {
+            ehlo_domain: "client.example.com",
+            extended: false,
+            peer: None,
+            encrypted: false,
+            authenticated: false,
+        };
+        let header = received_header(&from, "mx.example.org", "ABC123", Local::now());
+        assert!(header.starts_with("Received: from client.example.com\r\n\tby mx.example.org with SMTP id ABC123;\r\n"), "{}", header);
+    }
+
+    #[test]
+    fn message_ids_are_unique_test() {
+        assert_ne!(next_message_id(), next_message_id());
+    }
+}
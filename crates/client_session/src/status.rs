@@ -0,0 +1,63 @@
+use crate::dispatch::Command;
+
+// Replies whose code alone does not tell what happened, recognized by a part of their text
+const BY_TEXT: &[(u16, &str, &str)] = &[
+    (421, "Timeout", "4.4.2"),
+    (451, "Timeout", "4.4.2"),
+    (550, "No such user", "5.1.1"),
+    (550, "Relaying denied", "5.7.1"),
+    (550, "mailbox unavailable", "5.2.0"),
+];
+
+// The RFC 3463 code of a reply, a 250 also depends on the command it answers
+pub fn enhanced_code(code: u16, text: &str, command: Option<Command>) -> &'static str {
+    if let Some((_, _, enhanced)) = BY_TEXT.iter().find(|(by_code, part, _)| *by_code == code && text.contains(part)) {
+        return enhanced;
+    }
+    match (code, command) {
+        (250, Some(Command::MailFrom)) => "2.1.0",
+        (250, Some(Command::RcptTo | Command::Vrfy)) => "2.1.5",
+        (235, _) => "2.7.0",
+        (421 | 454, _) => "4.7.0",
+        (451, _) => "4.3.0",
+        (500, _) => "5.5.2",
+        (501, _) => "5.5.4",
+        (502 | 503, _) => "5.5.1",
+        (504, _) => "5.5.4",
+        (530, _) => "5.7.0",
+        (535, _) => "5.7.8",
+        (552, _) => "5.3.4",
+        (553, _) => "5.7.1",
+        (200..=299, _) => "2.0.0",
+        (400..=499, _) => "4.0.0",
+        _ => "5.0.0",
+    }
+}
+
+// Puts the enhanced code between the reply code and the text. Intermediate replies, like the
+// 354 of DATA or the 334 challenges of AUTH, have none and are left as they are.
+pub fn enhance(reply: &[u8], command: Option<Command>) -> Option<Vec<u8>> {
+    let (code, text) = std::str::from_utf8(reply).ok()?.split_once(' ')?;
+    let code: u16 = code.parse().ok()?;
+    if !matches!(code / 100, 2 | 4 | 5) {
+        return None;
+    }
+    Some(format!("{} {} {}", code, enhanced_code(code, text, command), text).into_bytes())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn enhance_test() {
+        assert_eq!(enhance(b"250 OK\r\n", Some(Command::MailFrom)), Some(b"250 2.1.0 OK\r\n".to_vec()));
+        assert_eq!(enhance(b"250 OK\r\n", Some(Command::RcptTo)), Some(b"250 2.1.5 OK\r\n".to_vec()));
+        assert_eq!(enhance(b"250 OK\r\n", Some(Command::Data)), Some(b"250 2.0.0 OK\r\n".to_vec()));
+        assert_eq!(enhance(b"550 No such user here\r\n", Some(Command::RcptTo)), Some(b"550 5.1.1 No such user here\r\n".to_vec()));
+        assert_eq!(enhance(b"421 localhost Timeout waiting for command\r\n", None),
+            Some(b"421 4.4.2 localhost Timeout waiting for command\r\n".to_vec()));
+        assert_eq!(enhance(b"354 End data with <CR><LF>.<CR><LF>\r\n", Some(Command::Data)), None);
+        assert_eq!(enhance(b"334 \r\n", Some(Command::Auth)), None);
+    }
+}
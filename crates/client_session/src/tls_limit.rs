@@ -0,0 +1,44 @@
+use std::sync::{atomic::{AtomicUsize, Ordering}, Arc};
+
+// Counting semaphore bounding the TLS handshakes in progress across all sessions
+#[derive(Debug)]
+pub struct TlsHandshakeLimiter {
+    limit: usize,
+    in_progress: AtomicUsize,
+}
+
+// Releases the handshake slot when dropped
+#[derive(Debug)]
+pub struct TlsHandshakePermit {
+    limiter: Arc<TlsHandshakeLimiter>,
+}
+
+impl TlsHandshakeLimiter {
+    pub fn new(limit: usize) -> Self {
+        Self {
+            limit,
+            in_progress: AtomicUsize::new(0),
+        }
+    }
+
+    pub fn try_acquire(self: &Arc<Self>) -> Option<TlsHandshakePermit> {
+        self.in_progress
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |count| (count < self.limit).then_some(count + 1))
+            .ok()
+            .map(|_| TlsHandshakePermit { limiter: self.clone() })
+    }
+
+    pub fn limit(&self) -> usize {
+        self.limit
+    }
+
+    pub fn in_progress(&self) -> usize {
+        self.in_progress.load(Ordering::Acquire)
+    }
+}
+
+impl Drop for TlsHandshakePermit {
+    fn drop(&mut self) {
+        self.limiter.in_progress.fetch_sub(1, Ordering::AcqRel);
+    }
+}
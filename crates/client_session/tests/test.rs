@@ -0,0 +1,1456 @@
+mod utils;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use utils::*;
+    use client_session::{ParseMode, SessionConfig};
+    use concurrent_runtime::ConcurrentRuntime;
+    use client_session::{
+        dead_letter::DeadLetterCapture,
+        delivery::LocalRecipients,
+        envelope::{DsnNotify, DsnReturn, EmailAddress, Envelope, MailParams},
+        extension::{ExtensionContext, ExtensionReply, SmtpExtension},
+        tls_limit::TlsHandshakeLimiter,
+    };
+    use mail_database::IMailDB;
+    use relay::RelayClient;
+    use std::sync::Mutex;
+    use std::sync::Arc;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn mail_from_matching_logged_user_test() {
+        let db = MemoryMailDB::with_users(&[("user1", "password"), ("user2", "password")]);
+        let mut client = TestClient::start(db.clone(), SessionConfig::default());
+
+        client.login("user1", "password");
+        assert!(client.send("MAIL FROM:<user1@example.com>").starts_with("250"));
+        assert!(client.send("RCPT TO:<user2>").starts_with("250"));
+        assert!(client.send("DATA").starts_with("354"));
+        assert!(client.send("Subject: Hello\r\n\r\nBody\r\n.").starts_with("250"));
+        assert!(client.quit().is_ok());
+
+        let mails = db.mails();
+        assert_eq!(mails.len(), 1);
+        assert_eq!(mails[0].sender, "user1");
+        assert_eq!(mails[0].receivers, vec!["user2".to_string()]);
+        assert_eq!(mails[0].subject, "Hello");
+        assert!(mails[0].body.contains("Body"));
+    }
+
+    #[test]
+    fn envelope_sender_stored_test() {
+        let db = MemoryMailDB::with_users(&[("user1", "password"), ("user2", "password")]);
+        let config = SessionConfig {
+            enforce_sender_ownership: false,
+            ..Default::default()
+        };
+        let mut client = TestClient::start(db.clone(), config);
+
+        client.login("user1", "password");
+        assert!(client.send("MAIL FROM:<user2>").starts_with("250"));
+        assert!(client.send("RCPT TO:<user1>").starts_with("250"));
+        assert!(client.send("DATA").starts_with("354"));
+        assert!(client.send("Subject: Hello\r\n\r\nBody\r\n.").starts_with("250"));
+        assert!(client.quit().is_ok());
+
+        // the MAIL FROM address, not the authenticated user
+        let mails = db.mails();
+        assert_eq!(mails.len(), 1);
+        assert_eq!(mails[0].sender, "user2");
+    }
+
+    #[test]
+    fn mail_from_not_owned_by_logged_user_test() {
+        let db = MemoryMailDB::with_users(&[("user1", "password")]);
+        let mut client = TestClient::start(db, SessionConfig::default());
+
+        client.login("user1", "password");
+        let reply = client.send("MAIL FROM:<user2@example.com>");
+        assert!(reply.starts_with("553"), "unexpected reply: {}", reply);
+        assert!(client.send("MAIL FROM:<USER1@example.com>").starts_with("250"));
+        assert!(client.quit().is_ok());
+    }
+
+    #[test]
+    fn mail_from_ownership_not_enforced_test() {
+        let db = MemoryMailDB::with_users(&[("user1", "password")]);
+        let config = SessionConfig {
+            enforce_sender_ownership: false,
+            ..Default::default()
+        };
+        let mut client = TestClient::start(db, config);
+
+        client.login("user1", "password");
+        assert!(client.send("MAIL FROM:<user2@example.com>").starts_with("250"));
+        assert!(client.quit().is_ok());
+    }
+
+    #[test]
+    fn failed_auth_does_not_allow_mail_from_test() {
+        let db = MemoryMailDB::with_users(&[("user1", "password")]);
+        let mut client = TestClient::start(db, SessionConfig::default());
+
+        assert!(client.send("EHLO client.example.com").starts_with("250"));
+        client.starttls();
+        assert!(client.auth_plain("user1", "wrong").starts_with("535 5.7.8 "));
+        assert!(client.auth_plain("nobody", "password").starts_with("535 5.7.8 "));
+        assert!(!client.send("MAIL FROM:<user1@example.com>").starts_with("250"));
+        assert!(client.quit().is_ok());
+    }
+
+    #[test]
+    fn pipelined_starttls_refused_test() {
+        let db = MemoryMailDB::with_users(&[("user1", "password")]);
+        let mut client = TestClient::start(db, SessionConfig::default());
+
+        assert!(client.send("EHLO client.example.com").starts_with("250"));
+        client.write("STARTTLS\r\nMAIL FROM:<user1@example.com>\r\n");
+        let reply = client.read_reply();
+        assert!(reply.starts_with("503"), "unexpected reply: {}", reply);
+
+        // the injected command is dropped, the connection stays usable in plaintext
+        let reply = client.send("NOOP");
+        assert!(reply.starts_with("250"), "unexpected reply: {}", reply);
+        client.starttls();
+        assert!(client.auth_plain("user1", "password").starts_with("235"));
+        assert!(client.quit().is_ok());
+    }
+
+    #[test]
+    fn pipelined_commands_answered_in_order_test() {
+        let db = MemoryMailDB::with_users(&[("user1", "password"), ("user2", "password")]);
+        let mut client = TestClient::start(db.clone(), SessionConfig::default());
+
+        client.login("user1", "password");
+        client.write("MAIL FROM:<user1@example.com>\r\nRCPT TO:<user2>\r\nDATA\r\n");
+        let mut replies = String::new();
+        while replies.lines().count() < 3 {
+            replies.push_str(&client.read_reply());
+        }
+        assert_eq!(replies, "250 2.1.0 OK\r\n250 2.1.5 OK\r\n354 End data with <CR><LF>.<CR><LF>\r\n");
+
+        assert!(client.send("Subject: Hello\r\n\r\nBody\r\n.").starts_with("250"));
+        assert!(client.quit().is_ok());
+        assert_eq!(db.mails().len(), 1);
+    }
+
+    #[test]
+    fn rset_rolls_back_mail_transaction_test() {
+        let db = MemoryMailDB::with_users(&[("user1", "password"), ("user2", "password")]);
+        let mut client = TestClient::start(db.clone(), SessionConfig::default());
+
+        client.login("user1", "password");
+        assert!(client.send("MAIL FROM:<user1@example.com>").starts_with("250"));
+        assert!(client.send("RCPT TO:<user2>").starts_with("250"));
+        assert!(client.send("RSET").starts_with("250"));
+        assert!(client.quit().is_ok());
+
+        let state = db.state.lock().unwrap();
+        assert!(state.mails.is_empty());
+        assert_eq!(state.rollbacks, 1);
+        assert_eq!(state.commits, 0);
+    }
+
+    #[test]
+    fn delivered_mail_commits_transaction_test() {
+        let db = MemoryMailDB::with_users(&[("user1", "password"), ("user2", "password")]);
+        let mut client = TestClient::start(db.clone(), SessionConfig::default());
+
+        client.login("user1", "password");
+        assert!(client.send("MAIL FROM:<user1@example.com>").starts_with("250"));
+        assert!(client.send("RCPT TO:<user2>").starts_with("250"));
+        assert!(client.send("DATA").starts_with("354"));
+        assert!(client.send("Subject: Hello\r\n\r\nBody\r\n.").starts_with("250"));
+        assert!(client.quit().is_ok());
+
+        let state = db.state.lock().unwrap();
+        assert_eq!(state.mails.len(), 1);
+        assert_eq!(state.commits, 1);
+        assert_eq!(state.rollbacks, 0);
+    }
+
+    #[test]
+    fn ehlo_capabilities_depend_on_session_state_test() {
+        let db = MemoryMailDB::with_users(&[("user1", "password")]);
+        let mut client = TestClient::start(db, SessionConfig::default());
+
+        let reply = client.send("EHLO client.example.com");
+        assert!(reply.starts_with("250-localhost greets you\r\n"), "unexpected reply: {}", reply);
+        assert!(reply.contains("250-STARTTLS\r\n"), "unexpected reply: {}", reply);
+        assert!(reply.contains("250-SIZE 10485760\r\n"), "unexpected reply: {}", reply);
+        assert!(reply.contains("250-8BITMIME\r\n"), "unexpected reply: {}", reply);
+        assert!(reply.contains("250-PIPELINING\r\n"), "unexpected reply: {}", reply);
+        assert!(!reply.contains("AUTH"), "unexpected reply: {}", reply);
+
+        client.starttls();
+        let reply = client.send("EHLO client.example.com");
+        assert!(!reply.contains("STARTTLS"), "unexpected reply: {}", reply);
+        assert!(reply.contains("250-AUTH PLAIN LOGIN\r\n"), "unexpected reply: {}", reply);
+
+        assert!(client.auth_plain("user1", "password").starts_with("235"));
+        let reply = client.send("EHLO client.example.com");
+        assert!(!reply.contains("STARTTLS"), "unexpected reply: {}", reply);
+        assert!(!reply.contains("AUTH"), "unexpected reply: {}", reply);
+        assert!(reply.ends_with("250 HELP\r\n"), "unexpected reply: {}", reply);
+
+        // authentication survives the repeated EHLO
+        assert!(client.send("MAIL FROM:<user1@example.com>").starts_with("250"));
+        assert!(client.quit().is_ok());
+    }
+
+    #[test]
+    fn bytes_received_test() {
+        let db = MemoryMailDB::with_users(&[("user1", "password"), ("user2", "password")]);
+        let mut client = TestClient::start(db, SessionConfig::default());
+
+        client.login("user1", "password");
+        assert!(client.send("MAIL FROM:<user1@example.com>").starts_with("250"));
+        assert!(client.send("RCPT TO:<user2>").starts_with("250"));
+        assert!(client.send("DATA").starts_with("354"));
+
+        let message = format!("Subject: Sizes\r\n\r\n{}\r\n.\r\n", "x".repeat(4000));
+        let before_data = client.bytes_sent;
+        client.write(&message);
+        assert!(client.read_reply().starts_with("250"));
+        assert_eq!(client.bytes_sent - before_data, message.len());
+
+        let expected = client.bytes_sent + "QUIT\r\n".len();
+        let (result, session_data) = client.finish();
+        assert!(result.is_ok());
+        assert_eq!(session_data.bytes_received, expected);
+    }
+
+    #[test]
+    fn command_rate_limit_test() {
+        let db = MemoryMailDB::with_users(&[("user1", "password")]);
+        let config = SessionConfig {
+            command_rate: 1.0,
+            command_burst: 5,
+            ..Default::default()
+        };
+        let mut client = TestClient::start(db, config);
+
+        for _ in 0..5 {
+            assert!(client.send("NOOP").starts_with("250"));
+        }
+        let reply = client.send("NOOP");
+        assert!(reply.starts_with("421"), "unexpected reply: {}", reply);
+        assert!(client.join().is_ok());
+    }
+
+    #[test]
+    fn command_rate_limit_ignores_data_test() {
+        let db = MemoryMailDB::with_users(&[("user1", "password"), ("user2", "password")]);
+        let config = SessionConfig {
+            command_rate: 0.1,
+            command_burst: 9,
+            ..Default::default()
+        };
+        let mut client = TestClient::start(db.clone(), config);
+
+        // EHLO, STARTTLS, AUTH, MAIL, RCPT, DATA and QUIT use 7 tokens, body lines use none
+        client.login("user1", "password");
+        assert!(client.send("MAIL FROM:<user1@example.com>").starts_with("250"));
+        assert!(client.send("RCPT TO:<user2>").starts_with("250"));
+        assert!(client.send("DATA").starts_with("354"));
+        let body: String = (0..100).map(|i| format!("line {}\r\n", i)).collect();
+        assert!(client.send(&format!("Subject: Many lines\r\n\r\n{}.", body)).starts_with("250"));
+        assert!(client.quit().is_ok());
+        assert_eq!(db.mails().len(), 1);
+    }
+
+    #[test]
+    fn transient_storage_failure_test() {
+        let db = MemoryMailDB::with_users(&[("user1", "password"), ("user2", "password")]);
+        db.state.lock().unwrap().fail_next_insert = true;
+        let mut client = TestClient::start(db.clone(), SessionConfig::default());
+
+        client.login("user1", "password");
+        assert!(client.send("MAIL FROM:<user1@example.com>").starts_with("250"));
+        assert!(client.send("RCPT TO:<user2>").starts_with("250"));
+        assert!(client.send("DATA").starts_with("354"));
+        let reply = client.send("Subject: Hello\r\n\r\nBody\r\n.");
+        assert_eq!(reply, "451 4.3.0 Requested action aborted: local error in processing\r\n");
+
+        // the session stays usable and the retry succeeds
+        assert!(client.send("MAIL FROM:<user1@example.com>").starts_with("250"));
+        assert!(client.send("RCPT TO:<user2>").starts_with("250"));
+        assert!(client.send("DATA").starts_with("354"));
+        assert!(client.send("Subject: Hello\r\n\r\nBody\r\n.").starts_with("250"));
+        assert!(client.quit().is_ok());
+        assert_eq!(db.mails().len(), 1);
+    }
+
+    #[test]
+    fn unknown_recipient_refused_at_rcpt_test() {
+        let db = MemoryMailDB::with_users(&[("user1", "password"), ("user2", "password")]);
+        let mut client = TestClient::start(db.clone(), SessionConfig::default());
+
+        client.login("user1", "password");
+        assert!(client.send("MAIL FROM:<user1@example.com>").starts_with("250"));
+        assert_eq!(client.send("RCPT TO:<nobody>"), "550 5.1.1 No such user here\r\n");
+        assert!(client.send("RCPT TO:<user2>").starts_with("250"));
+        assert_eq!(client.send("RCPT TO:<user3>"), "550 5.1.1 No such user here\r\n");
+        assert!(client.send("DATA").starts_with("354"));
+        let reply = client.send("Subject: Hello\r\n\r\nBody\r\n.");
+        assert!(reply.starts_with("250") && !reply.contains("delivered to"), "unexpected reply: {}", reply);
+        assert!(client.quit().is_ok());
+
+        let mails = db.mails();
+        assert_eq!(mails.len(), 1);
+        assert_eq!(mails[0].receivers, ["user2"]);
+    }
+
+    #[test]
+    fn every_domain_local_by_default_test() {
+        let db = MemoryMailDB::with_users(&[("user1", "password"), ("user2", "password")]);
+        let mut client = TestClient::start(db.clone(), SessionConfig::default());
+
+        // without local domains the user is looked up whatever the domain
+        client.login("user1", "password");
+        assert!(client.send("MAIL FROM:<user1@example.com>").starts_with("250"));
+        assert!(client.send("RCPT TO:<user2@example.org>").starts_with("250"));
+        assert_eq!(client.send("RCPT TO:<nobody@example.org>"), "550 5.1.1 No such user here\r\n");
+        assert!(client.send("DATA").starts_with("354"));
+        let reply = client.send("Subject: Hello\r\n\r\nBody\r\n.");
+        assert!(reply.starts_with("250") && !reply.contains("delivered to"), "unexpected reply: {}", reply);
+        assert!(client.quit().is_ok());
+
+        let mails = db.mails();
+        assert_eq!(mails.len(), 1);
+        assert_eq!(mails[0].receivers, ["user2"]);
+    }
+
+    #[test]
+    fn remote_recipients_relayed_test() {
+        let (port, server) = fake_smtp_server();
+        let db = MemoryMailDB::with_users(&[("user1", "password"), ("user2", "password")]);
+        let relay = RelayClient::new(Box::new(LoopbackResolver), "mx.example.com", port, 5);
+        let config = SessionConfig {
+            local_domains: vec!["example.com".to_string()],
+            relay: Some(Arc::new(relay)),
+            ..SessionConfig::default()
+        };
+        let mut client = TestClient::start(db.clone(), config);
+
+        client.login("user1", "password");
+        assert!(client.send("MAIL FROM:<user1@example.com>").starts_with("250"));
+        assert!(client.send("RCPT TO:<user2@example.com>").starts_with("250"));
+        assert!(client.send("RCPT TO:<nobody@example.com>").starts_with("550"));
+        assert!(client.send("RCPT TO:<user3@example.org>").starts_with("250"));
+        assert!(client.send("DATA").starts_with("354"));
+        let reply = client.send("Subject: Relayed\r\n\r\n..dot\r\n.");
+        assert!(reply.starts_with("250") && !reply.contains("delivered to"), "unexpected reply: {}", reply);
+        assert!(client.quit().is_ok());
+
+        // the local recipient has the message in the database, the remote one got it over SMTP
+        let mails = db.mails();
+        assert_eq!(mails.len(), 1);
+        assert_eq!(mails[0].receivers, ["user2"]);
+        let lines = server.join().unwrap();
+        assert_eq!(lines[1..3], ["MAIL FROM:<user1@example.com>", "RCPT TO:<user3@example.org>"]);
+        assert_eq!(lines[lines.len() - 5..], ["Subject: Relayed", "", "..dot", ".", "QUIT"]);
+    }
+
+    #[test]
+    fn unauthenticated_relaying_denied_test() {
+        let db = MemoryMailDB::with_users(&[("user1", "password")]);
+        let relay = RelayClient::new(Box::new(LoopbackResolver), "mx.example.com", 1, 5);
+        let config = SessionConfig {
+            require_auth: false,
+            local_domains: vec!["example.com".to_string()],
+            relay: Some(Arc::new(relay)),
+            ..SessionConfig::default()
+        };
+        let mut client = TestClient::start(db, config);
+
+        assert!(client.send("EHLO client.example.com").starts_with("250"));
+        assert!(client.send("MAIL FROM:<someone@example.net>").starts_with("250"));
+        assert_eq!(client.send("RCPT TO:<user3@example.org>"), "550 5.7.1 Relaying denied\r\n");
+        assert!(client.send("RCPT TO:<user1@example.com>").starts_with("250"));
+        assert!(client.quit().is_ok());
+    }
+
+    #[test]
+    fn permanent_storage_failure_test() {
+        let db = MemoryMailDB::with_users(&[("user1", "password"), ("user2", "password")]);
+        let mut client = TestClient::start(db.clone(), SessionConfig::default());
+
+        client.login("user1", "password");
+        assert!(client.send("MAIL FROM:<user1@example.com>").starts_with("250"));
+        assert!(client.send("RCPT TO:<user2@example.org>").starts_with("250"));
+        // the recipient is gone by the time the message is stored
+        db.state.lock().unwrap().users.remove("user2");
+        assert!(client.send("DATA").starts_with("354"));
+        let reply = client.send("Subject: Hello\r\n\r\nBody\r\n.");
+        assert_eq!(reply, "550 5.2.0 Requested action not taken: mailbox unavailable\r\n");
+        assert!(client.quit().is_ok());
+        assert!(db.mails().is_empty());
+    }
+
+    #[test]
+    fn refused_message_captured_test() {
+        let directory = std::env::temp_dir().join(format!("smtp-dead-letters-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&directory);
+        let db = MemoryMailDB::with_users(&[("user1", "password"), ("user2", "password")]);
+        db.state.lock().unwrap().fail_next_insert = true;
+        let config = SessionConfig {
+            max_message_size: 100,
+            dead_letters: Some(Arc::new(DeadLetterCapture::new(directory.clone(), 2, 1024))),
+            ..Default::default()
+        };
+        let mut client = TestClient::start(db, config);
+
+        client.login("user1", "password");
+        assert!(client.send("MAIL FROM:<user1@example.com>").starts_with("250"));
+        assert!(client.send("RCPT TO:<user2>").starts_with("250"));
+        assert!(client.send_chunk(&"x".repeat(101), true).starts_with("552"));
+        assert!(client.send("MAIL FROM:<user1@example.com>").starts_with("250"));
+        assert!(client.send("RCPT TO:<user2>").starts_with("250"));
+        assert!(client.send("DATA").starts_with("354"));
+        assert!(client.send("Subject: Hello\r\n\r\nBody\r\n.").starts_with("451"));
+        // the directory is full with two files
+        assert!(client.send("MAIL FROM:<user1@example.com>").starts_with("250"));
+        assert!(client.send("RCPT TO:<user2>").starts_with("250"));
+        assert!(client.send_chunk(&"x".repeat(101), true).starts_with("552"));
+        assert!(client.quit().is_ok());
+
+        let mut captures: Vec<_> = std::fs::read_dir(&directory).unwrap().map(|entry| entry.unwrap().path()).collect();
+        captures.sort();
+        assert_eq!(captures.len(), 2);
+        let capture = std::fs::read_to_string(&captures[0]).unwrap();
+        assert_eq!(capture, "X-Dead-Letter-Reply: 552 Message size exceeds fixed maximum message size\r\n\
+            X-Envelope-From: <user1@example.com>\r\nX-Envelope-To: <user2>\r\n");
+        let capture = std::fs::read_to_string(&captures[1]).unwrap();
+        assert!(capture.starts_with("X-Dead-Letter-Reply: 451 Requested action aborted: local error in processing\r\n"));
+        assert!(capture.ends_with("Subject: Hello\r\n\r\nBody\r\n"), "unexpected capture: {}", capture);
+        std::fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn encoded_subject_is_decoded_test() {
+        let db = MemoryMailDB::with_users(&[("user1", "password"), ("user2", "password")]);
+        let mut client = TestClient::start(db.clone(), SessionConfig::default());
+
+        client.login("user1", "password");
+        assert!(client.send("MAIL FROM:<user1@example.com>").starts_with("250"));
+        assert!(client.send("RCPT TO:<user2>").starts_with("250"));
+        assert!(client.send("DATA").starts_with("354"));
+        assert!(client.send("Subject: =?UTF-8?B?0J/RgNC40LLRltGC?=\r\n\r\nBody\r\n.").starts_with("250"));
+        assert!(client.quit().is_ok());
+
+        let mails = db.mails();
+        assert_eq!(mails[0].subject, "Привіт");
+        assert!(mails[0].body.contains("=?UTF-8?B?0J/RgNC40LLRltGC?="));
+    }
+
+    #[test]
+    fn full_session_on_runtime_test() {
+        let mut runtime = ConcurrentRuntime::new(2);
+        runtime.start();
+
+        let db = MemoryMailDB::with_users(&[("user1", "password"), ("user2", "password")]);
+        let mut client = TestClient::start_on_runtime(&runtime, db.clone(), SessionConfig::default());
+
+        assert!(client.send("EHLO client.example.com").starts_with("250"));
+        client.starttls();
+        assert!(client.send("EHLO client.example.com").starts_with("250"));
+        assert!(client.auth_plain("user1", "password").starts_with("235"));
+        assert!(client.send("MAIL FROM:<user1@example.com>").starts_with("250"));
+        assert!(client.send("RCPT TO:<user2>").starts_with("250"));
+        assert!(client.send("DATA").starts_with("354"));
+        assert!(client.send("Subject: Loopback\r\n\r\nBody\r\n.").starts_with("250"));
+        assert!(client.quit().is_ok());
+
+        let mails = db.mails();
+        assert_eq!(mails.len(), 1);
+        assert_eq!(mails[0].subject, "Loopback");
+        runtime.stop();
+    }
+
+    #[test]
+    fn slow_login_does_not_stall_other_sessions_test() {
+        let mut runtime = ConcurrentRuntime::new(1);
+        runtime.start();
+
+        let db = MemoryMailDB::with_users(&[("user1", "password")]);
+        let mut slow = TestClient::start_on_runtime(&runtime, db.clone(), SessionConfig::default());
+        assert!(slow.send("EHLO client.example.com").starts_with("250"));
+        slow.starttls();
+        db.state.lock().unwrap().login_delay = Duration::from_secs(2);
+        slow.write(&format!("AUTH PLAIN {}\r\n", base64::encode("\0user1\0password")));
+
+        // the only executor thread is free while the database works on the login
+        let started = Instant::now();
+        let mut other = TestClient::start_on_runtime(&runtime, db.clone(), SessionConfig::default());
+        assert!(other.send("NOOP").starts_with("250"));
+        assert!(started.elapsed() < Duration::from_secs(1), "took {:?}", started.elapsed());
+
+        assert!(slow.read_reply().starts_with("235"));
+        assert!(other.quit().is_ok());
+        assert!(slow.quit().is_ok());
+        runtime.stop();
+    }
+
+    #[test]
+    fn abandoned_delivery_does_not_stall_quit_test() {
+        let mut runtime = ConcurrentRuntime::new(1);
+        runtime.start();
+
+        let db = MemoryMailDB::with_users(&[("user1", "password"), ("user2", "password")]);
+        let config = SessionConfig { delivery_timeout: Duration::from_millis(100), ..Default::default() };
+        let mut slow = TestClient::start_on_runtime(&runtime, db.clone(), config);
+        slow.login("user1", "password");
+        assert!(slow.send("MAIL FROM:<user1@example.com>").starts_with("250"));
+        assert!(slow.send("RCPT TO:<user2>").starts_with("250"));
+        assert!(slow.send("DATA").starts_with("354"));
+        db.state.lock().unwrap().delivery_delay = Duration::from_secs(2);
+        assert!(slow.send("Subject: Slow\r\n\r\nHello\r\n.").starts_with("451"));
+
+        // the abandoned delivery still holds the database, neither QUIT nor the other session wait for it
+        let started = Instant::now();
+        assert!(slow.quit().is_ok());
+        let mut other = TestClient::start_on_runtime(&runtime, db.clone(), SessionConfig::default());
+        assert!(other.send("NOOP").starts_with("250"));
+        assert!(started.elapsed() < Duration::from_secs(1), "took {:?}", started.elapsed());
+
+        assert!(other.quit().is_ok());
+        runtime.stop();
+    }
+
+    #[test]
+    fn unix_socket_session_test() {
+        let path = std::env::temp_dir().join(format!("smtp-session-test-{}.sock", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+
+        let db = MemoryMailDB::with_users(&[("user1", "password")]);
+        let mut client = TestClient::start_unix(&path, db, SessionConfig::default());
+
+        let reply = client.send("EHLO client.example.com");
+        assert!(reply.starts_with("250"), "unexpected reply: {}", reply);
+        assert!(client.quit().is_ok());
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn unknown_and_not_implemented_commands_test() {
+        let db = MemoryMailDB::default();
+        let mut client = TestClient::start(db, SessionConfig::default());
+
+        assert!(client.send("TURN").starts_with("502"));
+        assert!(client.send("ZZZZ").starts_with("500"));
+        assert!(client.send("MAIL FROM:user1").starts_with("501"));
+        assert!(client.quit().is_ok());
+    }
+
+    #[test]
+    fn tls_handshake_limit_test() {
+        let db = MemoryMailDB::with_users(&[("user1", "password")]);
+        let config = SessionConfig {
+            tls_handshakes: Arc::new(TlsHandshakeLimiter::new(1)),
+            ..Default::default()
+        };
+        let mut first = TestClient::start(db.clone(), config.clone());
+        let mut second = TestClient::start(db, config.clone());
+
+        // the first handshake holds the only slot until the client side completes it
+        assert!(first.send("EHLO first.example.com").starts_with("250"));
+        assert!(first.send("STARTTLS").starts_with("220"));
+
+        assert!(second.send("EHLO second.example.com").starts_with("250"));
+        let reply = second.send("STARTTLS");
+        assert!(reply.starts_with("454"), "unexpected reply: {}", reply);
+
+        first.tls_handshake();
+        assert!(first.send("NOOP").starts_with("250"));
+        assert_eq!(config.tls_handshakes.in_progress(), 0);
+        second.starttls();
+
+        assert!(first.quit().is_ok());
+        assert!(second.quit().is_ok());
+    }
+
+    #[test]
+    fn tls_disabled_plaintext_auth_test() {
+        let db = MemoryMailDB::with_users(&[("user1", "password"), ("user2", "password")]);
+        let config = SessionConfig { require_tls: false, ..Default::default() };
+        let mut client = TestClient::start_without_tls(db, config);
+
+        let reply = client.send("EHLO client.example.com");
+        assert!(!reply.contains("STARTTLS"), "unexpected reply: {}", reply);
+        assert!(reply.contains("250-AUTH PLAIN LOGIN\r\n"), "unexpected reply: {}", reply);
+        assert!(client.send("STARTTLS").starts_with("502"));
+
+        assert!(client.auth_plain("user1", "password").starts_with("235"));
+        assert!(client.send("MAIL FROM:<user1@example.com>").starts_with("250"));
+        assert!(client.send("RCPT TO:<user2>").starts_with("250"));
+        assert!(client.quit().is_ok());
+    }
+
+    #[test]
+    fn tls_disabled_but_required_test() {
+        let db = MemoryMailDB::with_users(&[("user1", "password")]);
+        let mut client = TestClient::start_without_tls(db, SessionConfig::default());
+
+        let reply = client.send("EHLO client.example.com");
+        assert!(!reply.contains("STARTTLS"), "unexpected reply: {}", reply);
+        assert!(!reply.contains("AUTH"), "unexpected reply: {}", reply);
+        assert_eq!(client.auth_plain("user1", "password"), "530 5.7.0 Must issue a STARTTLS command first\r\n");
+        assert_eq!(client.send("REGISTER user1"), "530 5.7.0 Must issue a STARTTLS command first\r\n");
+        assert!(client.quit().is_ok());
+    }
+
+    #[test]
+    fn mail_from_requires_auth_test() {
+        let db = MemoryMailDB::with_users(&[("user1", "password")]);
+        let mut client = TestClient::start(db, SessionConfig::default());
+
+        assert!(client.send("EHLO client.example.com").starts_with("250"));
+        assert!(client.send("MAIL FROM:<user1@example.com>").starts_with("530"));
+        client.starttls();
+        assert!(client.send("MAIL FROM:<user1@example.com>").starts_with("530"));
+
+        assert!(client.auth_plain("user1", "password").starts_with("235"));
+        assert!(client.send("MAIL FROM:<user1@example.com>").starts_with("250"));
+        assert!(client.quit().is_ok());
+    }
+
+    #[test]
+    fn mail_from_without_auth_allowed_test() {
+        let db = MemoryMailDB::with_users(&[("user1", "password")]);
+        let config = SessionConfig { require_auth: false, ..Default::default() };
+        let mut client = TestClient::start(db, config);
+
+        assert!(client.send("EHLO client.example.com").starts_with("250"));
+        assert!(client.send("MAIL FROM:<someone@example.com>").starts_with("250"));
+        assert!(client.send("RCPT TO:<user1>").starts_with("250"));
+        assert!(client.quit().is_ok());
+    }
+
+    #[test]
+    fn connection_closed_during_data_test() {
+        let db = MemoryMailDB::with_users(&[("user1", "password"), ("user2", "password")]);
+        let mut client = TestClient::start(db.clone(), SessionConfig::default());
+        client.login("user1", "password");
+
+        assert!(client.send("MAIL FROM:<user1@example.com>").starts_with("250"));
+        assert!(client.send("RCPT TO:<user2>").starts_with("250"));
+        assert!(client.send("DATA").starts_with("354"));
+        client.write("Subject: Partial\r\n\r\nHalf of a");
+        assert!(client.disconnect().is_ok());
+
+        assert!(db.mails().is_empty());
+        assert_eq!(db.state.lock().unwrap().rollbacks, 1);
+    }
+
+    #[test]
+    fn delivery_timeout_test() {
+        let db = MemoryMailDB::with_users(&[("user1", "password"), ("user2", "password")]);
+        let backend = SlowBackend { delay: Duration::from_millis(500), backend: RecordingBackend::default() };
+        let deliveries = backend.backend.deliveries.clone();
+        let config = SessionConfig { delivery_timeout: Duration::from_millis(100), ..Default::default() };
+        let mut client = TestClient::start_with_backend(db.clone(), config, Box::new(backend));
+
+        client.login("user1", "password");
+        assert!(client.send("MAIL FROM:<user1@example.com>").starts_with("250"));
+        assert!(client.send("RCPT TO:<user2>").starts_with("250"));
+        assert!(client.send("DATA").starts_with("354"));
+        client.write("Subject: Slow\r\n\r\nHello\r\n.\r\n");
+        assert_eq!(client.read_reply(), "451 4.3.0 Requested action aborted: local error in processing\r\n");
+
+        // the abandoned delivery still holds the mail transaction
+        assert!(client.send("MAIL FROM:<user1@example.com>").starts_with("451"));
+        std::thread::sleep(Duration::from_millis(600));
+        // the backend finished, but its transaction was rolled back
+        assert_eq!(deliveries.lock().unwrap().len(), 1);
+        assert_eq!(db.state.lock().unwrap().rollbacks, 1);
+        assert!(client.send("MAIL FROM:<user1@example.com>").starts_with("250"));
+        assert!(client.quit().is_ok());
+        assert!(db.mails().is_empty());
+    }
+
+    #[test]
+    fn delivery_backend_receives_message_test() {
+        let db = MemoryMailDB::with_users(&[("user1", "password"), ("user2", "password"), ("user3", "password")]);
+        let backend = RecordingBackend::default();
+        let mut client = TestClient::start_with_backend(db.clone(), SessionConfig::default(), Box::new(backend.clone()));
+
+        client.login("user1", "password");
+        assert!(client.send("MAIL FROM:<user1@example.com>").starts_with("250"));
+        assert!(client.send("RCPT TO:<user2>").starts_with("250"));
+        assert!(client.send("RCPT TO:<user3@example.org>").starts_with("250"));
+        assert!(client.send("DATA").starts_with("354"));
+        client.write("Subject: Backend\r\n\r\nHello\r\n.\r\n");
+        assert!(client.read_reply().starts_with("250"));
+        assert!(client.quit().is_ok());
+
+        let deliveries = backend.deliveries.lock().unwrap();
+        assert_eq!(deliveries.len(), 1);
+        let (envelope, data) = &deliveries[0];
+        assert_eq!(envelope.mail_from.as_ref().map(EmailAddress::to_string).as_deref(), Some("user1@example.com"));
+        assert_eq!(envelope.recipient_names(), vec!["user2", "user3@example.org"]);
+        let data = String::from_utf8_lossy(data);
+        assert!(data.starts_with("Received: "));
+        assert!(data.contains("\r\nSubject: Backend\r\n\r\nHello"));
+
+        // the database was not used for storage, but the transaction was still committed
+        let state = db.state.lock().unwrap();
+        assert!(state.mails.is_empty());
+        assert_eq!(state.commits, 1);
+    }
+
+    #[test]
+    fn envelope_follows_transaction_test() {
+        let db = MemoryMailDB::with_users(&[("user1", "password"), ("user2", "password"), ("user3", "password")]);
+        let mut client = TestClient::start(db, SessionConfig::default());
+
+        client.login("user1", "password");
+        assert!(client.send("MAIL FROM:<user1@example.com>").starts_with("250"));
+        assert!(client.send("RCPT TO:<user2>").starts_with("250"));
+        assert!(client.send("RCPT TO:<bad address@example.com>").starts_with("501"));
+        assert!(client.send("RCPT TO:<user3@example.org>").starts_with("250"));
+        let (result, session_data) = client.finish();
+        assert!(result.is_ok());
+        assert_eq!(session_data.envelope, Envelope {
+            mail_from: Some(EmailAddress { local_part: "user1".to_string(), domain: Some("example.com".to_string()) }),
+            recipients: vec![
+                EmailAddress { local_part: "user2".to_string(), domain: None },
+                EmailAddress { local_part: "user3".to_string(), domain: Some("example.org".to_string()) },
+            ],
+            notify: vec![None, None],
+            params: MailParams::default(),
+            message_id: None,
+        });
+    }
+
+    #[test]
+    fn dsn_parameters_recorded_test() {
+        let db = MemoryMailDB::with_users(&[("user1", "password"), ("user2", "password"), ("user3", "password")]);
+        let mut client = TestClient::start(db, SessionConfig::default());
+
+        let reply = client.send("EHLO client.example.com");
+        assert!(reply.contains("250-DSN\r\n"), "unexpected reply: {}", reply);
+        client.starttls();
+        assert!(client.auth_plain("user1", "password").starts_with("235"));
+        assert!(client.send("MAIL FROM:<user1@example.com> RET=HDRS ENVID=QQ314159").starts_with("250"));
+        assert!(client.send("RCPT TO:<user2> NOTIFY=SUCCESS,FAILURE").starts_with("250"));
+        assert!(client.send("RCPT TO:<user3> NOTIFY=SOMETIMES").starts_with("501"));
+        assert!(client.send("RCPT TO:<user3>").starts_with("250"));
+        let (result, session_data) = client.finish();
+        assert!(result.is_ok());
+
+        let envelope = session_data.envelope;
+        assert_eq!(envelope.params.ret, Some(DsnReturn::Headers));
+        assert_eq!(envelope.params.envid.as_deref(), Some("QQ314159"));
+        assert_eq!(envelope.recipient_names(), ["user2", "user3"]);
+        assert_eq!(envelope.notify, [Some(DsnNotify { success: true, failure: true, delay: false }), None]);
+    }
+
+    #[test]
+    fn envelope_reset_by_rset_test() {
+        let db = MemoryMailDB::with_users(&[("user1", "password"), ("user2", "password")]);
+        let mut client = TestClient::start(db, SessionConfig::default());
+
+        client.login("user1", "password");
+        assert!(client.send("MAIL FROM:<user1@example.com>").starts_with("250"));
+        assert!(client.send("RCPT TO:<user2>").starts_with("250"));
+        assert!(client.send("RSET").starts_with("250"));
+        let (_, session_data) = client.finish();
+        assert_eq!(session_data.envelope, Envelope::default());
+    }
+
+    #[test]
+    fn message_latency_metric_test() {
+        let db = MemoryMailDB::with_users(&[("user1", "password"), ("user2", "password")]);
+        let config = SessionConfig::default();
+        let mut client = TestClient::start(db, config.clone());
+
+        client.login("user1", "password");
+        for subject in ["First", "Second"] {
+            assert!(client.send("MAIL FROM:<user1@example.com>").starts_with("250"));
+            assert!(client.send("RCPT TO:<user2>").starts_with("250"));
+            assert!(client.send("DATA").starts_with("354"));
+            client.write(&format!("Subject: {}\r\n\r\nHello\r\n.\r\n", subject));
+            assert!(client.read_reply().starts_with("250"));
+        }
+        // an abandoned transaction is not measured
+        assert!(client.send("MAIL FROM:<user1@example.com>").starts_with("250"));
+        assert!(client.send("RSET").starts_with("250"));
+        assert!(client.quit().is_ok());
+
+        let latency = config.metrics.snapshot().message_latency;
+        assert_eq!(latency.count, 2);
+        assert!(latency.max > Duration::ZERO);
+        assert!(latency.total >= latency.max);
+        assert!(latency.average().unwrap() > Duration::ZERO);
+    }
+
+    #[test]
+    fn transaction_commands_before_mail_from_test() {
+        let db = MemoryMailDB::with_users(&[("user1", "password"), ("user2", "password")]);
+        let mut client = TestClient::start(db, SessionConfig::default());
+
+        client.login("user1", "password");
+        assert!(client.send("RCPT TO:<user2>").starts_with("503"));
+        assert!(client.send("DATA").starts_with("503"));
+        assert!(client.send("STARTTLS").starts_with("500"));
+
+        // the session is still usable afterwards
+        assert!(client.send("MAIL FROM:<user1@example.com>").starts_with("250"));
+        assert!(client.send("RCPT TO:<user2>").starts_with("250"));
+        assert!(client.quit().is_ok());
+    }
+
+    #[test]
+    fn is_local_recipient_test() {
+        let db: Box<dyn IMailDB + Send> = Box::new(MemoryMailDB::with_users(&[("user1", "password")]));
+        let mut recipients = LocalRecipients::new(Arc::new(Mutex::new(db)), vec!["example.com".to_string()]);
+
+        let local_user = EmailAddress::parse("user1@Example.com").unwrap();
+        let bare_user = EmailAddress::parse("user1").unwrap();
+        let unknown_user = EmailAddress::parse("nobody@example.com").unwrap();
+        let remote_user = EmailAddress::parse("user1@example.org").unwrap();
+
+        assert!(recipients.is_local_recipient(&local_user).unwrap());
+        assert!(recipients.is_local_recipient(&bare_user).unwrap());
+        assert!(!recipients.is_local_recipient(&unknown_user).unwrap());
+        assert!(!recipients.is_local_recipient(&remote_user).unwrap());
+        assert_eq!(recipients.mailbox_name(&local_user), "user1");
+        assert_eq!(recipients.mailbox_name(&remote_user), "user1@example.org");
+    }
+
+    #[test]
+    fn rcpt_to_checks_local_recipients_test() {
+        let db = MemoryMailDB::with_users(&[("user1", "password"), ("user2", "password")]);
+        let config = SessionConfig { local_domains: vec!["example.com".to_string()], ..Default::default() };
+        let mut client = TestClient::start(db.clone(), config);
+
+        client.login("user1", "password");
+        assert!(client.send("MAIL FROM:<user1@example.com>").starts_with("250"));
+        assert!(client.send("RCPT TO:<nobody@example.com>").starts_with("550"));
+        assert!(client.send("RCPT TO:<user2@example.org>").starts_with("550"));
+        assert!(client.send("RCPT TO:<user2@example.com>").starts_with("250"));
+        assert!(client.send("DATA").starts_with("354"));
+        assert!(client.send("Subject: Local\r\n\r\nBody\r\n.").starts_with("250"));
+        assert!(client.quit().is_ok());
+
+        // stored under the user name, without the local domain
+        let mails = db.mails();
+        assert_eq!(mails.len(), 1);
+        assert_eq!(mails[0].receivers, vec!["user2".to_string()]);
+    }
+
+    #[test]
+    fn vrfy_test() {
+        let db = MemoryMailDB::with_users(&[("user1", "password")]);
+        let mut client = TestClient::start(db, SessionConfig::default());
+
+        assert!(client.send("VRFY user1").starts_with("500"));
+        assert!(client.send("EHLO client.example.com").starts_with("250"));
+        assert_eq!(client.send("VRFY user1"), "250 2.1.5 <user1>\r\n");
+        assert!(client.send("VRFY <user1@example.com>").starts_with("250"));
+        assert!(client.send("VRFY nobody").starts_with("550"));
+        assert!(client.send("VRFY").starts_with("501"));
+        assert!(client.quit().is_ok());
+    }
+
+    #[test]
+    fn expn_test() {
+        let db = MemoryMailDB::with_users(&[("user1", "password")]);
+        let mut client = TestClient::start(db, SessionConfig::default());
+
+        assert!(client.send("EXPN staff").starts_with("500"));
+        assert!(client.send("EHLO client.example.com").starts_with("250"));
+        assert_eq!(client.send("EXPN staff"), "252 2.0.0 Cannot VRFY user, but will accept message\r\n");
+        assert!(client.send("EXPN").starts_with("501"));
+        assert!(client.quit().is_ok());
+    }
+
+    // Greets the client back by its EHLO name
+    struct HelloExtension;
+
+    impl SmtpExtension for HelloExtension {
+        fn keyword(&self) -> Option<String> {
+            Some("XHELLO".to_string())
+        }
+
+        fn handle(&self, command: &str, context: &ExtensionContext) -> Option<ExtensionReply> {
+            command.eq_ignore_ascii_case("XHELLO").then(|| ExtensionReply {
+                code: 250,
+                text: format!("Hello {}", context.ehlo_domain),
+            })
+        }
+    }
+
+    #[test]
+    fn extension_command_test() {
+        let db = MemoryMailDB::with_users(&[("user1", "password")]);
+        let mut config = SessionConfig::default();
+        config.extensions.register(Arc::new(HelloExtension));
+        let mut client = TestClient::start(db, config);
+
+        let reply = client.send("EHLO client.example.com");
+        assert!(reply.ends_with("250-HELP\r\n250 XHELLO\r\n"), "unexpected reply: {}", reply);
+        assert_eq!(client.send("XHELLO"), "250 2.0.0 Hello client.example.com\r\n");
+        assert!(client.send("XGOODBYE").starts_with("500"));
+        assert!(client.quit().is_ok());
+    }
+
+    #[test]
+    fn lenient_parse_mode_test() {
+        let db = MemoryMailDB::with_users(&[("user1", "password"), ("user2", "password")]);
+        let config = SessionConfig { parse_mode: ParseMode::Lenient, ..Default::default() };
+        let mut client = TestClient::start(db, config);
+        client.login("user1", "password");
+        assert!(client.send("mail from :<user1@example.com>").starts_with("250"));
+        assert!(client.send("Rcpt To :<user2>").starts_with("250"));
+        assert!(client.quit().is_ok());
+
+        let db = MemoryMailDB::with_users(&[("user1", "password")]);
+        let mut client = TestClient::start(db, SessionConfig::default());
+        client.login("user1", "password");
+        assert!(client.send("MAIL FROM :<user1@example.com>").starts_with("501"));
+        assert!(client.send("mail from:<user1@example.com>").starts_with("250"));
+        assert!(client.quit().is_ok());
+    }
+
+    #[test]
+    fn auth_login_test() {
+        let db = MemoryMailDB::with_users(&[("user1", "password")]);
+        let mut client = TestClient::start(db, SessionConfig::default());
+        assert!(client.send("EHLO client.example.com").starts_with("250"));
+        client.starttls();
+
+        assert_eq!(client.send("AUTH LOGIN"), "334 VXNlcm5hbWU6\r\n");
+        assert_eq!(client.send(&base64::encode("user1")), "334 UGFzc3dvcmQ6\r\n");
+        assert!(client.send(&base64::encode("wrong")).starts_with("535"));
+
+        assert!(client.send("AUTH LOGIN").starts_with("334"));
+        assert!(client.send("*").starts_with("501 5.5.4 Authentication canceled"));
+        assert!(client.send("AUTH LOGIN").starts_with("334"));
+        assert!(client.send("not base64!").starts_with("334"));
+        assert!(client.send(&base64::encode("password")).starts_with("501"));
+
+        // the user name may come with the command
+        assert_eq!(client.send(&format!("AUTH LOGIN {}", base64::encode("user1"))), "334 UGFzc3dvcmQ6\r\n");
+        assert!(client.send(&base64::encode("password")).starts_with("235"));
+        assert!(client.send("MAIL FROM:<user1@example.com>").starts_with("250"));
+        assert!(client.quit().is_ok());
+    }
+
+    #[test]
+    fn unsupported_auth_mechanism_test() {
+        let db = MemoryMailDB::with_users(&[("user1", "password")]);
+        let mut client = TestClient::start(db, SessionConfig::default());
+        assert!(client.send("EHLO client.example.com").starts_with("250"));
+        client.starttls();
+
+        assert_eq!(client.send("AUTH CRAM-MD5"), "504 5.5.4 Unrecognized authentication type\r\n");
+        assert!(client.send("AUTH XOAUTH2 dG9rZW4=").starts_with("501"));
+        assert!(client.auth_plain("user1", "password").starts_with("235"));
+        assert!(client.quit().is_ok());
+    }
+
+    #[test]
+    fn mail_from_size_test() {
+        let db = MemoryMailDB::with_users(&[("user1", "password"), ("user2", "password")]);
+        let config = SessionConfig { max_message_size: 1000, ..Default::default() };
+        let mut client = TestClient::start(db, config);
+
+        client.login("user1", "password");
+        assert!(client.send("MAIL FROM:<user1@example.com> SIZE=1001").starts_with("552"));
+        assert!(client.send("MAIL FROM:<user1@example.com> SIZE=1000 BODY=8BITMIME").starts_with("250"));
+        assert!(client.send("RCPT TO:<user2>").starts_with("250"));
+        assert!(client.send("DATA").starts_with("354"));
+        assert!(client.send("Subject: Sized\r\n\r\nBody\r\n.").starts_with("250"));
+        // the null sender of a bounce
+        assert!(client.send("MAIL FROM:<>").starts_with("250"));
+        assert!(client.send("RCPT TO:<user2>").starts_with("250"));
+        assert!(client.quit().is_ok());
+    }
+
+    #[test]
+    fn data_up_to_advertised_size_test() {
+        let db = MemoryMailDB::with_users(&[("user1", "password"), ("user2", "password")]);
+        let mut client = TestClient::start(db.clone(), SessionConfig::default());
+
+        let reply = client.send("EHLO client.example.com");
+        let size: usize = reply.lines()
+            .find_map(|line| line.get(4..)?.strip_prefix("SIZE "))
+            .and_then(|size| size.parse().ok())
+            .expect("SIZE not advertised");
+        client.starttls();
+        assert!(client.auth_plain("user1", "password").starts_with("235"));
+
+        // a message just under the advertised size is accepted
+        let header = "Subject: Sized\r\n\r\n";
+        let line = format!("{}\r\n", "x".repeat(998));
+        let message = format!("{}{}", header, line.repeat((size - header.len() - 1) / line.len()));
+        assert!(size - message.len() < line.len());
+        assert!(client.send(&format!("MAIL FROM:<user1@example.com> SIZE={}", message.len())).starts_with("250"));
+        assert!(client.send("RCPT TO:<user2>").starts_with("250"));
+        assert!(client.send("DATA").starts_with("354"));
+        client.write(&message);
+        let reply = client.send(".");
+        assert!(reply.starts_with("250"), "unexpected reply: {}", reply);
+        assert!(client.quit().is_ok());
+        assert_eq!(db.mails().len(), 1);
+    }
+
+    #[test]
+    fn bdat_test() {
+        let db = MemoryMailDB::with_users(&[("user1", "password"), ("user2", "password")]);
+        let config = SessionConfig { max_message_size: 100, ..Default::default() };
+        let mut client = TestClient::start(db.clone(), config);
+
+        let reply = client.send("EHLO client.example.com");
+        assert!(reply.contains("250-CHUNKING\r\n"), "unexpected reply: {}", reply);
+        client.starttls();
+        assert!(client.auth_plain("user1", "password").starts_with("235"));
+        assert!(client.send("MAIL FROM:<user1@example.com>").starts_with("250"));
+        assert!(client.send("RCPT TO:<user2>").starts_with("250"));
+        // a lone dot line does not end a chunk
+        assert_eq!(client.send_chunk("Subject: Chunked\r\n.\r\n", false), "250 2.0.0 21 octets received\r\n");
+        assert!(client.send("DATA").starts_with("503"));
+        assert!(client.send_chunk("Body\r\n", true).starts_with("250 2.0.0 OK: queued as "));
+
+        // a chunk outside of a transaction is read and refused
+        assert!(client.send_chunk("QUIT\r\n", true).starts_with("503"));
+        assert!(client.send("MAIL FROM:<user1@example.com>").starts_with("250"));
+        assert!(client.send("RCPT TO:<user2>").starts_with("250"));
+        assert!(client.send_chunk(&"x".repeat(101), false).starts_with("552"));
+        assert!(client.send("NOOP").starts_with("250"));
+        assert!(client.quit().is_ok());
+
+        let mails = db.mails();
+        assert_eq!(mails.len(), 1);
+        assert!(mails[0].body.ends_with("Subject: Chunked\r\n.\r\nBody\r\n"), "unexpected body: {}", mails[0].body);
+    }
+
+    #[test]
+    fn failed_data_resets_transaction_test() {
+        let db = MemoryMailDB::with_users(&[("user1", "password"), ("user2", "password")]);
+        let mut client = TestClient::start(db.clone(), SessionConfig::default());
+        client.login("user1", "password");
+
+        assert!(client.send("MAIL FROM:<user1@example.com>").starts_with("250"));
+        assert!(client.send("RCPT TO:<user2>").starts_with("250"));
+        assert!(client.send("DATA").starts_with("354"));
+        // a header line over the limit fails the DATA command
+        let reply = client.send(&format!("Subject: {}\r\n\r\nBody\r\n.", "x".repeat(1000)));
+        assert!(reply.starts_with("552"), "unexpected reply: {}", reply);
+
+        // no RSET, the envelope of the failed transaction is gone and the login is kept
+        assert!(client.send("MAIL FROM:<user1@example.com>").starts_with("250"));
+        assert!(client.send("RCPT TO:<user1>").starts_with("250"));
+        assert!(client.send("DATA").starts_with("354"));
+        assert!(client.send("Subject: Fresh\r\n\r\nBody\r\n.").starts_with("250"));
+        assert!(client.quit().is_ok());
+
+        let mails = db.mails();
+        assert_eq!(mails.len(), 1);
+        assert_eq!(mails[0].receivers, vec!["user1".to_string()]);
+        assert_eq!(mails[0].subject, "Fresh");
+    }
+
+    #[test]
+    fn oversized_data_read_to_terminator_test() {
+        let db = MemoryMailDB::with_users(&[("user1", "password"), ("user2", "password")]);
+        let config = SessionConfig { max_message_size: 100_000, ..Default::default() };
+        let mut client = TestClient::start(db.clone(), config);
+        client.login("user1", "password");
+
+        assert!(client.send("MAIL FROM:<user1@example.com>").starts_with("250"));
+        assert!(client.send("RCPT TO:<user2>").starts_with("250"));
+        assert!(client.send("DATA").starts_with("354"));
+        client.write(&format!("Subject: Big\r\n\r\n{}", format!("{}\r\n", "x".repeat(998)).repeat(200)));
+        // past the limit, still part of the message and not a command
+        client.write("RSET\r\n.\r\n");
+        let reply = client.read_reply();
+        assert!(reply.starts_with("552 "), "unexpected reply: {}", reply);
+
+        // the next reply is the one to MAIL FROM, nothing answered the RSET line
+        assert_eq!(client.send("MAIL FROM:<user1@example.com>"), "250 2.1.0 OK\r\n");
+        assert!(client.send("RCPT TO:<user2>").starts_with("250"));
+        assert!(client.send("DATA").starts_with("354"));
+        // a single line longer than the limit is not buffered up to its CRLF
+        client.write(&"x".repeat(300_000));
+        let reply = client.send("\r\n.");
+        assert!(reply.starts_with("552 "), "unexpected reply: {}", reply);
+
+        assert!(client.quit().is_ok());
+        assert!(db.mails().is_empty());
+    }
+
+    #[test]
+    fn eight_bit_data_accepted_test() {
+        let db = MemoryMailDB::with_users(&[("user1", "password"), ("user2", "password")]);
+        let mut client = TestClient::start(db.clone(), SessionConfig::default());
+        client.login("user1", "password");
+
+        // a body that is not UTF-8 is kept, as with BDAT
+        assert!(client.send("MAIL FROM:<user1@example.com> BODY=8BITMIME").starts_with("250"));
+        assert!(client.send("RCPT TO:<user2>").starts_with("250"));
+        assert!(client.send("DATA").starts_with("354"));
+        client.write_bytes(b"Subject: Latin-1\r\n\r\nCaf\xE9\r\n.\r\n");
+        let reply = client.read_reply();
+        assert!(reply.starts_with("250"), "unexpected reply: {}", reply);
+        assert!(client.quit().is_ok());
+
+        let mails = db.mails();
+        assert_eq!(mails.len(), 1);
+        assert!(mails[0].body.ends_with("Caf\u{FFFD}\r\n"), "unexpected body: {}", mails[0].body);
+    }
+
+    #[test]
+    fn auth_continuation_abort_test() {
+        let db = MemoryMailDB::with_users(&[("user1", "password")]);
+        let mut client = TestClient::start(db, SessionConfig::default());
+        assert!(client.send("EHLO client.example.com").starts_with("250"));
+        client.starttls();
+
+        assert!(client.send("AUTH PLAIN").starts_with("334"));
+        assert!(client.send("*").starts_with("501 5.5.4 Authentication canceled"));
+        assert!(client.send("AUTH PLAIN").starts_with("334"));
+        assert!(client.send("").starts_with("501"));
+        // garbage that decodes but is not a PLAIN response
+        assert!(client.send("AUTH PLAIN").starts_with("334"));
+        assert!(client.send(&base64::encode("user1")).starts_with("501"));
+
+        // the session is still usable after the aborted attempts
+        assert!(client.send("AUTH PLAIN").starts_with("334"));
+        assert!(client.send(&base64::encode("\0user1\0password")).starts_with("235"));
+        assert!(client.send("MAIL FROM:<user1@example.com>").starts_with("250"));
+        assert!(client.quit().is_ok());
+    }
+
+    #[test]
+    fn received_header_prepended_test() {
+        let db = MemoryMailDB::with_users(&[("user1", "password"), ("user2", "password")]);
+        let config = SessionConfig { hostname: "mx.example.org".to_string(), ..Default::default() };
+        let mut client = TestClient::start(db.clone(), config);
+
+        client.login("user1", "password");
+        assert!(client.send("MAIL FROM:<user1@example.com>").starts_with("250"));
+        assert!(client.send("RCPT TO:<user2>").starts_with("250"));
+        assert!(client.send("DATA").starts_with("354"));
+        assert!(client.send("Subject: Traced\r\n\r\nBody\r\n.").starts_with("250"));
+        assert!(client.quit().is_ok());
+
+        let mails = db.mails();
+        let mut lines = mails[0].body.split("\r\n");
+        assert_eq!(lines.next(), Some("Received: from client.example.com ([127.0.0.1])"));
+        let by = lines.next().unwrap();
+        assert!(by.starts_with("\tby mx.example.org with ESMTPSA id "), "unexpected line: {}", by);
+        assert!(by.ends_with(';'), "unexpected line: {}", by);
+        let date = lines.next().unwrap().trim_start();
+        assert!(chrono::DateTime::parse_from_rfc2822(date).is_ok(), "unexpected date: {}", date);
+        assert_eq!(lines.next(), Some("Subject: Traced"));
+        assert_eq!(mails[0].subject, "Traced");
+    }
+
+    #[test]
+    fn message_id_in_reply_test() {
+        let db = MemoryMailDB::with_users(&[("user1", "password"), ("user2", "password")]);
+        let mut client = TestClient::start(db.clone(), SessionConfig::default());
+
+        client.login("user1", "password");
+        let mut ids = Vec::new();
+        for _ in 0..2 {
+            assert!(client.send("MAIL FROM:<user1@example.com>").starts_with("250"));
+            assert!(client.send("RCPT TO:<user2>").starts_with("250"));
+            assert!(client.send("DATA").starts_with("354"));
+            let reply = client.send("Subject: Hello\r\n\r\nBody\r\n.");
+            let id = reply.strip_prefix("250 2.0.0 OK: queued as ").and_then(|id| id.strip_suffix("\r\n"))
+                .unwrap_or_else(|| panic!("unexpected reply: {}", reply)).to_string();
+            assert!(id.len() > 6 && id.chars().all(|c| c.is_ascii_hexdigit()), "unexpected id: {}", id);
+            ids.push(id);
+        }
+        assert!(client.quit().is_ok());
+        assert_ne!(ids[0], ids[1]);
+
+        // stored with the message, and named in its Received header
+        let mails = db.mails();
+        for (mail, id) in mails.iter().zip(&ids) {
+            assert_eq!(mail.message_id.as_ref(), Some(id));
+            assert!(mail.body.contains(&format!(" id {};", id)), "unexpected body: {}", mail.body);
+        }
+    }
+
+    #[test]
+    fn header_line_too_long_test() {
+        let db = MemoryMailDB::with_users(&[("user1", "password"), ("user2", "password")]);
+        let mut client = TestClient::start(db.clone(), SessionConfig::default());
+
+        client.login("user1", "password");
+        assert!(client.send("MAIL FROM:<user1@example.com>").starts_with("250"));
+        assert!(client.send("RCPT TO:<user2>").starts_with("250"));
+        assert!(client.send("DATA").starts_with("354"));
+        let subject = format!("Subject: {}", "x".repeat(990));
+        let reply = client.send(&format!("{}\r\n\r\nBody\r\n.", subject));
+        assert!(reply.starts_with("552 "), "unexpected reply: {}", reply);
+
+        // a body line of the same length is fine, the header line limit only applies above the empty line
+        assert!(client.send("MAIL FROM:<user1@example.com>").starts_with("250"));
+        assert!(client.send("RCPT TO:<user2>").starts_with("250"));
+        assert!(client.send("DATA").starts_with("354"));
+        let reply = client.send(&format!("Subject: Short\r\n\r\n{}\r\n.", subject));
+        assert!(reply.starts_with("250"), "unexpected reply: {}", reply);
+        assert!(client.quit().is_ok());
+
+        let mails = db.mails();
+        assert_eq!(mails.len(), 1);
+        assert_eq!(mails[0].subject, "Short");
+    }
+
+    #[test]
+    fn header_line_too_long_at_end_of_data_test() {
+        let db = MemoryMailDB::with_users(&[("user1", "password"), ("user2", "password")]);
+        let config = SessionConfig { max_message_size: 1500, ..Default::default() };
+        let mut client = TestClient::start(db.clone(), config);
+        client.login("user1", "password");
+
+        // the long header line is the last one before the terminator
+        let subject = format!("Subject: {}", "x".repeat(990));
+        assert!(client.send("MAIL FROM:<user1@example.com>").starts_with("250"));
+        assert!(client.send("RCPT TO:<user2>").starts_with("250"));
+        assert!(client.send("DATA").starts_with("354"));
+        let reply = client.send(&format!("{}\r\n.", subject));
+        assert!(reply.starts_with("552 "), "unexpected reply: {}", reply);
+
+        // and together with a message over the size limit
+        assert!(client.send("MAIL FROM:<user1@example.com>").starts_with("250"));
+        assert!(client.send("RCPT TO:<user2>").starts_with("250"));
+        assert!(client.send("DATA").starts_with("354"));
+        let reply = client.send(&format!("{}\r\n{}\r\n.", subject, subject));
+        assert!(reply.starts_with("552 "), "unexpected reply: {}", reply);
+        assert!(client.quit().is_ok());
+        assert!(db.mails().is_empty());
+    }
+
+    #[test]
+    fn command_timeout_test() {
+        let db = MemoryMailDB::with_users(&[("user1", "password")]);
+        let mut client = TestClient::start_with_timeout(db.clone(), SessionConfig::default(), 1);
+        assert!(client.send("EHLO client.example.com").starts_with("250"));
+
+        // nothing is sent until the server gives up
+        let reply = client.read_reply();
+        assert!(reply.starts_with("421 ") && reply.contains("Timeout waiting for command"), "unexpected reply: {}", reply);
+        assert!(client.join().is_ok());
+        assert_eq!(db.state.lock().unwrap().rollbacks, 0);
+    }
+
+    #[test]
+    fn session_duration_limit_test() {
+        let db = MemoryMailDB::with_users(&[("user1", "password")]);
+        let config = SessionConfig { max_session_duration: Duration::from_secs(2), ..Default::default() };
+        let mut client = TestClient::start_with_timeout(db, config, 1);
+        assert!(client.send("EHLO client.example.com").starts_with("250"));
+
+        // each NOOP comes well within the idle timeout
+        let reply = loop {
+            std::thread::sleep(Duration::from_millis(300));
+            let reply = client.send("NOOP");
+            if !reply.starts_with("250") {
+                break reply;
+            }
+        };
+        assert!(reply.starts_with("421 ") && reply.contains("Session time limit exceeded"), "unexpected reply: {}", reply);
+        assert!(client.join().is_ok());
+    }
+
+    #[test]
+    fn data_timeout_test() {
+        let db = MemoryMailDB::with_users(&[("user1", "password"), ("user2", "password")]);
+        let mut client = TestClient::start_with_timeout(db.clone(), SessionConfig::default(), 1);
+        client.login("user1", "password");
+        assert!(client.send("MAIL FROM:<user1@example.com>").starts_with("250"));
+        assert!(client.send("RCPT TO:<user2>").starts_with("250"));
+        assert!(client.send("DATA").starts_with("354"));
+        client.write("Subject: Unfinished\r\n\r\nThe rest never arrives");
+
+        let reply = client.read_reply();
+        assert_eq!(reply, "451 4.4.2 Timeout waiting for data from client\r\n");
+        assert!(client.join().is_ok());
+
+        let state = db.state.lock().unwrap();
+        assert!(state.mails.is_empty());
+        assert_eq!(state.rollbacks, 1);
+        assert_eq!(state.commits, 0);
+    }
+
+    #[test]
+    fn dot_inside_line_does_not_end_data_test() {
+        let db = MemoryMailDB::with_users(&[("user1", "password"), ("user2", "password")]);
+        let mut client = TestClient::start(db.clone(), SessionConfig::default());
+        client.login("user1", "password");
+        assert!(client.send("MAIL FROM:<user1@example.com>").starts_with("250"));
+        assert!(client.send("RCPT TO:<user2>").starts_with("250"));
+        assert!(client.send("DATA").starts_with("354"));
+
+        // the dot ends a sentence, and the chunk the server reads
+        client.write("Subject: Dots\r\n\r\nSentence ends here.\r\n");
+        std::thread::sleep(std::time::Duration::from_millis(100));
+        client.write("And here.\r\n.\r\n");
+        assert!(client.read_reply().starts_with("250 2.0.0 OK: queued as "));
+        assert_eq!(client.send("NOOP"), "250 2.0.0 OK\r\n");
+
+        // an empty message ends with the first line
+        assert!(client.send("MAIL FROM:<user1@example.com>").starts_with("250"));
+        assert!(client.send("RCPT TO:<user2>").starts_with("250"));
+        assert!(client.send("DATA").starts_with("354"));
+        assert!(client.send(".").starts_with("250 2.0.0 OK: queued as "));
+        assert!(client.quit().is_ok());
+
+        let mails = db.mails();
+        assert_eq!(mails.len(), 2);
+        assert!(mails[0].body.ends_with("Sentence ends here.\r\nAnd here.\r\n"), "unexpected body: {}", mails[0].body);
+    }
+
+    #[test]
+    fn reply_injection_test() {
+        let db = MemoryMailDB::with_users(&[("user1\r\n250 injected", "password")]);
+        let mut client = TestClient::start(db, SessionConfig::default());
+        client.login("user1\r\n250 injected", "password");
+
+        let reply = client.send("MAIL FROM:<user2@example.com>");
+        assert_eq!(reply, "553 5.7.1 Sender address not owned by authenticated user user1  250 injected\r\n");
+        assert_eq!(client.send("NOOP"), "250 2.0.0 OK\r\n");
+        assert!(client.quit().is_ok());
+    }
+
+    #[test]
+    fn bad_command_limit_test() {
+        let db = MemoryMailDB::with_users(&[("user1", "password")]);
+        let config = SessionConfig { max_bad_commands: 3, ..Default::default() };
+        let mut client = TestClient::start(db, config);
+
+        assert!(client.send("HELLO").starts_with("500"));
+        assert!(client.send("DATA").starts_with("500"));
+        // a handled command starts the count again
+        assert!(client.send("NOOP").starts_with("250"));
+        assert!(client.send("HELLO").starts_with("500"));
+        assert!(client.send("TURN").starts_with("502"));
+        assert_eq!(client.send("HELLO"), "421 localhost Too many errors, closing connection\r\n");
+        assert!(client.join().is_ok());
+    }
+
+    #[test]
+    fn parser_line_limit_test() {
+        let db = MemoryMailDB::with_users(&[("user1", "password")]);
+        // the session reads longer lines, the parser still holds commands to 512 bytes
+        let config = SessionConfig { max_line_length: 4096, ..Default::default() };
+        let mut client = TestClient::start(db, config);
+        client.login("user1", "password");
+
+        let address = format!("<{}@example.com>", "a".repeat(600));
+        assert_eq!(client.send(&format!("MAIL FROM:{}", address)), "500 5.5.2 Line too long\r\n");
+        assert_eq!(client.send("NOOP"), "250 2.0.0 OK\r\n");
+        assert!(client.send("MAIL FROM:<user1@example.com>").starts_with("250"));
+        assert!(client.quit().is_ok());
+    }
+
+    #[test]
+    fn oversized_auth_test() {
+        let db = MemoryMailDB::with_users(&[("user1", "password")]);
+        let mut client = TestClient::start(db, SessionConfig::default());
+        assert!(client.send("EHLO client.example.com").starts_with("250"));
+        client.starttls();
+
+        let blob = "QUFB".repeat(1024 * 1024);
+        assert_eq!(client.send(&format!("AUTH PLAIN {}", blob)), "500 5.5.2 Line too long\r\n");
+        assert_eq!(client.send("AUTH PLAIN"), "334 \r\n");
+        assert_eq!(client.send(&blob), "500 5.5.2 Line too long\r\n");
+
+        // the session goes on with the next line
+        assert_eq!(client.send("NOOP"), "250 2.0.0 OK\r\n");
+        assert!(client.auth_plain("user1", "password").starts_with("235"));
+        assert!(client.quit().is_ok());
+    }
+
+    #[test]
+    fn non_ascii_command_test() {
+        let db = MemoryMailDB::with_users(&[("user1", "password")]);
+        let mut client = TestClient::start(db, SessionConfig::default());
+
+        client.write_bytes(b"EH\xC0LO client.example.com\r\n");
+        assert_eq!(client.read_reply(), "500 Command contains invalid characters\r\n");
+        client.write_bytes(b"NOOP\xFF\r\n");
+        assert_eq!(client.read_reply(), "500 Command contains invalid characters\r\n");
+
+        // invalid UTF-8 in an argument does not end the session either
+        assert!(client.send("EHLO client.example.com").starts_with("250"));
+        client.write_bytes(b"HELO \xFFclient\r\n");
+        assert!(client.read_reply().starts_with("250"));
+        assert!(client.quit().is_ok());
+    }
+
+    #[test]
+    fn partial_delivery_test() {
+        let db = MemoryMailDB::with_users(&[("user1", "password"), ("user2", "password"), ("user3", "password")]);
+        db.state.lock().unwrap().full_mailboxes.push("user3".to_string());
+        let mut client = TestClient::start(db.clone(), SessionConfig::default());
+
+        client.login("user1", "password");
+        assert!(client.send("MAIL FROM:<user1@example.com>").starts_with("250"));
+        assert!(client.send("RCPT TO:<user2>").starts_with("250"));
+        assert!(client.send("RCPT TO:<user3>").starts_with("250"));
+        assert!(client.send("DATA").starts_with("354"));
+        let reply = client.send("Subject: Partial\r\n\r\nBody\r\n.");
+        assert!(reply.starts_with("250 2.0.0 OK: queued as "), "unexpected reply: {}", reply);
+        assert!(reply.ends_with(", delivered to 1 of 2 recipients\r\n"), "unexpected reply: {}", reply);
+
+        // nobody can receive it, so the message is refused
+        assert!(client.send("MAIL FROM:<user1@example.com>").starts_with("250"));
+        assert!(client.send("RCPT TO:<user3>").starts_with("250"));
+        assert!(client.send("DATA").starts_with("354"));
+        assert!(client.send("Subject: Refused\r\n\r\nBody\r\n.").starts_with("550"));
+        assert!(client.quit().is_ok());
+
+        let mails = db.mails();
+        assert_eq!(mails.len(), 1);
+        assert_eq!(mails[0].receivers, vec!["user2".to_string()]);
+        let state = db.state.lock().unwrap();
+        assert_eq!(state.commits, 1);
+        assert_eq!(state.rollbacks, 1);
+    }
+
+    #[test]
+    fn enhanced_status_codes_test() {
+        let db = MemoryMailDB::with_users(&[("user1", "password"), ("user2", "password")]);
+        let config = SessionConfig { local_domains: vec!["example.com".to_string()], ..Default::default() };
+        let mut client = TestClient::start(db, config);
+
+        let reply = client.send("EHLO client.example.com");
+        assert!(reply.contains("250-ENHANCEDSTATUSCODES\r\n"), "unexpected reply: {}", reply);
+        client.starttls();
+        assert_eq!(client.auth_plain("user1", "password"), "235 2.7.0 OK\r\n");
+        assert_eq!(client.send("MAIL FROM:<user1@example.com>"), "250 2.1.0 OK\r\n");
+        assert_eq!(client.send("RCPT TO:<nobody@example.com>"), "550 5.1.1 No such user here\r\n");
+        assert_eq!(client.send("RCPT TO:<user2@example.org>"), "550 5.7.1 Relaying denied\r\n");
+        assert_eq!(client.send("RCPT TO:<user2@example.com>"), "250 2.1.5 OK\r\n");
+        assert_eq!(client.send("DATA"), "354 End data with <CR><LF>.<CR><LF>\r\n");
+        assert!(client.send("Subject: Codes\r\n\r\nBody\r\n.").starts_with("250 2.0.0 OK: queued as "));
+        assert!(client.quit().is_ok());
+    }
+
+    #[test]
+    fn helo_keeps_basic_status_codes_test() {
+        let db = MemoryMailDB::with_users(&[("user2", "password")]);
+        let config = SessionConfig { require_auth: false, ..Default::default() };
+        let mut client = TestClient::start(db, config);
+
+        assert_eq!(client.send("HELO client.example.com"), "250 localhost greets you\r\n");
+        assert_eq!(client.send("MAIL FROM:<sender@example.org>"), "250 OK\r\n");
+        assert_eq!(client.send("RCPT TO:<user2>"), "250 OK\r\n");
+        assert_eq!(client.send("VRFY"), "501 Syntax error in parameters or arguments: Could not parse the argument for the command: VRFY\r\n");
+
+        // a later EHLO switches them on
+        assert!(client.send("EHLO client.example.com").starts_with("250"));
+        assert_eq!(client.send("NOOP"), "250 2.0.0 OK\r\n");
+        assert!(client.quit().is_ok());
+    }
+}
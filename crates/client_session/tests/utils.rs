@@ -0,0 +1,555 @@
+use std::{
+    collections::HashMap,
+    io::{BufRead, BufReader, Read, Write},
+    net::{IpAddr, Ipv4Addr, TcpListener, TcpStream},
+    os::unix::net::{UnixListener, UnixStream},
+    path::Path,
+    sync::{mpsc, Arc, Mutex},
+    thread,
+    time::Duration,
+};
+
+use client_session::{
+    delivery::{DeliveryBackend, DeliveryError},
+    envelope::Envelope,
+    error::ClientSessionError, ClientSession, SessionConfig, SessionData,
+};
+use async_trait::async_trait;
+use concurrent_runtime::ConcurrentRuntime;
+use mail_database::{IMailDB, MailError, RecipientResults};
+use native_tls::{TlsConnector, TlsStream};
+use relay::{error::ResolveError, MailHost, Resolver};
+use smart_stream::{AsyncStream, TlsAcceptor};
+
+#[derive(Debug, Clone)]
+pub struct StoredMail {
+    pub sender: String,
+    pub receivers: Vec<String>,
+    pub subject: String,
+    pub body: String,
+    pub message_id: Option<String>,
+}
+
+#[derive(Default)]
+pub struct MemoryState {
+    pub users: HashMap<String, String>,
+    pub mails: Vec<StoredMail>,
+    pub commits: usize,
+    pub rollbacks: usize,
+    // makes the next insert fail as if the database went away
+    pub fail_next_insert: bool,
+    // users over their quota, deliver_to_recipients skips them
+    pub full_mailboxes: Vec<String>,
+    // how long login takes, like a slow database
+    pub login_delay: Duration,
+    // how long deliver_to_recipients takes, the session's database lock is held meanwhile
+    pub delivery_delay: Duration,
+}
+
+// In-memory IMailDB; clones share the same storage so tests can inspect it
+// after handing a copy to the session.
+#[derive(Default, Clone)]
+pub struct MemoryMailDB {
+    pub state: Arc<Mutex<MemoryState>>,
+    logged_user: Option<String>,
+    envelope_sender: Option<String>,
+    message_id: Option<String>,
+    // number of stored mails when the open transaction began
+    transaction_start: Option<usize>,
+}
+
+impl MemoryMailDB {
+    pub fn with_users(users: &[(&str, &str)]) -> Self {
+        let db = Self::default();
+        for (user, pass) in users {
+            db.state.lock().unwrap().users.insert(user.to_string(), pass.to_string());
+        }
+        db
+    }
+
+    pub fn mails(&self) -> Vec<StoredMail> {
+        self.state.lock().unwrap().mails.clone()
+    }
+
+    // Like PgMailDB, a local user named by MAIL FROM is the sender, otherwise the logged in user
+    fn sender(&self, state: &MemoryState) -> Result<String, MailError> {
+        let logged_user = self.logged_user.clone().ok_or(MailError::UserNotLoggedIn)?;
+        Ok(self.envelope_sender.clone()
+            .filter(|sender| state.users.contains_key(sender))
+            .unwrap_or(logged_user))
+    }
+}
+
+impl IMailDB for MemoryMailDB {
+    fn connect(&mut self, _connection_string: &str) -> Result<(), MailError> {
+        Ok(())
+    }
+
+    fn disconnect(&mut self) {}
+
+    fn is_connected(&mut self) -> bool {
+        true
+    }
+
+    fn sign_up(&mut self, user_name: &str, password: &str) -> Result<(), MailError> {
+        let mut state = self.state.lock().unwrap();
+        if state.users.contains_key(user_name) {
+            return Err(MailError::UserAlreadyExist);
+        }
+        state.users.insert(user_name.to_string(), password.to_string());
+        Ok(())
+    }
+
+    fn login(&mut self, user_name: &str, password: &str) -> Result<(), MailError> {
+        let delay = self.state.lock().unwrap().login_delay;
+        std::thread::sleep(delay);
+        match self.state.lock().unwrap().users.get(user_name) {
+            Some(stored) if stored == password => {
+                self.logged_user = Some(user_name.to_string());
+                Ok(())
+            }
+            Some(_) => Err(MailError::UserAuthError),
+            None => Err(MailError::UserNotFound),
+        }
+    }
+
+    fn verify_credentials(&mut self, user_name: &str, password: &str) -> Result<bool, MailError> {
+        Ok(self.state.lock().unwrap().users.get(user_name).is_some_and(|stored| stored == password))
+    }
+
+    fn insert_email(&mut self, receiver: &str, subject: &str, body: &str) -> Result<(), MailError> {
+        self.insert_multiple_emails(vec![receiver], subject, body)
+    }
+
+    fn insert_multiple_emails(&mut self, receivers: Vec<&str>, subject: &str, body: &str) -> Result<(), MailError> {
+        let mut state = self.state.lock().unwrap();
+        let sender = self.sender(&state)?;
+        if receivers.is_empty() {
+            return Err(MailError::EmptyReceiversError);
+        }
+        if std::mem::take(&mut state.fail_next_insert) {
+            return Err(MailError::NoConnection);
+        }
+        if receivers.iter().any(|receiver| !state.users.contains_key(*receiver)) {
+            return Err(MailError::UserNotFound);
+        }
+        state.mails.push(StoredMail {
+            sender,
+            receivers: receivers.iter().map(|receiver| receiver.to_string()).collect(),
+            subject: subject.to_string(),
+            body: body.to_string(),
+            message_id: self.message_id.clone(),
+        });
+        Ok(())
+    }
+
+    // Recipients that can receive the message share one StoredMail, its position is their message id
+    fn deliver_to_recipients(&mut self, receivers: Vec<&str>, subject: &str, body: &str)
+        -> Result<RecipientResults, MailError> {
+        let delay = self.state.lock().unwrap().delivery_delay;
+        std::thread::sleep(delay);
+        let mut state = self.state.lock().unwrap();
+        let sender = self.sender(&state)?;
+        if receivers.is_empty() {
+            return Err(MailError::EmptyReceiversError);
+        }
+        if std::mem::take(&mut state.fail_next_insert) {
+            return Err(MailError::NoConnection);
+        }
+        let message_id = state.mails.len() as i32 + 1;
+        let results: RecipientResults = receivers.iter()
+            .map(|receiver| {
+                let result = if !state.users.contains_key(*receiver) {
+                    Err(MailError::UserNotFound)
+                } else if state.full_mailboxes.iter().any(|full| full == receiver) {
+                    Err(MailError::QuotaExceeded)
+                } else {
+                    Ok(message_id)
+                };
+                (receiver.to_string(), result)
+            })
+            .collect();
+        let delivered: Vec<String> = results.iter()
+            .filter(|(_, result)| result.is_ok())
+            .map(|(receiver, _)| receiver.clone())
+            .collect();
+        if !delivered.is_empty() {
+            state.mails.push(StoredMail {
+                sender,
+                receivers: delivered,
+                subject: subject.to_string(),
+                body: body.to_string(),
+                message_id: self.message_id.clone(),
+            });
+        }
+        Ok(results)
+    }
+
+    fn set_envelope_sender(&mut self, sender: Option<&str>) {
+        self.envelope_sender = sender.map(str::to_string);
+    }
+
+    fn set_message_id(&mut self, message_id: Option<&str>) {
+        self.message_id = message_id.map(str::to_string);
+    }
+
+    fn user_exists(&mut self, user_name: &str) -> Result<bool, MailError> {
+        Ok(self.state.lock().unwrap().users.contains_key(user_name))
+    }
+
+    // Message ids are 1-based positions in the mail list
+    fn get_message(&mut self, user_name: &str, email_message_id: i32) -> Result<mail_database::StoredMail, MailError> {
+        let state = self.state.lock().unwrap();
+        let index = usize::try_from(email_message_id - 1).map_err(|_| MailError::NotFound)?;
+        let mail = state.mails.get(index).ok_or(MailError::NotFound)?;
+        let recipient = mail.receivers.iter().find(|receiver| *receiver == user_name);
+        if mail.sender != user_name && recipient.is_none() {
+            return Err(MailError::Unauthorized);
+        }
+        Ok(mail_database::StoredMail {
+            email_message_id,
+            sender: mail.sender.clone(),
+            recipient: recipient.unwrap_or(&mail.receivers[0]).clone(),
+            subject: mail.subject.clone(),
+            body: mail.body.clone(),
+            sent_at: None,
+            message_id: mail.message_id.clone(),
+        })
+    }
+
+    fn begin_transaction(&mut self) -> Result<(), MailError> {
+        self.transaction_start = Some(self.state.lock().unwrap().mails.len());
+        Ok(())
+    }
+
+    fn commit_transaction(&mut self) -> Result<(), MailError> {
+        self.transaction_start.take().ok_or(MailError::NoConnection)?;
+        self.state.lock().unwrap().commits += 1;
+        Ok(())
+    }
+
+    fn rollback_transaction(&mut self) -> Result<(), MailError> {
+        let start = self.transaction_start.take().ok_or(MailError::NoConnection)?;
+        let mut state = self.state.lock().unwrap();
+        state.mails.truncate(start);
+        state.rollbacks += 1;
+        Ok(())
+    }
+}
+
+type Delivery = (Envelope, Vec<u8>);
+
+// Delivery backend keeping every message it receives
+#[derive(Clone, Default)]
+pub struct RecordingBackend {
+    pub deliveries: Arc<Mutex<Vec<Delivery>>>,
+}
+
+impl DeliveryBackend for RecordingBackend {
+    fn deliver(&self, envelope: &Envelope, data: &[u8]) -> Result<usize, DeliveryError> {
+        self.deliveries.lock().unwrap().push((envelope.clone(), data.to_vec()));
+        Ok(envelope.recipients.len())
+    }
+}
+
+// Records messages like RecordingBackend, after keeping the session waiting for `delay`
+pub struct SlowBackend {
+    pub delay: Duration,
+    pub backend: RecordingBackend,
+}
+
+impl DeliveryBackend for SlowBackend {
+    fn deliver(&self, envelope: &Envelope, data: &[u8]) -> Result<usize, DeliveryError> {
+        std::thread::sleep(self.delay);
+        self.backend.deliver(envelope, data)
+    }
+}
+
+// Every domain is its own mail host on 127.0.0.1, where fake_smtp_server listens
+pub struct LoopbackResolver;
+
+#[async_trait]
+impl Resolver for LoopbackResolver {
+    async fn mx_lookup(&self, _domain: &str) -> Result<Vec<MailHost>, ResolveError> {
+        Err(ResolveError::NotFound)
+    }
+
+    async fn address_lookup(&self, _host: &str) -> Result<Vec<IpAddr>, ResolveError> {
+        Ok(vec![IpAddr::V4(Ipv4Addr::LOCALHOST)])
+    }
+}
+
+// Accepts one connection on 127.0.0.1, answers every command positively and
+// returns the received lines once the client quits
+pub fn fake_smtp_server() -> (u16, thread::JoinHandle<Vec<String>>) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+
+    let server = thread::spawn(move || {
+        let (mut stream, _) = listener.accept().unwrap();
+        let mut reader = BufReader::new(stream.try_clone().unwrap());
+        let mut lines = Vec::new();
+        let mut in_data = false;
+
+        stream.write_all(b"220 fake ready\r\n").unwrap();
+        loop {
+            let mut line = String::new();
+            if reader.read_line(&mut line).unwrap() == 0 {
+                break;
+            }
+            let line = line.trim_end().to_string();
+            lines.push(line.clone());
+
+            let reply: &[u8] = if in_data {
+                if line != "." {
+                    continue;
+                }
+                in_data = false;
+                b"250 OK\r\n"
+            } else if line == "DATA" {
+                in_data = true;
+                b"354 Go ahead\r\n"
+            } else if line == "QUIT" {
+                stream.write_all(b"221 Bye\r\n").unwrap();
+                break;
+            } else {
+                b"250 OK\r\n"
+            };
+            stream.write_all(reply).unwrap();
+        }
+        lines
+    });
+    (port, server)
+}
+
+pub fn tls_acceptor() -> TlsAcceptor {
+    smart_stream::tls_acceptor(
+        include_bytes!("certs/server.crt"),
+        include_bytes!("certs/server.key"),
+    ).unwrap()
+}
+
+enum ClientStream {
+    Plain(TcpStream),
+    Encrypted(TlsStream<TcpStream>),
+    Unix(UnixStream),
+}
+
+impl ClientStream {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        match self {
+            Self::Plain(stream) => stream.read(buf),
+            Self::Encrypted(stream) => stream.read(buf),
+            Self::Unix(stream) => stream.read(buf),
+        }
+    }
+
+    fn write_all(&mut self, buf: &[u8]) -> std::io::Result<()> {
+        match self {
+            Self::Plain(stream) => stream.write_all(buf),
+            Self::Encrypted(stream) => stream.write_all(buf),
+            Self::Unix(stream) => stream.write_all(buf),
+        }
+    }
+}
+
+type SessionOutcome = (Result<(), ClientSessionError>, SessionData);
+
+// Raw SMTP client talking to a ClientSession over a loopback TCP connection
+pub struct TestClient {
+    stream: Option<ClientStream>,
+    session: Option<mpsc::Receiver<SessionOutcome>>,
+    // plaintext bytes written by the client, TLS handshakes excluded
+    pub bytes_sent: usize,
+}
+
+impl TestClient {
+    // Runs the session on a dedicated thread
+    pub fn start(db: MemoryMailDB, config: SessionConfig) -> Self {
+        Self::start_with(db, config, Self::tcp_connection(), Self::spawn_thread)
+    }
+
+    // Like start, but the session has no TLS acceptor
+    pub fn start_without_tls(db: MemoryMailDB, config: SessionConfig) -> Self {
+        Self::start_with_tls(db, config, None, None, Self::tcp_connection(), Self::spawn_thread)
+    }
+
+    // Like start, but accepted messages go to `backend` instead of the database
+    pub fn start_with_backend(db: MemoryMailDB, config: SessionConfig, backend: Box<dyn DeliveryBackend + Send + Sync>) -> Self {
+        Self::start_with_tls(db, config, Some(tls_acceptor()), Some(backend), Self::tcp_connection(), Self::spawn_thread)
+    }
+
+    // Like start, but the server side gives up reading after `timeout` seconds
+    pub fn start_with_timeout(db: MemoryMailDB, config: SessionConfig, timeout: u64) -> Self {
+        Self::start_with(db, config, Self::tcp_connection_with_timeout(timeout), Self::spawn_thread)
+    }
+
+    // Runs the session as a task of the given runtime, like the server does
+    pub fn start_on_runtime(runtime: &ConcurrentRuntime, db: MemoryMailDB, config: SessionConfig) -> Self {
+        Self::start_with(db, config, Self::tcp_connection(), |session| runtime.spawn(session))
+    }
+
+    // Connects through a Unix domain socket bound at `path`
+    pub fn start_unix(path: &Path, db: MemoryMailDB, config: SessionConfig) -> Self {
+        let listener = UnixListener::bind(path).unwrap();
+        let stream = UnixStream::connect(path).unwrap();
+        stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        let (server_stream, _) = listener.accept().unwrap();
+        let connection = (ClientStream::Unix(stream), AsyncStream::from_unix(server_stream, 5).unwrap());
+        Self::start_with(db, config, connection, Self::spawn_thread)
+    }
+
+    fn tcp_connection() -> (ClientStream, AsyncStream) {
+        Self::tcp_connection_with_timeout(5)
+    }
+
+    fn tcp_connection_with_timeout(timeout: u64) -> (ClientStream, AsyncStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let stream = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        let (server_stream, _) = listener.accept().unwrap();
+        (ClientStream::Plain(stream), AsyncStream::new(server_stream, timeout).unwrap())
+    }
+
+    fn spawn_thread(session: futures::future::BoxFuture<'static, ()>) {
+        thread::spawn(move || futures::executor::block_on(session));
+    }
+
+    fn start_with<S>(db: MemoryMailDB, config: SessionConfig, connection: (ClientStream, AsyncStream), spawn: S) -> Self
+    where
+        S: FnOnce(futures::future::BoxFuture<'static, ()>),
+    {
+        Self::start_with_tls(db, config, Some(tls_acceptor()), None, connection, spawn)
+    }
+
+    fn start_with_tls<S>(db: MemoryMailDB, config: SessionConfig, acceptor: Option<TlsAcceptor>,
+        backend: Option<Box<dyn DeliveryBackend + Send + Sync>>, (stream, connection): (ClientStream, AsyncStream), spawn: S) -> Self
+    where
+        S: FnOnce(futures::future::BoxFuture<'static, ()>),
+    {
+        let (sender, receiver) = mpsc::channel();
+        spawn(Box::pin(async move {
+            let mut session = ClientSession::with_database(connection, acceptor.as_ref(), Box::new(db), config);
+            if let Some(backend) = backend {
+                session = session.with_delivery_backend(backend);
+            }
+            let result = session.run().await;
+            let _ = sender.send((result, session.session_data().clone()));
+        }));
+
+        let mut client = Self {
+            stream: Some(stream),
+            session: Some(receiver),
+            bytes_sent: 0,
+        };
+        let greeting = client.read_reply();
+        assert!(greeting.starts_with("220"), "unexpected greeting: {}", greeting);
+        client
+    }
+
+    // Reads a complete (possibly multiline) reply
+    pub fn read_reply(&mut self) -> String {
+        let stream = self.stream.as_mut().unwrap();
+        let mut reply = Vec::new();
+        let mut chunk = [0; 1024];
+        loop {
+            let n = stream.read(&mut chunk).unwrap();
+            if n == 0 {
+                break;
+            }
+            reply.extend_from_slice(&chunk[..n]);
+            let text = String::from_utf8_lossy(&reply);
+            if text.ends_with("\r\n") && Self::is_last_line(text.trim_end().lines().last().unwrap_or("")) {
+                break;
+            }
+        }
+        String::from_utf8(reply).unwrap()
+    }
+
+    fn is_last_line(line: &str) -> bool {
+        line.len() == 3 || line.as_bytes().get(3) == Some(&b' ')
+    }
+
+    pub fn write(&mut self, data: &str) {
+        self.write_bytes(data.as_bytes());
+    }
+
+    // For data that is not valid UTF-8
+    pub fn write_bytes(&mut self, data: &[u8]) {
+        self.stream.as_mut().unwrap().write_all(data).unwrap();
+        self.bytes_sent += data.len();
+    }
+
+    pub fn send(&mut self, command: &str) -> String {
+        self.write(&format!("{}\r\n", command));
+        self.read_reply()
+    }
+
+    // BDAT with the chunk in the same write, as a pipelining client sends it
+    pub fn send_chunk(&mut self, chunk: &str, last: bool) -> String {
+        let flag = if last { " LAST" } else { "" };
+        self.write(&format!("BDAT {}{}\r\n{}", chunk.len(), flag, chunk));
+        self.read_reply()
+    }
+
+    pub fn starttls(&mut self) {
+        let reply = self.send("STARTTLS");
+        assert!(reply.starts_with("220"), "STARTTLS failed: {}", reply);
+        self.tls_handshake();
+    }
+
+    // Client side of the handshake, after the server accepted STARTTLS
+    pub fn tls_handshake(&mut self) {
+        let stream = match self.stream.take() {
+            Some(ClientStream::Plain(stream)) => stream,
+            _ => panic!("stream is already encrypted"),
+        };
+        let connector = TlsConnector::builder()
+            .danger_accept_invalid_certs(true)
+            .build()
+            .unwrap();
+        self.stream = Some(ClientStream::Encrypted(connector.connect("localhost", stream).unwrap()));
+    }
+
+    pub fn auth_plain(&mut self, user: &str, password: &str) -> String {
+        let credentials = base64::encode(&format!("\0{}\0{}", user, password));
+        self.send(&format!("AUTH PLAIN {}", credentials))
+    }
+
+    // EHLO, STARTTLS and AUTH PLAIN with the given credentials
+    pub fn login(&mut self, user: &str, password: &str) {
+        let reply = self.send("EHLO client.example.com");
+        assert!(reply.starts_with("250"), "EHLO failed: {}", reply);
+        self.starttls();
+        let reply = self.auth_plain(user, password);
+        assert!(reply.starts_with("235"), "AUTH failed: {}", reply);
+    }
+
+    pub fn quit(self) -> Result<(), ClientSessionError> {
+        self.finish().0
+    }
+
+    // Waits for the session to end on its own, e.g. after the server closed the connection
+    pub fn join(mut self) -> Result<(), ClientSessionError> {
+        self.wait_for_session().0
+    }
+
+    // Closes the connection without QUIT and waits for the session to notice
+    pub fn disconnect(mut self) -> Result<(), ClientSessionError> {
+        self.stream.take();
+        self.wait_for_session().0
+    }
+
+    // Like quit, but also returns the final session data
+    pub fn finish(mut self) -> (Result<(), ClientSessionError>, SessionData) {
+        let reply = self.send("QUIT");
+        assert!(reply.starts_with("221"), "QUIT failed: {}", reply);
+        self.wait_for_session()
+    }
+
+    fn wait_for_session(&mut self) -> SessionOutcome {
+        self.session.take().unwrap()
+            .recv_timeout(Duration::from_secs(5))
+            .expect("session did not finish")
+    }
+}
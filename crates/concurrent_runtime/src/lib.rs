@@ -1,4 +1,8 @@
-use std::{ops::Index, sync::{atomic::{AtomicBool, Ordering}, Arc}};
+use std::{
+    ops::Index,
+    sync::{atomic::{AtomicBool, AtomicU64, Ordering}, Arc},
+    time::{Duration, Instant},
+};
 use futures::{
     future::BoxFuture,
     task::{Context, Poll},
@@ -6,6 +10,7 @@ use futures::{
 };
 use crossbeam::{epoch::{pin, Atomic}, queue::SegQueue};
 mod threadpool;
+pub use threadpool::{ExecuteError, ThreadPool};
 
 use logger::info;
 use logger_proc_macro::*;
@@ -13,33 +18,78 @@ use logger_proc_macro::*;
 type Task = BoxFuture<'static, ()>;
 type GlobalTaskQueue = SegQueue<Task>;
 
+// An executor that has not polled its queue for this long is not considered alive
+const HEARTBEAT_TIMEOUT: Duration = Duration::from_secs(5);
+
+// Liveness of a single executor thread, shared with the runtime
+#[derive(Debug)]
+struct Heartbeat {
+    epoch: Instant,
+    last_beat_ms: AtomicU64,
+    running: AtomicBool,
+}
+
+impl Heartbeat {
+    fn new() -> Self {
+        Heartbeat {
+            epoch: Instant::now(),
+            last_beat_ms: AtomicU64::new(0),
+            running: AtomicBool::new(false),
+        }
+    }
+
+    fn beat(&self) {
+        self.last_beat_ms.store(self.epoch.elapsed().as_millis() as u64, Ordering::Relaxed);
+    }
+
+    fn is_alive(&self) -> bool {
+        let since_last_beat = self.epoch.elapsed()
+            .saturating_sub(Duration::from_millis(self.last_beat_ms.load(Ordering::Relaxed)));
+        self.running.load(Ordering::Relaxed) && since_last_beat < HEARTBEAT_TIMEOUT
+    }
+}
+
+// Marks the executor as stopped when run returns or unwinds from a panicking task
+struct RunningGuard<'a>(&'a Heartbeat);
+
+impl Drop for RunningGuard<'_> {
+    fn drop(&mut self) {
+        self.0.running.store(false, Ordering::Relaxed);
+    }
+}
+
 #[derive(Debug)]
 pub struct Executor {
     global_queue: Arc<GlobalTaskQueue>,
     termination_flag: Arc<AtomicBool>,
+    heartbeat: Arc<Heartbeat>,
 }
 
 impl Executor {
     #[log(Trace)]
-    fn new(global_queue: Arc<GlobalTaskQueue>) -> Self {
+    fn new(global_queue: Arc<GlobalTaskQueue>, heartbeat: Arc<Heartbeat>) -> Self {
         Executor {
             global_queue,
             termination_flag: Arc::new(AtomicBool::new(false)),
+            heartbeat,
         }
     }
     
     #[log(Trace)]
     fn run(&mut self) {
-        loop {
-            if !self.termination_flag.load(Ordering::Relaxed) {
-                if let Some(mut task) = self.global_queue.pop() {
-                    let waker = futures::task::noop_waker_ref();
-                    let mut context = Context::from_waker(waker);
-
-                    match task.as_mut().poll(&mut context) {
-                        Poll::Ready(_) => info!("Async coroutine finished"),
-                        Poll::Pending => self.global_queue.push(task),
-                    }
+        self.heartbeat.beat();
+        self.heartbeat.running.store(true, Ordering::Relaxed);
+        let _guard = RunningGuard(&self.heartbeat);
+
+        while !self.termination_flag.load(Ordering::Relaxed) {
+            self.heartbeat.beat();
+            if let Some(mut task) = self.global_queue.pop() {
+                let waker = futures::task::noop_waker_ref();
+                let mut context = Context::from_waker(waker);
+
+                match task.as_mut().poll(&mut context) {
+                    Poll::Ready(_) => info!("Async coroutine finished"),
+                    Poll::Pending => self.global_queue.push(task),
                 }
             }
         }
@@ -54,6 +104,7 @@ impl Executor {
 #[derive(Debug)]
 struct ExecutorManager {
     executors: Vec<Arc<Atomic<Executor>>>,
+    heartbeats: Vec<Arc<Heartbeat>>,
     global_async_queue: Arc<GlobalTaskQueue>,
 }
 
@@ -62,17 +113,21 @@ impl ExecutorManager {
     fn new() -> Self {
         ExecutorManager {
             executors: Vec::new(),
+            heartbeats: Vec::new(),
             global_async_queue: Arc::new(SegQueue::new()),
         }
     }
 
     #[log(Trace)]
     fn create_executor(&mut self) -> Arc<Atomic<Executor>> {
+        let heartbeat = Arc::new(Heartbeat::new());
         let executor = Arc::new(Atomic::new(Executor::new(
-            self.global_async_queue.clone()
+            self.global_async_queue.clone(),
+            heartbeat.clone(),
         )));
 
         self.executors.push(executor.clone());
+        self.heartbeats.push(heartbeat);
         executor
     }
 
@@ -81,6 +136,11 @@ impl ExecutorManager {
         self.global_async_queue.push(task);
     }
     
+    #[log(Trace)]
+    fn active_executors(&self) -> usize {
+        self.heartbeats.iter().filter(|heartbeat| heartbeat.is_alive()).count()
+    }
+
     #[log(Trace)]
     fn stop(&mut self) {
         for executor in self.executors.clone() {
@@ -144,6 +204,20 @@ impl ConcurrentRuntime {
         self.executors_manager.create_async_task(task);
     }
 
+    // Number of executor threads that are running and recently polled the task queue
+    #[log(Trace)]
+    pub fn active_workers(&self) -> usize {
+        self.executors_manager.active_executors()
+    }
+
+    // True when every worker thread runs an executor, false before start,
+    // after stop or once a worker died on a panicking task
+    #[log(Trace)]
+    pub fn is_healthy(&self) -> bool {
+        let workers = self.threadpool.workers_count();
+        workers > 0 && self.active_workers() == workers
+    }
+
     #[log(Trace)]
     pub fn stop(&mut self) {
         self.executors_manager.stop();
@@ -1,5 +1,5 @@
-use std::{fmt::Debug, thread};
-use crossbeam::channel::{unbounded, Sender, Receiver};
+use std::{any::Any, fmt::Debug, panic::{self, AssertUnwindSafe}, thread};
+use crossbeam::channel::{bounded, unbounded, Sender, Receiver, TrySendError};
 use logger::{
     info,
     error,
@@ -25,10 +25,27 @@ pub enum Message {
 
 type Job = Box<dyn FnOnce() + Send + 'static>;
 
+#[derive(Debug, PartialEq, Eq)]
+pub enum ExecuteError {
+    // the job queue of a bounded pool is full
+    Saturated,
+    // all workers are gone
+    Disconnected,
+}
+
 impl ThreadPool {
     #[log(Trace)]
     pub fn new(size: usize) -> ThreadPool {
-        let (sender, receiver) = unbounded();
+        Self::with_channel(size, unbounded())
+    }
+
+    // At most `capacity` jobs may wait for a free worker, see try_execute
+    #[log(Trace)]
+    pub fn bounded(size: usize, capacity: usize) -> ThreadPool {
+        Self::with_channel(size, bounded(capacity))
+    }
+
+    fn with_channel(size: usize, (sender, receiver): (Sender<Message>, Receiver<Message>)) -> ThreadPool {
         let mut workers = Vec::with_capacity(size);
         for id in 0..size {
             workers.push(Worker::new(id, receiver.clone()));
@@ -46,6 +63,19 @@ impl ThreadPool {
         let _ = self.sender.send(Message::NewJob(job));
     }
 
+    // Like execute, but fails instead of blocking when the queue of a bounded pool is full
+    #[log(Debug)]
+    pub fn try_execute<F>(&self, f: F) -> Result<(), ExecuteError>
+    where
+        F: Fn() + Send + 'static,
+    {
+        let job = Box::new(f);
+        self.sender.try_send(Message::NewJob(job)).map_err(|err| match err {
+            TrySendError::Full(_) => ExecuteError::Saturated,
+            TrySendError::Disconnected(_) => ExecuteError::Disconnected,
+        })
+    }
+
     #[log(Trace)]
     pub fn workers_count(&self) -> usize {
         self.workers.len()
@@ -85,7 +115,10 @@ impl Worker {
             match message {
                 Message::NewJob(job) => {
                     info!("Worker {} got a job. Executing...", id);
-                    job();
+                    // a panicking job must not take the worker down with it
+                    if let Err(payload) = panic::catch_unwind(AssertUnwindSafe(job)) {
+                        error!("Job panicked in worker {}: {}", id, Self::panic_message(payload.as_ref()));
+                    }
                 }
                 Message::Terminate => {
                     info!("Worker {} was told to terminate.", id);
@@ -99,4 +132,58 @@ impl Worker {
             thread: Some(thread),
         }
     }
+
+    fn panic_message(payload: &(dyn Any + Send)) -> &str {
+        if let Some(message) = payload.downcast_ref::<&str>() {
+            message
+        } else if let Some(message) = payload.downcast_ref::<String>() {
+            message
+        } else {
+            "unknown panic"
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn worker_survives_panicking_job_test() {
+        let pool = ThreadPool::new(1);
+        let (sender, receiver) = unbounded();
+
+        pool.execute(|| panic!("job failure"));
+        pool.execute(move || sender.send(42).unwrap());
+
+        assert_eq!(receiver.recv_timeout(Duration::from_secs(5)), Ok(42));
+    }
+
+    #[test]
+    fn bounded_pool_saturation_test() {
+        let pool = ThreadPool::bounded(1, 1);
+        let (started_sender, started) = unbounded();
+        let (release_sender, release) = unbounded::<()>();
+
+        // keep the only worker busy until released
+        pool.execute(move || {
+            started_sender.send(()).unwrap();
+            let _ = release.recv();
+        });
+        started.recv_timeout(Duration::from_secs(5)).unwrap();
+
+        assert_eq!(pool.try_execute(|| {}), Ok(()));
+        assert_eq!(pool.try_execute(|| {}), Err(ExecuteError::Saturated));
+
+        release_sender.send(()).unwrap();
+    }
+
+    #[test]
+    fn unbounded_pool_accepts_jobs_test() {
+        let pool = ThreadPool::new(1);
+        for _ in 0..100 {
+            assert_eq!(pool.try_execute(|| {}), Ok(()));
+        }
+    }
 }
@@ -0,0 +1,43 @@
+#[cfg(test)]
+mod tests {
+    use std::{thread, time::{Duration, Instant}};
+    use concurrent_runtime::ConcurrentRuntime;
+
+    fn wait_for_active_workers(runtime: &ConcurrentRuntime, expected: usize) -> bool {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while Instant::now() < deadline {
+            if runtime.active_workers() == expected {
+                return true;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        false
+    }
+
+    #[test]
+    fn healthy_after_start_test() {
+        let mut runtime = ConcurrentRuntime::new(2);
+        assert!(!runtime.is_healthy());
+
+        runtime.start();
+        assert!(wait_for_active_workers(&runtime, 2));
+        assert!(runtime.is_healthy());
+
+        runtime.stop();
+        assert!(wait_for_active_workers(&runtime, 0));
+        assert!(!runtime.is_healthy());
+    }
+
+    #[test]
+    fn panicked_worker_is_reported_test() {
+        let mut runtime = ConcurrentRuntime::new(3);
+        runtime.start();
+        assert!(wait_for_active_workers(&runtime, 3));
+
+        runtime.spawn(async { panic!("task failure") });
+        assert!(wait_for_active_workers(&runtime, 2));
+        assert!(!runtime.is_healthy());
+
+        runtime.stop();
+    }
+}
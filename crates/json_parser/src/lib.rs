@@ -62,6 +62,100 @@ impl JsonValue {
             None
         }
     }
+
+    #[log(Trace)]
+    pub fn is_null(&self) -> bool {
+        matches!(self, JsonValue::Null)
+    }
+
+    // Strict counterpart of `value["key"]`: None if the key is absent
+    // (or self is not an object), Some(&JsonValue::Null) for an explicit null
+    #[log(Trace)]
+    pub fn get(&self, key: &str) -> Option<&JsonValue> {
+        if let JsonValue::Object(map) = self {
+            map.get(key)
+        } else {
+            None
+        }
+    }
+
+    // Strict counterpart of `value[index]`
+    #[log(Trace)]
+    pub fn get_index(&self, index: usize) -> Option<&JsonValue> {
+        if let JsonValue::Array(arr) = self {
+            arr.get(index)
+        } else {
+            None
+        }
+    }
+
+    // Lays `other` over self: objects are merged key by key, recursively,
+    // anything else (scalars, arrays, mismatched types) is replaced by `other`
+    #[log(Trace)]
+    pub fn merge(&mut self, other: JsonValue) {
+        match (self, other) {
+            (JsonValue::Object(map), JsonValue::Object(other)) => {
+                for (key, value) in other {
+                    match map.get_mut(&key) {
+                        Some(existing) => existing.merge(value),
+                        None => {
+                            map.insert(key, value);
+                        },
+                    }
+                }
+            },
+            (this, other) => *this = other,
+        }
+    }
+}
+
+// Compact JSON text; numbers that JSON cannot represent, like NaN, are written as null
+impl std::fmt::Display for JsonValue {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            JsonValue::Object(map) => {
+                write!(f, "{{")?;
+                for (i, (key, value)) in map.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ",")?;
+                    }
+                    write_json_string(f, key)?;
+                    write!(f, ":{}", value)?;
+                }
+                write!(f, "}}")
+            },
+            JsonValue::Array(array) => {
+                write!(f, "[")?;
+                for (i, value) in array.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ",")?;
+                    }
+                    write!(f, "{}", value)?;
+                }
+                write!(f, "]")
+            },
+            JsonValue::String(value) => write_json_string(f, value),
+            JsonValue::Number(value) if value.is_finite() => write!(f, "{}", value),
+            JsonValue::Number(_) | JsonValue::Null => write!(f, "null"),
+            JsonValue::Bool(value) => write!(f, "{}", value),
+        }
+    }
+}
+
+fn write_json_string(f: &mut std::fmt::Formatter, value: &str) -> std::fmt::Result {
+    write!(f, "\"")?;
+    for c in value.chars() {
+        match c {
+            '"' => write!(f, "\\\"")?,
+            '\\' => write!(f, "\\\\")?,
+            '\n' => write!(f, "\\n")?,
+            '\r' => write!(f, "\\r")?,
+            '\t' => write!(f, "\\t")?,
+            c if c.is_control() => write!(f, "\\u{:04x}", c as u32)?,
+            c => write!(f, "{}", c)?,
+        }
+    }
+    write!(f, "\"")
 }
 
 impl Index<&str> for JsonValue {
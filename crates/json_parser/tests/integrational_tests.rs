@@ -1,6 +1,6 @@
 #[cfg(test)]
 mod tests {
-    use json_parser::JsonParser;
+    use json_parser::{JsonParser, JsonValue};
     #[test]
     fn test_single_pair() {
         let code = r#"
@@ -173,4 +173,96 @@ mod tests {
         assert_eq!(age2, 28.0);
         assert!(is_student2);
     }
+
+    #[test]
+    fn strict_get_test() {
+        let code = r#"
+            {
+                "present": null,
+                "nested": { "value": 1 },
+                "list": [ null ]
+            }
+        "#;
+
+        let mut perser = JsonParser::default();
+        let json_value = perser.parse(code).unwrap();
+
+        // the Index operator cannot tell these apart
+        assert!(json_value["present"].is_null());
+        assert!(json_value["absent"].is_null());
+
+        assert!(json_value.get("present").unwrap().is_null());
+        assert!(json_value.get("absent").is_none());
+        assert!(json_value.get("nested").and_then(|nested| nested.get("typo")).is_none());
+        assert_eq!(json_value.get("nested").and_then(|nested| nested.get("value")).and_then(|v| v.as_number()), Some(1.0));
+
+        let list = json_value.get("list").unwrap();
+        assert!(list.get_index(0).unwrap().is_null());
+        assert!(list.get_index(1).is_none());
+        assert!(json_value.get_index(0).is_none());
+    }
+
+    #[test]
+    fn merge_nested_objects_test() {
+        let mut perser = JsonParser::default();
+        let mut base = perser.parse(r#"{ "server": { "ip": "127.0.0.1", "port": 25 }, "name": "base" }"#).unwrap();
+        let other = perser.parse(r#"{ "server": { "port": 2525, "tls": { "enabled": true } } }"#).unwrap();
+        base.merge(other);
+
+        assert_eq!(base["server"]["ip"].as_str().unwrap(), "127.0.0.1");
+        assert_eq!(base["server"]["port"].as_number(), Some(2525.0));
+        assert_eq!(base["server"]["tls"]["enabled"].as_bool(), Some(true));
+        assert_eq!(base["name"].as_str().unwrap(), "base");
+    }
+
+    #[test]
+    fn merge_overrides_scalar_test() {
+        let mut perser = JsonParser::default();
+        let mut base = perser.parse(r#"{ "level": "info", "size": 10, "flag": true }"#).unwrap();
+        base.merge(perser.parse(r#"{ "level": "debug", "size": null, "flag": { "on": false } }"#).unwrap());
+
+        assert_eq!(base["level"].as_str().unwrap(), "debug");
+        // null is a value like any other, not a missing key
+        assert!(base.get("size").unwrap().is_null());
+        // a mismatched type is taken from the other side
+        assert_eq!(base["flag"]["on"].as_bool(), Some(false));
+    }
+
+    #[test]
+    fn merge_replaces_array_test() {
+        let mut perser = JsonParser::default();
+        let mut base = perser.parse(r#"{ "list": [1, 2, 3], "objects": [{ "a": 1 }] }"#).unwrap();
+        base.merge(perser.parse(r#"{ "list": [4], "objects": [{ "b": 2 }] }"#).unwrap());
+
+        let list = base["list"].as_array().unwrap();
+        assert_eq!(list.len(), 1);
+        assert_eq!(list[0].as_number(), Some(4.0));
+        // array elements are not merged with each other
+        assert!(base["objects"][0].get("a").is_none());
+        assert_eq!(base["objects"][0]["b"].as_number(), Some(2.0));
+
+        let mut scalar = perser.parse("5").unwrap();
+        scalar.merge(perser.parse(r#"{ "a": 1 }"#).unwrap());
+        assert_eq!(scalar["a"].as_number(), Some(1.0));
+    }
+
+    #[test]
+    fn display_round_trip_test() {
+        let mut parser = JsonParser::default();
+        let value = parser.parse(r#"{ "name": "smtp", "ports": [25, 587.5], "tls": true, "relay": null, "limits": { "size": 1024 } }"#).unwrap();
+        let reparsed = parser.parse(&value.to_string()).unwrap();
+        assert_eq!(reparsed["name"].as_str().unwrap(), "smtp");
+        assert_eq!(reparsed["ports"][1].as_number().unwrap(), 587.5);
+        assert_eq!(reparsed["tls"].as_bool(), Some(true));
+        assert!(reparsed["relay"].is_null());
+        assert_eq!(reparsed["limits"]["size"].as_number().unwrap(), 1024.0);
+    }
+
+    #[test]
+    fn display_escapes_strings_test() {
+        let value = JsonValue::String("say \"hi\"\\\r\n\u{1}".to_string());
+        assert_eq!(value.to_string(), r#""say \"hi\"\\\r\n\u0001""#);
+        assert_eq!(JsonValue::Number(f64::NAN).to_string(), "null");
+        assert_eq!(JsonValue::Array(vec![JsonValue::Number(1.0), JsonValue::Bool(false)]).to_string(), "[1,false]");
+    }
 }
@@ -1,7 +1,7 @@
 mod logger; pub use logger::*;
 mod logger_macro;
 
-use std::sync::{Arc, LazyLock};
+use std::{sync::{Arc, LazyLock}, time::Duration};
 
 static LOGGER : LazyLock<Arc<Logger>> = LazyLock::new(||{Arc::new(Logger::new(Box::new(NoopLogTarget), LogLevel::Info, 1))});
 
@@ -30,6 +30,14 @@ pub fn set_logger_cache_capacity(capacity: usize) {
     LOGGER.update_cache_capacity(capacity);
 }
 
+pub fn set_logger_flush_interval(interval: Duration) {
+    LOGGER.update_flush_interval(interval);
+}
+
+pub fn set_logger_queue_limit(limit: Option<QueueLimit>) {
+    LOGGER.update_queue_limit(limit);
+}
+
 pub fn get_logger_level() -> LogLevel {
     LOGGER.get_log_level()
 }
@@ -1,7 +1,9 @@
 #![allow(dead_code)]
 
-use std::{fs::File, path, sync::{atomic::{AtomicPtr, AtomicU32}, Arc, Mutex}};
-use chrono::{DateTime, Local};
+use std::{collections::{HashMap, VecDeque}, fs::File, net::{SocketAddr, TcpStream}, path, sync::{atomic::{AtomicU32, AtomicU64, AtomicU8}, Arc, Mutex, MutexGuard}, time::{Duration, Instant}};
+use crossbeam::channel::{Receiver, Sender, TrySendError};
+use crossbeam::channel::RecvTimeoutError;
+use chrono::{DateTime, Local, NaiveDate};
 
 pub struct LogMessage {
     level: LogLevel,
@@ -19,13 +21,16 @@ impl std::fmt::Display for LogMessage {
             LogLevel::Error => format!("\x1b[31m{}\x1b[0m", uncolored),
             LogLevel::Debug => format!("\x1b[34m{}\x1b[0m", uncolored),
             LogLevel::Trace => format!("\x1b[35m{}\x1b[0m", uncolored),
+            LogLevel::Off => uncolored,
         };
         write!(f, "{}", colored)
     }
 }
 
+// Off is only meant as a logger level, it filters out every message
 #[derive(Debug, Eq, PartialEq, PartialOrd, Ord, Clone, Copy)]
 pub enum LogLevel {
+    Off,
     Info,
     Warn,
     Error,
@@ -33,16 +38,85 @@ pub enum LogLevel {
     Trace,
 }
 
+// A level that other threads may change while the logger thread reads it
+struct AtomicLogLevel(AtomicU8);
+
+impl AtomicLogLevel {
+    // in the order of the LogLevel discriminants
+    const LEVELS: [LogLevel; 6] = [
+        LogLevel::Off, LogLevel::Info, LogLevel::Warn, LogLevel::Error, LogLevel::Debug, LogLevel::Trace,
+    ];
+
+    fn new(level: LogLevel) -> Self {
+        AtomicLogLevel(AtomicU8::new(level as u8))
+    }
+
+    fn load(&self) -> LogLevel {
+        Self::LEVELS[self.0.load(std::sync::atomic::Ordering::Acquire) as usize]
+    }
+
+    fn store(&self, level: LogLevel) {
+        self.0.store(level as u8, std::sync::atomic::Ordering::Release);
+    }
+}
+
+impl std::fmt::Display for LogLevel {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let name = match self {
+            LogLevel::Off => "off",
+            LogLevel::Info => "info",
+            LogLevel::Warn => "warn",
+            LogLevel::Error => "error",
+            LogLevel::Debug => "debug",
+            LogLevel::Trace => "trace",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct ParseLogLevelError(String);
+
+impl std::fmt::Display for ParseLogLevelError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "Invalid log level: {}", self.0)
+    }
+}
+
+impl std::error::Error for ParseLogLevelError {}
+
+impl std::str::FromStr for LogLevel {
+    type Err = ParseLogLevelError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "off" => Ok(LogLevel::Off),
+            "info" => Ok(LogLevel::Info),
+            "warn" => Ok(LogLevel::Warn),
+            "error" => Ok(LogLevel::Error),
+            "debug" => Ok(LogLevel::Debug),
+            "trace" => Ok(LogLevel::Trace),
+            _ => Err(ParseLogLevelError(s.to_string())),
+        }
+    }
+}
+
+// Errors are left to the caller, the logger thread reports them and writes the batch to its
+// fallback target instead
 pub trait LogTarget {
-    fn log(&self, message: &str);
-    fn flush(&mut self);
+    fn log(&self, message: &str) -> std::io::Result<()>;
+    fn flush(&mut self) -> std::io::Result<()>;
 }
 
 pub struct NoopLogTarget;
 
 impl LogTarget for NoopLogTarget {
-    fn log(&self, _message: &str) {}
-    fn flush(&mut self) {}
+    fn log(&self, _message: &str) -> std::io::Result<()> {
+        Ok(())
+    }
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
 }
 
 pub struct ConsoleLogTarget;
@@ -50,17 +124,23 @@ pub struct ConsoleLogTarget;
 use std::io::Write;
 
 impl LogTarget for ConsoleLogTarget {
-    fn log(&self, message: &str) {
-        let result = write!(std::io::stdout(), "{}", message);
-        if result.is_err() {
-            eprintln!("Failed to write to stdout");
-        }
+    fn log(&self, message: &str) -> std::io::Result<()> {
+        write!(std::io::stdout(), "{}", message)
     }
-    fn flush(&mut self) {
-        let result = std::io::stdout().flush();
-        if result.is_err() {
-            eprintln!("Failed to flush stdout");
-        }
+    fn flush(&mut self) -> std::io::Result<()> {
+        std::io::stdout().flush()
+    }
+}
+
+// Used when the logger thread is gone, see Logger::log
+pub struct StderrLogTarget;
+
+impl LogTarget for StderrLogTarget {
+    fn log(&self, message: &str) -> std::io::Result<()> {
+        write!(std::io::stderr(), "{}", message)
+    }
+    fn flush(&mut self) -> std::io::Result<()> {
+        std::io::stderr().flush()
     }
 }
 
@@ -69,19 +149,11 @@ pub struct FileLogTarget {
 }
 
 impl LogTarget for FileLogTarget {
-    fn log(&self, message: &str) {
-        use std::io::Write;
-        let result = write!(&self.file, "{}", message);
-        if result.is_err() {
-            eprintln!("Failed to write to file");
-        }
+    fn log(&self, message: &str) -> std::io::Result<()> {
+        write!(&self.file, "{}", message)
     }
-    fn flush(&mut self) {
-        let result = self.file.flush();
-        if result.is_err() {
-            eprintln!("Failed to flush file");
-        }
-
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.file.flush()
     }
 }
 
@@ -92,7 +164,439 @@ impl FileLogTarget {
     }
 }
 
+// Writes to a file named after the local date of each message, e.g. "server-%Y-%m-%d.log",
+// and switches to a new file once the date changes. A line without a timestamp stays in the file
+// of the line before it, or goes by the clock at the start of a batch.
+pub struct DateRotatingFileLogTarget {
+    pattern: String,
+    clock: Box<dyn Fn() -> DateTime<Local> + Send + Sync>,
+    current: Mutex<(NaiveDate, File)>,
+}
+
+impl DateRotatingFileLogTarget {
+    pub fn new(pattern: &str) -> std::io::Result<Self> {
+        Self::with_clock(pattern, Box::new(Local::now))
+    }
+
+    pub fn with_clock(pattern: &str, clock: Box<dyn Fn() -> DateTime<Local> + Send + Sync>) -> std::io::Result<Self> {
+        let date = clock().date_naive();
+        let file = Self::open(pattern, date)?;
+        Ok(DateRotatingFileLogTarget {
+            pattern: pattern.to_string(),
+            clock,
+            current: Mutex::new((date, file)),
+        })
+    }
+
+    fn open(pattern: &str, date: NaiveDate) -> std::io::Result<File> {
+        let path = date.format(pattern).to_string();
+        std::fs::OpenOptions::new().create(true).append(true).open(path)
+    }
+
+    // The file of the previous date is kept when the new one cannot be opened
+    fn rotate_if_needed(&self, current: &mut (NaiveDate, File), date: NaiveDate) -> std::io::Result<()> {
+        if date != current.0 {
+            let file = Self::open(&self.pattern, date)?;
+            let _ = current.1.flush();
+            *current = (date, file);
+        }
+        Ok(())
+    }
+
+    // The date a LogMessage line starts with, after its color code
+    fn line_date(line: &str) -> Option<NaiveDate> {
+        let line = match line.strip_prefix('\x1b') {
+            Some(colored) => colored.split_once('m')?.1,
+            None => line,
+        };
+        NaiveDate::parse_from_str(line.strip_prefix('[')?.get(..10)?, "%Y-%m-%d").ok()
+    }
+}
+
+impl LogTarget for DateRotatingFileLogTarget {
+    // A batch queued before midnight still goes to the file of that day when written after it
+    fn log(&self, message: &str) -> std::io::Result<()> {
+        let mut current = self.current.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        let mut date = None;
+        for line in message.split_inclusive('\n') {
+            let line_date = *date.insert(Self::line_date(line)
+                .or(date)
+                .unwrap_or_else(|| (self.clock)().date_naive()));
+            self.rotate_if_needed(&mut current, line_date)?;
+            write!(current.1, "{}", line)?;
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.current.get_mut().unwrap_or_else(|poisoned| poisoned.into_inner()).1.flush()
+    }
+}
+
+// Wraps another target and suppresses identical messages repeated within `window`.
+// The first occurrence is passed through, the following ones are counted and
+// reported as a single "... (repeated N times)" line once the window expires.
+// Messages are compared without their timestamp and thread id.
+pub struct DedupLogTarget {
+    target: Box<dyn LogTarget + Send + Sync>,
+    window: Duration,
+    seen: Mutex<HashMap<String, DedupEntry>>,
+}
+
+struct DedupEntry {
+    window_start: Instant,
+    suppressed: usize,
+    last_line: String,
+}
+
+impl DedupLogTarget {
+    pub fn new(target: Box<dyn LogTarget + Send + Sync>, window: Duration) -> Self {
+        DedupLogTarget {
+            target,
+            window,
+            seen: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn dedup_key(line: &str) -> &str {
+        let line = line.trim_end();
+        // skip the color escape sequence, then the timestamp and thread id groups
+        let mut rest = line.strip_prefix('\x1b')
+            .and_then(|line| line.split_once('m'))
+            .map_or(line, |(_, rest)| rest);
+        for _ in 0..2 {
+            if let Some((_, tail)) = rest.split_once("] ") {
+                rest = tail;
+            }
+        }
+        rest
+    }
+
+    fn summary(entry: &DedupEntry) -> String {
+        format!("{} ... (repeated {} times)\n", entry.last_line.trim_end(), entry.suppressed)
+    }
+
+    fn drain_expired(&self, seen: &mut HashMap<String, DedupEntry>, now: Instant) -> String {
+        let mut output = String::new();
+        seen.retain(|_, entry| {
+            if now.duration_since(entry.window_start) < self.window {
+                return true;
+            }
+            if entry.suppressed > 0 {
+                output.push_str(&Self::summary(entry));
+            }
+            false
+        });
+        output
+    }
+}
+
+impl LogTarget for DedupLogTarget {
+    fn log(&self, message: &str) -> std::io::Result<()> {
+        let now = Instant::now();
+        let mut seen = self.seen.lock().unwrap();
+        let mut output = self.drain_expired(&mut seen, now);
+
+        for line in message.split_inclusive('\n') {
+            match seen.get_mut(Self::dedup_key(line)) {
+                Some(entry) => {
+                    entry.suppressed += 1;
+                    entry.last_line = line.to_string();
+                }
+                None => {
+                    seen.insert(Self::dedup_key(line).to_string(), DedupEntry {
+                        window_start: now,
+                        suppressed: 0,
+                        last_line: line.to_string(),
+                    });
+                    output.push_str(line);
+                }
+            }
+        }
+
+        if output.is_empty() {
+            return Ok(());
+        }
+        self.target.log(&output)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        let output = {
+            let mut seen = self.seen.lock().unwrap();
+            self.drain_expired(&mut seen, Instant::now())
+        };
+        if !output.is_empty() {
+            self.target.log(&output)?;
+        }
+        self.target.flush()
+    }
+}
+
+
+
+// Writes every batch to each of its targets in order. A failing target does not keep the batch
+// from the others, the first error is returned once all were written to.
+pub struct MultiLogTarget {
+    targets: Vec<Box<dyn LogTarget + Send + Sync>>,
+}
+
+impl MultiLogTarget {
+    pub fn new(targets: Vec<Box<dyn LogTarget + Send + Sync>>) -> Self {
+        MultiLogTarget { targets }
+    }
+}
+
+impl LogTarget for MultiLogTarget {
+    fn log(&self, message: &str) -> std::io::Result<()> {
+        self.targets.iter()
+            .map(|target| target.log(message))
+            .fold(Ok(()), Result::and)
+    }
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.targets.iter_mut()
+            .map(|target| target.flush())
+            .fold(Ok(()), Result::and)
+    }
+}
+
+// Ships batches to a log collector over TCP. While the collector is unreachable the batches
+// wait in a buffer of `capacity` batches, the oldest are dropped to make room, and the
+// connection is retried with an exponential backoff. Connecting may block for up to
+// TCP_CONNECT_TIMEOUT, so the target is best wrapped in a BackgroundLogTarget.
+pub struct TcpLogTarget {
+    address: SocketAddr,
+    capacity: usize,
+    min_backoff: Duration,
+    max_backoff: Duration,
+    state: Mutex<TcpLogState>,
+    dropped: AtomicU64,
+}
+
+struct TcpLogState {
+    stream: Option<TcpStream>,
+    pending: VecDeque<String>,
+    backoff: Duration,
+    retry_at: Option<Instant>,
+    // the collector was reported unreachable on stderr, cleared once it is back
+    warned: bool,
+}
+
+pub const TCP_CONNECT_TIMEOUT: Duration = Duration::from_secs(1);
 
+impl TcpLogTarget {
+    pub fn new(address: SocketAddr, capacity: usize) -> Self {
+        Self::with_backoff(address, capacity, Duration::from_millis(100), Duration::from_secs(30))
+    }
+
+    pub fn with_backoff(address: SocketAddr, capacity: usize, min_backoff: Duration, max_backoff: Duration) -> Self {
+        TcpLogTarget {
+            address,
+            capacity: capacity.max(1),
+            min_backoff,
+            max_backoff,
+            state: Mutex::new(TcpLogState {
+                stream: None,
+                pending: VecDeque::new(),
+                backoff: min_backoff,
+                retry_at: None,
+                warned: false,
+            }),
+            dropped: AtomicU64::new(0),
+        }
+    }
+
+    pub fn dropped_batches(&self) -> u64 {
+        self.dropped.load(std::sync::atomic::Ordering::Relaxed)
+    }
+
+    fn fail(&self, state: &mut TcpLogState, err: std::io::Error) {
+        if !state.warned {
+            eprintln!("Log collector {} is unreachable: {}", self.address, err);
+            state.warned = true;
+        }
+        state.stream = None;
+        state.retry_at = Some(Instant::now() + state.backoff);
+        state.backoff = (state.backoff * 2).min(self.max_backoff);
+    }
+
+    // Writes the buffered batches in order, a batch stays buffered until it is written
+    fn send_pending(&self, state: &mut TcpLogState) {
+        if state.retry_at.is_some_and(|retry_at| Instant::now() < retry_at) {
+            return;
+        }
+        if state.stream.is_none() {
+            match TcpStream::connect_timeout(&self.address, TCP_CONNECT_TIMEOUT) {
+                Ok(stream) => {
+                    if state.warned {
+                        eprintln!("Log collector {} is reachable again", self.address);
+                    }
+                    state.stream = Some(stream);
+                    state.backoff = self.min_backoff;
+                    state.retry_at = None;
+                    state.warned = false;
+                },
+                Err(err) => return self.fail(state, err),
+            }
+        }
+        while let Some(message) = state.pending.front() {
+            let result = state.stream.as_mut().map_or(Ok(()), |stream| stream.write_all(message.as_bytes()));
+            match result {
+                Ok(()) => { state.pending.pop_front(); },
+                Err(err) => return self.fail(state, err),
+            }
+        }
+    }
+}
+
+// An unreachable collector is not an error, the batches wait in the buffer
+impl LogTarget for TcpLogTarget {
+    fn log(&self, message: &str) -> std::io::Result<()> {
+        let mut state = self.state.lock().unwrap();
+        if state.pending.len() >= self.capacity {
+            state.pending.pop_front();
+            self.dropped.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+        }
+        state.pending.push_back(message.to_string());
+        self.send_pending(&mut state);
+        Ok(())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        let mut state = self.state.lock().unwrap();
+        self.send_pending(&mut state);
+        if let Some(Err(err)) = state.stream.as_mut().map(|stream| stream.flush()) {
+            self.fail(&mut state, err);
+        }
+        Ok(())
+    }
+}
+
+enum BackgroundCommand {
+    Log(String),
+    Flush,
+}
+
+// Hands batches to a worker thread that owns the wrapped target, so a slow target only holds
+// up its own worker and not the logger thread or the other targets of a MultiLogTarget.
+// Batches that do not fit in the queue of `capacity` batches are dropped.
+pub struct BackgroundLogTarget {
+    sender: Option<Sender<BackgroundCommand>>,
+    worker: Option<std::thread::JoinHandle<()>>,
+    dropped: AtomicU64,
+}
+
+impl BackgroundLogTarget {
+    pub fn new(mut target: Box<dyn LogTarget + Send + Sync>, capacity: usize) -> std::io::Result<Self> {
+        let (sender, receiver) = crossbeam::channel::bounded(capacity);
+        let worker = std::thread::Builder::new().name("log-target".to_string()).spawn(move || {
+            // the worker is the only one to see the errors of the wrapped target
+            for command in receiver {
+                let result = match command {
+                    BackgroundCommand::Log(message) => target.log(&message),
+                    BackgroundCommand::Flush => target.flush(),
+                };
+                if let Err(err) = result {
+                    eprintln!("Background log target failed: {}", err);
+                }
+            }
+            if let Err(err) = target.flush() {
+                eprintln!("Background log target failed: {}", err);
+            }
+        })?;
+        Ok(BackgroundLogTarget {
+            sender: Some(sender),
+            worker: Some(worker),
+            dropped: AtomicU64::new(0),
+        })
+    }
+
+    pub fn dropped_batches(&self) -> u64 {
+        self.dropped.load(std::sync::atomic::Ordering::Relaxed)
+    }
+}
+
+impl LogTarget for BackgroundLogTarget {
+    fn log(&self, message: &str) -> std::io::Result<()> {
+        if let Some(sender) = &self.sender {
+            if let Err(TrySendError::Full(_)) = sender.try_send(BackgroundCommand::Log(message.to_string())) {
+                self.dropped.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+            }
+        }
+        Ok(())
+    }
+    // a flush that does not fit is skipped, the worker is busy writing anyway
+    fn flush(&mut self) -> std::io::Result<()> {
+        if let Some(sender) = &self.sender {
+            let _ = sender.try_send(BackgroundCommand::Flush);
+        }
+        Ok(())
+    }
+}
+
+impl Drop for BackgroundLogTarget {
+    // Lets the worker write out what is queued before it stops
+    fn drop(&mut self) {
+        self.sender.take();
+        if let Some(worker) = self.worker.take() {
+            let _ = worker.join();
+        }
+    }
+}
+
+// What a logging thread does when the queue of messages waiting for the logger thread is full
+#[derive(Debug, Eq, PartialEq, Clone, Copy)]
+pub enum OverflowPolicy {
+    // wait until the logger thread catches up
+    Block,
+    // discard the message being logged
+    DropNewest,
+    // discard the oldest queued message to make room
+    DropOldest,
+}
+
+impl std::fmt::Display for OverflowPolicy {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let name = match self {
+            OverflowPolicy::Block => "block",
+            OverflowPolicy::DropNewest => "drop-newest",
+            OverflowPolicy::DropOldest => "drop-oldest",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+impl std::str::FromStr for OverflowPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "block" => Ok(OverflowPolicy::Block),
+            "drop-newest" => Ok(OverflowPolicy::DropNewest),
+            "drop-oldest" => Ok(OverflowPolicy::DropOldest),
+            _ => Err(format!("Invalid overflow policy: {}", s)),
+        }
+    }
+}
+
+#[derive(Debug, Eq, PartialEq, Clone, Copy)]
+pub struct QueueLimit {
+    pub capacity: usize,
+    pub policy: OverflowPolicy,
+}
+
+// How long a blocked logging thread sleeps before looking at the queue again
+const QUEUE_POLL_INTERVAL: Duration = Duration::from_millis(1);
+
+// Shares the receiving end of the channel with logging threads, so they can drop the oldest
+// message. The logger thread clears it when it exits, even by panicking, after which sends
+// fail and messages take the fallback path.
+struct QueueDrain(Arc<Mutex<Option<Receiver<LogCommand>>>>);
+
+impl Drop for QueueDrain {
+    fn drop(&mut self) {
+        self.0.lock().unwrap_or_else(|poisoned| poisoned.into_inner()).take();
+    }
+}
 
 pub enum LogCommand {
     Log(LogMessage),
@@ -100,76 +604,189 @@ pub enum LogCommand {
     Terminate,
 }
 
+// Cached messages are written out at least this often, even if the cache is not full
+pub const DEFAULT_FLUSH_INTERVAL: Duration = Duration::from_secs(5);
+
+// Every message goes through one channel to the single logger thread, which writes the batches
+// in the order it receives them. Each target thus sees the messages of a thread in the order
+// they were logged, interleaved with those of other threads. A target replaced by update_target
+// gets a prefix of that order and its successor the rest.
 pub struct Logger {
     pub sender: crossbeam::channel::Sender<LogCommand>,
     logger_thread: Mutex<Option<std::thread::JoinHandle<()>>>,
-    level: Arc<AtomicPtr<LogLevel>>,
-    target: Arc<AtomicPtr<Box<dyn LogTarget + Send + Sync>>>,
+    level: Arc<AtomicLogLevel>,
+    // only the logger thread writes to it, a replaced target is dropped right away
+    target: Arc<Mutex<Box<dyn LogTarget + Send + Sync>>>,
     cache_capacity: Arc<AtomicU32>,
+    // milliseconds, zero disables the periodic flush
+    flush_interval: Arc<AtomicU64>,
+    // written synchronously once the logger thread cannot receive messages anymore,
+    // and by the logger thread with the batches the target failed to write
+    fallback: Arc<Mutex<Box<dyn LogTarget + Send + Sync>>>,
+    failed_batches: Arc<AtomicU64>,
+    // None leaves the queue unbounded
+    queue_limit: Mutex<Option<QueueLimit>>,
+    drain: Arc<Mutex<Option<Receiver<LogCommand>>>>,
+    dropped: AtomicU64,
 }
 
 impl Logger {
     pub fn new(target: Box<dyn LogTarget + Send + Sync>, level: LogLevel, cache_capacity: usize) -> Self {
         let (sender, receiver) = crossbeam::channel::unbounded();
 
-        let level_ptr = Arc::new(AtomicPtr::new(Box::into_raw(Box::new(level))));
-        let target_ptr = Arc::new(AtomicPtr::new(Box::into_raw(Box::new(target))));
+        let level = Arc::new(AtomicLogLevel::new(level));
+        let target = Arc::new(Mutex::new(target));
         let cache_capacity = Arc::new(AtomicU32::new(cache_capacity as u32));
+        let flush_interval = Arc::new(AtomicU64::new(DEFAULT_FLUSH_INTERVAL.as_millis() as u64));
+        let drain = Arc::new(Mutex::new(Some(receiver.clone())));
+        let fallback: Arc<Mutex<Box<dyn LogTarget + Send + Sync>>> = Arc::new(Mutex::new(Box::new(StderrLogTarget)));
+        let failed_batches = Arc::new(AtomicU64::new(0));
+
+        // without a logger thread the receiver is dropped and every message takes the fallback path
+        let logger_thread = Self::start_logger_thread(receiver,
+                QueueDrain(drain.clone()),
+                target.clone(),
+                fallback.clone(),
+                failed_batches.clone(),
+                level.clone(),
+                cache_capacity.clone(),
+                flush_interval.clone())
+            .map_err(|err| eprintln!("Failed to start logger thread: {}", err))
+            .ok();
 
-        let logger = Logger {
+        Logger {
             sender,
-            logger_thread: Mutex::new(Some(Self::start_logger_thread(receiver, 
-                target_ptr.clone(),
-                level_ptr.clone(),
-                cache_capacity.clone()))),
-            level: level_ptr.clone(),
-            target: target_ptr.clone(),
+            logger_thread: Mutex::new(logger_thread),
+            level,
+            target,
             cache_capacity: cache_capacity.clone(),
-        };
-
-        logger
+            flush_interval,
+            fallback,
+            failed_batches,
+            queue_limit: Mutex::new(None),
+            drain,
+            dropped: AtomicU64::new(0),
+        }
     }
 
     pub fn log(&self, level: LogLevel, message: String) {
+        if level == LogLevel::Off {
+            return;
+        }
         let message = LogMessage {
             level,
             thread_id: std::thread::current().id(),
             timestamp: chrono::Local::now(),
             message,
         };
-        match self.sender.send(LogCommand::Log(message)) {
-            Ok(_) => {},
-            Err(_) => eprintln!("Failed to send log message to logger thread"),
+        if !self.make_room() {
+            self.dropped.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+            return;
+        }
+        // the send only fails when the logger thread has stopped, after terminate or a panic
+        if let Err(crossbeam::channel::SendError(LogCommand::Log(message))) = self.sender.send(LogCommand::Log(message)) {
+            self.log_fallback(message);
         }
     }
 
+    // Applies the overflow policy when the queue is full, false means the new message is dropped
+    fn make_room(&self) -> bool {
+        let limit = match *self.queue_limit.lock().unwrap_or_else(|poisoned| poisoned.into_inner()) {
+            Some(limit) => limit,
+            None => return true,
+        };
+        match limit.policy {
+            OverflowPolicy::Block => {
+                while self.sender.len() >= limit.capacity && self.is_draining() {
+                    std::thread::sleep(QUEUE_POLL_INTERVAL);
+                }
+                true
+            },
+            OverflowPolicy::DropNewest => self.sender.len() < limit.capacity,
+            OverflowPolicy::DropOldest => {
+                let drain = self.drain.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+                if let Some(receiver) = drain.as_ref() {
+                    while receiver.len() >= limit.capacity {
+                        match receiver.try_recv() {
+                            Ok(LogCommand::Log(_)) => {
+                                self.dropped.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+                            },
+                            // flush and terminate are requeued, terminate() is waiting on them
+                            Ok(command) => {
+                                let _ = self.sender.send(command);
+                                break;
+                            },
+                            Err(_) => break,
+                        }
+                    }
+                }
+                true
+            },
+        }
+    }
+
+    fn is_draining(&self) -> bool {
+        self.drain.lock().unwrap_or_else(|poisoned| poisoned.into_inner()).is_some()
+    }
+
+    fn log_fallback(&self, message: LogMessage) {
+        if message.level > self.get_log_level() {
+            return;
+        }
+        let mut fallback = Self::lock(&self.fallback);
+        // there is nowhere left to report a failed write
+        let _ = fallback.log(&format!("{}\n", message));
+        let _ = fallback.flush();
+    }
+
+    pub fn update_fallback_target(&self, target: Box<dyn LogTarget + Send + Sync>) {
+        *Self::lock(&self.fallback) = target;
+    }
+
+    // Batches the target failed to write, they went to the fallback target instead
+    pub fn failed_batches(&self) -> u64 {
+        self.failed_batches.load(std::sync::atomic::Ordering::Relaxed)
+    }
+
+    #[allow(clippy::too_many_arguments)]
     fn start_logger_thread(receiver: crossbeam::channel::Receiver<LogCommand>,
-        target: Arc<AtomicPtr<Box<dyn LogTarget + Send + Sync>>>,
-        level: Arc<AtomicPtr<LogLevel>>,
-        cache_capacity: Arc<AtomicU32>) -> std::thread::JoinHandle<()> {
+        drain: QueueDrain,
+        target: Arc<Mutex<Box<dyn LogTarget + Send + Sync>>>,
+        fallback: Arc<Mutex<Box<dyn LogTarget + Send + Sync>>>,
+        failed_batches: Arc<AtomicU64>,
+        level: Arc<AtomicLogLevel>,
+        cache_capacity: Arc<AtomicU32>,
+        flush_interval: Arc<AtomicU64>) -> std::io::Result<std::thread::JoinHandle<()>> {
 
 
-        std::thread::spawn(move || {
+        std::thread::Builder::new().name("logger".to_string()).spawn(move || {
+            let _drain = drain;
 
             let mut cache = Vec::with_capacity(cache_capacity.load(std::sync::atomic::Ordering::Acquire) as usize);
+            let mut last_flush = Instant::now();
 
             loop {
-                match receiver.recv() {
-                    Ok(LogCommand::Log(message)) => {
-                        let current_level = level.load(std::sync::atomic::Ordering::Acquire);
-                        let current_level = unsafe { &*current_level };
+                let interval = Duration::from_millis(flush_interval.load(std::sync::atomic::Ordering::Acquire));
+                let command = if interval.is_zero() {
+                    receiver.recv().map_err(|_| RecvTimeoutError::Disconnected)
+                } else {
+                    receiver.recv_timeout(interval.saturating_sub(last_flush.elapsed()))
+                };
 
-                        if message.level > *current_level {
+                match command {
+                    Ok(LogCommand::Log(message)) => {
+                        if message.level > level.load() {
                             continue;
                         }
 
                         cache.push(message);
 
                         let cache_capacity = cache_capacity.load(std::sync::atomic::Ordering::Acquire) as usize;
-                        if cache.len() >= cache_capacity {
-                            if let Some(target) = unsafe { target.load(std::sync::atomic::Ordering::Acquire).as_mut() } {
-                                Self::flush(target, &mut cache);
-                            }
+                        // under steady traffic the receive never times out, so check the interval here too
+                        let interval_elapsed = !interval.is_zero() && last_flush.elapsed() >= interval;
+                        if cache.len() >= cache_capacity || interval_elapsed {
+                            Self::flush(&target, &fallback, &failed_batches, &mut cache);
+                            last_flush = Instant::now();
 
                             if cache.capacity() != cache_capacity {
                                 cache = Vec::with_capacity(cache_capacity);
@@ -177,62 +794,89 @@ impl Logger {
                         }
                     }
                     Ok(LogCommand::Flush) => {
-                        if let Some(target) = unsafe { target.load(std::sync::atomic::Ordering::Acquire).as_mut() } {
-                            Self::flush(target, &mut cache);
+                        Self::flush(&target, &fallback, &failed_batches, &mut cache);
+                        last_flush = Instant::now();
+                    }
+                    Err(RecvTimeoutError::Timeout) => {
+                        if !cache.is_empty() {
+                            Self::flush(&target, &fallback, &failed_batches, &mut cache);
                         }
+                        last_flush = Instant::now();
                     }
                     Ok(LogCommand::Terminate) => {
-
-                        if let Some(target) = unsafe { target.load(std::sync::atomic::Ordering::Acquire).as_mut() } {
-                            Self::flush(target, &mut cache);
-                        }
-
+                        // messages that raced with terminate are written after the cache, in the same batch format
                         while let Ok(LogCommand::Log(message)) = receiver.try_recv() {
-                            let current_level = level.load(std::sync::atomic::Ordering::Acquire);
-                            let current_level = unsafe { &*current_level };
-
-                            if message.level > *current_level {
+                            if message.level > level.load() {
                                 continue;
                             }
 
-                            if let Some(target) = unsafe { target.load(std::sync::atomic::Ordering::Acquire).as_ref() } {
-                                target.log(&message.to_string());
-                            }
+                            cache.push(message);
                         }
+                        Self::flush(&target, &fallback, &failed_batches, &mut cache);
 
                         break;
                     }
-                    Err(_) => break,
+                    Err(RecvTimeoutError::Disconnected) => break,
                 }
             }
         })
     }
 
-    fn flush(target: &mut Box<dyn LogTarget + Send + Sync>, cache: &mut Vec<LogMessage>) {
+    fn lock(target: &Mutex<Box<dyn LogTarget + Send + Sync>>) -> MutexGuard<'_, Box<dyn LogTarget + Send + Sync>> {
+        target.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    // A batch the target cannot take is written to the fallback target after the error, so a
+    // broken log file or console does not silently swallow the messages
+    fn flush(target: &Mutex<Box<dyn LogTarget + Send + Sync>>, fallback: &Mutex<Box<dyn LogTarget + Send + Sync>>,
+        failed_batches: &AtomicU64, cache: &mut Vec<LogMessage>) {
         let combined_logs = Self::concat_cache(cache);
-        target.log(&combined_logs);
-        target.flush();
         cache.clear();
+        let result = {
+            let mut target = Self::lock(target);
+            target.log(&combined_logs).and_then(|()| target.flush())
+        };
+        if let Err(err) = result {
+            failed_batches.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+            let mut fallback = Self::lock(fallback);
+            let _ = fallback.log(&format!("Log target failed: {}\n{}", err, combined_logs));
+            let _ = fallback.flush();
+        }
     }
 
-    fn concat_cache(cache: &Vec<LogMessage>) -> String {
-        cache.iter().map(|message| format!("{}\n", message.to_string())).collect()
+    fn concat_cache(cache: &[LogMessage]) -> String {
+        cache.iter().map(|message| format!("{}\n", message)).collect()
     }
 
     pub fn update_level(&self, level: LogLevel) {
-        let new_level_ptr = Box::into_raw(Box::new(level));
-        self.level.store(new_level_ptr, std::sync::atomic::Ordering::Release);
+        self.level.store(level);
     }
 
+    // Waits for a flush in progress; the previous target is dropped once the lock is released
     pub fn update_target(&self, target: Box<dyn LogTarget + Send + Sync>) {
-        let new_target_ptr = Box::into_raw(Box::new(target));
-        self.target.store(new_target_ptr, std::sync::atomic::Ordering::Release);
+        let previous = std::mem::replace(&mut *Self::lock(&self.target), target);
+        drop(previous);
     }
 
     pub fn update_cache_capacity(&self, capacity: usize) {
         self.cache_capacity.store(capacity as u32, std::sync::atomic::Ordering::Release);
     }
 
+    // Zero disables the periodic flush, cached messages then wait for a full cache
+    pub fn update_flush_interval(&self, interval: Duration) {
+        self.flush_interval.store(interval.as_millis() as u64, std::sync::atomic::Ordering::Release);
+    }
+
+    // None removes the limit
+    pub fn update_queue_limit(&self, limit: Option<QueueLimit>) {
+        *self.queue_limit.lock().unwrap_or_else(|poisoned| poisoned.into_inner()) = limit;
+    }
+
+    // Messages discarded by the DropNewest and DropOldest policies
+    pub fn dropped_messages(&self) -> u64 {
+        self.dropped.load(std::sync::atomic::Ordering::Relaxed)
+    }
+
     pub fn terminate(&self) {
         let result = self.sender.send(LogCommand::Terminate);
         if result.is_err() {
@@ -247,8 +891,604 @@ impl Logger {
     }
 
     pub fn get_log_level(&self) -> LogLevel {
-        let level_ptr = self.level.load(std::sync::atomic::Ordering::Acquire);
-        let level = unsafe { &*level_ptr };
-        *level
+        self.level.load()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct RecordingLogTarget {
+        records: Arc<Mutex<String>>,
+    }
+
+    impl LogTarget for RecordingLogTarget {
+        fn log(&self, message: &str) -> std::io::Result<()> {
+            self.records.lock().unwrap().push_str(message);
+            Ok(())
+        }
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    const ALL_LEVELS: [LogLevel; 6] = [
+        LogLevel::Off, LogLevel::Info, LogLevel::Warn, LogLevel::Error, LogLevel::Debug, LogLevel::Trace,
+    ];
+
+    #[test]
+    fn log_level_round_trips_through_strings() {
+        for level in ALL_LEVELS {
+            assert_eq!(level.to_string().parse::<LogLevel>(), Ok(level));
+        }
+        assert_eq!("off".parse::<LogLevel>(), Ok(LogLevel::Off));
+        assert_eq!("error".parse::<LogLevel>(), Ok(LogLevel::Error));
+        assert_eq!("warn".parse::<LogLevel>(), Ok(LogLevel::Warn));
+        assert_eq!("info".parse::<LogLevel>(), Ok(LogLevel::Info));
+        assert_eq!("debug".parse::<LogLevel>(), Ok(LogLevel::Debug));
+        assert_eq!("trace".parse::<LogLevel>(), Ok(LogLevel::Trace));
+    }
+
+    #[test]
+    fn log_level_parse_ignores_case() {
+        assert_eq!("TRACE".parse::<LogLevel>(), Ok(LogLevel::Trace));
+        assert_eq!("Warn".parse::<LogLevel>(), Ok(LogLevel::Warn));
+        assert_eq!("oFf".parse::<LogLevel>(), Ok(LogLevel::Off));
+    }
+
+    #[test]
+    fn log_level_parse_rejects_unknown_level() {
+        assert_eq!("verbose".parse::<LogLevel>(), Err(ParseLogLevelError("verbose".to_string())));
+        assert!("".parse::<LogLevel>().is_err());
+    }
+
+    #[test]
+    fn off_level_filters_every_message() {
+        let records = Arc::new(Mutex::new(String::new()));
+        let logger = Logger::new(Box::new(RecordingLogTarget { records: records.clone() }), LogLevel::Off, 1);
+        for level in ALL_LEVELS {
+            logger.log(level, format!("{} message", level));
+        }
+        logger.terminate();
+        assert!(records.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn dedup_target_suppresses_repeated_messages() {
+        let records = Arc::new(Mutex::new(String::new()));
+        let target = DedupLogTarget::new(
+            Box::new(RecordingLogTarget { records: records.clone() }),
+            Duration::from_millis(100),
+        );
+        let logger = Logger::new(Box::new(target), LogLevel::Trace, 1);
+
+        for _ in 0..1000 {
+            logger.log(LogLevel::Error, "Database connection lost".to_string());
+        }
+        std::thread::sleep(Duration::from_millis(200));
+        logger.log(LogLevel::Info, "Storm is over".to_string());
+        logger.terminate();
+
+        let records = records.lock().unwrap();
+        let lines: Vec<&str> = records.lines().collect();
+        assert!(lines.len() < 10, "expected few lines, got {}", lines.len());
+        assert!(lines[0].contains("Database connection lost"));
+        assert!(records.contains("(repeated 999 times)"));
+        assert!(lines.last().unwrap().contains("Storm is over"));
+    }
+
+    #[test]
+    fn date_rotating_target_rolls_over_at_midnight() {
+        use chrono::TimeZone;
+
+        let dir = std::env::temp_dir().join(format!("date-rotating-log-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let pattern = dir.join("server-%Y-%m-%d.log").to_string_lossy().to_string();
+
+        let now = Arc::new(Mutex::new(Local.with_ymd_and_hms(2024, 5, 31, 23, 59, 59).unwrap()));
+        let clock = now.clone();
+        let mut target = DateRotatingFileLogTarget::with_clock(&pattern, Box::new(move || *clock.lock().unwrap())).unwrap();
+
+        target.log("before midnight\n").unwrap();
+        target.flush().unwrap();
+        *now.lock().unwrap() = Local.with_ymd_and_hms(2024, 6, 1, 0, 0, 1).unwrap();
+        target.log("after midnight\n").unwrap();
+        target.flush().unwrap();
+
+        let first = std::fs::read_to_string(dir.join("server-2024-05-31.log")).unwrap();
+        let second = std::fs::read_to_string(dir.join("server-2024-06-01.log")).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(first, "before midnight\n");
+        assert_eq!(second, "after midnight\n");
+    }
+
+    fn line_at(timestamp: DateTime<Local>, text: &str) -> String {
+        let message = LogMessage {
+            level: LogLevel::Info,
+            thread_id: std::thread::current().id(),
+            timestamp,
+            message: text.to_string(),
+        };
+        format!("{}\n", message)
+    }
+
+    #[test]
+    fn date_rotating_target_follows_message_time() {
+        use chrono::TimeZone;
+
+        let dir = std::env::temp_dir().join(format!("date-rotating-log-time-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let pattern = dir.join("server-%Y-%m-%d.log").to_string_lossy().to_string();
+
+        // the batch is written after midnight, but its first message was logged before
+        let clock = || Local.with_ymd_and_hms(2024, 6, 1, 0, 0, 1).unwrap();
+        let mut target = DateRotatingFileLogTarget::with_clock(&pattern, Box::new(clock)).unwrap();
+        let before = line_at(Local.with_ymd_and_hms(2024, 5, 31, 23, 59, 59).unwrap(), "queued\ncontinued");
+        let after = line_at(Local.with_ymd_and_hms(2024, 6, 1, 0, 0, 0).unwrap(), "next day");
+        target.log(&format!("{}{}", before, after)).unwrap();
+        target.flush().unwrap();
+
+        let first = std::fs::read_to_string(dir.join("server-2024-05-31.log")).unwrap();
+        let second = std::fs::read_to_string(dir.join("server-2024-06-01.log")).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(first, before);
+        assert_eq!(second, after);
+    }
+
+    #[test]
+    fn date_rotating_target_keeps_file_when_rollover_fails() {
+        use chrono::TimeZone;
+
+        let dir = std::env::temp_dir().join(format!("date-rotating-log-fail-{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("2024-05-31")).unwrap();
+        // there is no directory for the next day, its file cannot be created
+        let pattern = dir.join("%Y-%m-%d").join("server.log").to_string_lossy().to_string();
+
+        let clock = || Local.with_ymd_and_hms(2024, 5, 31, 23, 0, 0).unwrap();
+        let mut target = DateRotatingFileLogTarget::with_clock(&pattern, Box::new(clock)).unwrap();
+        let next_day = line_at(Local.with_ymd_and_hms(2024, 6, 1, 0, 0, 0).unwrap(), "next day");
+        assert!(target.log(&next_day).is_err());
+        let same_day = line_at(Local.with_ymd_and_hms(2024, 5, 31, 23, 59, 0).unwrap(), "same day");
+        target.log(&same_day).unwrap();
+        target.flush().unwrap();
+
+        let written = std::fs::read_to_string(dir.join("2024-05-31").join("server.log")).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(written, same_day);
+    }
+
+    #[test]
+    fn partial_cache_is_flushed_after_interval() {
+        let records = Arc::new(Mutex::new(String::new()));
+        let logger = Logger::new(Box::new(RecordingLogTarget { records: records.clone() }), LogLevel::Info, 100);
+        logger.update_flush_interval(Duration::from_millis(100));
+
+        for i in 0..3 {
+            logger.log(LogLevel::Info, format!("message {}", i));
+        }
+        std::thread::sleep(Duration::from_millis(20));
+        assert!(records.lock().unwrap().is_empty());
+
+        std::thread::sleep(Duration::from_millis(300));
+        assert_eq!(records.lock().unwrap().lines().count(), 3);
+        logger.terminate();
+    }
+
+    #[test]
+    fn stopped_logger_falls_back_to_synchronous_target() {
+        let records = Arc::new(Mutex::new(String::new()));
+        let fallback = Arc::new(Mutex::new(String::new()));
+        let logger = Logger::new(Box::new(RecordingLogTarget { records: records.clone() }), LogLevel::Info, 1);
+        logger.update_fallback_target(Box::new(RecordingLogTarget { records: fallback.clone() }));
+
+        logger.log(LogLevel::Info, "before stop".to_string());
+        logger.terminate();
+        logger.log(LogLevel::Info, "after stop".to_string());
+        logger.log(LogLevel::Trace, "filtered after stop".to_string());
+
+        assert!(records.lock().unwrap().contains("before stop"));
+        assert!(!records.lock().unwrap().contains("after stop"));
+        let fallback = fallback.lock().unwrap();
+        assert!(fallback.contains("after stop"));
+        assert!(!fallback.contains("filtered"));
+    }
+
+    // Fails the nth write, counted from 1
+    struct FailingLogTarget {
+        records: Arc<Mutex<String>>,
+        writes: AtomicU32,
+        fail_at: u32,
+    }
+
+    impl LogTarget for FailingLogTarget {
+        fn log(&self, message: &str) -> std::io::Result<()> {
+            if self.writes.fetch_add(1, std::sync::atomic::Ordering::Relaxed) + 1 == self.fail_at {
+                return Err(std::io::Error::other("disk full"));
+            }
+            self.records.lock().unwrap().push_str(message);
+            Ok(())
+        }
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn failed_batch_goes_to_fallback_target() {
+        let records = Arc::new(Mutex::new(String::new()));
+        let fallback = Arc::new(Mutex::new(String::new()));
+        let target = FailingLogTarget { records: records.clone(), writes: AtomicU32::new(0), fail_at: 2 };
+        let logger = Logger::new(Box::new(target), LogLevel::Info, 1);
+        logger.update_fallback_target(Box::new(RecordingLogTarget { records: fallback.clone() }));
+
+        for message in ["first", "second", "third"] {
+            logger.log(LogLevel::Info, message.to_string());
+        }
+        logger.terminate();
+
+        let records = records.lock().unwrap();
+        assert!(records.contains("first") && records.contains("third") && !records.contains("second"), "{}", records);
+        let fallback = fallback.lock().unwrap();
+        assert!(fallback.starts_with("Log target failed: disk full\n"), "{}", fallback);
+        assert!(fallback.contains("second") && !fallback.contains("third"), "{}", fallback);
+        assert_eq!(logger.failed_batches(), 1);
+    }
+
+    #[test]
+    fn dedup_target_passes_distinct_messages() {
+        let records = Arc::new(Mutex::new(String::new()));
+        let target = DedupLogTarget::new(
+            Box::new(RecordingLogTarget { records: records.clone() }),
+            Duration::from_secs(60),
+        );
+        let logger = Logger::new(Box::new(target), LogLevel::Info, 1);
+
+        for i in 0..10 {
+            logger.log(LogLevel::Info, format!("message {}", i));
+        }
+        logger.terminate();
+
+        assert_eq!(records.lock().unwrap().lines().count(), 10);
+    }
+
+    // Blocks in log until opened, standing in for a stalled file write
+    struct StalledLogTarget {
+        records: Arc<Mutex<String>>,
+        gate: Arc<(Mutex<bool>, std::sync::Condvar)>,
+        entered: Arc<std::sync::atomic::AtomicBool>,
+    }
+
+    impl LogTarget for StalledLogTarget {
+        fn log(&self, message: &str) -> std::io::Result<()> {
+            self.entered.store(true, std::sync::atomic::Ordering::Release);
+            let (open, opened) = &*self.gate;
+            let _open = opened.wait_while(open.lock().unwrap(), |open| !*open).unwrap();
+            self.records.lock().unwrap().push_str(message);
+            Ok(())
+        }
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    struct StalledLogger {
+        logger: Arc<Logger>,
+        records: Arc<Mutex<String>>,
+        gate: Arc<(Mutex<bool>, std::sync::Condvar)>,
+    }
+
+    impl StalledLogger {
+        // The first message is taken by the logger thread, which then stalls writing it
+        fn new(limit: QueueLimit) -> Self {
+            let records = Arc::new(Mutex::new(String::new()));
+            let gate = Arc::new((Mutex::new(false), std::sync::Condvar::new()));
+            let entered = Arc::new(std::sync::atomic::AtomicBool::new(false));
+            let target = StalledLogTarget { records: records.clone(), gate: gate.clone(), entered: entered.clone() };
+            let logger = Arc::new(Logger::new(Box::new(target), LogLevel::Info, 1));
+            logger.update_queue_limit(Some(limit));
+
+            logger.log(LogLevel::Info, "<first>".to_string());
+            while !entered.load(std::sync::atomic::Ordering::Acquire) {
+                std::thread::sleep(Duration::from_millis(1));
+            }
+            Self { logger, records, gate }
+        }
+
+        fn release(&self) -> String {
+            let (open, opened) = &*self.gate;
+            *open.lock().unwrap() = true;
+            opened.notify_all();
+            self.logger.terminate();
+            self.records.lock().unwrap().clone()
+        }
+    }
+
+    #[test]
+    fn drop_newest_policy_discards_messages_over_the_limit() {
+        let stalled = StalledLogger::new(QueueLimit { capacity: 4, policy: OverflowPolicy::DropNewest });
+        for i in 0..20 {
+            stalled.logger.log(LogLevel::Info, format!("<{}>", i));
+        }
+        assert_eq!(stalled.logger.sender.len(), 4);
+        assert_eq!(stalled.logger.dropped_messages(), 16);
+
+        let records = stalled.release();
+        assert!(records.contains("<first>"));
+        assert!(records.contains("<0>") && records.contains("<3>"));
+        assert!(!records.contains("<4>") && !records.contains("<19>"));
+    }
+
+    #[test]
+    fn drop_oldest_policy_keeps_latest_messages() {
+        let stalled = StalledLogger::new(QueueLimit { capacity: 4, policy: OverflowPolicy::DropOldest });
+        for i in 0..20 {
+            stalled.logger.log(LogLevel::Info, format!("<{}>", i));
+        }
+        assert_eq!(stalled.logger.sender.len(), 4);
+        assert_eq!(stalled.logger.dropped_messages(), 16);
+
+        let records = stalled.release();
+        assert!(records.contains("<first>"));
+        assert!(!records.contains("<0>") && !records.contains("<15>"));
+        assert!(records.contains("<16>") && records.contains("<19>"));
+    }
+
+    #[test]
+    fn block_policy_waits_for_the_logger_thread() {
+        let stalled = StalledLogger::new(QueueLimit { capacity: 2, policy: OverflowPolicy::Block });
+        let logger = stalled.logger.clone();
+        let producer = std::thread::spawn(move || {
+            for i in 0..10 {
+                logger.log(LogLevel::Info, format!("<{}>", i));
+            }
+        });
+        std::thread::sleep(Duration::from_millis(100));
+        assert!(!producer.is_finished());
+        assert_eq!(stalled.logger.sender.len(), 2);
+
+        let (open, opened) = &*stalled.gate;
+        *open.lock().unwrap() = true;
+        opened.notify_all();
+        producer.join().unwrap();
+
+        let records = stalled.release();
+        assert_eq!(stalled.logger.dropped_messages(), 0);
+        assert!((0..10).all(|i| records.contains(&format!("<{}>", i))));
+    }
+
+    #[test]
+    fn overflow_policy_parse_test() {
+        for policy in [OverflowPolicy::Block, OverflowPolicy::DropNewest, OverflowPolicy::DropOldest] {
+            assert_eq!(policy.to_string().parse::<OverflowPolicy>(), Ok(policy));
+        }
+        assert_eq!("Drop-Oldest".parse::<OverflowPolicy>(), Ok(OverflowPolicy::DropOldest));
+        assert!("drop".parse::<OverflowPolicy>().is_err());
+    }
+
+    // Sleeps on every write, like a target shipping logs over a slow network
+    struct SlowLogTarget {
+        records: Arc<Mutex<String>>,
+        delay: Duration,
+    }
+
+    impl LogTarget for SlowLogTarget {
+        fn log(&self, message: &str) -> std::io::Result<()> {
+            std::thread::sleep(self.delay);
+            self.records.lock().unwrap().push_str(message);
+            Ok(())
+        }
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn slow_background_target_does_not_delay_other_targets() {
+        let fast = Arc::new(Mutex::new(String::new()));
+        let slow = Arc::new(Mutex::new(String::new()));
+        let slow_target = SlowLogTarget { records: slow.clone(), delay: Duration::from_millis(500) };
+        let target = MultiLogTarget::new(vec![
+            Box::new(BackgroundLogTarget::new(Box::new(slow_target), 16).unwrap()),
+            Box::new(RecordingLogTarget { records: fast.clone() }),
+        ]);
+        let logger = Logger::new(Box::new(target), LogLevel::Info, 1);
+
+        let started = Instant::now();
+        for i in 0..5 {
+            logger.log(LogLevel::Info, format!("<{}>", i));
+        }
+        while !fast.lock().unwrap().contains("<4>") {
+            assert!(started.elapsed() < Duration::from_millis(400), "fast target was held up");
+            std::thread::sleep(Duration::from_millis(1));
+        }
+        assert!(!slow.lock().unwrap().contains("<4>"));
+
+        logger.terminate();
+    }
+
+    #[test]
+    fn background_target_drops_batches_when_full() {
+        let records = Arc::new(Mutex::new(String::new()));
+        let slow_target = SlowLogTarget { records: records.clone(), delay: Duration::from_millis(50) };
+        let target = BackgroundLogTarget::new(Box::new(slow_target), 2).unwrap();
+
+        for i in 0..10 {
+            target.log(&format!("<{}>\n", i)).unwrap();
+        }
+        // two queued, plus the one the worker may have taken already
+        assert!(target.dropped_batches() >= 7);
+        drop(target);
+
+        // what was queued is written before the worker stops
+        let records = records.lock().unwrap();
+        assert!(records.contains("<0>") && records.contains("<1>"));
+        assert!(!records.contains("<9>"));
+    }
+
+    fn read_until(stream: &mut TcpStream, needle: &str) -> String {
+        use std::io::Read;
+
+        stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        let mut received = String::new();
+        let mut buf = [0u8; 1024];
+        while !received.contains(needle) {
+            let n = stream.read(&mut buf).unwrap();
+            assert!(n > 0, "collector connection closed before {}", needle);
+            received.push_str(&String::from_utf8_lossy(&buf[..n]));
+        }
+        received
+    }
+
+    #[test]
+    fn tcp_target_resumes_after_collector_restart() {
+        use std::net::TcpListener;
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let target = TcpLogTarget::with_backoff(address, 100, Duration::from_millis(10), Duration::from_millis(50));
+
+        target.log("<0>\n").unwrap();
+        let (mut collector, _) = listener.accept().unwrap();
+        read_until(&mut collector, "<0>");
+
+        // the collector goes down, the writes fail once the peer reset is noticed
+        drop(collector);
+        drop(listener);
+        for i in 1..5 {
+            target.log(&format!("<{}>\n", i)).unwrap();
+            std::thread::sleep(Duration::from_millis(20));
+        }
+
+        // and comes back on the same port
+        let listener = TcpListener::bind(address).unwrap();
+        listener.set_nonblocking(true).unwrap();
+        let deadline = Instant::now() + Duration::from_secs(5);
+        let mut collector = loop {
+            target.log("<resumed>\n").unwrap();
+            match listener.accept() {
+                Ok((stream, _)) => break stream,
+                Err(err) if err.kind() == std::io::ErrorKind::WouldBlock => {
+                    assert!(Instant::now() < deadline, "target did not reconnect");
+                    std::thread::sleep(Duration::from_millis(10));
+                },
+                Err(err) => panic!("{}", err),
+            }
+        };
+        collector.set_nonblocking(false).unwrap();
+        let received = read_until(&mut collector, "<resumed>");
+        // what was buffered during the outage is sent first
+        assert!(received.contains("<4>"));
+        assert!(received.find("<4>") < received.find("<resumed>"));
+        assert_eq!(target.dropped_batches(), 0);
+    }
+
+    #[test]
+    fn tcp_target_drops_oldest_while_unreachable() {
+        let address = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+        let target = TcpLogTarget::with_backoff(address, 2, Duration::from_secs(60), Duration::from_secs(60));
+        for i in 0..5 {
+            target.log(&format!("<{}>\n", i)).unwrap();
+        }
+        assert_eq!(target.dropped_batches(), 3);
+        let state = target.state.lock().unwrap();
+        assert_eq!(state.pending, ["<3>\n", "<4>\n"]);
+    }
+
+    struct CountedLogTarget {
+        drops: Arc<AtomicU64>,
+    }
+
+    impl LogTarget for CountedLogTarget {
+        fn log(&self, _message: &str) -> std::io::Result<()> {
+            Ok(())
+        }
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Drop for CountedLogTarget {
+        fn drop(&mut self) {
+            self.drops.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+        }
+    }
+
+    #[test]
+    fn replaced_targets_are_dropped() {
+        let drops = Arc::new(AtomicU64::new(0));
+        let logger = Logger::new(Box::new(CountedLogTarget { drops: drops.clone() }), LogLevel::Info, 1);
+
+        for i in 0..100 {
+            logger.update_target(Box::new(CountedLogTarget { drops: drops.clone() }));
+            logger.update_level(AtomicLogLevel::LEVELS[i % AtomicLogLevel::LEVELS.len()]);
+            logger.log(LogLevel::Info, format!("<{}>", i));
+        }
+        assert_eq!(drops.load(std::sync::atomic::Ordering::Relaxed), 100);
+        for level in ALL_LEVELS {
+            logger.update_level(level);
+            assert_eq!(logger.get_log_level(), level);
+        }
+
+        logger.terminate();
+        drop(logger);
+        assert_eq!(drops.load(std::sync::atomic::Ordering::Relaxed), 101);
+    }
+
+    #[test]
+    fn targets_keep_the_order_of_each_thread() {
+        const PRODUCERS: usize = 4;
+        const MESSAGES: usize = 500;
+
+        fn recorder() -> (Arc<Mutex<String>>, Box<dyn LogTarget + Send + Sync>) {
+            let records = Arc::new(Mutex::new(String::new()));
+            (records.clone(), Box::new(RecordingLogTarget { records }))
+        }
+
+        // the message numbers of every producer, in the order the target received them
+        fn sequences(records: &Mutex<String>) -> Vec<Vec<usize>> {
+            let mut sequences = vec![Vec::new(); PRODUCERS];
+            for line in records.lock().unwrap().lines() {
+                let text = line.rsplit_once("producer ").unwrap().1.trim_end_matches("\x1b[0m");
+                let (producer, message) = text.split_once(" message ").unwrap();
+                sequences[producer.parse::<usize>().unwrap()].push(message.parse().unwrap());
+            }
+            sequences
+        }
+
+        let (first, first_target) = recorder();
+        let (background, background_target) = recorder();
+        let background_target = BackgroundLogTarget::new(background_target, MESSAGES * PRODUCERS).unwrap();
+        let logger = Arc::new(Logger::new(
+            Box::new(MultiLogTarget::new(vec![first_target, Box::new(background_target)])), LogLevel::Info, 7));
+        logger.update_flush_interval(Duration::from_millis(1));
+
+        let producers: Vec<_> = (0..PRODUCERS).map(|producer| {
+            let logger = logger.clone();
+            std::thread::spawn(move || {
+                for message in 0..MESSAGES {
+                    logger.log(LogLevel::Info, format!("producer {} message {}", producer, message));
+                }
+            })
+        }).collect();
+
+        std::thread::sleep(Duration::from_millis(5));
+        let (second, second_target) = recorder();
+        logger.update_target(second_target);
+        logger.update_cache_capacity(3);
+        for producer in producers {
+            producer.join().unwrap();
+        }
+        logger.terminate();
+
+        let expected: Vec<usize> = (0..MESSAGES).collect();
+        let (first, second) = (sequences(&first), sequences(&second));
+        for (producer, background) in sequences(&background).iter().enumerate() {
+            assert!(background.windows(2).all(|pair| pair[0] < pair[1]), "producer {} out of order", producer);
+            assert_eq!(background, &first[producer]);
+            // the replacement target continues where the first one stopped
+            let combined: Vec<usize> = first[producer].iter().chain(&second[producer]).copied().collect();
+            assert_eq!(combined, expected, "producer {} out of order", producer);
+        }
     }
 }
@@ -19,13 +19,11 @@ enum ProcLogLevel {
 
 #[proc_macro_attribute]
 pub fn log(attr: TokenStream, item: TokenStream) -> TokenStream {
-    let log_level = attr.to_string().trim_matches('"').to_lowercase();
-    assert!(log_level == "trace" || log_level == "debug", "Invalid log level");
-
-    let log_level = match log_level.as_str() {
-        "trace" => ProcLogLevel::Trace,
-        "debug" => ProcLogLevel::Debug,
-        _ => panic!("Invalid log level"),
+    let log_level = match attr.to_string().trim_matches('"').parse::<logger::LogLevel>() {
+        Ok(logger::LogLevel::Trace) => ProcLogLevel::Trace,
+        Ok(logger::LogLevel::Debug) => ProcLogLevel::Debug,
+        Ok(level) => panic!("Unsupported log level: {}, expected trace or debug", level),
+        Err(err) => panic!("{}", err),
     };
 
     let input_fn: ItemFn = parse_macro_input!(item as ItemFn);
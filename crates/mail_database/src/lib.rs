@@ -3,7 +3,9 @@ pub mod schema;
 
 use diesel::prelude::*;
 use diesel::pg::PgConnection;
+use diesel::connection::{AnsiTransactionManager, TransactionManager};
 use models::NewUser;
+pub use models::StoredMail;
 use thiserror::Error;
 use argon2::{Argon2, PasswordHasher, PasswordVerifier, Params, Algorithm, Version};
 use argon2::password_hash::{rand_core, PasswordHash, SaltString};
@@ -40,17 +42,116 @@ pub enum MailError {
 
     #[error("Password verification error")]
     PasswordVerifyError,
+
+    #[error("User is not allowed to access the message")]
+    Unauthorized,
+
+    #[error("Message not found")]
+    NotFound,
+
+    #[error("Invalid input: {0}")]
+    InvalidInput(String),
+
+    #[error("Mailbox quota exceeded")]
+    QuotaExceeded,
+
+    #[error("Password reset token is invalid or expired")]
+    InvalidResetToken,
+
+    #[error("Password does not meet the password policy")]
+    WeakPassword,
+
+    #[error("Could not write the export")]
+    ExportError(#[from] std::io::Error),
 }
 
+impl MailError {
+    // Transient errors come from the database itself and may go away on retry,
+    // the rest are caused by the request (unknown users, missing login, ...)
+    pub fn is_transient(&self) -> bool {
+        match self {
+            MailError::ConnectionError(_) | MailError::NoConnection => true,
+            MailError::QueryError(diesel::result::Error::NotFound) => false,
+            MailError::QueryError(_) => true,
+            MailError::PasswordHashError | MailError::PasswordVerifyError => true,
+            MailError::ExportError(_) => true,
+            MailError::UserNotFound
+            | MailError::UserAlreadyExist
+            | MailError::UserAuthError
+            | MailError::UserNotLoggedIn
+            | MailError::EmptyReceiversError
+            | MailError::Unauthorized
+            | MailError::NotFound
+            | MailError::InvalidInput(_)
+            | MailError::QuotaExceeded
+            | MailError::InvalidResetToken
+            | MailError::WeakPassword => false,
+        }
+    }
+}
+
+// The outcome for each recipient of deliver_to_recipients, in the order they were given
+pub type RecipientResults = Vec<(String, Result<i32, MailError>)>;
+
 pub trait IMailDB {
     fn connect(&mut self, connection_string: &str) -> Result<(), MailError>;
     fn disconnect(&mut self);
     fn is_connected(&mut self) -> bool;
     fn sign_up(&mut self, user_name: &str, password: &str) -> Result<(), MailError>;
     fn login(&mut self, user_name: &str, password: &str) -> Result<(), MailError>;
+    // Checks a password without logging in, unknown users are reported as false as well
+    fn verify_credentials(&mut self, user_name: &str, password: &str) -> Result<bool, MailError>;
     fn insert_email(&mut self, receiver: &str, subject: &str, body: &str) -> Result<(), MailError>;
     fn insert_multiple_emails(&mut self, receivers: Vec<&str>, subject: &str, body: &str) -> Result<(), MailError>;
+    // Unlike insert_multiple_emails, a recipient that cannot receive the message does not
+    // stop the others. Each stored message id is reported next to its recipient.
+    fn deliver_to_recipients(&mut self, receivers: Vec<&str>, subject: &str, body: &str)
+        -> Result<RecipientResults, MailError>;
     fn user_exists(&mut self, user_name: &str) -> Result<bool,MailError>;
+    // Only the sender or the recipient of the message may read it
+    fn get_message(&mut self, user_name: &str, email_message_id: i32) -> Result<StoredMail, MailError>;
+    // The MAIL FROM of the messages inserted next. A local user named there is stored as
+    // their sender, otherwise the logged in user is. The logged in user is always stored
+    // as the submitter.
+    fn set_envelope_sender(&mut self, _sender: Option<&str>) {}
+    // The id the SMTP server assigned to the messages inserted next, stored with each of them
+    fn set_message_id(&mut self, _message_id: Option<&str>) {}
+    // Explicit transaction control; inserts made in between are committed or rolled back together.
+    // Implementations without transaction support may keep the no-op defaults.
+    fn begin_transaction(&mut self) -> Result<(), MailError> {
+        Ok(())
+    }
+    fn commit_transaction(&mut self) -> Result<(), MailError> {
+        Ok(())
+    }
+    fn rollback_transaction(&mut self) -> Result<(), MailError> {
+        Ok(())
+    }
+}
+
+// Checked on sign up and password reset. The default accepts any password, as before.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct PasswordPolicy {
+    // in characters
+    pub min_length: usize,
+    // how many of lowercase letters, uppercase letters, digits and other characters must appear
+    pub min_character_classes: usize,
+}
+
+impl PasswordPolicy {
+    pub fn check(&self, password: &str) -> Result<(), MailError> {
+        let classes: [fn(char) -> bool; 4] = [
+            char::is_lowercase,
+            char::is_uppercase,
+            |c| c.is_ascii_digit(),
+            |c| !c.is_alphanumeric(),
+        ];
+        let used_classes = classes.iter().filter(|class| password.chars().any(class)).count();
+        if password.chars().count() < self.min_length || used_classes < self.min_character_classes {
+            return Err(MailError::WeakPassword);
+        }
+        Ok(())
+    }
 }
 
 // PostgreSQL MailDB implementation using Diesel
@@ -62,8 +163,20 @@ pub struct PgMailDB {
     user_id: Option<u32>,
     conn: Option<PgConnection>,
     hash_algorithm : Argon2<'static>,
+    max_user_name_len: usize,
+    max_password_len: usize,
+    password_policy: PasswordPolicy,
+    // messages a mailbox may hold, None for no limit
+    mailbox_quota: Option<usize>,
+    reset_token_ttl: std::time::Duration,
+    envelope_sender: Option<String>,
+    message_id: Option<String>,
 }
 
+pub const DEFAULT_MAX_USER_NAME_LEN: usize = 64;
+pub const DEFAULT_MAX_PASSWORD_LEN: usize = 256;
+pub const DEFAULT_RESET_TOKEN_TTL: std::time::Duration = std::time::Duration::from_secs(60 * 60);
+
 impl PgMailDB {
     pub fn new(host_name: String) -> Self {
         let argon2 = Argon2::new(Algorithm::Argon2id,
@@ -71,12 +184,284 @@ impl PgMailDB {
             Params::new(65536, 2, 1, None).unwrap()
         );
         PgMailDB {
-            host_name: host_name,
+            host_name,
             hash_algorithm: argon2,
+            max_user_name_len: DEFAULT_MAX_USER_NAME_LEN,
+            max_password_len: DEFAULT_MAX_PASSWORD_LEN,
+            reset_token_ttl: DEFAULT_RESET_TOKEN_TTL,
             ..Default::default()
         }
     }
 
+    // Lengths are counted in characters; the user_name column holds at most 100
+    pub fn with_credential_limits(mut self, max_user_name_len: usize, max_password_len: usize) -> Self {
+        self.max_user_name_len = max_user_name_len;
+        self.max_password_len = max_password_len;
+        self
+    }
+
+    pub fn with_password_policy(mut self, policy: PasswordPolicy) -> Self {
+        self.password_policy = policy;
+        self
+    }
+
+    pub fn with_mailbox_quota(mut self, max_messages: usize) -> Self {
+        self.mailbox_quota = Some(max_messages);
+        self
+    }
+
+    pub fn with_reset_token_ttl(mut self, ttl: std::time::Duration) -> Self {
+        self.reset_token_ttl = ttl;
+        self
+    }
+
+    // Every host the database serves, sorted by name. A host is created by the first
+    // PgMailDB that connects with its name.
+    pub fn list_hosts(&mut self) -> Result<Vec<String>, MailError> {
+        use crate::schema::hosts::dsl::*;
+
+        let conn = self.conn.as_mut().ok_or_else(|| MailError::NoConnection)?;
+        Ok(hosts.select(host_name).order(host_name).load::<String>(conn)?)
+    }
+
+    // Writes every message the user received, oldest first, in the mboxrd format: a "From "
+    // line with the sender and the time it was stored, then the message with LF line endings
+    // and ">" added before lines that look like "From " or an escaped one. Returns the count.
+    pub fn export_mbox(&mut self, input_user_name: &str, writer: &mut impl std::io::Write) -> Result<usize, MailError> {
+        use crate::schema::users::dsl::*;
+        use crate::schema::email_messages;
+        use crate::schema::mail_bodies;
+
+        let conn = self.conn.as_mut().ok_or_else(|| MailError::NoConnection)?;
+
+        let id = users.filter(user_name.eq(input_user_name))
+            .filter(host_id.eq(self.host_id as i32))
+            .select(user_id)
+            .first::<i32>(conn)
+            .optional()?
+            .ok_or(MailError::UserNotFound)?;
+
+        let messages = email_messages::table
+            .inner_join(mail_bodies::table)
+            .filter(email_messages::recipient_id.eq(id))
+            .order(email_messages::email_message_id)
+            .select((email_messages::sender_id, email_messages::sent_at, mail_bodies::body_content))
+            .load::<(Option<i32>, Option<chrono::NaiveDateTime>, String)>(conn)?;
+
+        for (sender, mail_sent_at, body) in &messages {
+            let sender = match sender {
+                Some(sender) => users.filter(user_id.eq(sender)).select(user_name).first::<String>(conn)?,
+                None => "MAILER-DAEMON".to_string(),
+            };
+            let sent_at = mail_sent_at.unwrap_or_default().format("%a %b %e %H:%M:%S %Y");
+            writeln!(writer, "From {} {}", sender, sent_at)?;
+            for line in body.lines() {
+                if line.trim_start_matches('>').starts_with("From ") {
+                    writer.write_all(b">")?;
+                }
+                writeln!(writer, "{}", line)?;
+            }
+            writeln!(writer)?;
+        }
+        Ok(messages.len())
+    }
+
+    // Creates a password reset token for the user, to be handed over out of band. Only its
+    // hash is stored, and issuing a new token revokes the ones not used yet.
+    pub fn issue_reset_token(&mut self, input_user_name: &str) -> Result<String, MailError> {
+        use crate::schema::users::dsl::*;
+        use crate::schema::password_reset_tokens;
+        use crate::models::NewResetToken;
+
+        let ttl = chrono::Duration::from_std(self.reset_token_ttl)
+            .map_err(|_| MailError::InvalidInput("reset token lifetime is too long".to_string()))?;
+        let conn = self.conn.as_mut().ok_or_else(|| MailError::NoConnection)?;
+
+        let id = users.filter(user_name.eq(input_user_name))
+            .filter(host_id.eq(self.host_id as i32))
+            .select(user_id)
+            .first::<i32>(conn)
+            .optional()?
+            .ok_or(MailError::UserNotFound)?;
+
+        let mut token_bytes = [0u8; 32];
+        rand_core::RngCore::fill_bytes(&mut rand_core::OsRng, &mut token_bytes);
+        let token: String = token_bytes.iter().map(|byte| format!("{:02x}", byte)).collect();
+
+        let salt = SaltString::generate(&mut rand_core::OsRng);
+        let hashed_token = self.hash_algorithm.hash_password(token.as_bytes(), &salt)
+            .map_err(|_| MailError::PasswordHashError)?
+            .to_string();
+
+        conn.transaction::<_, MailError, _>(|conn| {
+            diesel::delete(password_reset_tokens::table
+                    .filter(password_reset_tokens::user_id.eq(id))
+                    .filter(password_reset_tokens::used_at.is_null()))
+                .execute(conn)?;
+            diesel::insert_into(password_reset_tokens::table)
+                .values(NewResetToken {
+                    user_id: id,
+                    token_hash: &hashed_token,
+                    expires_at: chrono::Utc::now().naive_utc() + ttl,
+                })
+                .execute(conn)?;
+            Ok(())
+        })?;
+
+        Ok(token)
+    }
+
+    // Sets a new password if the token was issued for this user, has not expired and was not
+    // used before. Every failure with the token itself is reported as InvalidResetToken.
+    pub fn reset_password_with_token(&mut self, input_user_name: &str, token: &str, new_password: &str)
+        -> Result<(), MailError> {
+        use crate::schema::users::dsl::*;
+        use crate::schema::password_reset_tokens;
+        use crate::models::ResetToken;
+
+        self.validate_credentials(input_user_name, new_password)?;
+
+        let conn = self.conn.as_mut().ok_or_else(|| MailError::NoConnection)?;
+
+        let id = users.filter(user_name.eq(input_user_name))
+            .filter(host_id.eq(self.host_id as i32))
+            .select(user_id)
+            .first::<i32>(conn)
+            .optional()?
+            .ok_or(MailError::UserNotFound)?;
+
+        let stored_token = password_reset_tokens::table
+            .filter(password_reset_tokens::user_id.eq(id))
+            .filter(password_reset_tokens::used_at.is_null())
+            .select(ResetToken::as_select())
+            .first::<ResetToken>(conn)
+            .optional()?
+            .ok_or(MailError::InvalidResetToken)?;
+
+        let parsed_hash = PasswordHash::new(&stored_token.token_hash)
+            .map_err(|_| MailError::PasswordVerifyError)?;
+        if self.hash_algorithm.verify_password(token.as_bytes(), &parsed_hash).is_err() {
+            return Err(MailError::InvalidResetToken);
+        }
+        let now = chrono::Utc::now().naive_utc();
+        if stored_token.expires_at <= now {
+            return Err(MailError::InvalidResetToken);
+        }
+
+        let salt = SaltString::generate(&mut rand_core::OsRng);
+        let hashed_password = self.hash_algorithm.hash_password(new_password.as_bytes(), &salt)
+            .map_err(|_| MailError::PasswordHashError)?
+            .to_string();
+
+        conn.transaction::<_, MailError, _>(|conn| {
+            // the used_at filter keeps two concurrent resets from both using the token
+            let claimed = diesel::update(password_reset_tokens::table
+                    .filter(password_reset_tokens::token_id.eq(stored_token.token_id))
+                    .filter(password_reset_tokens::used_at.is_null()))
+                .set(password_reset_tokens::used_at.eq(now))
+                .execute(conn)?;
+            if claimed == 0 {
+                return Err(MailError::InvalidResetToken);
+            }
+            diesel::update(users.filter(user_id.eq(id)))
+                .set(password_hash.eq(&hashed_password))
+                .execute(conn)?;
+            Ok(())
+        })
+    }
+
+    // Stores one message of an already inserted body, checking the recipient's quota first
+    // The envelope sender when it is a local user, the submitter otherwise
+    fn resolve_sender(&mut self, submitter: i32) -> Result<i32, MailError> {
+        use crate::schema::users::dsl::*;
+
+        let Some(sender) = self.envelope_sender.as_deref() else {
+            return Ok(submitter);
+        };
+        let conn = self.conn.as_mut().ok_or_else(|| MailError::NoConnection)?;
+        let sender_id = users.filter(user_name.eq(sender))
+            .filter(host_id.eq(self.host_id as i32))
+            .select(user_id)
+            .first::<i32>(conn)
+            .optional()?;
+        Ok(sender_id.unwrap_or(submitter))
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn insert_for_recipient(connection: &mut PgConnection, host: i32, sender: i32, submitter: i32, receiver: &str,
+        subject: &str, body_id: i32, quota: Option<usize>, message_id: Option<&str>) -> Result<i32, MailError> {
+        use crate::schema::users::dsl::*;
+        use crate::schema::email_messages;
+        use crate::models::NewMail;
+
+        let receiver_id: i32 = users.filter(user_name.eq(receiver))
+            .filter(host_id.eq(host))
+            .select(user_id)
+            .first::<i32>(connection)
+            .optional()?
+            .ok_or(MailError::UserNotFound)?;
+
+        if let Some(quota) = quota {
+            let stored = email_messages::table
+                .filter(email_messages::recipient_id.eq(receiver_id))
+                .count()
+                .get_result::<i64>(connection)?;
+            if stored as usize >= quota {
+                return Err(MailError::QuotaExceeded);
+            }
+        }
+
+        let new_mail = NewMail {
+            sender_id: sender,
+            recipient_id: receiver_id,
+            subject,
+            mail_body_id: body_id,
+            is_received: false,
+            submitted_by_user_id: submitter,
+            message_id,
+        };
+        Ok(diesel::insert_into(email_messages::table)
+            .values(new_mail)
+            .returning(email_messages::email_message_id)
+            .get_result::<i32>(connection)?)
+    }
+
+    // Looks the user up and verifies the password, leaving the logged in user as it is
+    fn check_password(&mut self, input_user_name: &str, password: &str) -> Result<models::UserInfo, MailError> {
+        use crate::schema::users::dsl::*;
+        use crate::models::UserInfo;
+
+        let conn = self.conn.as_mut().ok_or_else(|| MailError::NoConnection)?;
+
+        // Check if the user exists
+        let user_info = users
+            .filter(user_name.eq(input_user_name))
+            .filter(host_id.eq(self.host_id as i32))
+            .select(UserInfo::as_select())
+            .first::<UserInfo>(conn)
+            .map_err(|_| MailError::UserNotFound)?;
+
+        let parsed_hash = PasswordHash::new(&user_info.password_hash)
+            .map_err(|_| MailError::PasswordVerifyError)?;
+
+        // Verify password
+        if self.hash_algorithm.verify_password(password.as_bytes(), &parsed_hash).is_ok() {
+            Ok(user_info)
+        } else {
+            Err(MailError::UserAuthError)
+        }
+    }
+
+    fn validate_credentials(&self, user_name: &str, password: &str) -> Result<(), MailError> {
+        if user_name.chars().count() > self.max_user_name_len {
+            return Err(MailError::InvalidInput(format!("user name is longer than {} characters", self.max_user_name_len)));
+        }
+        if password.chars().count() > self.max_password_len {
+            return Err(MailError::InvalidInput(format!("password is longer than {} characters", self.max_password_len)));
+        }
+        self.password_policy.check(password)
+    }
+
     fn ensure_host_id(&mut self) -> Result<(), MailError> {
         use crate::schema::hosts::dsl::*;
 
@@ -129,6 +514,9 @@ impl IMailDB for PgMailDB {
     fn sign_up(&mut self, input_user_name: &str, password: &str) -> Result<(), MailError> {
         use crate::schema::users::dsl::*;
 
+        // reject oversized credentials before they reach the database or Argon2
+        self.validate_credentials(input_user_name, password)?;
+
         let conn = self.conn.as_mut().ok_or_else(|| MailError::NoConnection)?;
 
         // Check if the user exists
@@ -138,7 +526,7 @@ impl IMailDB for PgMailDB {
             .first::<i32>(conn)
             .ok();
 
-        if let Some(_) = existing_user {
+        if existing_user.is_some() {
             return Err(MailError::UserAlreadyExist);
         }
         // Generate hashed password
@@ -161,29 +549,17 @@ impl IMailDB for PgMailDB {
     }
 
     fn login(&mut self, input_user_name: &str, password: &str) -> Result<(), MailError> {
-        use crate::schema::users::dsl::*;
-        use crate::models::UserInfo;
-
-        let conn = self.conn.as_mut().ok_or_else(|| MailError::NoConnection)?;
-
-        // Check if the user exists
-        let user_info = users
-            .filter(user_name.eq(input_user_name))
-            .filter(host_id.eq(self.host_id as i32))
-            .select(UserInfo::as_select())
-            .first::<UserInfo>(conn)
-            .map_err(|_| MailError::UserNotFound)?;
-
-        let parsed_hash = PasswordHash::new(&user_info.password_hash)
-            .map_err(|_| MailError::PasswordVerifyError)?;
+        let user_info = self.check_password(input_user_name, password)?;
+        self.user_id = Some(user_info.user_id as u32);
+        self.user_name = Some(user_info.user_name);
+        Ok(())
+    }
 
-        // Verify password
-        if self.hash_algorithm.verify_password(password.as_bytes(), &parsed_hash).is_ok() {
-            self.user_id = Some(user_info.user_id as u32);
-            self.user_name = Some(user_info.user_name);
-            Ok(())
-        } else {
-            Err(MailError::UserAuthError)
+    fn verify_credentials(&mut self, input_user_name: &str, password: &str) -> Result<bool, MailError> {
+        match self.check_password(input_user_name, password) {
+            Ok(_) => Ok(true),
+            Err(MailError::UserNotFound | MailError::UserAuthError) => Ok(false),
+            Err(err) => Err(err),
         }
     }
 
@@ -199,47 +575,75 @@ impl IMailDB for PgMailDB {
             return Err(MailError::EmptyReceiversError);
         }
 
-        use crate::schema::users::dsl::*;
         use crate::schema::mail_bodies::dsl::*;
-        use crate::schema::email_messages;
-        use crate::models::NewMail;
 
+        let submitter = self.user_id.unwrap() as i32;
+        let sender = self.resolve_sender(submitter)?;
+        let host = self.host_id as i32;
+        let quota = self.mailbox_quota;
+        let message_id = self.message_id.as_deref();
         self.conn.as_mut().ok_or_else(|| MailError::NoConnection)?
             .transaction(
             |connection|
             {
-                let mut receiver_ids: Vec<i32> = Vec::new();
+                let body_id: i32 =  diesel::insert_into(mail_bodies)
+                    .values(body_content.eq(body))
+                    .returning(mail_body_id)
+                    .get_result(connection)?;
 
                 for receiver in receivers {
-                    let receiver_id: i32 = users.filter(user_name.eq(receiver))
-                        .filter(host_id.eq(self.host_id as i32))
-                        .select(user_id)
-                        .first::<i32>(connection)?;
-
-                    receiver_ids.push(receiver_id);
+                    Self::insert_for_recipient(connection, host, sender, submitter, receiver, subject, body_id, quota,
+                        message_id)?;
                 }
+                Ok::<(), MailError>(())
+            }
+        )?;
+        Ok(())
+    }
 
-                let body_id: i32 =  diesel::insert_into(mail_bodies)
+    fn deliver_to_recipients(&mut self, receivers: Vec<&str>, subject: &str, body: &str)
+        -> Result<RecipientResults, MailError> {
+        if self.user_id.is_none() || self.user_name.is_none() {
+            return Err(MailError::UserNotLoggedIn);
+        }
+        if receivers.is_empty() {
+            return Err(MailError::EmptyReceiversError);
+        }
+
+        use crate::schema::mail_bodies::dsl::*;
+
+        let submitter = self.user_id.unwrap() as i32;
+        let sender = self.resolve_sender(submitter)?;
+        let host = self.host_id as i32;
+        let quota = self.mailbox_quota;
+        let message_id = self.message_id.as_deref();
+        self.conn.as_mut().ok_or_else(|| MailError::NoConnection)?
+            .transaction(
+            |connection|
+            {
+                let body_id: i32 = diesel::insert_into(mail_bodies)
                     .values(body_content.eq(body))
                     .returning(mail_body_id)
                     .get_result(connection)?;
 
-                for id in receiver_ids {
-                    let new_mail = NewMail {
-                        sender_id: self.user_id.unwrap() as i32,
-                        recipient_id: id,
-                        subject : subject,
-                        mail_body_id : body_id,
-                        is_received: false
-                    };
-                    diesel::insert_into(email_messages::table)
-                        .values(new_mail)
+                // each recipient gets a savepoint, so a failure only undoes its own insert
+                let results: RecipientResults = receivers.into_iter()
+                    .map(|receiver| {
+                        let result = connection.transaction(|connection| {
+                            Self::insert_for_recipient(connection, host, sender, submitter, receiver, subject, body_id, quota,
+                                message_id)
+                        });
+                        (receiver.to_string(), result)
+                    })
+                    .collect();
+
+                if results.iter().all(|(_, result)| result.is_err()) {
+                    diesel::delete(mail_bodies.filter(mail_body_id.eq(body_id)))
                         .execute(connection)?;
                 }
-                diesel::result::QueryResult::Ok(())
+                Ok(results)
             }
-        )?;
-        Ok(())
+        )
     }
 
     fn user_exists(&mut self, input_user_name: &str) -> Result<bool,MailError> {
@@ -255,4 +659,81 @@ impl IMailDB for PgMailDB {
         )
 
     }
+
+    fn set_envelope_sender(&mut self, sender: Option<&str>) {
+        self.envelope_sender = sender.map(str::to_string);
+    }
+
+    fn set_message_id(&mut self, message_id: Option<&str>) {
+        self.message_id = message_id.map(str::to_string);
+    }
+
+    fn get_message(&mut self, input_user_name: &str, message_id: i32) -> Result<StoredMail, MailError> {
+        use crate::schema::users::dsl::*;
+        use crate::schema::email_messages;
+        use crate::schema::mail_bodies;
+
+        let conn = self.conn.as_mut().ok_or_else(|| MailError::NoConnection)?;
+
+        let (sender, recipient, mail_subject, body, mail_sent_at, stored_message_id) = email_messages::table
+            .inner_join(mail_bodies::table)
+            .filter(email_messages::email_message_id.eq(message_id))
+            .select((
+                email_messages::sender_id,
+                email_messages::recipient_id,
+                email_messages::subject,
+                mail_bodies::body_content,
+                email_messages::sent_at,
+                email_messages::message_id,
+            ))
+            .first::<(Option<i32>, Option<i32>, Option<String>, String, Option<chrono::NaiveDateTime>, Option<String>)>(conn)
+            .optional()?
+            .ok_or(MailError::NotFound)?;
+
+        let requester = users.filter(user_name.eq(input_user_name))
+            .filter(host_id.eq(self.host_id as i32))
+            .select(user_id)
+            .first::<i32>(conn)
+            .optional()?;
+
+        match requester {
+            Some(id) if Some(id) == sender || Some(id) == recipient => {},
+            _ => return Err(MailError::Unauthorized),
+        }
+
+        let mut name_of = |id: Option<i32>| -> Result<String, MailError> {
+            match id {
+                Some(id) => Ok(users.filter(user_id.eq(id)).select(user_name).first::<String>(conn)?),
+                None => Ok(String::new()),
+            }
+        };
+
+        Ok(StoredMail {
+            email_message_id: message_id,
+            sender: name_of(sender)?,
+            recipient: name_of(recipient)?,
+            subject: mail_subject.unwrap_or_default(),
+            body,
+            sent_at: mail_sent_at,
+            message_id: stored_message_id,
+        })
+    }
+
+    fn begin_transaction(&mut self) -> Result<(), MailError> {
+        let conn = self.conn.as_mut().ok_or_else(|| MailError::NoConnection)?;
+        AnsiTransactionManager::begin_transaction(conn)?;
+        Ok(())
+    }
+
+    fn commit_transaction(&mut self) -> Result<(), MailError> {
+        let conn = self.conn.as_mut().ok_or_else(|| MailError::NoConnection)?;
+        AnsiTransactionManager::commit_transaction(conn)?;
+        Ok(())
+    }
+
+    fn rollback_transaction(&mut self) -> Result<(), MailError> {
+        let conn = self.conn.as_mut().ok_or_else(|| MailError::NoConnection)?;
+        AnsiTransactionManager::rollback_transaction(conn)?;
+        Ok(())
+    }
 }
@@ -46,4 +46,36 @@ pub struct NewMail<'a> {
     pub subject: &'a str,
     pub mail_body_id: i32,
     pub is_received: bool,
+    pub submitted_by_user_id: i32,
+    pub message_id: Option<&'a str>,
+}
+
+#[derive(Insertable)]
+#[diesel(table_name = crate::schema::password_reset_tokens)]
+pub struct NewResetToken<'a> {
+    pub user_id: i32,
+    pub token_hash: &'a str,
+    pub expires_at: NaiveDateTime,
+}
+
+#[derive(Queryable, Selectable)]
+#[diesel(table_name = crate::schema::password_reset_tokens)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+pub struct ResetToken {
+    pub token_id: i32,
+    pub token_hash: String,
+    pub expires_at: NaiveDateTime,
+}
+
+// A message as seen by one of its participants
+#[derive(Debug, Clone, PartialEq)]
+pub struct StoredMail {
+    pub email_message_id: i32,
+    pub sender: String,
+    pub recipient: String,
+    pub subject: String,
+    pub body: String,
+    pub sent_at: Option<NaiveDateTime>,
+    // the id the server gave the message when accepting it, None for older messages
+    pub message_id: Option<String>,
 }
@@ -11,6 +11,9 @@ diesel::table! {
         mail_body_id -> Nullable<Int4>,
         sent_at -> Nullable<Timestamp>,
         is_received -> Nullable<Bool>,
+        submitted_by_user_id -> Nullable<Int4>,
+        #[max_length = 64]
+        message_id -> Nullable<Varchar>,
     }
 }
 
@@ -30,6 +33,17 @@ diesel::table! {
     }
 }
 
+diesel::table! {
+    #[sql_name = "passwordResetTokens"]
+    password_reset_tokens (token_id) {
+        token_id -> Int4,
+        user_id -> Int4,
+        token_hash -> Text,
+        expires_at -> Timestamp,
+        used_at -> Nullable<Timestamp>,
+    }
+}
+
 diesel::table! {
     users (user_id) {
         user_id -> Int4,
@@ -42,11 +56,13 @@ diesel::table! {
 }
 
 diesel::joinable!(email_messages -> mail_bodies (mail_body_id));
+diesel::joinable!(password_reset_tokens -> users (user_id));
 diesel::joinable!(users -> hosts (host_id));
 
 diesel::allow_tables_to_appear_in_same_query!(
     email_messages,
     hosts,
     mail_bodies,
+    password_reset_tokens,
     users,
 );
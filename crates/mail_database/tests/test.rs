@@ -82,6 +82,31 @@ mod tests {
         assert!(pg.login("user1", "password").is_err());
     }
 
+    #[test]
+    fn verify_credentials_test() {
+        use mail_database::MailError;
+
+        let (mut ctx, _) = setup_database(CONNECTION_STR, "verify_credentials_test");
+
+        let conn_str = ctx.get_connection_string();
+        let pg = &mut ctx.pg_db;
+
+        assert!(pg.connect(&conn_str).is_ok());
+        assert!(pg.sign_up("user1", "password").is_ok());
+        assert!(pg.sign_up("user2", "password").is_ok());
+        assert!(pg.verify_credentials("user1", "password").unwrap());
+        assert!(!pg.verify_credentials("user1", "fake_password").unwrap());
+        assert!(!pg.verify_credentials("not-existing-user", "password").unwrap());
+
+        // verifying does not log the user in
+        assert!(matches!(pg.insert_email("user2", "subj", "body"), Err(MailError::UserNotLoggedIn)));
+        assert!(pg.login("user1", "password").is_ok());
+        assert!(pg.insert_email("user2", "subj", "body").is_ok());
+
+        pg.disconnect();
+        assert!(matches!(pg.verify_credentials("user1", "password"), Err(MailError::NoConnection)));
+    }
+
     #[test]
     fn insert_emails_test() {
         use mail_database::schema::mail_bodies::dsl::*;
@@ -116,6 +141,352 @@ mod tests {
         assert!(pg.insert_multiple_emails(vec!["user1"], "subj", "body").is_err());
     }
 
-}
+    #[test]
+    fn submitted_by_test() {
+        use mail_database::schema::{email_messages, users};
+
+        let (mut ctx, mut conn) = setup_database(CONNECTION_STR, "submitted_by_test");
+
+        let conn_str = ctx.get_connection_string();
+        let pg = &mut ctx.pg_db;
+
+        assert!(pg.connect(&conn_str).is_ok());
+        assert!(pg.sign_up("user1", "password").is_ok());
+        assert!(pg.sign_up("user2", "password").is_ok());
+        assert!(pg.login("user1", "password").is_ok());
+
+        let id_of = |conn: &mut PgConnection, name: &str| users::table
+            .filter(users::user_name.eq(name))
+            .select(users::user_id)
+            .first::<i32>(conn)
+            .unwrap();
+        let user1 = id_of(&mut conn, "user1");
+        let user2 = id_of(&mut conn, "user2");
+
+        // MAIL FROM names another local user, the logged in user is still the submitter
+        pg.set_envelope_sender(Some("user2"));
+        assert!(pg.insert_email("user2", "subj", "body").is_ok());
+        // a foreign sender is stored as the logged in user
+        pg.set_envelope_sender(Some("someone@example.com"));
+        assert!(pg.insert_email("user2", "subj", "body").is_ok());
+
+        let stored = email_messages::table
+            .order(email_messages::email_message_id)
+            .select((email_messages::sender_id, email_messages::submitted_by_user_id))
+            .load::<(Option<i32>, Option<i32>)>(&mut conn)
+            .unwrap();
+        assert_eq!(stored, vec![(Some(user2), Some(user1)), (Some(user1), Some(user1))]);
+    }
+
+    #[test]
+    fn transaction_test() {
+        use mail_database::schema::mail_bodies::dsl::*;
+        use mail_database::schema::email_messages;
+
+        let (mut ctx, mut conn) = setup_database(CONNECTION_STR, "transaction_test");
+
+        let conn_str = ctx.get_connection_string();
+        let pg = &mut ctx.pg_db;
+
+        assert!(pg.connect(&conn_str).is_ok());
+        assert!(pg.sign_up("user1", "password").is_ok());
+        assert!(pg.login("user1", "password").is_ok());
+
+        assert!(pg.begin_transaction().is_ok());
+        assert!(pg.insert_email("user1", "subj", "body").is_ok());
+        assert!(pg.insert_email("user1", "subj", "body").is_ok());
+        assert!(pg.rollback_transaction().is_ok());
+
+        let bodies_count = mail_bodies.count().get_result::<i64>(&mut conn).unwrap();
+        assert_eq!(bodies_count, 0);
+        let mails_count = email_messages::table.count().get_result::<i64>(&mut conn).unwrap();
+        assert_eq!(mails_count, 0);
 
+        assert!(pg.begin_transaction().is_ok());
+        assert!(pg.insert_email("user1", "subj", "body").is_ok());
+        // a failing insert inside the transaction does not abort it
+        assert!(pg.insert_email("not-existing-user", "subj", "body").is_err());
+        assert!(pg.commit_transaction().is_ok());
+
+        let mails_count = email_messages::table.count().get_result::<i64>(&mut conn).unwrap();
+        assert_eq!(mails_count, 1);
+
+        assert!(pg.commit_transaction().is_err());
+        pg.disconnect();
+        assert!(pg.begin_transaction().is_err());
+    }
+
+    #[test]
+    fn error_classification_test() {
+        let (mut ctx, _) = setup_database(CONNECTION_STR, "error_classification_test");
+
+        let conn_str = ctx.get_connection_string();
+        let pg = &mut ctx.pg_db;
+
+        assert!(pg.connect(&conn_str).is_ok());
+        assert!(pg.sign_up("user1", "password").is_ok());
+        assert!(pg.login("user1", "password").is_ok());
+
+        let err = pg.insert_email("not-existing-user", "subj", "body").unwrap_err();
+        assert!(!err.is_transient());
+
+        pg.disconnect();
+        let err = pg.user_exists("user1").unwrap_err();
+        assert!(err.is_transient());
+    }
+
+    #[test]
+    fn get_message_test() {
+        use mail_database::schema::email_messages::dsl::*;
+
+        let (mut ctx, mut conn) = setup_database(CONNECTION_STR, "get_message_test");
+
+        let conn_str = ctx.get_connection_string();
+        let pg = &mut ctx.pg_db;
+
+        assert!(pg.connect(&conn_str).is_ok());
+        assert!(pg.sign_up("user1", "password").is_ok());
+        assert!(pg.sign_up("user2", "password").is_ok());
+        assert!(pg.sign_up("user3", "password").is_ok());
+        assert!(pg.login("user1", "password").is_ok());
+        assert!(pg.insert_email("user2", "subj", "body").is_ok());
+
+        let stored_id = email_messages.select(email_message_id).first::<i32>(&mut conn).unwrap();
+
+        let message = pg.get_message("user2", stored_id).unwrap();
+        assert_eq!(message.email_message_id, stored_id);
+        assert_eq!(message.sender, "user1");
+        assert_eq!(message.recipient, "user2");
+        assert_eq!(message.subject, "subj");
+        assert_eq!(message.body, "body");
+        assert!(pg.get_message("user1", stored_id).is_ok());
+
+        assert!(matches!(pg.get_message("user3", stored_id), Err(mail_database::MailError::Unauthorized)));
+        assert!(matches!(pg.get_message("not-existing-user", stored_id), Err(mail_database::MailError::Unauthorized)));
+        assert!(matches!(pg.get_message("user2", stored_id + 1), Err(mail_database::MailError::NotFound)));
+    }
+
+    #[test]
+    fn sign_up_length_limits_test() {
+        let (mut ctx, _) = setup_database(CONNECTION_STR, "sign_up_length_limits_test");
+
+        let conn_str = ctx.get_connection_string();
+        let pg = &mut ctx.pg_db;
+
+        let long_name = "u".repeat(mail_database::DEFAULT_MAX_USER_NAME_LEN + 1);
+        let long_password = "p".repeat(mail_database::DEFAULT_MAX_PASSWORD_LEN + 1);
+
+        // validation happens before the connection is used
+        assert!(matches!(pg.sign_up(&long_name, "password"), Err(mail_database::MailError::InvalidInput(_))));
+
+        assert!(pg.connect(&conn_str).is_ok());
+        assert!(matches!(pg.sign_up(&long_name, "password"), Err(mail_database::MailError::InvalidInput(_))));
+        assert!(matches!(pg.sign_up("user1", &long_password), Err(mail_database::MailError::InvalidInput(_))));
+        assert!(!pg.user_exists(&long_name).unwrap());
+        assert!(!pg.user_exists("user1").unwrap());
+
+        let name = "u".repeat(mail_database::DEFAULT_MAX_USER_NAME_LEN);
+        let password = "p".repeat(mail_database::DEFAULT_MAX_PASSWORD_LEN);
+        assert!(pg.sign_up(&name, &password).is_ok());
+    }
+
+    #[test]
+    fn custom_credential_limits_test() {
+        let mut pg = mail_database::PgMailDB::new("testhost".to_string()).with_credential_limits(4, 8);
+
+        assert!(matches!(pg.sign_up("user1", "password"), Err(mail_database::MailError::InvalidInput(_))));
+        assert!(matches!(pg.sign_up("user", "password1"), Err(mail_database::MailError::InvalidInput(_))));
+        // within the limits the call proceeds to the (missing) connection
+        assert!(matches!(pg.sign_up("user", "password"), Err(mail_database::MailError::NoConnection)));
+    }
 
+    #[test]
+    fn password_policy_test() {
+        use mail_database::{MailError, PasswordPolicy};
+
+        let (ctx, _) = setup_database(CONNECTION_STR, "password_policy_test");
+
+        let conn_str = ctx.get_connection_string();
+        let policy = PasswordPolicy { min_length: 8, min_character_classes: 0 };
+        let mut pg = mail_database::PgMailDB::new("testhost".to_string()).with_password_policy(policy);
+
+        assert!(pg.connect(&conn_str).is_ok());
+        assert!(matches!(pg.sign_up("user1", "short"), Err(MailError::WeakPassword)));
+        assert!(!pg.user_exists("user1").unwrap());
+        assert!(pg.sign_up("user1", "long enough").is_ok());
+        assert!(pg.login("user1", "long enough").is_ok());
+
+        let mixed = PasswordPolicy { min_length: 8, min_character_classes: 3 };
+        assert!(matches!(mixed.check("lowercase only"), Err(MailError::WeakPassword)));
+        assert!(mixed.check("Mixed case 1").is_ok());
+    }
+
+    #[test]
+    fn message_id_test() {
+        let (mut ctx, _) = setup_database(CONNECTION_STR, "message_id_test");
+
+        let conn_str = ctx.get_connection_string();
+        let pg = &mut ctx.pg_db;
+
+        assert!(pg.connect(&conn_str).is_ok());
+        assert!(pg.sign_up("user1", "password").is_ok());
+        assert!(pg.sign_up("user2", "password").is_ok());
+        assert!(pg.login("user1", "password").is_ok());
+        assert!(pg.insert_email("user2", "subj", "body").is_ok());
+        pg.set_message_id(Some("6710F2A1000001"));
+        let results = pg.deliver_to_recipients(vec!["user2"], "subj", "body").unwrap();
+
+        assert_eq!(pg.get_message("user2", 1).unwrap().message_id, None);
+        let stored = pg.get_message("user2", *results[0].1.as_ref().unwrap()).unwrap();
+        assert_eq!(stored.message_id.as_deref(), Some("6710F2A1000001"));
+    }
+
+    #[test]
+    fn export_mbox_test() {
+        let (mut ctx, _) = setup_database(CONNECTION_STR, "export_mbox_test");
+
+        let conn_str = ctx.get_connection_string();
+        let pg = &mut ctx.pg_db;
+
+        assert!(pg.connect(&conn_str).is_ok());
+        assert!(pg.sign_up("user1", "password").is_ok());
+        assert!(pg.sign_up("user2", "password").is_ok());
+        assert!(pg.login("user1", "password").is_ok());
+        assert!(pg.insert_email("user2", "first", "Subject: first\r\n\r\nFrom the start\r\n>From quoted\r\n").is_ok());
+        assert!(pg.insert_email("user2", "second", "Subject: second\r\n\r\nBody\r\n").is_ok());
+        assert!(pg.insert_email("user1", "other", "Subject: other\r\n\r\nBody\r\n").is_ok());
+
+        let mut mbox = Vec::new();
+        assert_eq!(pg.export_mbox("user2", &mut mbox).unwrap(), 2);
+        let mbox = String::from_utf8(mbox).unwrap();
+        let separators: Vec<&str> = mbox.lines().filter(|line| line.starts_with("From ")).collect();
+        assert_eq!(separators.len(), 2);
+        assert!(separators.iter().all(|line| line.starts_with("From user1 ")), "{}", mbox);
+        assert!(mbox.contains("\n\n>From the start\n>>From quoted\n\nFrom user1 "), "{}", mbox);
+        assert!(mbox.ends_with("Subject: second\n\nBody\n\n"), "{}", mbox);
+        assert!(!mbox.contains('\r'));
+
+        assert!(matches!(pg.export_mbox("not-existing-user", &mut Vec::new()), Err(mail_database::MailError::UserNotFound)));
+    }
+
+    #[test]
+    fn deliver_to_recipients_test() {
+        use mail_database::schema::mail_bodies::dsl::*;
+        use mail_database::schema::email_messages;
+        use mail_database::MailError;
+
+        let (ctx, mut conn) = setup_database(CONNECTION_STR, "deliver_to_recipients_test");
+
+        let conn_str = ctx.get_connection_string();
+        let mut pg = mail_database::PgMailDB::new("testhost".to_string()).with_mailbox_quota(1);
+
+        assert!(pg.connect(&conn_str).is_ok());
+        assert!(pg.sign_up("user1", "password").is_ok());
+        assert!(pg.sign_up("user2", "password").is_ok());
+        assert!(pg.login("user1", "password").is_ok());
+        // fills the mailbox of user2
+        assert!(pg.insert_email("user2", "subj", "body").is_ok());
+
+        let results = pg.deliver_to_recipients(vec!["user1", "user2", "not-existing-user"], "subj", "body").unwrap();
+        assert_eq!(results.len(), 3);
+        assert_eq!(results[0].0, "user1");
+        assert!(results[0].1.is_ok());
+        assert!(matches!(results[1], (ref name, Err(MailError::QuotaExceeded)) if name == "user2"));
+        assert!(matches!(results[2], (ref name, Err(MailError::UserNotFound)) if name == "not-existing-user"));
+
+        let stored = pg.get_message("user1", *results[0].1.as_ref().unwrap()).unwrap();
+        assert_eq!(stored.recipient, "user1");
+        let mails_count = email_messages::table.count().get_result::<i64>(&mut conn).unwrap();
+        assert_eq!(mails_count, 2);
+        let bodies_count = mail_bodies.count().get_result::<i64>(&mut conn).unwrap();
+        assert_eq!(bodies_count, 2);
+
+        // a body nobody received is not kept
+        let results = pg.deliver_to_recipients(vec!["user2"], "subj", "body").unwrap();
+        assert!(results[0].1.is_err());
+        let bodies_count = mail_bodies.count().get_result::<i64>(&mut conn).unwrap();
+        assert_eq!(bodies_count, 2);
+
+        // the all-or-nothing insert respects the quota as well
+        assert!(matches!(pg.insert_multiple_emails(vec!["user2"], "subj", "body"), Err(MailError::QuotaExceeded)));
+        pg.disconnect();
+    }
+
+    #[test]
+    fn list_hosts_test() {
+        use mail_database::{MailError, PgMailDB};
+
+        let (mut ctx, _) = setup_database(CONNECTION_STR, "list_hosts_test");
+
+        let conn_str = ctx.get_connection_string();
+        let mut other = PgMailDB::new("example.org".to_string());
+        let pg = &mut ctx.pg_db;
+        assert!(matches!(pg.list_hosts(), Err(MailError::NoConnection)));
+
+        assert!(pg.connect(&conn_str).is_ok());
+        assert!(pg.sign_up("user1", "password").is_ok());
+        assert_eq!(pg.list_hosts().unwrap(), vec!["testhost".to_string()]);
+
+        // the same user name on another host is a different user
+        assert!(other.connect(&conn_str).is_ok());
+        assert!(other.sign_up("user1", "other_password").is_ok());
+        assert!(other.verify_credentials("user1", "other_password").unwrap());
+        assert!(!pg.verify_credentials("user1", "other_password").unwrap());
+
+        assert_eq!(pg.list_hosts().unwrap(), vec!["example.org".to_string(), "testhost".to_string()]);
+        assert_eq!(other.list_hosts().unwrap(), pg.list_hosts().unwrap());
+    }
+
+    #[test]
+    fn reset_password_with_token_test() {
+        use mail_database::MailError;
+
+        let (mut ctx, _) = setup_database(CONNECTION_STR, "reset_password_with_token_test");
+
+        let conn_str = ctx.get_connection_string();
+        let pg = &mut ctx.pg_db;
+
+        assert!(pg.connect(&conn_str).is_ok());
+        assert!(pg.sign_up("user1", "password").is_ok());
+        assert!(matches!(pg.issue_reset_token("not-existing-user"), Err(MailError::UserNotFound)));
+
+        let token = pg.issue_reset_token("user1").unwrap();
+        assert!(matches!(pg.reset_password_with_token("user1", "wrong-token", "new-password"),
+            Err(MailError::InvalidResetToken)));
+        assert!(pg.reset_password_with_token("user1", &token, "new-password").is_ok());
+        assert!(matches!(pg.login("user1", "password"), Err(MailError::UserAuthError)));
+        assert!(pg.login("user1", "new-password").is_ok());
+
+        // tokens are single-use
+        assert!(matches!(pg.reset_password_with_token("user1", &token, "other-password"),
+            Err(MailError::InvalidResetToken)));
+        assert!(pg.login("user1", "new-password").is_ok());
+
+        // a newer token revokes the previous one
+        let revoked = pg.issue_reset_token("user1").unwrap();
+        let token = pg.issue_reset_token("user1").unwrap();
+        assert!(matches!(pg.reset_password_with_token("user1", &revoked, "other-password"),
+            Err(MailError::InvalidResetToken)));
+        assert!(pg.reset_password_with_token("user1", &token, "other-password").is_ok());
+        pg.disconnect();
+    }
+
+    #[test]
+    fn expired_reset_token_test() {
+        use mail_database::MailError;
+
+        let (ctx, _) = setup_database(CONNECTION_STR, "expired_reset_token_test");
+
+        let conn_str = ctx.get_connection_string();
+        let mut pg = mail_database::PgMailDB::new("testhost".to_string())
+            .with_reset_token_ttl(std::time::Duration::ZERO);
+
+        assert!(pg.connect(&conn_str).is_ok());
+        assert!(pg.sign_up("user1", "password").is_ok());
+        let token = pg.issue_reset_token("user1").unwrap();
+        assert!(matches!(pg.reset_password_with_token("user1", &token, "new-password"),
+            Err(MailError::InvalidResetToken)));
+        assert!(pg.login("user1", "password").is_ok());
+        pg.disconnect();
+    }
+}
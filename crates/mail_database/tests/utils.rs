@@ -18,7 +18,7 @@ impl TestContext {
         let query = diesel::sql_query(format!("CREATE DATABASE {}", db_name).as_str());
         query
             .execute(&mut conn)
-            .expect(format!("Could not create database {}", db_name).as_str());
+            .unwrap_or_else(|_| panic!("Could not create database {}", db_name));
 
         Self {
                 base_url: base_url.to_string(),
@@ -53,7 +53,7 @@ impl Drop for TestContext {
         let query = diesel::sql_query(format!("DROP DATABASE {}", self.db_name).as_str());
         query
             .execute(&mut conn)
-            .expect(&format!("Couldn't drop database {}", self.db_name));
+            .unwrap_or_else(|_| panic!("Couldn't drop database {}", self.db_name));
     }
 }
 use diesel_migrations::{embed_migrations, EmbeddedMigrations, MigrationHarness};
@@ -0,0 +1,207 @@
+use std::net::{IpAddr, SocketAddr};
+
+use logger::{info, warn};
+use logger_proc_macro::log;
+use smart_stream::AsyncStream;
+
+use crate::error::RelayError;
+use crate::reply::{ServerCapabilities, SmtpReply};
+use crate::resolver::{mail_hosts, MailHost, Resolver};
+
+// A relay that takes every outbound message, whatever the recipient domain
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SmartHost {
+    // a host name or an IP address
+    pub host: String,
+    pub port: u16,
+    pub credentials: Option<SmartHostCredentials>,
+    // send the credentials even when the smart host does not offer STARTTLS
+    pub allow_plaintext_auth: bool,
+}
+
+// Sent with AUTH PLAIN after EHLO
+#[derive(Clone, PartialEq, Eq)]
+pub struct SmartHostCredentials {
+    pub username: String,
+    pub password: String,
+}
+
+impl std::fmt::Debug for SmartHostCredentials {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SmartHostCredentials").field("username", &self.username).finish_non_exhaustive()
+    }
+}
+
+// Delivers messages to remote domains over SMTP
+pub struct RelayClient {
+    resolver: Box<dyn Resolver>,
+    helo_name: String,
+    port: u16,
+    timeout: u64,
+    smart_host: Option<SmartHost>,
+}
+
+// The resolver is left out, it is a trait object
+impl std::fmt::Debug for RelayClient {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("RelayClient")
+            .field("helo_name", &self.helo_name)
+            .field("port", &self.port)
+            .field("smart_host", &self.smart_host)
+            .finish_non_exhaustive()
+    }
+}
+
+impl RelayClient {
+    pub fn new(resolver: Box<dyn Resolver>, helo_name: &str, port: u16, timeout: u64) -> Self {
+        Self {
+            resolver,
+            helo_name: helo_name.to_string(),
+            port,
+            timeout,
+            smart_host: None,
+        }
+    }
+
+    // Every message goes to the smart host, the MX records of the domains are not looked up
+    pub fn with_smart_host(mut self, smart_host: SmartHost) -> Self {
+        self.smart_host = Some(smart_host);
+        self
+    }
+
+    // Tries the mail hosts of the domain in preference order and returns the first
+    // one that accepts the connection with a 2xx greeting. With a smart host it is the only candidate.
+    #[log(debug)]
+    pub async fn connect(&self, domain: &str) -> Result<(MailHost, AsyncStream), RelayError> {
+        let (hosts, port) = match &self.smart_host {
+            Some(smart_host) => (vec![MailHost { preference: 0, exchange: smart_host.host.clone() }], smart_host.port),
+            None => (mail_hosts(self.resolver.as_ref(), domain).await?, self.port),
+        };
+
+        for host in hosts {
+            let addresses = match host.exchange.parse::<IpAddr>() {
+                Ok(address) => vec![address],
+                Err(_) => match self.resolver.address_lookup(&host.exchange).await {
+                    Ok(addresses) => addresses,
+                    Err(err) => {
+                        warn!("Could not resolve mail host {}: {:?}", host.exchange, err);
+                        continue;
+                    }
+                },
+            };
+
+            for address in addresses {
+                let address = SocketAddr::new(address, port);
+                let mut stream = match AsyncStream::connect(address, self.timeout).await {
+                    Ok(stream) => stream,
+                    Err(err) => {
+                        warn!("Could not connect to {} ({}): {}", host.exchange, address, err);
+                        continue;
+                    }
+                };
+                match Self::read_reply(&mut stream).await {
+                    Ok(greeting) if greeting.starts_with('2') => {
+                        info!("Connected to mail host {} ({})", host.exchange, address);
+                        return Ok((host, stream));
+                    },
+                    Ok(greeting) => warn!("Mail host {} rejected connection: {}", host.exchange, greeting.trim_end()),
+                    Err(err) => warn!("Mail host {} dropped connection: {}", host.exchange, err),
+                }
+            }
+        }
+        Err(RelayError::AllHostsFailed(domain.to_string()))
+    }
+
+    // `data` is the message content without the terminating "<CRLF>.<CRLF>", it is dot-stuffed here
+    #[log(debug)]
+    pub async fn deliver(&self, domain: &str, mail_from: &str, recipients: &[String], data: &str)
+    -> Result<(), RelayError> {
+        let (host, mut stream) = self.connect(domain).await?;
+
+        let ehlo = Self::command(&mut stream, &format!("EHLO {}", self.helo_name), "250").await?;
+        if let Some(smart_host) = &self.smart_host {
+            if let Some(credentials) = &smart_host.credentials {
+                self.authenticate(&mut stream, &host, &ehlo, smart_host, credentials).await?;
+            }
+        }
+        Self::command(&mut stream, &format!("MAIL FROM:<{}>", mail_from), "250").await?;
+        for recipient in recipients {
+            Self::command(&mut stream, &format!("RCPT TO:<{}>", recipient), "25").await?;
+        }
+        Self::command(&mut stream, "DATA", "354").await?;
+
+        stream.write(Self::data_payload(data).as_bytes()).await?;
+        Self::expect(&mut stream, "250").await?;
+
+        // the message is accepted at this point, a failed QUIT does not matter
+        let _ = Self::command(&mut stream, "QUIT", "221").await;
+        Ok(())
+    }
+
+    // AUTH PLAIN over verified TLS, or in clear text when the smart host allows it and does not offer STARTTLS
+    async fn authenticate(&self, stream: &mut AsyncStream, host: &MailHost, ehlo: &str, smart_host: &SmartHost,
+        credentials: &SmartHostCredentials) -> Result<(), RelayError> {
+        let mut capabilities = ServerCapabilities::from_ehlo(&SmtpReply::parse(ehlo)?);
+        if capabilities.supports_starttls {
+            Self::command(stream, "STARTTLS", "220").await?;
+            // the credentials only go to the host the certificate was issued for
+            stream.connect_tls_verified(&smart_host.host).await?;
+            // the capabilities before STARTTLS are void (RFC 3207)
+            let ehlo = Self::command(stream, &format!("EHLO {}", self.helo_name), "250").await?;
+            capabilities = ServerCapabilities::from_ehlo(&SmtpReply::parse(&ehlo)?);
+        } else if !smart_host.allow_plaintext_auth {
+            return Err(RelayError::TlsRequired(host.exchange.clone()));
+        } else {
+            warn!("Smart host {} does not offer STARTTLS, sending credentials in clear text", host.exchange);
+        }
+        if !capabilities.supports_auth("PLAIN") {
+            return Err(RelayError::AuthUnavailable(host.exchange.clone()));
+        }
+        let response = base64::encode(&format!("\0{}\0{}", credentials.username, credentials.password));
+        Self::command(stream, &format!("AUTH PLAIN {}", response), "235").await?;
+        Ok(())
+    }
+
+    // Lines starting with a dot get a second one, so none ends the data early (RFC 5321 4.5.2),
+    // then the final "." line follows on a line of its own
+    fn data_payload(data: &str) -> String {
+        let mut payload = String::with_capacity(data.len() + 5);
+        for line in data.split_inclusive('\n') {
+            if line.starts_with('.') {
+                payload.push('.');
+            }
+            payload.push_str(line);
+        }
+        if !payload.ends_with("\r\n") {
+            payload.push_str("\r\n");
+        }
+        payload.push_str(".\r\n");
+        payload
+    }
+
+    async fn command(stream: &mut AsyncStream, command: &str, expected: &str) -> Result<String, RelayError> {
+        stream.write(format!("{}\r\n", command).as_bytes()).await?;
+        Self::expect(stream, expected).await
+    }
+
+    async fn expect(stream: &mut AsyncStream, expected: &str) -> Result<String, RelayError> {
+        let reply = Self::read_reply(stream).await?;
+        if reply.starts_with(expected) {
+            Ok(reply)
+        } else {
+            Err(RelayError::UnexpectedReply(reply))
+        }
+    }
+
+    // Reads a complete reply, multiline replies end with a "XYZ text" line
+    async fn read_reply(stream: &mut AsyncStream) -> Result<String, RelayError> {
+        let mut reply = String::new();
+        loop {
+            reply.push_str(&stream.read_until("\r\n").await?);
+            let last_line = reply.trim_end().lines().last().unwrap_or("");
+            if last_line.len() <= 3 || last_line.as_bytes()[3] == b' ' {
+                return Ok(reply);
+            }
+        }
+    }
+}
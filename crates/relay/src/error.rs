@@ -0,0 +1,62 @@
+use smart_stream::error::SmartStreamError;
+
+#[derive(Debug)]
+pub enum ResolveError {
+    // the name does not exist or has no records of the requested type
+    NotFound,
+    Temporary(String),
+}
+
+#[derive(Debug)]
+pub enum RelayError {
+    Resolve(ResolveError),
+    // neither MX nor address records exist, the domain cannot receive mail
+    NoMailHost(String),
+    // every mail host of the domain refused or dropped the connection
+    AllHostsFailed(String),
+    // the smart host has credentials configured but does not offer AUTH PLAIN
+    AuthUnavailable(String),
+    // the smart host has credentials configured but does not offer STARTTLS to protect them
+    TlsRequired(String),
+    SmartStream(SmartStreamError),
+    UnexpectedReply(String),
+}
+
+impl RelayError {
+    // Permanent errors should bounce the message, the rest may be retried later
+    pub fn is_permanent(&self) -> bool {
+        match self {
+            Self::NoMailHost(_) => true,
+            Self::UnexpectedReply(reply) => reply.starts_with('5'),
+            _ => false,
+        }
+    }
+}
+
+impl std::fmt::Display for RelayError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Resolve(err) => write!(f, "DNS lookup failed: {:?}", err),
+            Self::NoMailHost(domain) => write!(f, "No mail host found for domain '{}'", domain),
+            Self::AllHostsFailed(domain) => write!(f, "Could not connect to any mail host of '{}'", domain),
+            Self::AuthUnavailable(host) => write!(f, "Mail host '{}' does not offer AUTH PLAIN", host),
+            Self::TlsRequired(host) => write!(f, "Mail host '{}' does not offer STARTTLS, credentials are not sent in clear text", host),
+            Self::SmartStream(err) => write!(f, "Connection error: {}", err),
+            Self::UnexpectedReply(reply) => write!(f, "Unexpected reply: {}", reply.trim_end()),
+        }
+    }
+}
+
+impl std::error::Error for RelayError {}
+
+impl From<ResolveError> for RelayError {
+    fn from(err: ResolveError) -> Self {
+        Self::Resolve(err)
+    }
+}
+
+impl From<SmartStreamError> for RelayError {
+    fn from(err: SmartStreamError) -> Self {
+        Self::SmartStream(err)
+    }
+}
@@ -0,0 +1,8 @@
+pub mod error;
+pub mod resolver;
+pub mod client;
+pub mod reply;
+
+pub use client::{RelayClient, SmartHost, SmartHostCredentials};
+pub use reply::{ServerCapabilities, SmtpReply};
+pub use resolver::{mail_hosts, DnsResolver, MailHost, Resolver};
@@ -0,0 +1,72 @@
+use crate::error::RelayError;
+
+// A parsed server reply; `lines` holds the text after the code of every line
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SmtpReply {
+    pub code: u16,
+    pub lines: Vec<String>,
+}
+
+impl SmtpReply {
+    // Accepts single and multiline replies ("250-first", ..., "250 last").
+    // All lines must carry the same code.
+    pub fn parse(raw: &str) -> Result<SmtpReply, RelayError> {
+        let malformed = || RelayError::UnexpectedReply(raw.to_string());
+        let mut code = None;
+        let mut lines = Vec::new();
+
+        for line in raw.lines().map(|line| line.trim_end_matches('\r')).filter(|line| !line.is_empty()) {
+            let line_code = line.get(..3)
+                .filter(|digits| digits.bytes().all(|b| b.is_ascii_digit()))
+                .and_then(|digits| digits.parse::<u16>().ok())
+                .ok_or_else(malformed)?;
+            if *code.get_or_insert(line_code) != line_code {
+                return Err(malformed());
+            }
+            match line.get(3..4) {
+                None | Some(" ") | Some("-") => lines.push(line.get(4..).unwrap_or("").to_string()),
+                Some(_) => return Err(malformed()),
+            }
+        }
+
+        Ok(SmtpReply { code: code.ok_or_else(malformed)?, lines })
+    }
+
+    pub fn is_positive(&self) -> bool {
+        (200..400).contains(&self.code)
+    }
+}
+
+// What a server advertised in its EHLO reply
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ServerCapabilities {
+    pub supports_starttls: bool,
+    pub auth_mechanisms: Vec<String>,
+    // None when SIZE is absent, Some(0) when advertised without a limit
+    pub max_size: Option<u64>,
+    pub supports_pipelining: bool,
+}
+
+impl ServerCapabilities {
+    // The first line is the greeting, every following line is a keyword with optional parameters
+    pub fn from_ehlo(reply: &SmtpReply) -> ServerCapabilities {
+        let mut capabilities = ServerCapabilities::default();
+
+        for line in reply.lines.iter().skip(1) {
+            let mut words = line.split_whitespace();
+            let keyword = words.next().unwrap_or("").to_ascii_uppercase();
+            match keyword.as_str() {
+                "STARTTLS" => capabilities.supports_starttls = true,
+                "PIPELINING" => capabilities.supports_pipelining = true,
+                "SIZE" => capabilities.max_size = Some(words.next().and_then(|size| size.parse().ok()).unwrap_or(0)),
+                "AUTH" => capabilities.auth_mechanisms.extend(words.map(str::to_ascii_uppercase)),
+                _ => {},
+            }
+        }
+        capabilities
+    }
+
+    pub fn supports_auth(&self, mechanism: &str) -> bool {
+        self.auth_mechanisms.iter().any(|supported| supported.eq_ignore_ascii_case(mechanism))
+    }
+}
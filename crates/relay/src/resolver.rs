@@ -0,0 +1,86 @@
+use std::net::IpAddr;
+
+use async_std_resolver::{resolver_from_system_conf, AsyncStdResolver};
+use trust_dns_resolver::error::ResolveErrorKind;
+use async_trait::async_trait;
+use logger_proc_macro::log;
+
+use crate::error::{RelayError, ResolveError};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MailHost {
+    pub preference: u16,
+    pub exchange: String,
+}
+
+#[async_trait]
+pub trait Resolver: Send + Sync {
+    async fn mx_lookup(&self, domain: &str) -> Result<Vec<MailHost>, ResolveError>;
+    async fn address_lookup(&self, host: &str) -> Result<Vec<IpAddr>, ResolveError>;
+}
+
+// Mail hosts of a domain ordered by preference, as described in RFC 5321 section 5.1.
+// A domain without MX records is its own mail host with preference 0 (implicit MX),
+// provided it has an address record.
+#[log(debug)]
+pub async fn mail_hosts(resolver: &dyn Resolver, domain: &str) -> Result<Vec<MailHost>, RelayError> {
+    match resolver.mx_lookup(domain).await {
+        Ok(mut hosts) if !hosts.is_empty() => {
+            // a single "." exchange is a null MX (RFC 7505), the domain accepts no mail
+            if hosts.iter().all(|host| host.exchange.trim_end_matches('.').is_empty()) {
+                return Err(RelayError::NoMailHost(domain.to_string()));
+            }
+            hosts.sort_by_key(|host| host.preference);
+            return Ok(hosts);
+        },
+        Ok(_) | Err(ResolveError::NotFound) => {},
+        Err(err) => return Err(err.into()),
+    }
+
+    match resolver.address_lookup(domain).await {
+        Ok(addresses) if !addresses.is_empty() => Ok(vec![MailHost {
+            preference: 0,
+            exchange: domain.to_string(),
+        }]),
+        Ok(_) | Err(ResolveError::NotFound) => Err(RelayError::NoMailHost(domain.to_string())),
+        Err(err) => Err(err.into()),
+    }
+}
+
+// Resolver backed by the system DNS configuration
+pub struct DnsResolver {
+    resolver: AsyncStdResolver,
+}
+
+impl DnsResolver {
+    pub async fn from_system_conf() -> Result<Self, ResolveError> {
+        let resolver = resolver_from_system_conf().await
+            .map_err(|err| ResolveError::Temporary(err.to_string()))?;
+        Ok(Self { resolver })
+    }
+
+    fn convert_error(err: async_std_resolver::ResolveError) -> ResolveError {
+        match err.kind() {
+            ResolveErrorKind::NoRecordsFound { .. } => ResolveError::NotFound,
+            _ => ResolveError::Temporary(err.to_string()),
+        }
+    }
+}
+
+#[async_trait]
+impl Resolver for DnsResolver {
+    async fn mx_lookup(&self, domain: &str) -> Result<Vec<MailHost>, ResolveError> {
+        let lookup = self.resolver.mx_lookup(domain).await.map_err(Self::convert_error)?;
+        Ok(lookup.iter()
+            .map(|mx| MailHost {
+                preference: mx.preference(),
+                exchange: mx.exchange().to_utf8().trim_end_matches('.').to_string(),
+            })
+            .collect())
+    }
+
+    async fn address_lookup(&self, host: &str) -> Result<Vec<IpAddr>, ResolveError> {
+        let lookup = self.resolver.lookup_ip(host).await.map_err(Self::convert_error)?;
+        Ok(lookup.iter().collect())
+    }
+}
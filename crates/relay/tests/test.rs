@@ -0,0 +1,215 @@
+mod utils;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use utils::*;
+    use futures::executor::block_on;
+    use relay::{error::RelayError, mail_hosts, MailHost, RelayClient, ServerCapabilities, SmartHost, SmartHostCredentials, SmtpReply};
+
+    #[test]
+    fn mx_present_test() {
+        let resolver = MockResolver::default()
+            .with_mx("example.com", 20, "mx2.example.com")
+            .with_mx("example.com", 10, "mx1.example.com")
+            .with_address("example.com", "192.0.2.1");
+
+        let hosts = block_on(mail_hosts(&resolver, "example.com")).unwrap();
+        assert_eq!(hosts, vec![
+            MailHost { preference: 10, exchange: "mx1.example.com".to_string() },
+            MailHost { preference: 20, exchange: "mx2.example.com".to_string() },
+        ]);
+    }
+
+    #[test]
+    fn implicit_mx_test() {
+        let resolver = MockResolver::default()
+            .with_address("example.com", "192.0.2.1");
+
+        let hosts = block_on(mail_hosts(&resolver, "example.com")).unwrap();
+        assert_eq!(hosts, vec![MailHost { preference: 0, exchange: "example.com".to_string() }]);
+    }
+
+    #[test]
+    fn no_mail_host_test() {
+        let resolver = MockResolver::default();
+
+        let result = block_on(mail_hosts(&resolver, "example.com"));
+        assert!(matches!(&result, Err(RelayError::NoMailHost(domain)) if domain == "example.com"));
+        assert!(result.unwrap_err().is_permanent());
+
+        let resolver = MockResolver::default()
+            .with_mx("example.com", 0, ".")
+            .with_address("example.com", "192.0.2.1");
+        assert!(matches!(block_on(mail_hosts(&resolver, "example.com")), Err(RelayError::NoMailHost(_))));
+    }
+
+    #[test]
+    fn relay_falls_back_to_next_host_test() {
+        let (port, server) = fake_smtp_server();
+        // nothing listens on 127.0.0.2, so the preferred host refuses the connection
+        let resolver = MockResolver::default()
+            .with_mx("example.com", 10, "mx1.example.com")
+            .with_mx("example.com", 20, "mx2.example.com")
+            .with_address("mx1.example.com", "127.0.0.2")
+            .with_address("mx2.example.com", "127.0.0.1");
+        let client = RelayClient::new(Box::new(resolver), "relay.test", port, 5);
+
+        let recipients = vec!["user@example.com".to_string()];
+        block_on(client.deliver("example.com", "sender@test", &recipients, "Subject: Hi\r\n\r\nHello")).unwrap();
+
+        let lines = server.join().unwrap();
+        assert_eq!(lines[0], "EHLO relay.test");
+        assert_eq!(lines[1], "MAIL FROM:<sender@test>");
+        assert_eq!(lines[2], "RCPT TO:<user@example.com>");
+        assert_eq!(lines[3], "DATA");
+        // the data did not end with CRLF, the terminator is put on a line of its own
+        assert_eq!(lines[lines.len() - 3..], ["Hello", ".", "QUIT"]);
+    }
+
+    #[test]
+    fn relay_dot_stuffing_test() {
+        let (port, server) = fake_smtp_server();
+        let resolver = MockResolver::default()
+            .with_address("example.com", "127.0.0.1");
+        let client = RelayClient::new(Box::new(resolver), "relay.test", port, 5);
+
+        let recipients = vec!["user@example.com".to_string()];
+        let data = "Subject: Hi\r\n\r\n.\r\n..two dots\r\nlast line\r\n";
+        block_on(client.deliver("example.com", "sender@test", &recipients, data)).unwrap();
+
+        let lines = server.join().unwrap();
+        let data_start = lines.iter().position(|line| line == "DATA").unwrap() + 1;
+        // the data ended with CRLF already, no empty line is added before the terminator
+        assert_eq!(lines[data_start..], ["Subject: Hi", "", "..", "...two dots", "last line", ".", "QUIT"]);
+    }
+
+    #[test]
+    fn relay_through_smart_host_test() {
+        let (port, server) = fake_smtp_server();
+        // the MX of the domain does not accept connections, only the smart host does
+        let resolver = MockResolver::default()
+            .with_mx("example.com", 10, "mx1.example.com")
+            .with_address("mx1.example.com", "127.0.0.2")
+            .with_address("smtp.relay.test", "127.0.0.1");
+        let smart_host = SmartHost {
+            host: "smtp.relay.test".to_string(),
+            port,
+            credentials: Some(SmartHostCredentials { username: "user".to_string(), password: "secret".to_string() }),
+            allow_plaintext_auth: true,
+        };
+        let client = RelayClient::new(Box::new(resolver), "relay.test", 1, 5).with_smart_host(smart_host);
+
+        let recipients = vec!["user@example.com".to_string()];
+        block_on(client.deliver("example.com", "sender@test", &recipients, "Subject: Hi\r\n\r\nHello")).unwrap();
+
+        let lines = server.join().unwrap();
+        assert_eq!(lines[0], "EHLO relay.test");
+        // the fake server offers no STARTTLS, so with plain text allowed AUTH follows the first EHLO
+        assert_eq!(lines[1], "AUTH PLAIN AHVzZXIAc2VjcmV0");
+        assert_eq!(lines[2], "MAIL FROM:<sender@test>");
+        assert_eq!(lines.last().unwrap(), "QUIT");
+    }
+
+    #[test]
+    fn smart_host_requires_tls_test() {
+        let (port, server) = fake_smtp_server();
+        let resolver = MockResolver::default()
+            .with_address("smtp.relay.test", "127.0.0.1");
+        let smart_host = SmartHost {
+            host: "smtp.relay.test".to_string(),
+            port,
+            credentials: Some(SmartHostCredentials { username: "user".to_string(), password: "secret".to_string() }),
+            allow_plaintext_auth: false,
+        };
+        let client = RelayClient::new(Box::new(resolver), "relay.test", 1, 5).with_smart_host(smart_host);
+
+        let recipients = vec!["user@example.com".to_string()];
+        let result = block_on(client.deliver("example.com", "sender@test", &recipients, "Hello"));
+        assert!(matches!(result, Err(RelayError::TlsRequired(host)) if host == "smtp.relay.test"));
+
+        // the credentials never left the client
+        let lines = server.join().unwrap();
+        assert_eq!(lines, ["EHLO relay.test"]);
+    }
+
+    #[test]
+    fn smart_host_certificate_verified_test() {
+        let (port, server) = fake_starttls_server();
+        let resolver = MockResolver::default()
+            .with_address("smtp.relay.test", "127.0.0.1");
+        let smart_host = SmartHost {
+            host: "smtp.relay.test".to_string(),
+            port,
+            credentials: Some(SmartHostCredentials { username: "user".to_string(), password: "secret".to_string() }),
+            allow_plaintext_auth: false,
+        };
+        let client = RelayClient::new(Box::new(resolver), "relay.test", 1, 5).with_smart_host(smart_host);
+
+        let recipients = vec!["user@example.com".to_string()];
+        let result = block_on(client.deliver("example.com", "sender@test", &recipients, "Hello"));
+        assert!(matches!(result, Err(RelayError::SmartStream(_))), "{:?}", result);
+
+        // the self-signed certificate is not trusted, the credentials never left the client
+        let lines = server.join().unwrap();
+        assert_eq!(lines, ["EHLO relay.test", "STARTTLS"]);
+    }
+
+    #[test]
+    fn relay_all_hosts_failed_test() {
+        let resolver = MockResolver::default()
+            .with_address("example.com", "127.0.0.2");
+        let client = RelayClient::new(Box::new(resolver), "relay.test", 1, 5);
+
+        match block_on(client.connect("example.com")) {
+            Err(err) => {
+                assert!(matches!(err, RelayError::AllHostsFailed(_)));
+                assert!(!err.is_permanent());
+            },
+            Ok(_) => panic!("connection should have failed"),
+        }
+    }
+
+    #[test]
+    fn parse_ehlo_capabilities_test() {
+        let raw = "250-mx.example.com greets relay.test\r\n\
+                   250-PIPELINING\r\n\
+                   250-SIZE 35882577\r\n\
+                   250-STARTTLS\r\n\
+                   250-AUTH PLAIN login XOAUTH2\r\n\
+                   250-8BITMIME\r\n\
+                   250 HELP\r\n";
+        let reply = SmtpReply::parse(raw).unwrap();
+        assert_eq!(reply.code, 250);
+        assert_eq!(reply.lines.len(), 7);
+        assert_eq!(reply.lines[0], "mx.example.com greets relay.test");
+
+        let capabilities = ServerCapabilities::from_ehlo(&reply);
+        assert_eq!(capabilities, ServerCapabilities {
+            supports_starttls: true,
+            auth_mechanisms: vec!["PLAIN".to_string(), "LOGIN".to_string(), "XOAUTH2".to_string()],
+            max_size: Some(35882577),
+            supports_pipelining: true,
+        });
+        assert!(capabilities.supports_auth("login"));
+        assert!(!capabilities.supports_auth("CRAM-MD5"));
+    }
+
+    #[test]
+    fn parse_minimal_ehlo_test() {
+        let reply = SmtpReply::parse("250 mx.example.com\r\n").unwrap();
+        assert_eq!(ServerCapabilities::from_ehlo(&reply), ServerCapabilities::default());
+
+        let reply = SmtpReply::parse("250-mx.example.com\r\n250 SIZE\r\n").unwrap();
+        assert_eq!(ServerCapabilities::from_ehlo(&reply).max_size, Some(0));
+    }
+
+    #[test]
+    fn parse_malformed_reply_test() {
+        assert!(SmtpReply::parse("").is_err());
+        assert!(SmtpReply::parse("hello\r\n").is_err());
+        assert!(SmtpReply::parse("250-first\r\n251 second\r\n").is_err());
+        assert!(SmtpReply::parse("250xsomething\r\n").is_err());
+        assert!(!SmtpReply::parse("550 no\r\n").unwrap().is_positive());
+    }
+}
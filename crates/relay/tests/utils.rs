@@ -0,0 +1,134 @@
+use std::{
+    collections::HashMap,
+    io::{BufRead, BufReader, Write},
+    net::{IpAddr, TcpListener},
+    thread,
+};
+
+use async_trait::async_trait;
+use futures::executor::block_on;
+use relay::{error::ResolveError, MailHost, Resolver};
+use smart_stream::AsyncStream;
+
+// Resolver answering from fixed tables; unknown names are NotFound
+#[derive(Default)]
+pub struct MockResolver {
+    pub mx: HashMap<String, Vec<MailHost>>,
+    pub addresses: HashMap<String, Vec<IpAddr>>,
+}
+
+impl MockResolver {
+    pub fn with_mx(mut self, domain: &str, preference: u16, exchange: &str) -> Self {
+        self.mx.entry(domain.to_string()).or_default().push(MailHost {
+            preference,
+            exchange: exchange.to_string(),
+        });
+        self
+    }
+
+    pub fn with_address(mut self, host: &str, address: &str) -> Self {
+        self.addresses.entry(host.to_string()).or_default().push(address.parse().unwrap());
+        self
+    }
+}
+
+#[async_trait]
+impl Resolver for MockResolver {
+    async fn mx_lookup(&self, domain: &str) -> Result<Vec<MailHost>, ResolveError> {
+        self.mx.get(domain).cloned().ok_or(ResolveError::NotFound)
+    }
+
+    async fn address_lookup(&self, host: &str) -> Result<Vec<IpAddr>, ResolveError> {
+        self.addresses.get(host).cloned().ok_or(ResolveError::NotFound)
+    }
+}
+
+// Accepts one connection on 127.0.0.1, answers every command positively and
+// returns the received lines once the client quits
+pub fn fake_smtp_server() -> (u16, thread::JoinHandle<Vec<String>>) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+
+    let server = thread::spawn(move || {
+        let (mut stream, _) = listener.accept().unwrap();
+        let mut reader = BufReader::new(stream.try_clone().unwrap());
+        let mut lines = Vec::new();
+        let mut in_data = false;
+
+        stream.write_all(b"220 fake ready\r\n").unwrap();
+        loop {
+            let mut line = String::new();
+            if reader.read_line(&mut line).unwrap() == 0 {
+                break;
+            }
+            let line = line.trim_end().to_string();
+            lines.push(line.clone());
+
+            let reply: &[u8] = if in_data {
+                if line != "." {
+                    continue;
+                }
+                in_data = false;
+                b"250 OK\r\n"
+            } else if line == "DATA" {
+                in_data = true;
+                b"354 Go ahead\r\n"
+            } else if line == "QUIT" {
+                stream.write_all(b"221 Bye\r\n").unwrap();
+                break;
+            } else if line.starts_with("EHLO") {
+                b"250-fake\r\n250-AUTH PLAIN\r\n250 HELP\r\n"
+            } else if line.starts_with("AUTH") {
+                b"235 Authenticated\r\n"
+            } else {
+                b"250 OK\r\n"
+            };
+            stream.write_all(reply).unwrap();
+        }
+        lines
+    });
+    (port, server)
+}
+
+// Offers STARTTLS with the self-signed test certificate and returns the lines received until
+// the client gives up, in clear text or encrypted
+pub fn fake_starttls_server() -> (u16, thread::JoinHandle<Vec<String>>) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let acceptor = smart_stream::tls_acceptor(
+        include_bytes!("../../client_session/tests/certs/server.crt"),
+        include_bytes!("../../client_session/tests/certs/server.key"),
+    ).unwrap();
+
+    let server = thread::spawn(move || {
+        let (stream, _) = listener.accept().unwrap();
+        let mut stream = AsyncStream::new(stream, 5).unwrap();
+        let mut lines = Vec::new();
+        block_on(async {
+            stream.write(b"220 fake ready\r\n").await.unwrap();
+            while let Ok(line) = stream.read_line().await {
+                lines.push(line.clone());
+                let reply: &[u8] = if line.starts_with("EHLO") {
+                    b"250-fake\r\n250-STARTTLS\r\n250 AUTH PLAIN\r\n"
+                } else if line == "STARTTLS" {
+                    stream.write(b"220 Ready to start TLS\r\n").await.unwrap();
+                    if stream.accept_tls(&acceptor).await.is_err() {
+                        break;
+                    }
+                    continue;
+                } else if line.starts_with("AUTH") {
+                    b"235 Authenticated\r\n"
+                } else if line == "QUIT" {
+                    b"221 Bye\r\n"
+                } else {
+                    b"250 OK\r\n"
+                };
+                if stream.write(reply).await.is_err() {
+                    break;
+                }
+            }
+        });
+        lines
+    });
+    (port, server)
+}
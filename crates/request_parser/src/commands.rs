@@ -1,7 +1,7 @@
 pub const EHLO: &str = "EHLO";
 pub const HELO: &str = "HELO";
 pub const STARTTLS: &str = "STARTTLS";
-pub const AUTH_PLAIN: &str = "AUTH PLAIN";
+pub const AUTH: &str = "AUTH";
 pub const REGISTER: &str = "REGISTER";
 pub const MAIL_FROM: &str = "MAIL FROM";
 pub const RCPT_TO: &str = "RCPT TO";
@@ -9,4 +9,13 @@ pub const DATA: &str = "DATA";
 pub const QUIT: &str = "QUIT";
 pub const HELP: &str = "HELP";
 pub const NOOP: &str = "NOOP";
-pub const RSET: &str = "RSET";
\ No newline at end of file
+pub const RSET: &str = "RSET";
+pub const VRFY: &str = "VRFY";
+pub const EXPN: &str = "EXPN";
+pub const BDAT: &str = "BDAT";
+
+// Commands defined by SMTP and its extensions that this server recognizes but does not implement
+pub const NOT_IMPLEMENTED: [&str; 6] = ["TURN", "ETRN", "ATRN", "SEND", "SOML", "SAML"];
+
+// The longest command line of RFC 5321 section 4.5.3.1.4, CRLF included
+pub const MAX_COMMAND_LINE: usize = 512;
@@ -1,29 +1,190 @@
 use std::{fmt::Debug, slice::SliceIndex};
 mod commands; use commands::*;
+pub use commands::MAX_COMMAND_LINE;
 use logger_proc_macro::*;
 
+#[derive(Eq, Debug, PartialEq)]
+pub enum ParseError {
+    // the line is not an SMTP command at all
+    Unrecognized,
+    // a known SMTP command that this server does not support
+    NotImplemented(String),
+    InvalidArgument(String),
+    // parse_one found no CRLF, more of the command has to be read first
+    Incomplete,
+    // the line with CRLF is longer than the limit it carries
+    LineTooLong(usize),
+}
+
+impl std::fmt::Display for ParseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            ParseError::Unrecognized => write!(f, "Could not parse the SMTP command"),
+            ParseError::NotImplemented(command) => write!(f, "Command not implemented: {}", command),
+            ParseError::InvalidArgument(command) => write!(f, "Could not parse the argument for the command: {}", command),
+            ParseError::Incomplete => write!(f, "The command is not terminated by CRLF"),
+            ParseError::LineTooLong(limit) => write!(f, "The command line is longer than {} bytes", limit),
+        }
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ParseMode {
+    // commands are matched as RFC 5321 frames them
+    #[default]
+    Strict,
+    // for interactive testing: also a space before the colon of MAIL FROM and RCPT TO
+    Lenient,
+}
+
+// The argument of MAIL FROM, an empty address is the null sender of bounces
+#[derive(Eq, Debug, PartialEq, Default, Clone)]
+pub struct MailFrom {
+    pub address: String,
+    // the SIZE parameter, the message size in bytes the client announces
+    pub size: Option<usize>,
+    // the DSN parameters of RFC 3461: what a bounce should return, and the client's
+    // identifier of the message, still xtext encoded
+    pub ret: Option<DsnReturn>,
+    pub envid: Option<String>,
+    // the other ESMTP parameters with upper case keywords, the value is empty when missing
+    pub params: Vec<(String, String)>,
+}
+
+impl MailFrom {
+    pub fn new(address: &str) -> Self {
+        MailFrom { address: address.to_string(), ..Default::default() }
+    }
+}
+
+// Keyword and value pairs, as MailFrom and RcptTo keep the parameters they do not know
+type EsmtpParams = Vec<(String, String)>;
+
+// The argument of RCPT TO
+#[derive(Eq, Debug, PartialEq, Default, Clone)]
+pub struct RcptTo {
+    pub address: String,
+    // the NOTIFY parameter of RFC 3461, None leaves the choice to the server
+    pub notify: Option<DsnNotify>,
+    // the other ESMTP parameters, like MailFrom::params
+    pub params: Vec<(String, String)>,
+}
+
+impl RcptTo {
+    pub fn new(address: &str) -> Self {
+        RcptTo { address: address.to_string(), ..Default::default() }
+    }
+}
+
+// RET=FULL or RET=HDRS of MAIL FROM
+#[derive(Eq, Debug, PartialEq, Clone, Copy)]
+pub enum DsnReturn {
+    Full,
+    Headers,
+}
+
+impl DsnReturn {
+    fn parse(value: &str) -> Option<Self> {
+        if value.eq_ignore_ascii_case("FULL") {
+            Some(DsnReturn::Full)
+        } else if value.eq_ignore_ascii_case("HDRS") {
+            Some(DsnReturn::Headers)
+        } else {
+            None
+        }
+    }
+}
+
+// The events a recipient wants to be notified of, NOTIFY=NEVER sets none of them
+#[derive(Eq, Debug, PartialEq, Default, Clone, Copy)]
+pub struct DsnNotify {
+    pub success: bool,
+    pub failure: bool,
+    pub delay: bool,
+}
+
+impl DsnNotify {
+    // NEVER, or a comma separated list of SUCCESS, FAILURE and DELAY
+    fn parse(value: &str) -> Option<Self> {
+        let mut notify = DsnNotify::default();
+        if value.eq_ignore_ascii_case("NEVER") {
+            return Some(notify);
+        }
+        for event in value.split(',') {
+            let flag = match event.to_ascii_uppercase().as_str() {
+                "SUCCESS" => &mut notify.success,
+                "FAILURE" => &mut notify.failure,
+                "DELAY" => &mut notify.delay,
+                _ => return None,
+            };
+            *flag = true;
+        }
+        Some(notify)
+    }
+}
+
+// The SASL mechanism of AUTH, RFC 4954 compares its name without regard to case
+#[derive(Eq, Debug, PartialEq, Clone, Copy)]
+pub enum AuthMechanism {
+    Plain,
+    Login,
+    CramMd5,
+}
+
+impl AuthMechanism {
+    pub fn name(&self) -> &'static str {
+        match self {
+            AuthMechanism::Plain => "PLAIN",
+            AuthMechanism::Login => "LOGIN",
+            AuthMechanism::CramMd5 => "CRAM-MD5",
+        }
+    }
+
+    fn from_name(name: &str) -> Option<Self> {
+        [AuthMechanism::Plain, AuthMechanism::Login, AuthMechanism::CramMd5].into_iter()
+            .find(|mechanism| mechanism.name().eq_ignore_ascii_case(name))
+    }
+}
+
+impl std::fmt::Display for AuthMechanism {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
 #[allow(non_camel_case_types)]
 #[derive(Eq, Debug, PartialEq)]
 pub enum RequestType {
     EHLO(String),
+    // the RFC 821 greeting, the client does not use any ESMTP extension
+    HELO(String),
     STARTTLS,
-    AUTH_PLAIN(String),
+    // the initial response is None when the client waits for the first challenge
+    AUTH { mechanism: AuthMechanism, initial_response: Option<String> },
     REGISTER(String),
-    MAIL_FROM(String),
-    RCPT_TO(String),
+    MAIL_FROM(MailFrom),
+    RCPT_TO(RcptTo),
     DATA,
     QUIT,
     HELP,
     NOOP,
     RSET,
+    VRFY(String),
+    // the mailing list to expand
+    EXPN(String),
+    // a chunk of `size` bytes follows the command line, LAST marks the end of the message
+    BDAT { size: usize, last: bool },
 }
 
 impl std::fmt::Display for RequestType {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
         match self {
             RequestType::EHLO(_) => write!(f, "{EHLO}"),
+            RequestType::HELO(_) => write!(f, "{HELO}"),
             RequestType::STARTTLS => write!(f, "{STARTTLS}"),
-            RequestType::AUTH_PLAIN(_) => write!(f, "{AUTH_PLAIN}"),
+            RequestType::AUTH { mechanism, .. } => write!(f, "{AUTH} {mechanism}"),
             RequestType::REGISTER(_) => write!(f, "{REGISTER}"),
             RequestType::MAIL_FROM(_) => write!(f, "{MAIL_FROM}"),
             RequestType::RCPT_TO(_) => write!(f, "{RCPT_TO}"),
@@ -32,6 +193,9 @@ impl std::fmt::Display for RequestType {
             RequestType::HELP => write!(f, "{HELP}"),
             RequestType::NOOP => write!(f, "{NOOP}"),
             RequestType::RSET => write!(f, "{RSET}"),
+            RequestType::VRFY(_) => write!(f, "{VRFY}"),
+            RequestType::EXPN(_) => write!(f, "{EXPN}"),
+            RequestType::BDAT { .. } => write!(f, "{BDAT}"),
 
         }
     }
@@ -39,42 +203,92 @@ impl std::fmt::Display for RequestType {
 }
 
 impl RequestType {
+    // Never panics, any input either parses or yields a ParseError. Only the first line of a
+    // pipelined buffer is parsed, and a line without CRLF is parsed as a whole.
     #[log(trace)]
-    pub fn parse(raw_request: &str) -> Result<RequestType, String> {
+    pub fn parse(raw_request: &str) -> Result<RequestType, ParseError> {
+        match RequestType::parse_one(raw_request) {
+            Err(ParseError::Incomplete) => RequestType::parse_line(raw_request),
+            result => result.map(|(request, _)| request),
+        }
+    }
+
+    pub fn parse_with_mode(raw_request: &str, mode: ParseMode) -> Result<RequestType, ParseError> {
+        match mode {
+            ParseMode::Strict => RequestType::parse(raw_request),
+            ParseMode::Lenient => RequestType::parse(&RequestType::normalize_lenient(raw_request)),
+        }
+    }
+
+    // Drops the space before the colon of MAIL FROM and RCPT TO, the arguments are kept as they are
+    fn normalize_lenient(raw_request: &str) -> String {
+        let trimmed = raw_request.trim_start();
+        let verb = [MAIL_FROM, RCPT_TO].into_iter().find(|verb| {
+            trimmed.get(..verb.len()).is_some_and(|prefix| prefix.eq_ignore_ascii_case(verb))
+        });
+        match verb {
+            Some(verb) if trimmed[verb.len()..].trim_start().starts_with(':') => {
+                format!("{}{}", verb, trimmed[verb.len()..].trim_start())
+            },
+            _ => raw_request.to_string(),
+        }
+    }
+
+    // Parses the first command of `buf` and returns it with the number of bytes it takes,
+    // CRLF included. Whether it parses or not, the next command starts after that many bytes.
+    #[log(trace)]
+    pub fn parse_one(buf: &str) -> Result<(RequestType, usize), ParseError> {
+        let line_end = buf.find("\r\n").ok_or(ParseError::Incomplete)?;
+        RequestType::parse_line(&buf[..line_end]).map(|request| (request, line_end + 2))
+    }
+
+    #[log(trace)]
+    fn parse_line(raw_request: &str) -> Result<RequestType, ParseError> {
+        if raw_request.len() + 2 > MAX_COMMAND_LINE {
+            return Err(ParseError::LineTooLong(MAX_COMMAND_LINE));
+        }
         let raw_request = raw_request.trim_start().trim_end();
-        let request_res: Result<RequestType, String>;
+        let request_res: Result<RequestType, ParseError>;
 
-        if raw_request.starts_with(EHLO) || raw_request.starts_with(HELO) {
+        if RequestType::is_whole_verb(raw_request, EHLO) {
             request_res = RequestType::parse_command_with_arg(RequestType::EHLO, raw_request, EHLO.len() + 1..);
-        } else if raw_request.starts_with(STARTTLS) {
+        } else if RequestType::is_whole_verb(raw_request, HELO) {
+            request_res = RequestType::parse_command_with_arg(RequestType::HELO, raw_request, HELO.len() + 1..);
+        } else if RequestType::is_whole_verb(raw_request, STARTTLS) {
             request_res = Ok(RequestType::STARTTLS);
-        } else if raw_request.starts_with(AUTH_PLAIN) {
-            request_res =  RequestType::parse_command_with_arg(RequestType::AUTH_PLAIN, raw_request, AUTH_PLAIN.len() + 1..);
-        } else if raw_request.starts_with(REGISTER) {
+        } else if RequestType::is_whole_verb(raw_request, AUTH) {
+            request_res = RequestType::parse_auth(raw_request);
+        } else if RequestType::is_whole_verb(raw_request, REGISTER) {
             request_res =  RequestType::parse_command_with_arg(RequestType::REGISTER, raw_request, REGISTER.len() + 1..);
-        } else if raw_request.starts_with(MAIL_FROM) {
-            request_res =  RequestType::parse_command_with_arg(RequestType::MAIL_FROM, raw_request, MAIL_FROM.len() + 3..raw_request.len() - 1);
-        } else if raw_request.starts_with(RCPT_TO) {
-            request_res =  RequestType::parse_command_with_arg(RequestType::RCPT_TO, raw_request, RCPT_TO.len() + 3..raw_request.len() - 1);
-        } else if raw_request.starts_with(DATA) {
+        } else if RequestType::is_whole_verb(raw_request, MAIL_FROM) {
+            request_res =  RequestType::parse_mail_from(raw_request);
+        } else if RequestType::is_whole_verb(raw_request, RCPT_TO) {
+            request_res =  RequestType::parse_rcpt_to(raw_request);
+        } else if RequestType::is_whole_verb(raw_request, DATA) {
             request_res = Ok(RequestType::DATA);
-        } else if raw_request.starts_with(QUIT) {
+        } else if RequestType::is_whole_verb(raw_request, QUIT) {
             request_res = Ok(RequestType::QUIT);
-        } else if raw_request.starts_with(HELP) {
+        } else if RequestType::is_whole_verb(raw_request, HELP) {
             request_res = Ok(RequestType::HELP);
-        } else if raw_request.starts_with(NOOP) {
+        } else if RequestType::is_whole_verb(raw_request, NOOP) {
             request_res = Ok(RequestType::NOOP);
-        } else if raw_request.starts_with(RSET) {
+        } else if RequestType::is_whole_verb(raw_request, RSET) {
             request_res = Ok(RequestType::RSET);
+        } else if RequestType::is_whole_verb(raw_request, BDAT) {
+            request_res = RequestType::parse_bdat(raw_request);
+        } else if RequestType::is_whole_verb(raw_request, VRFY) {
+            request_res = RequestType::parse_command_with_arg(RequestType::VRFY, raw_request, VRFY.len() + 1..);
+        } else if RequestType::is_whole_verb(raw_request, EXPN) {
+            request_res = RequestType::parse_command_with_arg(RequestType::EXPN, raw_request, EXPN.len() + 1..);
         } else {
-            request_res = Err("Could not parse the SMTP command".into());
+            request_res = Err(RequestType::unrecognized_command_error(raw_request));
         }
 
         request_res
     }
     
     #[log(trace)]
-    fn parse_command_with_arg<I: SliceIndex<str> + Debug>(cmd_type: fn(String) -> RequestType, raw_request: &str, slice: I) -> Result<RequestType, String> 
+    fn parse_command_with_arg<I: SliceIndex<str> + Debug>(cmd_type: fn(String) -> RequestType, raw_request: &str, slice: I) -> Result<RequestType, ParseError> 
     where
         <I as SliceIndex<str>>::Output: std::fmt::Display + Debug,
     {
@@ -86,8 +300,119 @@ impl RequestType {
         }
     }
 
-    fn argument_parsing_error(command: &str) -> Result<RequestType, String> {
-        Err(format!("Could not parse the argument for the command: {}", command))
+    // Splits `:<path> KEYWORD=value ...` of MAIL FROM and RCPT TO into the path and its ESMTP
+    // parameters with upper case keywords, allowing a space before `<`
+    #[log(trace)]
+    fn parse_path_with_params<'a>(raw_request: &'a str, command: &str) -> Result<(&'a str, EsmtpParams), ParseError> {
+        let invalid = || ParseError::InvalidArgument(command.to_string());
+        let (path, params) = raw_request.get(command.len()..)
+            .and_then(|arg| arg.strip_prefix(':'))
+            .map(str::trim_start)
+            .and_then(|arg| arg.strip_prefix('<'))
+            .and_then(|arg| arg.split_once('>'))
+            .ok_or_else(invalid)?;
+        if !params.is_empty() && !params.starts_with(' ') {
+            return Err(invalid());
+        }
+        let params = params.split_whitespace()
+            .map(|param| {
+                let (keyword, value) = param.split_once('=').unwrap_or((param, ""));
+                (keyword.to_ascii_uppercase(), value.to_string())
+            })
+            .collect();
+        Ok((path, params))
+    }
+
+    #[log(trace)]
+    fn parse_rcpt_to(raw_request: &str) -> Result<RequestType, ParseError> {
+        let invalid = || ParseError::InvalidArgument(RCPT_TO.to_string());
+        let (address, params) = RequestType::parse_path_with_params(raw_request, RCPT_TO)?;
+        if address.is_empty() {
+            return Err(invalid());
+        }
+
+        let mut rcpt_to = RcptTo::new(address);
+        for (keyword, value) in params {
+            if keyword == "NOTIFY" {
+                rcpt_to.notify = Some(DsnNotify::parse(&value).ok_or_else(invalid)?);
+            } else {
+                rcpt_to.params.push((keyword, value));
+            }
+        }
+        Ok(RequestType::RCPT_TO(rcpt_to))
+    }
+
+    // `AUTH mechanism [initial-response]`, the mechanism does not have to be one the server offers
+    #[log(trace)]
+    fn parse_auth(raw_request: &str) -> Result<RequestType, ParseError> {
+        let mut args = raw_request[AUTH.len()..].split_whitespace();
+        let mechanism = args.next().and_then(AuthMechanism::from_name)
+            .ok_or_else(|| ParseError::InvalidArgument(AUTH.to_string()))?;
+        let initial_response = args.next().map(str::to_string);
+        if args.next().is_some() {
+            return RequestType::argument_parsing_error(AUTH);
+        }
+        Ok(RequestType::AUTH { mechanism, initial_response })
+    }
+
+    // Splits `:<path> KEYWORD=value ...` into the path and its ESMTP parameters
+    #[log(trace)]
+    fn parse_mail_from(raw_request: &str) -> Result<RequestType, ParseError> {
+        let invalid = || ParseError::InvalidArgument(MAIL_FROM.to_string());
+        let (address, params) = RequestType::parse_path_with_params(raw_request, MAIL_FROM)?;
+
+        let mut mail_from = MailFrom::new(address);
+        for (keyword, value) in params {
+            match keyword.as_str() {
+                "SIZE" => mail_from.size = Some(value.parse().map_err(|_| invalid())?),
+                "RET" => mail_from.ret = Some(DsnReturn::parse(&value).ok_or_else(invalid)?),
+                "ENVID" if !value.is_empty() => mail_from.envid = Some(value),
+                "ENVID" => return Err(invalid()),
+                _ => mail_from.params.push((keyword, value)),
+            }
+        }
+        Ok(RequestType::MAIL_FROM(mail_from))
+    }
+
+    // BDAT <size> [LAST]
+    #[log(trace)]
+    fn parse_bdat(raw_request: &str) -> Result<RequestType, ParseError> {
+        let mut args = raw_request[BDAT.len()..].split_whitespace();
+        let size = args.next().and_then(|size| size.parse::<usize>().ok());
+        let last = match args.next() {
+            None => Some(false),
+            Some(flag) if flag.eq_ignore_ascii_case("LAST") => Some(true),
+            Some(_) => None,
+        };
+        match (size, last, args.next()) {
+            (Some(size), Some(last), None) => Ok(RequestType::BDAT { size, last }),
+            _ => RequestType::argument_parsing_error(BDAT),
+        }
+    }
+
+    fn argument_parsing_error(command: &str) -> Result<RequestType, ParseError> {
+        Err(ParseError::InvalidArgument(command.to_string()))
+    }
+
+    // The verb, in any case, ends the line or is followed by whitespace, so NOOPX is not NOOP and
+    // DATABASE is not DATA. The path of MAIL FROM and RCPT TO may follow the verb's colon directly.
+    fn is_whole_verb(raw_request: &str, verb: &str) -> bool {
+        let rest = raw_request.get(..verb.len())
+            .filter(|prefix| prefix.eq_ignore_ascii_case(verb))
+            .map(|_| &raw_request[verb.len()..]);
+        rest.is_some_and(|rest| match rest.chars().next() {
+            None => true,
+            Some(':') => matches!(verb, MAIL_FROM | RCPT_TO),
+            Some(next) => next.is_whitespace(),
+        })
+    }
+
+    fn unrecognized_command_error(raw_request: &str) -> ParseError {
+        let verb = raw_request.split_whitespace().next().unwrap_or("");
+        match NOT_IMPLEMENTED.iter().find(|command| command.eq_ignore_ascii_case(verb)) {
+            Some(command) => ParseError::NotImplemented(command.to_string()),
+            None => ParseError::Unrecognized,
+        }
     }
 
 }
@@ -98,6 +423,30 @@ impl RequestType {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use proptest::prelude::*;
+
+    proptest! {
+        #[test]
+        fn parse_never_panics_on_random_bytes(bytes in proptest::collection::vec(any::<u8>(), 0..256)) {
+            let _ = RequestType::parse(&String::from_utf8_lossy(&bytes));
+        }
+
+        #[test]
+        fn parse_never_panics_after_known_command(
+            command in prop::sample::select(vec![EHLO, HELO, STARTTLS, AUTH, REGISTER, MAIL_FROM, RCPT_TO, DATA, QUIT, HELP,
+                NOOP, RSET, VRFY, EXPN, BDAT]),
+            tail in "\\PC{0,16}",
+        ) {
+            let _ = RequestType::parse(&format!("{}{}", command, tail));
+        }
+    }
+
+    #[test]
+    fn test_parse_multibyte_argument_boundary() {
+        // the argument slice starts in the middle of a multibyte character
+        assert!(RequestType::parse("EHLO\u{e9}x").is_err());
+        assert!(RequestType::parse("MAIL FROM:<\u{e9}>").is_ok());
+    }
     #[test]
     fn test_parse_ehlo() {
         let request = RequestType::parse("EHLO example.com").unwrap();
@@ -107,7 +456,7 @@ mod tests {
     #[test]
     fn test_parse_helo() {
         let request = RequestType::parse("HELO example.com").unwrap();
-        assert_eq!(request, RequestType::EHLO("example.com".to_string()));
+        assert_eq!(request, RequestType::HELO("example.com".to_string()));
     }
 
     #[test]
@@ -126,8 +475,29 @@ mod tests {
     #[test]
     fn test_parse_auth_plain() {
         let request = RequestType::parse("AUTH PLAIN login_and_password").unwrap();
-        assert_eq!(request, RequestType::AUTH_PLAIN("login_and_password".to_string()));
+        assert_eq!(request, RequestType::AUTH {
+            mechanism: AuthMechanism::Plain,
+            initial_response: Some("login_and_password".to_string()),
+        });
 
+        let request = RequestType::parse("AUTH PLAIN").unwrap();
+        assert_eq!(request, RequestType::AUTH { mechanism: AuthMechanism::Plain, initial_response: None });
+    }
+
+    #[test]
+    fn test_parse_auth_cram_md5() {
+        let request = RequestType::parse("AUTH CRAM-MD5").unwrap();
+        assert_eq!(request, RequestType::AUTH { mechanism: AuthMechanism::CramMd5, initial_response: None });
+        let request = RequestType::parse("AUTH cram-md5").unwrap();
+        assert_eq!(request, RequestType::AUTH { mechanism: AuthMechanism::CramMd5, initial_response: None });
+    }
+
+    #[test]
+    fn test_parse_auth_err() {
+        assert_eq!(RequestType::parse("AUTH"), Err(ParseError::InvalidArgument(AUTH.to_string())));
+        assert_eq!(RequestType::parse("AUTH XOAUTH2 dG9rZW4="), Err(ParseError::InvalidArgument(AUTH.to_string())));
+        assert_eq!(RequestType::parse("AUTH PLAIN one two"), Err(ParseError::InvalidArgument(AUTH.to_string())));
+        assert_eq!(RequestType::parse("AUTHPLAIN"), Err(ParseError::Unrecognized));
     }
 
     #[test]
@@ -139,19 +509,113 @@ mod tests {
     #[test]
     fn test_parse_mail_from() {
         let request = RequestType::parse("MAIL FROM:<user@example.com>").unwrap();
-        assert_eq!(request, RequestType::MAIL_FROM("user@example.com".to_string()));
+        assert_eq!(request, RequestType::MAIL_FROM(MailFrom::new("user@example.com")));
+    }
+
+    #[test]
+    fn test_parse_mail_from_size() {
+        let request = RequestType::parse("MAIL FROM:<user@example.com> SIZE=1048576").unwrap();
+        let expected = MailFrom { size: Some(1048576), ..MailFrom::new("user@example.com") };
+        assert_eq!(request, RequestType::MAIL_FROM(expected));
+
+        let request = RequestType::parse("MAIL FROM:<user@example.com> SIZE=big");
+        assert_eq!(request, Err(ParseError::InvalidArgument(MAIL_FROM.to_string())));
+    }
+
+    #[test]
+    fn test_parse_mail_from_params() {
+        let request = RequestType::parse("MAIL FROM:<user@example.com> body=8BITMIME SIZE=10 SMTPUTF8").unwrap();
+        let expected = MailFrom {
+            size: Some(10),
+            params: vec![("BODY".to_string(), "8BITMIME".to_string()), ("SMTPUTF8".to_string(), String::new())],
+            ..MailFrom::new("user@example.com")
+        };
+        assert_eq!(request, RequestType::MAIL_FROM(expected));
+
+        let request = RequestType::parse("MAIL FROM:<user@example.com>SIZE=10");
+        assert!(request.is_err());
+    }
+
+    #[test]
+    fn test_parse_null_mail_from() {
+        let request = RequestType::parse("MAIL FROM:<>").unwrap();
+        assert_eq!(request, RequestType::MAIL_FROM(MailFrom::new("")));
+
+        // bounces carry parameters like any other message
+        let request = RequestType::parse("MAIL FROM: <> SIZE=100\r\n").unwrap();
+        assert_eq!(request, RequestType::MAIL_FROM(MailFrom { size: Some(100), ..MailFrom::new("") }));
     }
 
     #[test]
     fn test_broken_mail_from() {
         let request = RequestType::parse("MAIL FROM:<");
-        assert_eq!(request.is_err(), true);
+        assert!(request.is_err());
+    }
+
+    #[test]
+    fn test_parse_mail_from_with_space() {
+        let request = RequestType::parse("MAIL FROM: <user@example.com>").unwrap();
+        assert_eq!(request, RequestType::MAIL_FROM(MailFrom::new("user@example.com")));
     }
 
     #[test]
     fn test_parse_rcpt_to() {
         let request = RequestType::parse("RCPT TO:<user@example.com>").unwrap();
-        assert_eq!(request, RequestType::RCPT_TO("user@example.com".to_string()));
+        assert_eq!(request, RequestType::RCPT_TO(RcptTo::new("user@example.com")));
+    }
+
+    #[test]
+    fn test_parse_rcpt_to_notify() {
+        let notify = |value: &str| match RequestType::parse(&format!("RCPT TO:<user@example.com> NOTIFY={}", value)) {
+            Ok(RequestType::RCPT_TO(rcpt_to)) => rcpt_to.notify,
+            request => panic!("unexpected request: {:?}", request),
+        };
+        let flags = |success, failure, delay| Some(DsnNotify { success, failure, delay });
+        assert_eq!(notify("NEVER"), flags(false, false, false));
+        assert_eq!(notify("SUCCESS"), flags(true, false, false));
+        assert_eq!(notify("FAILURE"), flags(false, true, false));
+        assert_eq!(notify("DELAY"), flags(false, false, true));
+        assert_eq!(notify("SUCCESS,FAILURE"), flags(true, true, false));
+        assert_eq!(notify("SUCCESS,DELAY"), flags(true, false, true));
+        assert_eq!(notify("failure,delay"), flags(false, true, true));
+        assert_eq!(notify("SUCCESS,FAILURE,DELAY"), flags(true, true, true));
+
+        let request = RequestType::parse("RCPT TO:<user@example.com> NOTIFY=FAILURE ORCPT=rfc822;user@example.com").unwrap();
+        let expected = RcptTo {
+            notify: flags(false, true, false),
+            params: vec![("ORCPT".to_string(), "rfc822;user@example.com".to_string())],
+            ..RcptTo::new("user@example.com")
+        };
+        assert_eq!(request, RequestType::RCPT_TO(expected));
+    }
+
+    #[test]
+    fn test_parse_rcpt_to_notify_err() {
+        for value in ["", "ALWAYS", "NEVER,SUCCESS", "SUCCESS,", "SUCCESS;FAILURE"] {
+            let request = RequestType::parse(&format!("RCPT TO:<user@example.com> NOTIFY={}", value));
+            assert_eq!(request, Err(ParseError::InvalidArgument(RCPT_TO.to_string())), "NOTIFY={}", value);
+        }
+    }
+
+    #[test]
+    fn test_parse_mail_from_dsn() {
+        let request = RequestType::parse("MAIL FROM:<user@example.com> RET=HDRS ENVID=QQ314159").unwrap();
+        let expected = MailFrom {
+            ret: Some(DsnReturn::Headers),
+            envid: Some("QQ314159".to_string()),
+            ..MailFrom::new("user@example.com")
+        };
+        assert_eq!(request, RequestType::MAIL_FROM(expected));
+
+        let request = RequestType::parse("MAIL FROM:<user@example.com> ret=full").unwrap();
+        assert_eq!(request, RequestType::MAIL_FROM(MailFrom { ret: Some(DsnReturn::Full), ..MailFrom::new("user@example.com") }));
+        let request = RequestType::parse("MAIL FROM:<user@example.com> ENVID=abc+2Bdef").unwrap();
+        assert_eq!(request, RequestType::MAIL_FROM(MailFrom { envid: Some("abc+2Bdef".to_string()), ..MailFrom::new("user@example.com") }));
+
+        for params in ["RET=BODY", "RET", "ENVID="] {
+            let request = RequestType::parse(&format!("MAIL FROM:<user@example.com> {}", params));
+            assert_eq!(request, Err(ParseError::InvalidArgument(MAIL_FROM.to_string())), "{}", params);
+        }
     }
 
     #[test]
@@ -178,17 +642,173 @@ mod tests {
         assert_eq!(request, RequestType::NOOP);
     }
 
+    #[test]
+    fn test_parse_noop_argument() {
+        // RFC 5321 allows an argument, which is ignored
+        assert_eq!(RequestType::parse("NOOP hello"), Ok(RequestType::NOOP));
+        assert_eq!(RequestType::parse("NOOP\tkeepalive"), Ok(RequestType::NOOP));
+        assert_eq!(RequestType::parse("NOOPX"), Err(ParseError::Unrecognized));
+        assert_eq!(RequestType::parse("NOOPhello world"), Err(ParseError::Unrecognized));
+    }
+
     #[test]
     fn test_parse_rset() {
         let request = RequestType::parse("RSET").unwrap();
         assert_eq!(request, RequestType::RSET);
     }
 
+    #[test]
+    fn test_parse_auth_login() {
+        let request = RequestType::parse("AUTH LOGIN").unwrap();
+        assert_eq!(request, RequestType::AUTH { mechanism: AuthMechanism::Login, initial_response: None });
+
+        let request = RequestType::parse("AUTH LOGIN dXNlcjE=").unwrap();
+        assert_eq!(request, RequestType::AUTH {
+            mechanism: AuthMechanism::Login,
+            initial_response: Some("dXNlcjE=".to_string()),
+        });
+    }
+
+    #[test]
+    fn test_parse_bdat() {
+        let request = RequestType::parse("BDAT 1000").unwrap();
+        assert_eq!(request, RequestType::BDAT { size: 1000, last: false });
+        let request = RequestType::parse("BDAT 0 LAST").unwrap();
+        assert_eq!(request, RequestType::BDAT { size: 0, last: true });
+
+        for invalid in ["BDAT", "BDAT -1", "BDAT 10 FIRST", "BDAT 10 LAST 1"] {
+            assert_eq!(RequestType::parse(invalid), Err(ParseError::InvalidArgument(BDAT.to_string())));
+        }
+    }
+
+    #[test]
+    fn test_parse_vrfy() {
+        let request = RequestType::parse("VRFY <user@example.com>").unwrap();
+        assert_eq!(request, RequestType::VRFY("<user@example.com>".to_string()));
+
+        let request = RequestType::parse("VRFY");
+        assert_eq!(request, Err(ParseError::InvalidArgument(VRFY.to_string())));
+    }
+
+    #[test]
+    fn test_parse_expn() {
+        let request = RequestType::parse("EXPN staff");
+        assert_eq!(request, Ok(RequestType::EXPN("staff".to_string())));
+
+        let request = RequestType::parse("EXPN");
+        assert_eq!(request, Err(ParseError::InvalidArgument(EXPN.to_string())));
+    }
+
+    #[test]
+    fn test_parse_line_too_long() {
+        let address = format!("<{}@example.com>", "a".repeat(MAX_COMMAND_LINE));
+        let request = RequestType::parse(&format!("MAIL FROM:{}\r\n", address));
+        assert_eq!(request, Err(ParseError::LineTooLong(MAX_COMMAND_LINE)));
+        let request = RequestType::parse_one(&format!("RCPT TO:{}\r\nNOOP\r\n", address));
+        assert_eq!(request, Err(ParseError::LineTooLong(MAX_COMMAND_LINE)));
+
+        // exactly at the limit
+        let line = format!("NOOP {}\r\n", "x".repeat(MAX_COMMAND_LINE - 7));
+        assert_eq!(line.len(), MAX_COMMAND_LINE);
+        assert_eq!(RequestType::parse(&line), Ok(RequestType::NOOP));
+    }
+
+    #[test]
+    fn test_parse_lenient() {
+        let request = RequestType::parse_with_mode("mail from :<a@b>", ParseMode::Lenient);
+        assert_eq!(request, Ok(RequestType::MAIL_FROM(MailFrom::new("a@b"))));
+        let request = RequestType::parse_with_mode("Rcpt To : <a@b>", ParseMode::Lenient);
+        assert_eq!(request, Ok(RequestType::RCPT_TO(RcptTo::new("a@b"))));
+        let request = RequestType::parse_with_mode("ehlo Example.com", ParseMode::Lenient);
+        assert_eq!(request, Ok(RequestType::EHLO("Example.com".to_string())));
+        let request = RequestType::parse_with_mode("auth plain AHVzZXIAcGFzcw==", ParseMode::Lenient);
+        assert_eq!(request, Ok(RequestType::AUTH {
+            mechanism: AuthMechanism::Plain,
+            initial_response: Some("AHVzZXIAcGFzcw==".to_string()),
+        }));
+    }
+
+    #[test]
+    fn test_parse_strict_rejects_lenient_forms() {
+        let request = RequestType::parse_with_mode("MAIL FROM :<a@b>", ParseMode::Strict);
+        assert_eq!(request, Err(ParseError::InvalidArgument(MAIL_FROM.to_string())));
+    }
+
+    #[test]
+    fn test_parse_verbs_in_any_case() {
+        for mode in [ParseMode::Strict, ParseMode::Lenient] {
+            let request = RequestType::parse_with_mode("mail from:<a@b>", mode);
+            assert_eq!(request, Ok(RequestType::MAIL_FROM(MailFrom::new("a@b"))));
+            let request = RequestType::parse_with_mode("Rcpt To:<a@b>", mode);
+            assert_eq!(request, Ok(RequestType::RCPT_TO(RcptTo::new("a@b"))));
+            assert_eq!(RequestType::parse_with_mode("ehlo Example.com", mode), Ok(RequestType::EHLO("Example.com".to_string())));
+            assert_eq!(RequestType::parse_with_mode("StartTLS", mode), Ok(RequestType::STARTTLS));
+            assert_eq!(RequestType::parse_with_mode("quit", mode), Ok(RequestType::QUIT));
+            assert_eq!(RequestType::parse_with_mode("bdat 10 last", mode), Ok(RequestType::BDAT { size: 10, last: true }));
+            assert_eq!(RequestType::parse_with_mode("noops", mode), Err(ParseError::Unrecognized));
+        }
+    }
+
+    #[test]
+    fn test_parse_verb_boundary() {
+        assert_eq!(RequestType::parse("QUITTER"), Err(ParseError::Unrecognized));
+        assert_eq!(RequestType::parse("DATABASE"), Err(ParseError::Unrecognized));
+        assert_eq!(RequestType::parse("NOOPS"), Err(ParseError::Unrecognized));
+        assert_eq!(RequestType::parse("MAIL FROMX:<a@b>"), Err(ParseError::Unrecognized));
+        assert_eq!(RequestType::parse("EHLOexample.com"), Err(ParseError::Unrecognized));
+        assert_eq!(RequestType::parse("QUIT"), Ok(RequestType::QUIT));
+        assert_eq!(RequestType::parse("DATA"), Ok(RequestType::DATA));
+        assert_eq!(RequestType::parse("RCPT TO:<a@b>"), Ok(RequestType::RCPT_TO(RcptTo::new("a@b"))));
+    }
+
     #[test]
     fn test_parse_unexpected() {
         let request = RequestType::parse("RCV FROM:<user@example.com>");
-        assert_eq!(request.is_err(), true);
+        assert!(request.is_err());
+    }
+
+    #[test]
+    fn test_parse_not_implemented() {
+        let request = RequestType::parse("TURN");
+        assert_eq!(request, Err(ParseError::NotImplemented("TURN".to_string())));
+
+        let request = RequestType::parse("etrn example.com");
+        assert_eq!(request, Err(ParseError::NotImplemented("ETRN".to_string())));
+    }
+
+    #[test]
+    fn test_parse_unrecognized() {
+        let request = RequestType::parse("ZZZZ");
+        assert_eq!(request, Err(ParseError::Unrecognized));
+    }
+
+    #[test]
+    fn test_parse_one_pipelined() {
+        let buf = "MAIL FROM:<user@example.com>\r\nRCPT TO:<other@example.com>\r\nDATA\r\n";
+        let (request, used) = RequestType::parse_one(buf).unwrap();
+        assert_eq!(request, RequestType::MAIL_FROM(MailFrom::new("user@example.com")));
+        assert_eq!(used, "MAIL FROM:<user@example.com>\r\n".len());
+
+        let rest = &buf[used..];
+        let (request, used) = RequestType::parse_one(rest).unwrap();
+        assert_eq!(request, RequestType::RCPT_TO(RcptTo::new("other@example.com")));
+
+        let rest = &rest[used..];
+        assert_eq!(RequestType::parse_one(rest), Ok((RequestType::DATA, rest.len())));
+        assert_eq!(RequestType::parse_one(&rest[rest.len()..]), Err(ParseError::Incomplete));
     }
-}
 
- 
+    #[test]
+    fn test_parse_one_incomplete() {
+        assert_eq!(RequestType::parse_one("NOOP\r\nQUI"), Ok((RequestType::NOOP, 6)));
+        assert_eq!(RequestType::parse_one("QUI"), Err(ParseError::Incomplete));
+        // parse takes a line without CRLF as it is
+        assert_eq!(RequestType::parse("QUIT"), Ok(RequestType::QUIT));
+    }
+
+    #[test]
+    fn test_parse_invalid_argument() {
+        let request = RequestType::parse("RCPT TO:user@example.com");
+        assert_eq!(request, Err(ParseError::InvalidArgument(RCPT_TO.to_string())));
+    }
+}
@@ -4,7 +4,10 @@ use async_std::future::TimeoutError;
 
 #[derive(Debug)]
 pub enum TlsError {
+    #[cfg(all(feature = "native-tls", not(feature = "rustls")))]
     NativeTls(native_tls::Error),
+    #[cfg(feature = "rustls")]
+    Rustls(futures_rustls::rustls::Error),
     StreamAlreadyEncrypted,
 }
 
@@ -17,6 +20,8 @@ pub enum SmartStreamError {
     CharsetConversion(FromUtf8Error),
     ClosedConnection(String),
     RuntimeError(String),
+    // the line exceeded the limit in bytes, the rest of it was read and discarded
+    LineTooLong(usize),
 }
 
 impl std::error::Error for SmartStreamError {}
@@ -33,12 +38,20 @@ impl From<std::io::Error> for SmartStreamError {
     }
 }
 
+#[cfg(all(feature = "native-tls", not(feature = "rustls")))]
 impl From<native_tls::Error> for SmartStreamError {
     fn from(err: native_tls::Error) -> Self {
         Self::Tls(TlsError::NativeTls(err))
     }
 }
 
+#[cfg(feature = "rustls")]
+impl From<futures_rustls::rustls::Error> for SmartStreamError {
+    fn from(err: futures_rustls::rustls::Error) -> Self {
+        Self::Tls(TlsError::Rustls(err))
+    }
+}
+
 impl From<AddrParseError> for SmartStreamError {
     fn from(err: AddrParseError) -> Self {
         Self::AddrParse(err)
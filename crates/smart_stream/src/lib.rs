@@ -1,21 +1,35 @@
 use std::{
-    net::TcpStream,
+    io::ErrorKind,
+    mem::MaybeUninit,
+    net::{Shutdown, SocketAddr, TcpStream},
+    os::{fd::{AsFd, BorrowedFd}, unix::net::UnixStream},
     pin::Pin,
     task::{Context, Poll},
+    time::{Duration, Instant},
 };
 
-use async_native_tls::{TlsAcceptor, TlsConnector, TlsStream};
 use async_std::{
     future::timeout,
     io::{Read, ReadExt, Write, WriteExt},
     net::TcpStream as AsyncTcpStream,
+    os::unix::net::UnixStream as AsyncUnixStream,
 };
+use socket2::{SockRef, TcpKeepalive};
 
 pub mod error;
 use error::{SmartStreamError, TlsError};
 
+mod tls;
+pub use tls::{tls_acceptor, TlsAcceptor};
+use tls::TlsStream;
+
+mod tls_info;
+pub use tls_info::TlsInfo;
+
 use logger_proc_macro::*;
 
+// rustls keeps its whole session state inline, a connection only ever holds one of these
+#[allow(clippy::large_enum_variant)]
 pub enum StreamIo<T>
 where
     T: Read + Write + Unpin,
@@ -75,21 +89,169 @@ where
     }
 }
 
+// The underlying connection, TLS is layered on top of either kind
+pub enum Transport {
+    Tcp(AsyncTcpStream),
+    Unix(AsyncUnixStream),
+}
+
+impl Transport {
+    fn shutdown(&self, how: Shutdown) -> std::io::Result<()> {
+        match self {
+            Self::Tcp(stream) => stream.shutdown(how),
+            Self::Unix(stream) => stream.shutdown(how),
+        }
+    }
+
+    // The peer address outlives the connection, only a peek sees the end of file or the reset
+    // of a peer that went away. async-std keeps sockets non-blocking, so a peek finding
+    // nothing to read fails with WouldBlock instead of waiting.
+    fn is_connected(&self) -> bool {
+        let mut byte = [MaybeUninit::uninit()];
+        match SockRef::from(&self.socket()).peek(&mut byte) {
+            Ok(0) => false,
+            Ok(_) => true,
+            Err(err) => matches!(err.kind(), ErrorKind::WouldBlock | ErrorKind::Interrupted),
+        }
+    }
+
+    // Unix domain socket peers have no IP address
+    fn peer_addr(&self) -> Option<SocketAddr> {
+        match self {
+            Self::Tcp(stream) => stream.peer_addr().ok(),
+            Self::Unix(_) => None,
+        }
+    }
+
+    // Socket options like TCP_NODELAY only exist on TCP connections
+    fn tcp(&self) -> Option<&AsyncTcpStream> {
+        match self {
+            Self::Tcp(stream) => Some(stream),
+            Self::Unix(_) => None,
+        }
+    }
+
+    // For socket calls async-std does not wrap
+    fn socket(&self) -> BorrowedFd<'_> {
+        match self {
+            Self::Tcp(stream) => stream.as_fd(),
+            Self::Unix(stream) => stream.as_fd(),
+        }
+    }
+
+    fn tcp_socket(&self) -> Option<BorrowedFd<'_>> {
+        self.tcp().map(|_| self.socket())
+    }
+
+    // Name to verify the peer certificate against when connecting with TLS
+    fn peer_domain(&self) -> std::io::Result<String> {
+        match self {
+            Self::Tcp(stream) => Ok(stream.peer_addr()?.ip().to_string()),
+            Self::Unix(_) => Ok("localhost".to_string()),
+        }
+    }
+}
+
+impl Read for Transport {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<Result<usize, std::io::Error>> {
+        match *self {
+            Self::Tcp(ref mut stream) => Pin::new(stream).poll_read(cx, buf),
+            Self::Unix(ref mut stream) => Pin::new(stream).poll_read(cx, buf),
+        }
+    }
+}
+
+impl Write for Transport {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<Result<usize, std::io::Error>> {
+        match *self {
+            Self::Tcp(ref mut stream) => Pin::new(stream).poll_write(cx, buf),
+            Self::Unix(ref mut stream) => Pin::new(stream).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<(), std::io::Error>> {
+        match *self {
+            Self::Tcp(ref mut stream) => Pin::new(stream).poll_flush(cx),
+            Self::Unix(ref mut stream) => Pin::new(stream).poll_flush(cx),
+        }
+    }
+
+    fn poll_close(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<(), std::io::Error>> {
+        match *self {
+            Self::Tcp(ref mut stream) => Pin::new(stream).poll_close(cx),
+            Self::Unix(ref mut stream) => Pin::new(stream).poll_close(cx),
+        }
+    }
+}
+
+// How long the next socket read may wait: the per-read timeout, cut short by the deadline
+fn read_timeout(timeout_secs: u64, deadline: Option<Instant>) -> Duration {
+    let timeout = Duration::from_secs(timeout_secs);
+    match deadline {
+        Some(deadline) => timeout.min(deadline.saturating_duration_since(Instant::now())),
+        None => timeout,
+    }
+}
+
 pub struct AsyncStream {
-    m_stream: Option<StreamIo<AsyncTcpStream>>,
+    m_stream: Option<StreamIo<Transport>>,
     m_buffsize: u16,
+    // seconds a single socket read may wait for the peer
     m_timeout: u64,
+    // reads fail with Timeout after this, however steadily the peer trickles bytes in
+    m_deadline: Option<Instant>,
+    // bytes read past the end of the last line or chunk, returned by the next read
+    m_pending: Vec<u8>,
+    // a read found the end of the stream, or failed on a broken one
+    m_eof: bool,
 }
 
 impl AsyncStream {
     #[log(Debug)]
     pub fn new(stream: TcpStream, timeout: u64) -> Result<Self, SmartStreamError> {
-        let stream = AsyncTcpStream::from(stream);
-        Ok(Self {
-            m_stream: Some(StreamIo::Plain(stream)),
+        // replies are small and each waits for the next command, Nagle would only delay them
+        stream.set_nodelay(true)?;
+        Ok(Self::with_transport(Transport::Tcp(AsyncTcpStream::from(stream)), timeout))
+    }
+
+    #[log(Debug)]
+    pub fn from_unix(stream: UnixStream, timeout: u64) -> Result<Self, SmartStreamError> {
+        Ok(Self::with_transport(Transport::Unix(AsyncUnixStream::from(stream)), timeout))
+    }
+
+    fn with_transport(transport: Transport, timeout: u64) -> Self {
+        Self {
+            m_stream: Some(StreamIo::Plain(transport)),
             m_buffsize: 1024,
             m_timeout: timeout,
-        })
+            m_deadline: None,
+            m_pending: Vec::new(),
+            m_eof: false,
+        }
+    }
+
+    #[log(Debug)]
+    pub async fn connect(address: SocketAddr, timeout_secs: u64) -> Result<Self, SmartStreamError> {
+        let stream = timeout(
+            std::time::Duration::from_secs(timeout_secs),
+            AsyncTcpStream::connect(address),
+        ).await??;
+        stream.set_nodelay(true)?;
+        Ok(Self::with_transport(Transport::Tcp(stream), timeout_secs))
     }
 
     #[log(Trace)]
@@ -97,29 +259,143 @@ impl AsyncStream {
         if let Some(stream) = self.m_stream.as_mut() {
             match stream {
                 StreamIo::Plain(stream) => {
-                    let _ = stream.shutdown(std::net::Shutdown::Both);
+                    let _ = stream.shutdown(Shutdown::Both);
                 }
                 StreamIo::Encrypted(stream) => {
-                    let _ = stream.get_ref().shutdown(std::net::Shutdown::Both);
+                    let _ = tls::get_ref(stream).shutdown(Shutdown::Both);
                 }
             }
         }
         self.m_stream.take();
     }
 
+    // False once the peer closed or reset the connection, unless it sent more before that
+    // which is still pending. A TLS library may hold decrypted data the socket no longer shows,
+    // so an encrypted stream is only closed once a read found its end.
     #[log(Trace)]
     pub fn is_open(&self) -> bool {
-        match &self.m_stream {
-            Some(stream) => match stream {
-                StreamIo::Plain(stream) => stream.peer_addr().is_ok(),
-                StreamIo::Encrypted(stream) => stream.get_ref().peer_addr().is_ok(),
-            },
+        match self.m_stream.as_ref() {
+            Some(StreamIo::Plain(transport)) => !self.m_pending.is_empty() || transport.is_connected(),
+            Some(StreamIo::Encrypted(_)) => !self.m_pending.is_empty() || !self.m_eof,
             None => false,
         }
     }
 
+    #[log(Trace)]
+    pub fn peer_addr(&self) -> Option<SocketAddr> {
+        match self.m_stream.as_ref()? {
+            StreamIo::Plain(stream) => stream.peer_addr(),
+            StreamIo::Encrypted(stream) => tls::get_ref(stream).peer_addr(),
+        }
+    }
+
+    #[log(Trace)]
+    pub fn set_timeout(&mut self, timeout_secs: u64) {
+        self.m_timeout = timeout_secs;
+    }
+
+    // Bounds the reads that follow as a whole, like a command line or a message; None removes it
+    #[log(Trace)]
+    pub fn set_deadline(&mut self, deadline: Option<Instant>) {
+        self.m_deadline = deadline;
+    }
+
+    // How many bytes a single read asks the socket for
+    #[log(Trace)]
+    pub fn buffer_size(&self) -> u16 {
+        self.m_buffsize
+    }
+
+    // Larger buffers take big messages in fewer reads, the default is 1024. A zero size would
+    // never read anything, so it is refused.
+    #[log(Trace)]
+    pub fn set_buffer_size(&mut self, size: u16) -> Result<(), SmartStreamError> {
+        if size == 0 {
+            return Err(SmartStreamError::RuntimeError("Buffer size must not be zero".to_string()));
+        }
+        self.m_buffsize = size;
+        Ok(())
+    }
+
+    fn transport(&self) -> Option<&Transport> {
+        match self.m_stream.as_ref()? {
+            StreamIo::Plain(stream) => Some(stream),
+            StreamIo::Encrypted(stream) => Some(tls::get_ref(stream)),
+        }
+    }
+
+    // None on a Unix domain socket, where TCP options do not apply
+    #[log(Trace)]
+    pub fn nodelay(&self) -> Option<bool> {
+        self.transport()?.tcp()?.nodelay().ok()
+    }
+
+    #[log(Trace)]
+    pub fn keepalive(&self) -> Option<bool> {
+        SockRef::from(&self.transport()?.tcp_socket()?).keepalive().ok()
+    }
+
+    // Probes the peer after `idle` without traffic, so a dead connection fails its next read
+    // instead of waiting out the timeout; None turns the probes off. Unix sockets ignore it.
+    #[log(Trace)]
+    pub fn set_keepalive(&mut self, idle: Option<Duration>) -> Result<(), SmartStreamError> {
+        let Some(socket) = self.transport().and_then(Transport::tcp_socket) else {
+            return Ok(());
+        };
+        let socket = SockRef::from(&socket);
+        match idle {
+            Some(idle) => socket.set_tcp_keepalive(&TcpKeepalive::new().with_time(idle))?,
+            None => socket.set_keepalive(false)?,
+        }
+        Ok(())
+    }
+
+    #[log(Trace)]
+    pub fn is_encrypted(&self) -> bool {
+        matches!(self.m_stream, Some(StreamIo::Encrypted(_)))
+    }
+
+    // None on a plain connection
+    #[log(Trace)]
+    pub fn tls_info(&self) -> Option<TlsInfo> {
+        match self.m_stream.as_ref()? {
+            StreamIo::Plain(_) => None,
+            StreamIo::Encrypted(stream) => Some(tls::info(stream)),
+        }
+    }
+
+    // Whether the peer sent more than the last read returned, like a command pipelined after STARTTLS
+    #[log(Trace)]
+    pub fn has_pending(&self) -> bool {
+        !self.m_pending.is_empty()
+    }
+
+    // Whether the next read_until with this delimiter is answered without waiting for the peer
+    #[log(Trace)]
+    pub fn pending_contains(&self, delimiter: &[u8]) -> bool {
+        self.m_pending.windows(delimiter.len()).any(|window| window == delimiter)
+    }
+
+    // Drops the bytes kept for the next read and returns how many there were
+    #[log(Trace)]
+    pub fn discard_pending(&mut self) -> usize {
+        std::mem::take(&mut self.m_pending).len()
+    }
+
+    // The server's certificate is not checked, which is enough for opportunistic encryption
     #[log(Trace)]
     pub async fn connect_tls(&mut self) -> Result<(), SmartStreamError> {
+        self.start_client_tls(None).await
+    }
+
+    // The handshake fails unless the server's certificate is trusted by the system and
+    // issued for `domain`
+    #[log(Trace)]
+    pub async fn connect_tls_verified(&mut self, domain: &str) -> Result<(), SmartStreamError> {
+        self.start_client_tls(Some(domain)).await
+    }
+
+    async fn start_client_tls(&mut self, verified_domain: Option<&str>) -> Result<(), SmartStreamError> {
         if !self.is_open() {
             return Err(SmartStreamError::ClosedConnection(
                 "Error on connect_tls occured".to_string(),
@@ -129,15 +405,19 @@ impl AsyncStream {
         let stream = self.m_stream.take().ok_or(SmartStreamError::RuntimeError(
             "Error taking stream from option".to_string(),
         ))?;
-
-        let connector = TlsConnector::new()
-            .danger_accept_invalid_certs(true)
-            .danger_accept_invalid_hostnames(true);
+        // whatever was sent before the handshake must not be taken as encrypted data
+        self.m_pending.clear();
+        self.m_eof = false;
 
         let stream = match stream {
             StreamIo::Plain(stream) => {
-                let domain = stream.peer_addr()?.ip().to_string();
-                let stream = connector.connect(domain, stream).await?;
+                let stream = match verified_domain {
+                    Some(domain) => tls::connect(stream, domain.to_string(), true).await?,
+                    None => {
+                        let domain = stream.peer_domain()?;
+                        tls::connect(stream, domain, false).await?
+                    }
+                };
                 StreamIo::Encrypted(stream)
             }
             StreamIo::Encrypted(_stream) => {
@@ -160,10 +440,13 @@ impl AsyncStream {
         let stream = self.m_stream.take().ok_or(SmartStreamError::RuntimeError(
             "Error taking stream from option".to_string(),
         ))?;
+        // whatever was sent before the handshake must not be taken as encrypted data
+        self.m_pending.clear();
+        self.m_eof = false;
 
         let stream = match stream {
             StreamIo::Plain(stream) => {
-                let stream = acceptor.accept(stream).await?;
+                let stream = tls::accept(acceptor, stream).await?;
                 StreamIo::Encrypted(stream)
             }
             StreamIo::Encrypted(_stream) => {
@@ -175,50 +458,89 @@ impl AsyncStream {
         Ok(())
     }
 
+    // The whole buffer is written, a group of pipelined replies must not be cut short
     #[log(Trace)]
     pub async fn write(&mut self, buf: &[u8]) -> Result<usize, SmartStreamError> {
-        if self.is_open() {
-            match self.m_stream.as_mut() {
-                Some(stream) => stream
-                    .write(buf.as_ref())
-                    .await
-                    .map_err(SmartStreamError::from),
-                None => Err(SmartStreamError::RuntimeError(
-                    "Error getting mutable reference on try to write".to_string(),
-                )),
-            }
-        } else {
-            Err(SmartStreamError::ClosedConnection(
+        // not checked with is_open, a peer that only shut down its sending side still reads replies
+        match self.m_stream.as_mut() {
+            Some(stream) => stream
+                .write_all(buf)
+                .await
+                .map(|_| buf.len())
+                .map_err(SmartStreamError::from),
+            None => Err(SmartStreamError::ClosedConnection(
                 "Error on write occured".to_string(),
-            ))
+            )),
         }
     }
 
     #[log(Trace)]
     pub async fn read_until(&mut self, expected_delimiter: &str) -> Result<String, SmartStreamError> {
+        let response = self.read_bytes_until(expected_delimiter.as_bytes()).await?;
+        Ok(String::from_utf8(response)?)
+    }
+
+    // One CRLF terminated line without its CRLF; lines that arrived with it are kept for the next calls
+    #[log(Trace)]
+    pub async fn read_line(&mut self) -> Result<String, SmartStreamError> {
+        let mut line = self.read_until("\r\n").await?;
+        line.truncate(line.len() - 2);
+        Ok(line)
+    }
+
+    // Like read_until, but leaves decoding the bytes to the caller
+    #[log(Trace)]
+    pub async fn read_bytes_until(&mut self, expected_delimiter: &[u8]) -> Result<Vec<u8>, SmartStreamError> {
+        self.read_bytes_until_limited(expected_delimiter, usize::MAX).await
+    }
+
+    // Fails with LineTooLong when the line, delimiter included, is longer than `max_len` bytes.
+    // The rest of the line is still read, keeping only enough bytes to find the delimiter,
+    // so the next read starts on the following line. Bytes after the delimiter are kept for
+    // the next read.
+    #[log(Trace)]
+    pub async fn read_bytes_until_limited(&mut self, expected_delimiter: &[u8], max_len: usize)
+        -> Result<Vec<u8>, SmartStreamError> {
         if self.is_open() {
             if let Some(stream) = self.m_stream.as_mut() {
-                let mut response = Vec::new();
+                let mut response = std::mem::take(&mut self.m_pending);
+                let mut too_long = false;
+                // the delimiter is not in response[..searched]
+                let mut searched: usize = 0;
 
                 let mut chunk = vec![0; self.m_buffsize as usize];
 
                 loop {
-                    let n = timeout(std::time::Duration::from_secs(self.m_timeout), stream.read(&mut chunk)).await??;
-                    
+                    let start = searched.saturating_sub(expected_delimiter.len().saturating_sub(1));
+                    let found = response[start..].windows(expected_delimiter.len())
+                        .position(|window| window == expected_delimiter);
+                    if let Some(position) = found {
+                        self.m_pending = response.split_off(start + position + expected_delimiter.len());
+                        break;
+                    }
+                    if response.len() > max_len {
+                        too_long = true;
+                        let keep = expected_delimiter.len().saturating_sub(1).min(response.len());
+                        response.drain(..response.len() - keep);
+                    }
+                    searched = response.len();
+
+                    let n = timeout(read_timeout(self.m_timeout, self.m_deadline), stream.read(&mut chunk)).await?
+                        .inspect_err(|_| self.m_eof = true)?;
+
                     if n == 0 {
+                        self.m_eof = true;
                         Err(SmartStreamError::ClosedConnection(
                             "Connection closed by peer".to_string()))?;
                     }
 
                     response.extend_from_slice(&chunk[..n]);
-
-                    if String::from_utf8(response.clone())?
-                        .ends_with(expected_delimiter) {
-                        break;
-                    }
                 }
 
-                Ok(String::from_utf8(response).unwrap())
+                if too_long || response.len() > max_len {
+                    return Err(SmartStreamError::LineTooLong(max_len));
+                }
+                Ok(response)
             } else {
                 Err(SmartStreamError::RuntimeError(
                     "Error getting mutable reference on try to read".to_string(),
@@ -230,6 +552,35 @@ impl AsyncStream {
             ))
         }
     }
+
+    // Reads exactly `len` bytes, as for a BDAT chunk
+    #[log(Trace)]
+    pub async fn read_exact(&mut self, len: usize) -> Result<Vec<u8>, SmartStreamError> {
+        if !self.is_open() {
+            return Err(SmartStreamError::ClosedConnection(
+                "Error on read_exact occured".to_string(),
+            ));
+        }
+        let stream = self.m_stream.as_mut().ok_or(SmartStreamError::RuntimeError(
+            "Error getting mutable reference on try to read".to_string(),
+        ))?;
+
+        let mut response = std::mem::take(&mut self.m_pending);
+        let mut chunk = vec![0; self.m_buffsize as usize];
+        while response.len() < len {
+            let wanted = (len - response.len()).min(chunk.len());
+            let n = timeout(read_timeout(self.m_timeout, self.m_deadline), stream.read(&mut chunk[..wanted])).await?
+                .inspect_err(|_| self.m_eof = true)?;
+            if n == 0 {
+                self.m_eof = true;
+                Err(SmartStreamError::ClosedConnection(
+                    "Connection closed by peer".to_string()))?;
+            }
+            response.extend_from_slice(&chunk[..n]);
+        }
+        self.m_pending = response.split_off(len);
+        Ok(response)
+    }
 }
 
 impl Drop for AsyncStream {
@@ -238,10 +589,10 @@ impl Drop for AsyncStream {
         if let Some(stream) = self.m_stream.as_mut() {
             match stream {
                 StreamIo::Plain(stream) => {
-                    let _ = stream.shutdown(std::net::Shutdown::Both);
+                    let _ = stream.shutdown(Shutdown::Both);
                 }
                 StreamIo::Encrypted(stream) => {
-                    let _ = stream.get_ref().shutdown(std::net::Shutdown::Both);
+                    let _ = tls::get_ref(stream).shutdown(Shutdown::Both);
                 }
             }
         }
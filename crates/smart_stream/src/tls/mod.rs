@@ -0,0 +1,20 @@
+// The TLS library behind StreamIo::Encrypted: native-tls, or rustls with the "rustls" feature,
+// which builds without OpenSSL. Both load the certificate and private key from PEM and, as
+// the caller asks, connect to servers with or without verifying their certificate.
+
+#[cfg(not(any(feature = "native-tls", feature = "rustls")))]
+compile_error!("smart_stream needs a TLS backend, enable the \"native-tls\" or the \"rustls\" feature");
+
+#[cfg(all(feature = "native-tls", not(feature = "rustls")))]
+mod native;
+#[cfg(all(feature = "native-tls", not(feature = "rustls")))]
+pub use native::{tls_acceptor, TlsAcceptor};
+#[cfg(all(feature = "native-tls", not(feature = "rustls")))]
+pub(crate) use native::{accept, connect, get_ref, info, TlsStream};
+
+#[cfg(feature = "rustls")]
+mod rustls;
+#[cfg(feature = "rustls")]
+pub use self::rustls::{tls_acceptor, TlsAcceptor};
+#[cfg(feature = "rustls")]
+pub(crate) use self::rustls::{accept, connect, get_ref, info, TlsStream};
@@ -0,0 +1,46 @@
+use async_native_tls::TlsConnector;
+use async_std::io::{Read, Write};
+use native_tls::Identity;
+
+pub use async_native_tls::{TlsAcceptor, TlsStream};
+
+use crate::{error::SmartStreamError, TlsInfo};
+
+pub fn tls_acceptor(certificate: &[u8], private_key: &[u8]) -> Result<TlsAcceptor, SmartStreamError> {
+    let identity = Identity::from_pkcs8(certificate, private_key)?;
+    Ok(TlsAcceptor::from(native_tls::TlsAcceptor::new(identity)?))
+}
+
+pub(crate) async fn accept<T>(acceptor: &TlsAcceptor, stream: T) -> Result<TlsStream<T>, SmartStreamError>
+where
+    T: Read + Write + Unpin,
+{
+    Ok(acceptor.accept(stream).await?)
+}
+
+pub(crate) async fn connect<T>(stream: T, domain: String, verify: bool) -> Result<TlsStream<T>, SmartStreamError>
+where
+    T: Read + Write + Unpin,
+{
+    let connector = TlsConnector::new()
+        .danger_accept_invalid_certs(!verify)
+        .danger_accept_invalid_hostnames(!verify);
+    Ok(connector.connect(domain, stream).await?)
+}
+
+pub(crate) fn get_ref<T: Read + Write + Unpin>(stream: &TlsStream<T>) -> &T {
+    stream.get_ref()
+}
+
+// native-tls does not report the negotiated protocol version or cipher suite
+pub(crate) fn info<T: Read + Write + Unpin>(stream: &TlsStream<T>) -> TlsInfo {
+    let peer_subject = stream.peer_certificate().ok().flatten()
+        .and_then(|certificate| certificate.to_der().ok())
+        .and_then(|der| TlsInfo::subject(&der));
+    TlsInfo {
+        version: None,
+        cipher: None,
+        server_certificate_hash: stream.tls_server_end_point().ok().flatten(),
+        peer_subject,
+    }
+}
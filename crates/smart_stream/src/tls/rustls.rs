@@ -0,0 +1,148 @@
+use std::{io::ErrorKind, sync::Arc};
+
+use async_std::io::{Read, Write};
+use futures_rustls::{
+    pki_types::{pem::PemObject, CertificateDer, PrivateKeyDer, ServerName, UnixTime},
+    rustls::{
+        client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier},
+        crypto::{ring, verify_tls12_signature, verify_tls13_signature, CryptoProvider},
+        ClientConfig, DigitallySignedStruct, ProtocolVersion, RootCertStore, ServerConfig, SignatureScheme,
+    },
+    TlsConnector,
+};
+
+pub use futures_rustls::TlsStream;
+
+use crate::{error::SmartStreamError, TlsInfo};
+
+pub fn tls_acceptor(certificate: &[u8], private_key: &[u8]) -> Result<TlsAcceptor, SmartStreamError> {
+    let chain = CertificateDer::pem_slice_iter(certificate)
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(invalid_pem)?;
+    let key = PrivateKeyDer::from_pem_slice(private_key).map_err(invalid_pem)?;
+    let config = ServerConfig::builder_with_provider(provider())
+        .with_safe_default_protocol_versions()?
+        .with_no_client_auth()
+        .with_single_cert(chain, key)?;
+    Ok(TlsAcceptor(futures_rustls::TlsAcceptor::from(Arc::new(config))))
+}
+
+// futures-rustls' acceptor is not Debug, which the log attributes of the functions taking it need
+#[derive(Clone)]
+pub struct TlsAcceptor(futures_rustls::TlsAcceptor);
+
+impl std::fmt::Debug for TlsAcceptor {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("TlsAcceptor")
+    }
+}
+
+pub(crate) async fn accept<T>(acceptor: &TlsAcceptor, stream: T) -> Result<TlsStream<T>, SmartStreamError>
+where
+    T: Read + Write + Unpin,
+{
+    Ok(acceptor.0.accept(stream).await?.into())
+}
+
+pub(crate) async fn connect<T>(stream: T, domain: String, verify: bool) -> Result<TlsStream<T>, SmartStreamError>
+where
+    T: Read + Write + Unpin,
+{
+    let provider = provider();
+    let builder = ClientConfig::builder_with_provider(provider.clone())
+        .with_safe_default_protocol_versions()?;
+    let config = match verify {
+        true => builder.with_root_certificates(system_roots()?),
+        false => builder.dangerous().with_custom_certificate_verifier(Arc::new(AcceptAnyCertificate(provider))),
+    }.with_no_client_auth();
+    let domain = ServerName::try_from(domain)
+        .map_err(|err| SmartStreamError::Io(std::io::Error::new(ErrorKind::InvalidInput, err)))?;
+    Ok(TlsConnector::from(Arc::new(config)).connect(domain, stream).await?.into())
+}
+
+pub(crate) fn get_ref<T>(stream: &TlsStream<T>) -> &T {
+    stream.get_ref().0
+}
+
+// rustls has no tls-server-end-point channel binding, so the certificate hash is None
+pub(crate) fn info<T>(stream: &TlsStream<T>) -> TlsInfo {
+    let (_, connection) = stream.get_ref();
+    TlsInfo {
+        version: connection.protocol_version().map(version_name),
+        cipher: connection.negotiated_cipher_suite().map(|suite| format!("{:?}", suite.suite())),
+        server_certificate_hash: None,
+        peer_subject: connection.peer_certificates()
+            .and_then(|chain| chain.first())
+            .and_then(|certificate| TlsInfo::subject(certificate)),
+    }
+}
+
+// Named like OpenSSL does, which native-tls uses on most platforms
+fn version_name(version: ProtocolVersion) -> String {
+    match version {
+        ProtocolVersion::TLSv1_2 => "TLSv1.2".to_string(),
+        ProtocolVersion::TLSv1_3 => "TLSv1.3".to_string(),
+        version => format!("{:?}", version),
+    }
+}
+
+// The certificates trusted by the system, as native-tls uses them
+fn system_roots() -> Result<RootCertStore, SmartStreamError> {
+    let certificates = rustls_native_certs::load_native_certs();
+    if certificates.certs.is_empty() {
+        let reason = certificates.errors.first().map_or("none found".to_string(), ToString::to_string);
+        return Err(SmartStreamError::Io(std::io::Error::new(ErrorKind::NotFound,
+            format!("Could not load the system root certificates: {}", reason))));
+    }
+    let mut roots = RootCertStore::empty();
+    roots.add_parsable_certificates(certificates.certs);
+    Ok(roots)
+}
+
+fn provider() -> Arc<CryptoProvider> {
+    Arc::new(ring::default_provider())
+}
+
+fn invalid_pem(err: futures_rustls::pki_types::pem::Error) -> SmartStreamError {
+    SmartStreamError::Io(std::io::Error::new(ErrorKind::InvalidData, err))
+}
+
+// The counterpart of native-tls' danger_accept_invalid_certs: any certificate is taken, but the
+// handshake signatures are still checked against it
+#[derive(Debug)]
+struct AcceptAnyCertificate(Arc<CryptoProvider>);
+
+impl ServerCertVerifier for AcceptAnyCertificate {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, futures_rustls::rustls::Error> {
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        certificate: &CertificateDer<'_>,
+        signature: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, futures_rustls::rustls::Error> {
+        verify_tls12_signature(message, certificate, signature, &self.0.signature_verification_algorithms)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        certificate: &CertificateDer<'_>,
+        signature: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, futures_rustls::rustls::Error> {
+        verify_tls13_signature(message, certificate, signature, &self.0.signature_verification_algorithms)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.0.signature_verification_algorithms.supported_schemes()
+    }
+}
@@ -0,0 +1,36 @@
+use std::fmt::Display;
+
+use x509_parser::prelude::{FromDer, X509Certificate};
+
+// What can be told about an established TLS connection. Each backend reports what its library
+// exposes: native-tls leaves out the protocol version and cipher suite, rustls the certificate hash.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TlsInfo {
+    // like "TLSv1.3"
+    pub version: Option<String>,
+    // like "TLS13_AES_256_GCM_SHA384"
+    pub cipher: Option<String>,
+    // hash of the server certificate, the tls-server-end-point channel binding of RFC 5929
+    pub server_certificate_hash: Option<Vec<u8>>,
+    // like "CN=mail.example.com", None when the peer sent no certificate
+    pub peer_subject: Option<String>,
+}
+
+impl TlsInfo {
+    // Parsed here rather than by the backend, both only hand out the DER encoding
+    pub(crate) fn subject(der: &[u8]) -> Option<String> {
+        let (_, certificate) = X509Certificate::from_der(der).ok()?;
+        Some(certificate.subject().to_string())
+    }
+}
+
+impl Display for TlsInfo {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let hash: String = self.server_certificate_hash.iter().flatten().map(|byte| format!("{:02x}", byte)).collect();
+        write!(f, "{} {}, server certificate {}, peer {}",
+            self.version.as_deref().unwrap_or("unknown version"),
+            self.cipher.as_deref().unwrap_or("unknown cipher"),
+            if hash.is_empty() { "unknown" } else { &hash },
+            self.peer_subject.as_deref().unwrap_or("without certificate"))
+    }
+}
@@ -0,0 +1,235 @@
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::Write,
+        net::{TcpListener, TcpStream},
+        os::unix::net::UnixStream,
+        thread,
+        time::{Duration, Instant},
+    };
+
+    use futures::{executor::block_on, join};
+    use smart_stream::{error::SmartStreamError, AsyncStream, TlsAcceptor};
+
+    fn tls_acceptor() -> TlsAcceptor {
+        smart_stream::tls_acceptor(
+            include_bytes!("../../client_session/tests/certs/server.crt"),
+            include_bytes!("../../client_session/tests/certs/server.key"),
+        ).unwrap()
+    }
+
+    #[test]
+    fn read_line_test() {
+        let (server, client) = UnixStream::pair().unwrap();
+        let mut server = AsyncStream::from_unix(server, 5).unwrap();
+        let mut client = AsyncStream::from_unix(client, 5).unwrap();
+
+        block_on(async {
+            client.write(b"NOOP\r\nHELP\r\nQU").await.unwrap();
+            assert_eq!(server.read_line().await.unwrap(), "NOOP");
+            assert!(server.pending_contains(b"\r\n"));
+            assert_eq!(server.read_line().await.unwrap(), "HELP");
+
+            // the start of a line is kept until its end arrives
+            client.write(b"IT\r\n").await.unwrap();
+            assert_eq!(server.read_line().await.unwrap(), "QUIT");
+            assert!(!server.has_pending());
+        });
+    }
+
+    #[test]
+    fn buffer_size_test() {
+        let (server, client) = UnixStream::pair().unwrap();
+        let mut server = AsyncStream::from_unix(server, 5).unwrap();
+        let mut client = AsyncStream::from_unix(client, 5).unwrap();
+        assert_eq!(server.buffer_size(), 1024);
+        assert!(server.set_buffer_size(0).is_err());
+        assert_eq!(server.buffer_size(), 1024);
+        server.set_buffer_size(8192).unwrap();
+        assert_eq!(server.buffer_size(), 8192);
+
+        let payload = "x".repeat(6000) + "\r\n";
+        block_on(async {
+            client.write(payload.as_bytes()).await.unwrap();
+            assert_eq!(server.read_until("\r\n").await.unwrap(), payload);
+        });
+    }
+
+    #[test]
+    fn write_sends_whole_buffer_test() {
+        let (server, client) = UnixStream::pair().unwrap();
+        let mut server = AsyncStream::from_unix(server, 5).unwrap();
+        let mut client = AsyncStream::from_unix(client, 5).unwrap();
+
+        // far more than the socket buffer takes at once
+        let replies = "250 OK\r\n".repeat(200_000);
+        block_on(async {
+            let (written, read) = join!(server.write(replies.as_bytes()), client.read_exact(replies.len()));
+            assert_eq!(written.unwrap(), replies.len());
+            assert_eq!(read.unwrap(), replies.as_bytes());
+        });
+    }
+
+    #[test]
+    fn deadline_test() {
+        let (server, mut client) = UnixStream::pair().unwrap();
+        let mut server = AsyncStream::from_unix(server, 5).unwrap();
+        // every byte comes well within the read timeout, the line never ends
+        let trickle = thread::spawn(move || {
+            for _ in 0..10 {
+                if client.write_all(b"x").is_err() {
+                    break;
+                }
+                thread::sleep(Duration::from_millis(200));
+            }
+        });
+
+        let started = Instant::now();
+        server.set_deadline(Some(started + Duration::from_secs(1)));
+        let result = block_on(server.read_until("\r\n"));
+        assert!(matches!(result, Err(SmartStreamError::Timeout(_))), "{:?}", result);
+        assert!(started.elapsed() < Duration::from_secs(2), "took {:?}", started.elapsed());
+        drop(server);
+        trickle.join().unwrap();
+    }
+
+    #[test]
+    fn socket_options_test() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let _client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (stream, _) = listener.accept().unwrap();
+        let mut server = AsyncStream::new(stream, 5).unwrap();
+        assert_eq!(server.nodelay(), Some(true));
+        assert_eq!(server.keepalive(), Some(false));
+        server.set_keepalive(Some(Duration::from_secs(30))).unwrap();
+        assert_eq!(server.keepalive(), Some(true));
+        server.set_keepalive(None).unwrap();
+        assert_eq!(server.keepalive(), Some(false));
+
+        let (unix, _peer) = UnixStream::pair().unwrap();
+        let mut unix = AsyncStream::from_unix(unix, 5).unwrap();
+        assert_eq!(unix.nodelay(), None);
+        assert!(unix.set_keepalive(Some(Duration::from_secs(30))).is_ok());
+    }
+
+    #[test]
+    fn is_open_test() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (stream, _) = listener.accept().unwrap();
+        let server = AsyncStream::new(stream, 5).unwrap();
+        assert!(server.is_open());
+        drop(client);
+        // the FIN takes a moment to arrive even over loopback
+        let deadline = Instant::now() + Duration::from_secs(2);
+        while server.is_open() && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert!(!server.is_open());
+
+        // lines the peer sent before closing are still there to read
+        let (server, mut client) = UnixStream::pair().unwrap();
+        let mut server = AsyncStream::from_unix(server, 5).unwrap();
+        client.write_all(b"NOOP\r\nQUIT\r\n").unwrap();
+        drop(client);
+        block_on(async {
+            assert_eq!(server.read_line().await.unwrap(), "NOOP");
+            assert!(server.is_open());
+            assert_eq!(server.read_line().await.unwrap(), "QUIT");
+        });
+        assert!(!server.is_open());
+
+        // an encrypted stream is open until a read finds the end, whatever the socket shows
+        let (server, client) = UnixStream::pair().unwrap();
+        let mut server = AsyncStream::from_unix(server, 5).unwrap();
+        let mut client = AsyncStream::from_unix(client, 5).unwrap();
+        let acceptor = tls_acceptor();
+        block_on(async {
+            let (accepted, connected) = join!(server.accept_tls(&acceptor), client.connect_tls());
+            accepted.unwrap();
+            connected.unwrap();
+            client.write(b"NOOP\r\nQUIT\r\n").await.unwrap();
+            drop(client);
+            assert_eq!(server.read_line().await.unwrap(), "NOOP");
+            assert!(server.is_open());
+            assert_eq!(server.read_line().await.unwrap(), "QUIT");
+            assert!(server.read_line().await.is_err());
+        });
+        assert!(!server.is_open());
+    }
+
+    #[test]
+    fn tls_info_test() {
+        let (server, client) = UnixStream::pair().unwrap();
+        let mut server = AsyncStream::from_unix(server, 5).unwrap();
+        let mut client = AsyncStream::from_unix(client, 5).unwrap();
+        assert_eq!(server.tls_info(), None);
+
+        let acceptor = tls_acceptor();
+        let (accepted, connected) = block_on(async { join!(server.accept_tls(&acceptor), client.connect_tls()) });
+        accepted.unwrap();
+        connected.unwrap();
+
+        let server_info = server.tls_info().unwrap();
+        // the client presented no certificate
+        assert_eq!(server_info.peer_subject, None);
+
+        let client_info = client.tls_info().unwrap();
+        assert_eq!(client_info.peer_subject.as_deref(), Some("CN=localhost"));
+        assert_eq!(client_info.server_certificate_hash, server_info.server_certificate_hash);
+
+        if cfg!(feature = "rustls") {
+            assert!(server_info.version.as_deref().is_some_and(|version| version.starts_with("TLSv1.")), "{:?}", server_info);
+            assert!(server_info.cipher.is_some(), "{:?}", server_info);
+            assert_eq!(client_info.version, server_info.version);
+            assert_eq!(client_info.cipher, server_info.cipher);
+        } else {
+            assert!(server_info.server_certificate_hash.as_ref().is_some_and(|hash| !hash.is_empty()), "{:?}", server_info);
+        }
+    }
+
+    // The test certificate is self-signed, so no system trusts it
+    #[test]
+    fn verified_tls_refuses_untrusted_certificate_test() {
+        let (server, client) = UnixStream::pair().unwrap();
+        let mut server = AsyncStream::from_unix(server, 5).unwrap();
+        let mut client = AsyncStream::from_unix(client, 5).unwrap();
+        let acceptor = tls_acceptor();
+        let (accepted, connected) = block_on(async {
+            join!(server.accept_tls(&acceptor), client.connect_tls_verified("localhost"))
+        });
+        assert!(connected.is_err());
+        assert!(accepted.is_err());
+        assert!(!client.is_encrypted());
+    }
+
+    // Run with the default features and with --features rustls, both backends must pass it
+    #[test]
+    fn starttls_upgrade_test() {
+        let (server, client) = UnixStream::pair().unwrap();
+        let mut server = AsyncStream::from_unix(server, 5).unwrap();
+        let mut client = AsyncStream::from_unix(client, 5).unwrap();
+        let acceptor = tls_acceptor();
+
+        block_on(async {
+            client.write(b"STARTTLS\r\n").await.unwrap();
+            assert_eq!(server.read_line().await.unwrap(), "STARTTLS");
+            server.write(b"220 Ready to start TLS\r\n").await.unwrap();
+            assert_eq!(client.read_line().await.unwrap(), "220 Ready to start TLS");
+            assert!(!server.is_encrypted());
+
+            let (accepted, connected) = join!(server.accept_tls(&acceptor), client.connect_tls());
+            accepted.unwrap();
+            connected.unwrap();
+            assert!(server.is_encrypted() && client.is_encrypted());
+
+            client.write(b"EHLO client.example.com\r\n").await.unwrap();
+            assert_eq!(server.read_line().await.unwrap(), "EHLO client.example.com");
+            server.write(b"250 OK\r\n").await.unwrap();
+            assert_eq!(client.read_line().await.unwrap(), "250 OK");
+
+            // a second upgrade is refused
+            assert!(matches!(server.accept_tls(&acceptor).await, Err(SmartStreamError::Tls(_))));
+        });
+    }
+}
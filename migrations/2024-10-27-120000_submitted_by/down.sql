@@ -0,0 +1 @@
+ALTER TABLE "emailMessages" DROP COLUMN submitted_by_user_id;
@@ -0,0 +1,2 @@
+ALTER TABLE "emailMessages"
+    ADD COLUMN submitted_by_user_id INTEGER REFERENCES users(user_id);
@@ -0,0 +1 @@
+ALTER TABLE "emailMessages" DROP COLUMN message_id;
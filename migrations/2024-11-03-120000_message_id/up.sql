@@ -0,0 +1,2 @@
+ALTER TABLE "emailMessages"
+    ADD COLUMN message_id VARCHAR(64);
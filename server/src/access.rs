@@ -0,0 +1,79 @@
+use std::net::IpAddr;
+
+use ipnet::IpNet;
+
+// Networks allowed to connect. A peer matching `deny` is always rejected,
+// a non-empty `allow` rejects every peer outside of it.
+#[derive(Debug, Clone, Default)]
+pub struct AccessList {
+    pub allow: Vec<IpNet>,
+    pub deny: Vec<IpNet>,
+    // greet rejected clients with 554 before closing, instead of closing silently
+    pub reject_banner: bool,
+}
+
+impl AccessList {
+    // Peers without an IP address (Unix domain sockets) are local and always allowed
+    pub fn is_allowed(&self, peer: Option<IpAddr>) -> bool {
+        let Some(ip) = peer else {
+            return true;
+        };
+        // an IPv4 client of a dual stack socket shows up as ::ffff:a.b.c.d
+        let ip = match ip {
+            IpAddr::V6(v6) => v6.to_ipv4_mapped().map_or(ip, IpAddr::V4),
+            ip => ip,
+        };
+        if self.deny.iter().any(|net| net.contains(&ip)) {
+            return false;
+        }
+        self.allow.is_empty() || self.allow.iter().any(|net| net.contains(&ip))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::listener::Listener;
+
+    fn nets(cidrs: &[&str]) -> Vec<IpNet> {
+        cidrs.iter().map(|cidr| cidr.parse().unwrap()).collect()
+    }
+
+    #[test]
+    fn allow_and_deny_test() {
+        let access = AccessList {
+            allow: nets(&["10.0.0.0/8", "fd00::/8"]),
+            deny: nets(&["10.1.0.0/16"]),
+            reject_banner: true,
+        };
+        assert!(access.is_allowed(Some("10.2.3.4".parse().unwrap())));
+        assert!(access.is_allowed(Some("fd12::1".parse().unwrap())));
+        assert!(access.is_allowed(Some("::ffff:10.2.3.4".parse().unwrap())));
+        assert!(!access.is_allowed(Some("10.1.2.3".parse().unwrap())));
+        assert!(!access.is_allowed(Some("192.0.2.1".parse().unwrap())));
+        assert!(!access.is_allowed(Some("2001:db8::1".parse().unwrap())));
+        assert!(access.is_allowed(None));
+    }
+
+    #[test]
+    fn empty_list_allows_everyone_test() {
+        let access = AccessList::default();
+        assert!(access.is_allowed(Some("192.0.2.1".parse().unwrap())));
+        assert!(access.is_allowed(Some("2001:db8::1".parse().unwrap())));
+    }
+
+    #[test]
+    fn accepted_peer_address_test() {
+        let listener = Listener::bind_tcp("127.0.0.1:0".parse().unwrap()).unwrap();
+        let Listener::Tcp(tcp) = &listener else { unreachable!() };
+        let _client = std::net::TcpStream::connect(tcp.local_addr().unwrap()).unwrap();
+        let stream = listener.accept(5, None).unwrap();
+        let peer = stream.peer_addr().map(|addr| addr.ip());
+        assert_eq!(peer, Some("127.0.0.1".parse().unwrap()));
+
+        let allowed = AccessList { allow: nets(&["127.0.0.0/8"]), ..Default::default() };
+        let denied = AccessList { deny: nets(&["127.0.0.1/32"]), ..Default::default() };
+        assert!(allowed.is_allowed(peer));
+        assert!(!denied.is_allowed(peer));
+    }
+}
@@ -1,126 +1,792 @@
-use json_parser::JsonParser;
+use json_parser::{JsonError, JsonParser, JsonValue};
 use std::{
     io::Read,
     fs::File,
-    path::Path,
+    net::{IpAddr, SocketAddr},
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
 };
 
-use logger::{info, warn, ConsoleLogTarget, FileLogTarget, LogLevel, LogTarget};
+use logger::{info, warn, BackgroundLogTarget, ConsoleLogTarget, DateRotatingFileLogTarget, FileLogTarget, MultiLogTarget, LogLevel, LogTarget, OverflowPolicy, QueueLimit, TcpLogTarget};
+use crate::access::AccessList;
+use ipnet::IpNet;
+use client_session::{dead_letter::DeadLetterCapture, tls_limit::TlsHandshakeLimiter, ParseMode, SessionConfig};
+use relay::{SmartHost, SmartHostCredentials};
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Io(std::io::Error),
+    Parse(JsonError),
+    MissingField(String),
+    WrongType(String),
+    // the field and what is wrong with its value
+    InvalidValue(String, String),
+}
+
+impl std::fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ConfigError::Io(err) => write!(f, "Could not read config: {}", err),
+            ConfigError::Parse(err) => write!(f, "Could not parse config: {:?}", err),
+            ConfigError::MissingField(field) => write!(f, "Missing required config field '{}'", field),
+            ConfigError::WrongType(field) => write!(f, "Config field '{}' has a wrong type", field),
+            ConfigError::InvalidValue(field, reason) => write!(f, "Config field '{}' has an invalid value: {}", field, reason),
+        }
+    }
+}
+
+impl From<std::io::Error> for ConfigError {
+    fn from(err: std::io::Error) -> Self {
+        Self::Io(err)
+    }
+}
+
+impl From<JsonError> for ConfigError {
+    fn from(err: JsonError) -> Self {
+        Self::Parse(err)
+    }
+}
+
+// Every setting with a default value. Settings whose absence means something, like
+// server.hostname (use the OS hostname), server.unix-socket (listen on TCP),
+// server.metrics-snapshot (metrics start from zero), communication.dead-letter.directory
+// (refused messages are not kept) or relay.smart-host (look up the MX of every domain),
+// are left out.
+const DEFAULT_CONFIG: &str = r#"{
+    "server": {
+        "ip-address": "127.0.0.1",
+        "port": 2525
+    },
+    "logging": {
+        "log-target": "console",
+        "log-level": "info",
+        "cache-capacity": 1000,
+        "flush-interval": 5,
+        "queue-capacity": 0,
+        "overflow-policy": "block",
+        "background-targets": [],
+        "background-queue": 64,
+        "tcp-collector": {
+            "ip-address": "127.0.0.1",
+            "port": 5170
+        },
+        "tcp-buffer": 1000
+    },
+    "thread-pool": {
+        "pool-size": 10
+    },
+    "tls": {
+        "enabled": true,
+        "certificate": "server/certs/server.crt",
+        "private-key": "server/certs/server.key",
+        "require-tls": true
+    },
+    "communication": {
+        "max-connection-timeout": 60,
+        "keepalive-idle": 60,
+        "enforce-sender-ownership": true,
+        "require-auth": true,
+        "command-rate": 10,
+        "command-burst": 50,
+        "max-bad-commands": 10,
+        "max-line-length": 512,
+        "max-message-size": 10485760,
+        "max-header-line-length": 998,
+        "max-session-duration": 600,
+        "command-deadline": 300,
+        "data-deadline": 600,
+        "delivery-timeout": 30,
+        "lenient-commands": false,
+        "accepted-charsets": ["utf-8", "us-ascii", "iso-8859-1", "latin1"],
+        "local-domains": [],
+        "relay-remote-recipients": false,
+        "max-tls-handshakes": 16,
+        "dead-letter": {
+            "max-files": 100,
+            "max-size": 1048576
+        }
+    },
+    "status": {
+        "enabled": false,
+        "ip-address": "127.0.0.1",
+        "port": 2526
+    },
+    "access": {
+        "allow": [],
+        "deny": [],
+        "reject-banner": true
+    }
+}"#;
+
+// Certificate and key files in PEM format, loaded at startup
+pub struct TlsConfig {
+    pub certificate: String,
+    pub private_key: String,
+}
 
 pub struct Config {
-    pub ip: String,
-    pub port: u16,
+    pub address: SocketAddr,
+    // when set, clients connect through this Unix domain socket instead of ip:port
+    pub unix_socket: Option<String>,
+    // where cumulative metrics are kept across restarts
+    pub metrics_snapshot: Option<String>,
     pub log_level: LogLevel,
     pub log_target: Box<dyn logger::LogTarget + Send + Sync + 'static>,
     pub capacity: usize,
+    pub flush_interval: Duration,
+    // None keeps the queue of messages waiting for the logger thread unbounded
+    pub log_queue_limit: Option<QueueLimit>,
     pub pool_size: usize,
     pub timeout: u64,
+    // idle time before a silent client is probed with TCP keepalive, None when it is 0
+    pub keepalive_idle: Option<Duration>,
+    // None when TLS is disabled, STARTTLS is then not offered
+    pub tls: Option<TlsConfig>,
+    pub access: AccessList,
+    // where the read-only status socket listens, None when it is disabled
+    pub status: Option<SocketAddr>,
+    // with local domains set, mail to other domains is accepted and relayed to their MX
+    pub relay_remote_recipients: bool,
+    // when set, outbound messages are relayed through it instead of the MX of their domain
+    pub smart_host: Option<SmartHost>,
+    pub session: SessionConfig,
 }
 
-impl Default for Config {
-    fn default() -> Self {
-        let mut parser = JsonParser::default();
+impl Config {
+    pub fn from_file(path: &Path) -> Result<Self, ConfigError> {
         let mut raw_config = String::new();
-        File::open("config.json").unwrap().read_to_string(&mut raw_config).unwrap();
-        let config_obj = parser.parse(&raw_config).unwrap();
+        File::open(path)?.read_to_string(&mut raw_config)?;
+        Self::from_json(&raw_config)
+    }
+
+    // The user's config is laid over DEFAULT_CONFIG, so it only needs the keys it changes
+    pub fn from_json(raw_config: &str) -> Result<Self, ConfigError> {
+        let mut parser = JsonParser::default();
+        let mut config_obj = parser.parse(DEFAULT_CONFIG)?;
+        config_obj.merge(parser.parse(raw_config)?);
 
-        let ip = match config_obj["server"]["ip-address"].as_str() {
-            Some(ip) => {
-                ip
+        let address = Self::socket_address(&config_obj, "server")?;
+        info!("Address: {}", address);
+
+        let defaults = SessionConfig::default();
+        let hostname = match Self::optional(&config_obj, "server.hostname", JsonValue::as_str)? {
+            Some(hostname) if is_valid_hostname(&hostname) => {
+                hostname
+            },
+            Some(hostname) => {
+                return Err(ConfigError::InvalidValue(
+                    "server.hostname".to_string(),
+                    format!("'{}' is not a valid domain name", hostname),
+                ));
             },
             None => {
-                warn!("IP address not found, using default");
-                "127.0.0.1".to_string()
+                warn!("Hostname not found, using the OS hostname");
+                os_hostname().unwrap_or_else(|| {
+                    warn!("OS hostname is not a valid domain name, using default");
+                    defaults.hostname.clone()
+                })
             }
         };
-        info!("IP address: {}", ip);
+        info!("Hostname: {}", hostname);
 
-        let port = match config_obj["server"]["port"].as_number() {
-            Some(port) => {
-                port as u16
-            },
-            None => {
-                warn!("Port not found, using default");
-                2525
-            }
+        let unix_socket = Self::optional(&config_obj, "server.unix-socket", JsonValue::as_str)?;
+        if let Some(path) = &unix_socket {
+            info!("Unix socket: {}", path);
+        }
+
+        let metrics_snapshot = Self::optional(&config_obj, "server.metrics-snapshot", JsonValue::as_str)?;
+        if let Some(path) = &metrics_snapshot {
+            info!("Metrics snapshot: {}", path);
+        }
+
+        let log_level = Self::required(&config_obj, "logging.log-level", JsonValue::as_str)?
+            .parse::<LogLevel>()
+            .map_err(|err| ConfigError::InvalidValue("logging.log-level".to_string(), err.to_string()))?;
+        info!("Log level: {}", log_level);
+
+        let capacity = Self::required(&config_obj, "logging.cache-capacity", JsonValue::as_number)? as usize;
+        info!("Cache capacity: {}", capacity);
+
+        let flush_interval = Self::required(&config_obj, "logging.flush-interval", JsonValue::as_number)?;
+        let flush_interval = Duration::from_secs_f64(flush_interval.max(0.0));
+        info!("Log flush interval: {:?}", flush_interval);
+
+        let queue_capacity = Self::required(&config_obj, "logging.queue-capacity", JsonValue::as_number)? as usize;
+        let policy = Self::required(&config_obj, "logging.overflow-policy", JsonValue::as_str)?
+            .parse::<OverflowPolicy>()
+            .map_err(|err| ConfigError::InvalidValue("logging.overflow-policy".to_string(), err))?;
+        let log_queue_limit = if queue_capacity == 0 {
+            info!("Log queue: unbounded");
+            None
+        } else {
+            info!("Log queue: {} messages, {} on overflow", queue_capacity, policy);
+            Some(QueueLimit { capacity: queue_capacity, policy })
         };
-        info!("Port: {}", port);
-
-        let log_level = match config_obj["logging"]["log-level"].as_str() {
-            Some(level) => match level.as_str() {
-                "trace" => LogLevel::Trace,
-                "debug" => LogLevel::Debug,
-                "info" => LogLevel::Info,
-                "warn" => LogLevel::Warn,
-                "error" => LogLevel::Error,
-                _ => {
-                    warn!("Invalid log level, using default");
-                    LogLevel::Info
-                },
-            },
-            None => {
-                warn!("Log level not found, using default");
-                LogLevel::Info
-            },
+
+        let pool_size = Self::required(&config_obj, "thread-pool.pool-size", JsonValue::as_number)? as usize;
+        info!("Thread pool size: {}", pool_size);
+
+        // a single target name, or a list of them to write every message to
+        let log_target_names = Self::required(&config_obj, "logging.log-target", Self::string_or_array)?;
+        let background_targets = Self::required(&config_obj, "logging.background-targets", Self::string_array)?;
+        let background_queue = Self::required(&config_obj, "logging.background-queue", JsonValue::as_number)? as usize;
+        let mut log_targets = Vec::new();
+        for name in &log_target_names {
+            let target = Self::log_target(&config_obj, name)?;
+            let target: Box<dyn LogTarget + Send + Sync + 'static> = if background_targets.contains(name) {
+                info!("Log target {} runs in the background, queue: {} batches", name, background_queue);
+                Box::new(BackgroundLogTarget::new(target, background_queue.max(1))?)
+            } else {
+                target
+            };
+            log_targets.push(target);
+        }
+        let log_target: Box<dyn LogTarget + Send + Sync + 'static> = match log_targets.len() {
+            1 => log_targets.remove(0),
+            _ => Box::new(MultiLogTarget::new(log_targets)),
         };
-        info!("Log level: {:?}", log_level);
 
-        let capacity = match config_obj["logging"]["cache-capacity"].as_number() {
-            Some(capacity) => {
-                capacity as usize
-            },
-            None => {
-                warn!("Cache capacity not found, using default");
-                1000
-            }
+        let timeout = Self::required(&config_obj, "communication.max-connection-timeout", JsonValue::as_number)? as u64;
+        info!("Timeout: {}", timeout);
+
+        let keepalive_idle = Self::required(&config_obj, "communication.keepalive-idle", JsonValue::as_number)?;
+        let keepalive_idle = (keepalive_idle > 0.0).then(|| Duration::from_secs_f64(keepalive_idle));
+        info!("TCP keepalive idle time: {:?}", keepalive_idle);
+
+        let enforce_sender_ownership = Self::required(&config_obj, "communication.enforce-sender-ownership", JsonValue::as_bool)?;
+        info!("Enforce sender ownership: {}", enforce_sender_ownership);
+
+        let require_auth = Self::required(&config_obj, "communication.require-auth", JsonValue::as_bool)?;
+        info!("Require authentication: {}", require_auth);
+
+        let command_rate = Self::required(&config_obj, "communication.command-rate", JsonValue::as_number)?;
+        info!("Command rate: {}", command_rate);
+
+        let command_burst = Self::required(&config_obj, "communication.command-burst", JsonValue::as_number)? as u32;
+        info!("Command burst: {}", command_burst);
+
+        let max_bad_commands = Self::required(&config_obj, "communication.max-bad-commands", JsonValue::as_number)? as usize;
+        info!("Max bad commands: {}", max_bad_commands);
+
+        let max_line_length = Self::required(&config_obj, "communication.max-line-length", JsonValue::as_number)? as usize;
+        info!("Max line length: {}", max_line_length);
+
+        let max_message_size = Self::required(&config_obj, "communication.max-message-size", JsonValue::as_number)? as usize;
+        info!("Max message size: {}", max_message_size);
+
+        let max_header_line_length = Self::required(&config_obj, "communication.max-header-line-length", JsonValue::as_number)? as usize;
+        info!("Max header line length: {}", max_header_line_length);
+
+        let max_session_duration = Self::required(&config_obj, "communication.max-session-duration", JsonValue::as_number)?;
+        let max_session_duration = Duration::from_secs_f64(max_session_duration.max(0.0));
+        info!("Max session duration: {:?}", max_session_duration);
+
+        let command_deadline = Self::required(&config_obj, "communication.command-deadline", JsonValue::as_number)?;
+        let command_deadline = Duration::from_secs_f64(command_deadline.max(0.0));
+        info!("Command deadline: {:?}", command_deadline);
+
+        let data_deadline = Self::required(&config_obj, "communication.data-deadline", JsonValue::as_number)?;
+        let data_deadline = Duration::from_secs_f64(data_deadline.max(0.0));
+        info!("Data deadline: {:?}", data_deadline);
+
+        let delivery_timeout = Self::required(&config_obj, "communication.delivery-timeout", JsonValue::as_number)?;
+        let delivery_timeout = Duration::from_secs_f64(delivery_timeout.max(0.0));
+        info!("Delivery timeout: {:?}", delivery_timeout);
+
+        let parse_mode = match Self::required(&config_obj, "communication.lenient-commands", JsonValue::as_bool)? {
+            true => ParseMode::Lenient,
+            false => ParseMode::Strict,
         };
-        info!("Cache capacity: {}", capacity);
+        info!("Command parsing: {:?}", parse_mode);
+
+        let accepted_charsets = Self::required(&config_obj, "communication.accepted-charsets", Self::string_array)?;
+        info!("Accepted charsets: {:?}", accepted_charsets);
+
+        let local_domains = Self::required(&config_obj, "communication.local-domains", Self::string_array)?;
+        if local_domains.is_empty() {
+            info!("No local domains, every domain is local");
+        } else {
+            info!("Local domains: {:?}", local_domains);
+        }
+
+        let relay_remote_recipients = Self::required(&config_obj, "communication.relay-remote-recipients", JsonValue::as_bool)?;
+        info!("Relay remote recipients: {}", relay_remote_recipients);
+
+        let max_tls_handshakes = Self::required(&config_obj, "communication.max-tls-handshakes", JsonValue::as_number)? as usize;
+        info!("TLS handshake limit: {}", max_tls_handshakes);
+
+        let tls_enabled = Self::required(&config_obj, "tls.enabled", JsonValue::as_bool)?;
+        info!("TLS enabled: {}", tls_enabled);
+
+        let tls = if tls_enabled {
+            let certificate = Self::required(&config_obj, "tls.certificate", JsonValue::as_str)?;
+            let private_key = Self::required(&config_obj, "tls.private-key", JsonValue::as_str)?;
+            info!("Certificate: {}", certificate);
+            info!("Private key: {}", private_key);
+            Some(TlsConfig { certificate, private_key })
+        } else {
+            None
+        };
+
+        let require_tls = Self::required(&config_obj, "tls.require-tls", JsonValue::as_bool)?;
+        info!("Require TLS: {}", require_tls);
+        if tls.is_none() && require_tls {
+            warn!("TLS is disabled but required for AUTH, clients will not be able to authenticate");
+        }
 
-        let pool_size = match config_obj["thread-pool"]["pool-size"].as_number() {
-            Some(pool_size) => {
-                pool_size as usize
+        let access = AccessList {
+            allow: Self::required(&config_obj, "access.allow", Self::networks)?,
+            deny: Self::required(&config_obj, "access.deny", Self::networks)?,
+            reject_banner: Self::required(&config_obj, "access.reject-banner", JsonValue::as_bool)?,
+        };
+        info!("Allowed networks: {:?}", access.allow);
+        info!("Denied networks: {:?}", access.deny);
+
+        let status = if Self::required(&config_obj, "status.enabled", JsonValue::as_bool)? {
+            let address = Self::socket_address(&config_obj, "status")?;
+            info!("Status socket: {}", address);
+            Some(address)
+        } else {
+            None
+        };
+
+        // the captures contain whole messages, see DeadLetterCapture before enabling this
+        let dead_letters = match Self::optional(&config_obj, "communication.dead-letter.directory", JsonValue::as_str)? {
+            Some(directory) => {
+                let max_files = Self::required(&config_obj, "communication.dead-letter.max-files", JsonValue::as_number)? as usize;
+                let max_size = Self::required(&config_obj, "communication.dead-letter.max-size", JsonValue::as_number)? as usize;
+                warn!("Refused messages are written to {}, up to {} files", directory, max_files);
+                Some(Arc::new(DeadLetterCapture::new(PathBuf::from(directory), max_files, max_size)))
             },
-            None => {
-                warn!("Thread pool size not found, using default");
-                10
+            None => None,
+        };
+
+        let smart_host = Self::smart_host(&config_obj)?;
+        if let Some(smart_host) = &smart_host {
+            if !relay_remote_recipients {
+                return Err(ConfigError::InvalidValue("relay.smart-host".to_string(),
+                    "outbound relaying is disabled, set communication.relay-remote-recipients".to_string()));
             }
+            info!("Smart host: {}:{}", smart_host.host, smart_host.port);
+        }
+
+        let session = SessionConfig {
+            hostname,
+            enforce_sender_ownership,
+            require_tls,
+            require_auth,
+            command_rate,
+            command_burst,
+            max_bad_commands,
+            max_line_length,
+            max_message_size,
+            max_header_line_length,
+            max_session_duration,
+            command_deadline,
+            data_deadline,
+            delivery_timeout,
+            parse_mode,
+            accepted_charsets,
+            local_domains,
+            relay: defaults.relay,
+            tls_handshakes: Arc::new(TlsHandshakeLimiter::new(max_tls_handshakes)),
+            metrics: defaults.metrics,
+            extensions: defaults.extensions,
+            dead_letters,
         };
-        info!("Thread pool size: {}", pool_size);
 
-        let log_target: Box<dyn LogTarget + Send + Sync + 'static> =
-        match config_obj["logging"]["log-target"].as_str().unwrap_or("console".to_string()).as_str() {
+        Ok(Self {
+            address,
+            unix_socket,
+            metrics_snapshot,
+            log_level,
+            log_target,
+            capacity,
+            flush_interval,
+            log_queue_limit,
+            pool_size,
+            timeout,
+            keepalive_idle,
+            tls,
+            access,
+            status,
+            relay_remote_recipients,
+            smart_host,
+            session,
+        })
+    }
+
+    // The port defaults to 25, the credentials are optional but need both fields
+    fn smart_host(config_obj: &JsonValue) -> Result<Option<SmartHost>, ConfigError> {
+        let Some(host) = Self::optional(config_obj, "relay.smart-host.host", JsonValue::as_str)? else {
+            return Ok(None);
+        };
+        let port = match Self::lookup(config_obj, "relay.smart-host.port") {
+            Some(value) if !value.is_null() => Self::port(config_obj, "relay.smart-host.port")?,
+            _ => 25,
+        };
+        let username = Self::optional(config_obj, "relay.smart-host.username", JsonValue::as_str)?;
+        let password = Self::optional(config_obj, "relay.smart-host.password", JsonValue::as_str)?;
+        let credentials = match (username, password) {
+            (Some(username), Some(password)) => Some(SmartHostCredentials { username, password }),
+            (None, None) => None,
+            _ => return Err(ConfigError::InvalidValue(
+                "relay.smart-host".to_string(), "username and password must be set together".to_string())),
+        };
+        let allow_plaintext_auth = Self::optional(config_obj, "relay.smart-host.allow-plaintext-auth", JsonValue::as_bool)?
+            .unwrap_or(false);
+        Ok(Some(SmartHost { host, port, credentials, allow_plaintext_auth }))
+    }
+
+    // The "ip-address" and "port" fields of a section
+    fn socket_address(config_obj: &JsonValue, section: &str) -> Result<SocketAddr, ConfigError> {
+        let ip_path = format!("{}.ip-address", section);
+        let ip = Self::required(config_obj, &ip_path, JsonValue::as_str)?
+            .parse::<IpAddr>()
+            .map_err(|err| ConfigError::InvalidValue(ip_path, err.to_string()))?;
+        let port = Self::port(config_obj, &format!("{}.port", section))?;
+        Ok(SocketAddr::new(ip, port))
+    }
+
+    fn port(config_obj: &JsonValue, path: &str) -> Result<u16, ConfigError> {
+        let port = Self::required(config_obj, path, JsonValue::as_number)?;
+        if port.fract() != 0.0 || !(0.0..=u16::MAX as f64).contains(&port) {
+            return Err(ConfigError::InvalidValue(path.to_string(), format!("{} is not a port number", port)));
+        }
+        Ok(port as u16)
+    }
+
+    // Looks up a dot separated path like "server.port", without the Null fallback of Index
+    fn lookup<'a>(config_obj: &'a JsonValue, path: &str) -> Option<&'a JsonValue> {
+        path.split('.').try_fold(config_obj, |value, key| value.get(key))
+    }
+
+    fn log_target(config_obj: &JsonValue, name: &str) -> Result<Box<dyn LogTarget + Send + Sync + 'static>, ConfigError> {
+        let target: Box<dyn LogTarget + Send + Sync + 'static> = match name {
             "console" => {
                 info!("Log target: console");
                 Box::new(ConsoleLogTarget)
             },
+            // the default file name depends on the target, so it is not part of DEFAULT_CONFIG
             "file" => {
-                let file_path = config_obj["logging"]["file-path"].as_str().unwrap_or("log.txt".to_string());
+                let file_path = Self::optional(config_obj, "logging.file-path", JsonValue::as_str)?
+                    .unwrap_or("log.txt".to_string());
                 info!("Log target: file");
                 info!("File path: {}", file_path);
                 Box::new(FileLogTarget::new(Path::new(&file_path)))
-            }
-            _ => Box::new(ConsoleLogTarget),
-        };
-
-        let timeout = match config_obj["communication"]["max-connection-timeout"].as_number() {
-            Some(timeout) => {
-                timeout as u64
             },
-            None => {
-                warn!("Timeout not found, using default");
-                60_u64
-            }
+            "daily-file" => {
+                let file_pattern = Self::optional(config_obj, "logging.file-path", JsonValue::as_str)?
+                    .unwrap_or("log-%Y-%m-%d.txt".to_string());
+                info!("Log target: daily file");
+                info!("File path pattern: {}", file_pattern);
+                Box::new(DateRotatingFileLogTarget::new(&file_pattern)?)
+            },
+            "tcp" => {
+                let address = Self::socket_address(config_obj, "logging.tcp-collector")?;
+                let buffer = Self::required(config_obj, "logging.tcp-buffer", JsonValue::as_number)? as usize;
+                info!("Log target: tcp");
+                info!("Log collector: {}", address);
+                Box::new(TcpLogTarget::new(address, buffer))
+            },
+            _ => return Err(ConfigError::InvalidValue(
+                "logging.log-target".to_string(),
+                format!("unknown log target '{}'", name),
+            )),
         };
-        info!("Timeout: {}", timeout);
+        Ok(target)
+    }
 
-        Self {
-            ip: ip.to_string(),
-            port,
-            log_level,
-            log_target,
-            capacity,
-            pool_size,
-            timeout,
+    fn string_or_array(value: &JsonValue) -> Option<Vec<String>> {
+        value.as_str().map(|name| vec![name]).or_else(|| Self::string_array(value))
+    }
+
+    fn string_array(value: &JsonValue) -> Option<Vec<String>> {
+        value.as_array()?.iter().map(JsonValue::as_str).collect()
+    }
+
+    // CIDR strings like "10.0.0.0/8" or "fd00::/8", a single address is a /32 or /128 network
+    fn networks(value: &JsonValue) -> Option<Vec<IpNet>> {
+        Self::string_array(value)?.iter()
+            .map(|net| net.parse::<IpNet>().ok().or_else(|| net.parse::<IpAddr>().ok().map(IpNet::from)))
+            .collect()
+    }
+
+    fn required<T>(config_obj: &JsonValue, path: &str, convert: fn(&JsonValue) -> Option<T>) -> Result<T, ConfigError> {
+        Self::optional(config_obj, path, convert)?
+            .ok_or_else(|| ConfigError::MissingField(path.to_string()))
+    }
+
+    // Absent fields and explicit nulls are None, present fields of a wrong type are an error
+    fn optional<T>(config_obj: &JsonValue, path: &str, convert: fn(&JsonValue) -> Option<T>) -> Result<Option<T>, ConfigError> {
+        match Self::lookup(config_obj, path) {
+            Some(value) if !value.is_null() => convert(value)
+                .map(Some)
+                .ok_or_else(|| ConfigError::WrongType(path.to_string())),
+            _ => Ok(None),
         }
     }
 }
+
+// The hostname of the machine, if it can be used in replies
+fn os_hostname() -> Option<String> {
+    gethostname::gethostname().into_string().ok()
+        .filter(|hostname| is_valid_hostname(hostname))
+}
+
+// A domain name made of dot separated labels of letters, digits and inner hyphens
+fn is_valid_hostname(hostname: &str) -> bool {
+    !hostname.is_empty() && hostname.len() <= 253 && hostname.split('.').all(|label| {
+        !label.is_empty() && label.len() <= 63
+            && !label.starts_with('-') && !label.ends_with('-')
+            && label.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn minimal_config_test() {
+        let config = Config::from_json(r#"{ "server": { "ip-address": "127.0.0.1", "port": 2525 } }"#).unwrap();
+        assert_eq!(config.address, "127.0.0.1:2525".parse().unwrap());
+        assert_eq!(config.pool_size, 10);
+        assert!(config.unix_socket.is_none());
+        assert!(config.metrics_snapshot.is_none());
+        assert!(config.session.enforce_sender_ownership);
+        assert!(config.tls.is_some());
+        assert!(config.session.require_tls);
+    }
+
+    #[test]
+    fn access_list_test() {
+        let config = Config::from_json(r#"{
+            "server": { "ip-address": "127.0.0.1", "port": 2525 },
+            "access": { "allow": ["10.0.0.0/8", "2001:db8::/32", "192.0.2.7"], "deny": ["10.1.0.0/16"] }
+        }"#).unwrap();
+        assert_eq!(config.access.allow.len(), 3);
+        assert!(config.access.is_allowed(Some("192.0.2.7".parse().unwrap())));
+        assert!(!config.access.is_allowed(Some("10.1.0.1".parse().unwrap())));
+        assert!(config.access.reject_banner);
+
+        let result = Config::from_json(r#"{
+            "server": { "ip-address": "127.0.0.1", "port": 2525 },
+            "access": { "deny": ["10.0.0.0/33"] }
+        }"#);
+        assert!(matches!(result, Err(ConfigError::WrongType(field)) if field == "access.deny"));
+    }
+
+    #[test]
+    fn tls_disabled_test() {
+        let config = Config::from_json(r#"{
+            "server": { "ip-address": "127.0.0.1", "port": 2525 },
+            "tls": { "enabled": false, "require-tls": false }
+        }"#).unwrap();
+        assert!(config.tls.is_none());
+        assert!(!config.session.require_tls);
+    }
+
+    #[test]
+    fn keepalive_test() {
+        let config = Config::from_json(r#"{
+            "server": { "ip-address": "127.0.0.1", "port": 2525 },
+            "communication": { "keepalive-idle": 0 }
+        }"#).unwrap();
+        assert_eq!(config.keepalive_idle, None);
+    }
+
+    #[test]
+    fn missing_required_field_test() {
+        // an explicit null removes the default
+        let result = Config::from_json(r#"{ "server": { "ip-address": null, "port": 2525 } }"#);
+        assert!(matches!(result, Err(ConfigError::MissingField(field)) if field == "server.ip-address"));
+
+        let result = Config::from_json(r#"{ "server": { "ip-address": "127.0.0.1", "port": null } }"#);
+        assert!(matches!(result, Err(ConfigError::MissingField(field)) if field == "server.port"));
+    }
+
+    #[test]
+    fn wrong_type_test() {
+        let result = Config::from_json(r#"{ "server": { "ip-address": "127.0.0.1", "port": "2525" } }"#);
+        assert!(matches!(result, Err(ConfigError::WrongType(field)) if field == "server.port"));
+
+        let result = Config::from_json(r#"{
+            "server": { "ip-address": "127.0.0.1", "port": 2525 },
+            "thread-pool": { "pool-size": "ten" }
+        }"#);
+        assert!(matches!(result, Err(ConfigError::WrongType(field)) if field == "thread-pool.pool-size"));
+    }
+
+    #[test]
+    fn bind_address_test() {
+        let config = Config::from_json(r#"{ "server": { "ip-address": "::1", "port": 25 } }"#).unwrap();
+        assert_eq!(config.address, "[::1]:25".parse().unwrap());
+
+        let result = Config::from_json(r#"{ "server": { "ip-address": "127.0.0.256" } }"#);
+        assert!(matches!(result, Err(ConfigError::InvalidValue(field, _)) if field == "server.ip-address"));
+
+        let result = Config::from_json(r#"{ "server": { "ip-address": "localhost" } }"#);
+        assert!(matches!(result, Err(ConfigError::InvalidValue(field, _)) if field == "server.ip-address"));
+
+        let result = Config::from_json(r#"{ "server": { "port": 65536 } }"#);
+        assert!(matches!(result, Err(ConfigError::InvalidValue(field, _)) if field == "server.port"));
+
+        let result = Config::from_json(r#"{ "server": { "port": -1 } }"#);
+        assert!(matches!(result, Err(ConfigError::InvalidValue(field, _)) if field == "server.port"));
+    }
+
+    #[test]
+    fn status_socket_test() {
+        let config = Config::from_json(r#"{ "status": { "enabled": true } }"#).unwrap();
+        assert_eq!(config.status, Some("127.0.0.1:2526".parse().unwrap()));
+
+        let result = Config::from_json(r#"{ "status": { "enabled": true, "port": 70000 } }"#);
+        assert!(matches!(result, Err(ConfigError::InvalidValue(field, _)) if field == "status.port"));
+    }
+
+    #[test]
+    fn hostname_test() {
+        let config = Config::from_json(r#"{ "server": { "ip-address": "127.0.0.1", "port": 2525 } }"#).unwrap();
+        let expected = os_hostname().unwrap_or_else(|| "localhost".to_string());
+        assert_eq!(config.session.hostname, expected);
+
+        let config = Config::from_json(r#"{
+            "server": { "ip-address": "127.0.0.1", "port": 2525, "hostname": "mx.example.com" }
+        }"#).unwrap();
+        assert_eq!(config.session.hostname, "mx.example.com");
+
+        let result = Config::from_json(r#"{
+            "server": { "ip-address": "127.0.0.1", "port": 2525, "hostname": "mx example.com" }
+        }"#);
+        assert!(matches!(result, Err(ConfigError::InvalidValue(field, _)) if field == "server.hostname"));
+    }
+
+    #[test]
+    fn invalid_log_level_test() {
+        let result = Config::from_json(r#"{ "logging": { "log-level": "verbose" } }"#);
+        assert!(matches!(result, Err(ConfigError::InvalidValue(field, _)) if field == "logging.log-level"));
+    }
+
+    #[test]
+    fn log_queue_limit_test() {
+        let config = Config::from_json(r#"{
+            "logging": { "queue-capacity": 10000, "overflow-policy": "drop-oldest" }
+        }"#).unwrap();
+        assert_eq!(config.log_queue_limit, Some(QueueLimit { capacity: 10000, policy: OverflowPolicy::DropOldest }));
+
+        let config = Config::from_json(r#"{ "logging": { "queue-capacity": 100 } }"#).unwrap();
+        assert_eq!(config.log_queue_limit, Some(QueueLimit { capacity: 100, policy: OverflowPolicy::Block }));
+
+        let result = Config::from_json(r#"{ "logging": { "overflow-policy": "drop-everything" } }"#);
+        assert!(matches!(result, Err(ConfigError::InvalidValue(field, _)) if field == "logging.overflow-policy"));
+    }
+
+    #[test]
+    fn multiple_log_targets_test() {
+        let file_path = std::env::temp_dir().join(format!("multiple-log-targets-{}.log", std::process::id()));
+        let config = Config::from_json(&format!(r#"{{
+            "logging": {{ "log-target": ["console", "file"], "file-path": {:?}, "background-targets": ["file"] }}
+        }}"#, file_path.to_string_lossy()));
+        assert!(config.is_ok());
+        drop(config);
+        let _ = std::fs::remove_file(file_path);
+
+        let result = Config::from_json(r#"{ "logging": { "log-target": ["console", 1] } }"#);
+        assert!(matches!(result, Err(ConfigError::WrongType(field)) if field == "logging.log-target"));
+
+        let result = Config::from_json(r#"{ "logging": { "log-target": ["console", "syslog"] } }"#);
+        assert!(matches!(result, Err(ConfigError::InvalidValue(field, _)) if field == "logging.log-target"));
+    }
+
+    #[test]
+    fn valid_hostname_test() {
+        assert!(is_valid_hostname("localhost"));
+        assert!(is_valid_hostname("mail-1.example.com"));
+        assert!(!is_valid_hostname(""));
+        assert!(!is_valid_hostname("example..com"));
+        assert!(!is_valid_hostname("-mail.example.com"));
+        assert!(!is_valid_hostname("mail_1.example.com"));
+    }
+
+    #[test]
+    fn partial_config_uses_embedded_defaults_test() {
+        let config = Config::from_json(r#"{ "server": { "port": 2600 } }"#).unwrap();
+        assert_eq!(config.address, "127.0.0.1:2600".parse().unwrap());
+        assert!(config.unix_socket.is_none());
+        assert_eq!(config.log_level, LogLevel::Info);
+        assert_eq!(config.capacity, 1000);
+        assert_eq!(config.flush_interval, Duration::from_secs(5));
+        assert!(config.log_queue_limit.is_none());
+        assert_eq!(config.pool_size, 10);
+        assert_eq!(config.timeout, 60);
+        assert_eq!(config.keepalive_idle, Some(Duration::from_secs(60)));
+        assert_eq!(config.tls.as_ref().map(|tls| tls.certificate.as_str()), Some("server/certs/server.crt"));
+        assert!(config.access.allow.is_empty() && config.access.deny.is_empty());
+        assert!(config.access.reject_banner);
+        assert!(config.status.is_none());
+        assert!(!config.relay_remote_recipients);
+        assert!(config.smart_host.is_none());
+
+        let defaults = SessionConfig::default();
+        assert_eq!(config.session.enforce_sender_ownership, defaults.enforce_sender_ownership);
+        assert_eq!(config.session.require_tls, defaults.require_tls);
+        assert_eq!(config.session.require_auth, defaults.require_auth);
+        assert_eq!(config.session.command_rate, defaults.command_rate);
+        assert_eq!(config.session.command_burst, defaults.command_burst);
+        assert_eq!(config.session.max_bad_commands, defaults.max_bad_commands);
+        assert_eq!(config.session.max_line_length, defaults.max_line_length);
+        assert_eq!(config.session.max_message_size, defaults.max_message_size);
+        assert_eq!(config.session.delivery_timeout, defaults.delivery_timeout);
+        assert_eq!(config.session.max_session_duration, defaults.max_session_duration);
+        assert_eq!(config.session.command_deadline, defaults.command_deadline);
+        assert_eq!(config.session.data_deadline, defaults.data_deadline);
+        assert_eq!(config.session.max_header_line_length, defaults.max_header_line_length);
+        assert_eq!(config.session.parse_mode, defaults.parse_mode);
+        assert_eq!(config.session.accepted_charsets, defaults.accepted_charsets);
+        assert_eq!(config.session.local_domains, defaults.local_domains);
+        assert!(config.session.relay.is_none());
+        assert_eq!(config.session.tls_handshakes.limit(), defaults.tls_handshakes.limit());
+        assert!(config.session.dead_letters.is_none());
+    }
+
+    #[test]
+    fn smart_host_test() {
+        let config = Config::from_json(r#"{
+            "communication": { "relay-remote-recipients": true },
+            "relay": { "smart-host": { "host": "smtp.example.net", "port": 587, "username": "relay", "password": "secret" } }
+        }"#).unwrap();
+        assert_eq!(config.smart_host, Some(SmartHost {
+            host: "smtp.example.net".to_string(),
+            port: 587,
+            credentials: Some(SmartHostCredentials { username: "relay".to_string(), password: "secret".to_string() }),
+            allow_plaintext_auth: false,
+        }));
+
+        let config = Config::from_json(r#"{
+            "communication": { "relay-remote-recipients": true },
+            "relay": { "smart-host": { "host": "192.0.2.25", "allow-plaintext-auth": true } }
+        }"#).unwrap();
+        assert_eq!(config.smart_host.map(|smart_host| (smart_host.port, smart_host.credentials, smart_host.allow_plaintext_auth)),
+            Some((25, None, true)));
+
+        let result = Config::from_json(r#"{
+            "communication": { "relay-remote-recipients": true },
+            "relay": { "smart-host": { "host": "smtp.example.net", "username": "relay" } }
+        }"#);
+        assert!(matches!(result, Err(ConfigError::InvalidValue(field, _)) if field == "relay.smart-host"));
+
+        // a smart host is only used for relaying
+        let result = Config::from_json(r#"{ "relay": { "smart-host": { "host": "smtp.example.net" } } }"#);
+        assert!(matches!(result, Err(ConfigError::InvalidValue(field, _)) if field == "relay.smart-host"));
+    }
+
+    #[test]
+    fn dead_letter_test() {
+        let config = Config::from_json(r#"{ "communication": { "dead-letter": { "directory": "/var/spool/smtp/dead", "max-files": 5 } } }"#).unwrap();
+        let dead_letters = format!("{:?}", config.session.dead_letters.unwrap());
+        assert!(dead_letters.contains("\"/var/spool/smtp/dead\""), "{}", dead_letters);
+        assert!(dead_letters.contains("max_files: 5, max_size: 1048576"), "{}", dead_letters);
+    }
+}
@@ -0,0 +1,122 @@
+use std::{
+    io,
+    net::{SocketAddr, TcpListener},
+    os::unix::{fs::FileTypeExt, net::{UnixListener, UnixStream}},
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use logger::warn;
+use smart_stream::{error::SmartStreamError, AsyncStream};
+
+// Accepts client connections either on a TCP address or on a Unix domain socket, whose file
+// is removed when the listener is dropped
+pub enum Listener {
+    Tcp(TcpListener),
+    Unix(UnixListener, PathBuf),
+}
+
+impl Listener {
+    pub fn bind_tcp(address: SocketAddr) -> io::Result<Self> {
+        Ok(Self::Tcp(TcpListener::bind(address)?))
+    }
+
+    pub fn bind_unix(path: &Path) -> io::Result<Self> {
+        // a socket file left over from a previous run would make bind fail, one that still
+        // accepts connections belongs to a running server, anything else at the path is not
+        // ours to delete
+        match std::fs::symlink_metadata(path) {
+            Ok(metadata) if metadata.file_type().is_socket() => {
+                if UnixStream::connect(path).is_ok() {
+                    return Err(io::Error::new(io::ErrorKind::AddrInUse,
+                        format!("{} is in use by a running server", path.display())));
+                }
+                std::fs::remove_file(path)?
+            },
+            Ok(_) => return Err(io::Error::new(io::ErrorKind::AlreadyExists,
+                format!("{} exists and is not a socket", path.display()))),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {},
+            Err(err) => return Err(err),
+        }
+        Ok(Self::Unix(UnixListener::bind(path)?, path.to_path_buf()))
+    }
+
+    // In non-blocking mode accept fails with WouldBlock instead of waiting, see try_accept
+    pub fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        match self {
+            Self::Tcp(listener) => listener.set_nonblocking(nonblocking),
+            Self::Unix(listener, _) => listener.set_nonblocking(nonblocking),
+        }
+    }
+
+    // Returns None when no client is waiting on a non-blocking listener
+    pub fn try_accept(&self, timeout: u64, keepalive: Option<Duration>) -> Result<Option<AsyncStream>, SmartStreamError> {
+        match self.accept(timeout, keepalive) {
+            Ok(stream) => Ok(Some(stream)),
+            Err(SmartStreamError::Io(err)) if err.kind() == io::ErrorKind::WouldBlock => Ok(None),
+            Err(err) => Err(err),
+        }
+    }
+
+    // `keepalive` is the idle time before a silent TCP client is probed, a vanished one then
+    // fails its next read. The connection is kept when the probes cannot be set up.
+    pub fn accept(&self, timeout: u64, keepalive: Option<Duration>) -> Result<AsyncStream, SmartStreamError> {
+        match self {
+            Self::Tcp(listener) => {
+                let (stream, _) = listener.accept()?;
+                stream.set_nonblocking(false)?;
+                let mut stream = AsyncStream::new(stream, timeout)?;
+                if let Err(err) = stream.set_keepalive(keepalive) {
+                    warn!("Could not set TCP keepalive of {:?}: {}", stream.peer_addr(), err);
+                }
+                Ok(stream)
+            },
+            Self::Unix(listener, _) => {
+                let (stream, _) = listener.accept()?;
+                stream.set_nonblocking(false)?;
+                AsyncStream::from_unix(stream, timeout)
+            },
+        }
+    }
+}
+
+impl Drop for Listener {
+    fn drop(&mut self) {
+        if let Self::Unix(_, path) = self {
+            if let Err(err) = std::fs::remove_file(path.as_path()) {
+                warn!("Could not remove the socket {}: {}", path.display(), err);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bind_unix_replaces_only_sockets_test() {
+        let path = std::env::temp_dir().join(format!("smtp-listener-test-{}.sock", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+
+        // the socket of a previous run that did not clean up is replaced
+        drop(UnixListener::bind(&path).unwrap());
+        assert!(path.exists());
+        let listener = Listener::bind_unix(&path).unwrap();
+
+        // the socket of a running server is left to it
+        let result = Listener::bind_unix(&path);
+        assert!(matches!(result, Err(err) if err.kind() == io::ErrorKind::AddrInUse));
+        assert!(UnixStream::connect(&path).is_ok());
+
+        // and removed when it stops
+        drop(listener);
+        assert!(!path.exists());
+
+        std::fs::write(&path, "not a socket").unwrap();
+        let result = Listener::bind_unix(&path);
+        assert!(matches!(result, Err(err) if err.kind() == io::ErrorKind::AlreadyExists));
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "not a socket");
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -1,66 +1,127 @@
 use concurrent_runtime::ConcurrentRuntime;
-use smart_stream::AsyncStream;
-use std::sync::Arc;
 
-use async_native_tls::TlsAcceptor;
-use native_tls::{Identity, TlsAcceptor as NativeTlsAcceptor};
+use smart_stream::TlsAcceptor;
+use relay::{DnsResolver, RelayClient};
 
-use std::net::TcpListener;
+mod access;
 mod config;
+use config::TlsConfig;
+mod listener;
+use listener::Listener;
+mod server;
+use server::SmtpServer;
+mod status;
+use status::{StatusReport, StatusServer};
+#[cfg(test)]
+mod test_support;
 
-use logger::info;
-
-use client_session::ClientSession;
+use logger::{error, info};
 
+use async_std::task::block_on;
 use dotenv::dotenv;
-use std::env;
+use std::{path::Path, sync::Arc};
 
 fn main() {
     dotenv().ok();
 
     logger::set_logger_target(Box::new(logger::ConsoleLogTarget));
 
-    let cfg = config::Config::default();
+    let mut cfg = match config::Config::from_file(Path::new("config.json")) {
+        Ok(cfg) => cfg,
+        Err(err) => {
+            error!("{}", err);
+            logger::terminate();
+            std::process::exit(1);
+        }
+    };
 
     logger::set_logger_level(cfg.log_level);
     logger::set_logger_target(cfg.log_target);
     logger::set_logger_cache_capacity(cfg.capacity);
+    logger::set_logger_flush_interval(cfg.flush_interval);
+    logger::set_logger_queue_limit(cfg.log_queue_limit);
+
+    // every session connects to the mail database with it
+    if std::env::var("CONNECTION_STRING").is_err() {
+        error!("CONNECTION_STRING must be set");
+        logger::terminate();
+        std::process::exit(1);
+    }
 
     let mut runtime = ConcurrentRuntime::new(cfg.pool_size);
     runtime.start();
     
-    let listener = TcpListener::bind(format!("{}:{}", cfg.ip, cfg.port)).unwrap();
-    let native_tls_acceptor: NativeTlsAcceptor = NativeTlsAcceptor::new(
-        Identity::from_pkcs8(
-            include_bytes!("../certs/server.crt"),
-            include_bytes!("../certs/server.key"),
-        ).unwrap(),
-    ).unwrap();
-
-    let acceptor = Arc::new(TlsAcceptor::from(native_tls_acceptor));
-    loop {
-        let (stream, _) = listener.accept().unwrap();
-        let async_stream = AsyncStream::new(stream, cfg.timeout).unwrap();
-        let acceptor = acceptor.clone();
-
-        runtime.spawn(async move {
-            let connection_string = env::var("CONNECTION_STRING").expect("CONNECTION_STRING must be set");
-            let connection_result = ClientSession::new(
-                async_stream, &acceptor,
-                &connection_string
-            );
-
-            match connection_result {
-                Ok(mut connection) => {
-                    let connection_promise = connection.run().await;
-                    match connection_promise {
-                        Ok(_) => info!("Connection closed"),
-                        Err(e) => info!("Connection error: {:?}", e),
-                    }
-                },
-                Err(e) => info!("Connection error: {:?}", e),
+    let listener = match &cfg.unix_socket {
+        Some(path) => Listener::bind_unix(Path::new(path)),
+        None => Listener::bind_tcp(cfg.address),
+    };
+    let listener = match listener {
+        Ok(listener) => listener,
+        Err(err) => {
+            error!("Could not listen on {}: {}", cfg.unix_socket.as_deref().unwrap_or(&cfg.address.to_string()), err);
+            logger::terminate();
+            std::process::exit(1);
+        }
+    };
+    let acceptor = match &cfg.tls {
+        Some(tls) => match load_tls_acceptor(tls) {
+            Ok(acceptor) => Some(acceptor),
+            Err(err) => {
+                error!("Could not set up TLS: {}", err);
+                logger::terminate();
+                std::process::exit(1);
+            }
+        },
+        None => None,
+    };
+    // messages to recipients outside the local domains are sent on to the MX of their domain,
+    // or to the smart host when there is one
+    if cfg.relay_remote_recipients {
+        match block_on(DnsResolver::from_system_conf()) {
+            Ok(resolver) => {
+                let mut client = RelayClient::new(Box::new(resolver), &cfg.session.hostname, 25, cfg.timeout);
+                if let Some(smart_host) = cfg.smart_host.take() {
+                    client = client.with_smart_host(smart_host);
+                }
+                cfg.session.relay = Some(Arc::new(client));
+            },
+            Err(err) => {
+                error!("Could not set up DNS lookups for relaying: {:?}", err);
+                logger::terminate();
+                std::process::exit(1);
             }
+        }
+    }
+    let status_report = StatusReport::new(acceptor.is_some(), &cfg.session);
+    let server = SmtpServer::new(listener, acceptor, cfg.timeout, cfg.session, cfg.access).unwrap()
+        .with_keepalive(cfg.keepalive_idle);
+    if let Some(path) = &cfg.metrics_snapshot {
+        server::load_metrics(&server, Path::new(path));
+    }
+
+    // the status socket only serves ops tooling, the server runs on without it
+    if let Some(address) = cfg.status {
+        let status = StatusServer::bind(address, status_report).and_then(|status| {
+            info!("Status socket listening on {}", status.local_addr()?);
+            status.spawn(server.shutdown_handle())
         });
+        if let Err(err) = status {
+            error!("Could not start the status socket on {}: {}", address, err);
+        }
+    }
 
+    // SIGINT and SIGTERM only stop the accept loop, the shutdown itself happens below
+    let handle = server.shutdown_handle();
+    if let Err(err) = ctrlc::set_handler(move || handle.shutdown()) {
+        error!("Could not install the signal handler: {}", err);
     }
+
+    server.run(&runtime);
+    server::shutdown(&server, &mut runtime, cfg.metrics_snapshot.as_deref().map(Path::new), logger::terminate);
+}
+
+fn load_tls_acceptor(tls: &TlsConfig) -> Result<TlsAcceptor, Box<dyn std::error::Error>> {
+    let certificate = std::fs::read(&tls.certificate)?;
+    let private_key = std::fs::read(&tls.private_key)?;
+    Ok(smart_stream::tls_acceptor(&certificate, &private_key)?)
 }
@@ -0,0 +1,347 @@
+use std::{
+    collections::HashMap,
+    env,
+    path::Path,
+    sync::{atomic::{AtomicBool, Ordering}, Arc},
+    thread,
+    time::Duration,
+};
+
+use client_session::{metrics::{LatencyStats, MetricsSnapshot}, ClientSession, SessionConfig};
+use concurrent_runtime::ConcurrentRuntime;
+use json_parser::JsonValue;
+use logger::{error, info};
+use mail_database::{IMailDB, MailError, PgMailDB};
+use smart_stream::TlsAcceptor;
+
+use crate::{access::AccessList, listener::Listener};
+
+// How long the accept loop sleeps when no client is waiting,
+// this bounds the delay between a shutdown request and the loop exiting
+const ACCEPT_POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+// Opens the mail database of a new session
+pub type DatabaseFactory = Arc<dyn Fn() -> Result<Box<dyn IMailDB + Send>, MailError> + Send + Sync>;
+
+pub struct SmtpServer {
+    listener: Listener,
+    acceptor: Option<Arc<TlsAcceptor>>,
+    timeout: u64,
+    // idle time before TCP keepalive probes a client, None leaves them off
+    keepalive: Option<Duration>,
+    session_config: SessionConfig,
+    access: Arc<AccessList>,
+    running: Arc<AtomicBool>,
+    database: DatabaseFactory,
+}
+
+// Stops the accept loop of the server it was taken from, may be moved into a signal handler
+#[derive(Clone)]
+pub struct ShutdownHandle {
+    running: Arc<AtomicBool>,
+}
+
+impl ShutdownHandle {
+    pub fn shutdown(&self) {
+        self.running.store(false, Ordering::Release);
+    }
+
+    pub fn is_running(&self) -> bool {
+        self.running.load(Ordering::Acquire)
+    }
+}
+
+// A metrics snapshot lacks a field, or it is not a non-negative number
+#[derive(Debug)]
+pub struct InvalidMetricsSnapshot(pub String);
+
+impl std::fmt::Display for InvalidMetricsSnapshot {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Metrics snapshot field '{}' is missing or invalid", self.0)
+    }
+}
+
+impl std::error::Error for InvalidMetricsSnapshot {}
+
+impl SmtpServer {
+    pub fn new(listener: Listener, acceptor: Option<TlsAcceptor>, timeout: u64, session_config: SessionConfig,
+        access: AccessList) -> std::io::Result<Self> {
+        listener.set_nonblocking(true)?;
+        Ok(SmtpServer {
+            listener,
+            acceptor: acceptor.map(Arc::new),
+            timeout,
+            keepalive: None,
+            session_config,
+            access: Arc::new(access),
+            running: Arc::new(AtomicBool::new(true)),
+            database: Arc::new(connect_postgres),
+        })
+    }
+
+    pub fn with_keepalive(mut self, idle: Option<Duration>) -> Self {
+        self.keepalive = idle;
+        self
+    }
+
+    // Replaces the PostgreSQL database of CONNECTION_STRING, test servers keep their mail in memory
+    #[cfg(test)]
+    pub fn with_database(mut self, database: DatabaseFactory) -> Self {
+        self.database = database;
+        self
+    }
+
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        ShutdownHandle { running: self.running.clone() }
+    }
+
+    pub fn is_running(&self) -> bool {
+        self.running.load(Ordering::Acquire)
+    }
+
+    // Accepts clients and spawns their sessions on the runtime until shutdown is requested
+    pub fn run(&self, runtime: &ConcurrentRuntime) {
+        while self.is_running() {
+            let async_stream = match self.listener.try_accept(self.timeout, self.keepalive) {
+                Ok(Some(stream)) => stream,
+                Ok(None) => {
+                    thread::sleep(ACCEPT_POLL_INTERVAL);
+                    continue;
+                },
+                Err(err) => {
+                    // errors like EMFILE persist until a session ends, retrying at once would spin
+                    error!("Could not accept connection: {}", err);
+                    thread::sleep(ACCEPT_POLL_INTERVAL);
+                    continue;
+                }
+            };
+            let acceptor = self.acceptor.clone();
+            let session_config = self.session_config.clone();
+            let access = self.access.clone();
+            let database = self.database.clone();
+
+            runtime.spawn(async move {
+                let mut async_stream = async_stream;
+                let peer = async_stream.peer_addr();
+                if !access.is_allowed(peer.map(|addr| addr.ip())) {
+                    info!("Rejected connection from {:?}", peer);
+                    if access.reject_banner {
+                        let _ = async_stream.write(b"554 Access denied\r\n").await;
+                    }
+                    return;
+                }
+
+                let connection_result = database().map(|db_connection| ClientSession::with_database(
+                    async_stream, acceptor.as_deref(),
+                    db_connection, session_config
+                ));
+
+                match connection_result {
+                    Ok(mut connection) => {
+                        let connection_promise = connection.run().await;
+                        match connection_promise {
+                            Ok(_) => info!("Connection closed"),
+                            Err(e) => info!("Connection error: {:?}", e),
+                        }
+                    },
+                    Err(e) => info!("Connection error: {:?}", e),
+                }
+            });
+        }
+        info!("Server stopped accepting connections");
+    }
+
+    pub fn shutdown(&self) {
+        self.shutdown_handle().shutdown();
+    }
+
+    // The cumulative totals, for the next process to resume from with import_metrics.
+    // Latencies are in whole milliseconds.
+    pub fn export_metrics(&self) -> JsonValue {
+        let metrics = self.session_config.metrics.snapshot();
+        let latency = metrics.message_latency;
+        JsonValue::Object(HashMap::from([
+            ("bytes-received".to_string(), JsonValue::Number(metrics.bytes_received as f64)),
+            ("messages-accepted".to_string(), JsonValue::Number(metrics.messages_accepted as f64)),
+            ("message-latency".to_string(), JsonValue::Object(HashMap::from([
+                ("count".to_string(), JsonValue::Number(latency.count as f64)),
+                ("total-ms".to_string(), JsonValue::Number(latency.total.as_millis() as f64)),
+                ("max-ms".to_string(), JsonValue::Number(latency.max.as_millis() as f64)),
+            ]))),
+        ]))
+    }
+
+    // Adds the totals of an exported snapshot to the counters of this server
+    pub fn import_metrics(&self, snapshot: &JsonValue) -> Result<(), InvalidMetricsSnapshot> {
+        let number = |path: &str| path.split('.')
+            .try_fold(snapshot, |value, key| value.get(key))
+            .and_then(JsonValue::as_number)
+            .filter(|number| *number >= 0.0)
+            .map(|number| number as u64)
+            .ok_or_else(|| InvalidMetricsSnapshot(path.to_string()));
+        let totals = MetricsSnapshot {
+            message_latency: LatencyStats {
+                count: number("message-latency.count")?,
+                total: Duration::from_millis(number("message-latency.total-ms")?),
+                max: Duration::from_millis(number("message-latency.max-ms")?),
+            },
+            bytes_received: number("bytes-received")?,
+            messages_accepted: number("messages-accepted")?,
+            ..Default::default()
+        };
+        self.session_config.metrics.restore(&totals);
+        Ok(())
+    }
+}
+
+// Every session opens its own connection to the database named by CONNECTION_STRING, main
+// checks that it is set before the first session
+fn connect_postgres() -> Result<Box<dyn IMailDB + Send>, MailError> {
+    let connection_string = env::var("CONNECTION_STRING").map_err(|_| MailError::NoConnection)?;
+    let mut db_connection = PgMailDB::new("localhost".to_string());
+    db_connection.connect(&connection_string)?;
+    Ok(Box::new(db_connection))
+}
+
+// Resumes the totals a previous process saved on shutdown, a missing file means a first start
+pub fn load_metrics(server: &SmtpServer, path: &Path) {
+    let snapshot = match std::fs::read_to_string(path) {
+        Ok(snapshot) => snapshot,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return,
+        Err(err) => {
+            error!("Could not read the metrics snapshot {}: {}", path.display(), err);
+            return;
+        }
+    };
+    let imported = json_parser::JsonParser::default().parse(&snapshot)
+        .map_err(|err| format!("{:?}", err))
+        .and_then(|snapshot| server.import_metrics(&snapshot).map_err(|err| err.to_string()));
+    match imported {
+        Ok(()) => info!("Metrics resumed from {}", path.display()),
+        Err(err) => error!("Could not resume metrics from {}: {}", path.display(), err),
+    }
+}
+
+// Stops accepting clients, then the runtime, saves the metrics for the next process
+// and finally flushes and stops the logger through terminate_logger, logger::terminate in main
+pub fn shutdown(server: &SmtpServer, runtime: &mut ConcurrentRuntime, metrics_snapshot: Option<&Path>,
+    terminate_logger: impl FnOnce()) {
+    info!("Shutting down");
+    server.shutdown();
+    runtime.stop();
+    info!("Metrics: {:?}", server.session_config.metrics.snapshot());
+    if let Some(path) = metrics_snapshot {
+        if let Err(err) = std::fs::write(path, server.export_metrics().to_string()) {
+            error!("Could not save the metrics snapshot {}: {}", path.display(), err);
+        }
+    }
+    terminate_logger();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{MemoryMailDB, TestServer};
+    use std::sync::Mutex;
+
+    struct RecordingLogTarget {
+        records: Arc<Mutex<String>>,
+    }
+
+    impl logger::LogTarget for RecordingLogTarget {
+        fn log(&self, message: &str) -> std::io::Result<()> {
+            self.records.lock().unwrap().push_str(message);
+            Ok(())
+        }
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn metrics_survive_restart_test() {
+        let listener = Listener::bind_tcp("127.0.0.1:0".parse().unwrap()).unwrap();
+        let server = SmtpServer::new(listener, None, 5, SessionConfig::default(), AccessList::default()).unwrap();
+        let metrics = &server.session_config.metrics;
+        metrics.record_bytes_received(1200);
+        metrics.record_message_accepted();
+        metrics.record_message_accepted();
+        metrics.record_message_latency(Duration::from_millis(250));
+        metrics.record_message_latency(Duration::from_millis(100));
+        let exported = server.export_metrics().to_string();
+
+        let listener = Listener::bind_tcp("127.0.0.1:0".parse().unwrap()).unwrap();
+        let restarted = SmtpServer::new(listener, None, 5, SessionConfig::default(), AccessList::default()).unwrap();
+        restarted.session_config.metrics.record_message_accepted();
+        let snapshot = json_parser::JsonParser::default().parse(&exported).unwrap();
+        restarted.import_metrics(&snapshot).unwrap();
+
+        let totals = restarted.session_config.metrics.snapshot();
+        assert_eq!(totals.bytes_received, 1200);
+        assert_eq!(totals.messages_accepted, 3);
+        assert_eq!(totals.message_latency, LatencyStats {
+            count: 2,
+            total: Duration::from_millis(350),
+            max: Duration::from_millis(250),
+        });
+
+        let broken = json_parser::JsonParser::default().parse(r#"{ "bytes-received": -1 }"#).unwrap();
+        assert!(matches!(restarted.import_metrics(&broken), Err(InvalidMetricsSnapshot(field)) if field == "message-latency.count"));
+    }
+
+    #[test]
+    fn end_to_end_mail_test() {
+        let db = MemoryMailDB::with_users(&[("user1", "password"), ("user2", "password")]);
+        let server = TestServer::start(db.clone(), SessionConfig::default());
+
+        let mut client = server.connect();
+        assert!(client.ehlo().contains("STARTTLS"));
+        client.auth_plain("user1", "password");
+        let reply = client.send_mail("user1@example.com", &["user2"], "Subject: Hello\r\n\r\nBody");
+        assert!(reply.contains("queued as"), "unexpected reply: {}", reply);
+        client.quit();
+
+        let mails = db.mails();
+        assert_eq!(mails.len(), 1);
+        assert_eq!(mails[0].sender, "user1");
+        assert_eq!(mails[0].receivers, ["user2"]);
+        assert_eq!(mails[0].subject, "Hello");
+        assert!(mails[0].body.contains("Subject: Hello\r\n\r\nBody\r\n"), "unexpected body: {:?}", mails[0].body);
+    }
+
+    #[test]
+    fn shutdown_flushes_logs_and_stops_runtime_test() {
+        // a logger of its own, terminating the global one would silence the other tests
+        let records = Arc::new(Mutex::new(String::new()));
+        let logger = logger::Logger::new(Box::new(RecordingLogTarget { records: records.clone() }), logger::LogLevel::Info, 1000);
+        logger.update_flush_interval(Duration::ZERO);
+
+        let mut runtime = ConcurrentRuntime::new(1);
+        runtime.start();
+        let listener = Listener::bind_tcp("127.0.0.1:0".parse().unwrap()).unwrap();
+        let server = SmtpServer::new(listener, None, 5, SessionConfig::default(), AccessList::default()).unwrap();
+
+        // stands in for the signal handler
+        let handle = server.shutdown_handle();
+        let signal = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(100));
+            handle.shutdown();
+        });
+        server.run(&runtime);
+        signal.join().unwrap();
+        assert!(!server.is_running());
+
+        logger.log(logger::LogLevel::Info, "last message before shutdown".to_string());
+        assert!(!records.lock().unwrap().contains("last message before shutdown"));
+
+        shutdown(&server, &mut runtime, None, || logger.terminate());
+        assert!(records.lock().unwrap().contains("last message before shutdown"));
+
+        // executors notice the termination flag on their next poll
+        let deadline = std::time::Instant::now() + Duration::from_secs(5);
+        while runtime.is_healthy() && std::time::Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert!(!runtime.is_healthy());
+    }
+}
@@ -0,0 +1,119 @@
+use std::{
+    collections::HashMap,
+    io::{self, Write},
+    net::{SocketAddr, TcpListener},
+    sync::Arc,
+    thread,
+    time::{Duration, Instant},
+};
+
+use client_session::{metrics::MetricsCollector, ClientSession, SessionConfig};
+use json_parser::JsonValue;
+use logger::error;
+
+use crate::server::ShutdownHandle;
+
+const ACCEPT_POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+// The state reported on the status socket, read anew for every client
+pub struct StatusReport {
+    started: Instant,
+    capabilities: Vec<String>,
+    metrics: Arc<MetricsCollector>,
+}
+
+impl StatusReport {
+    pub fn new(tls_enabled: bool, session: &SessionConfig) -> Self {
+        Self {
+            started: Instant::now(),
+            // every keyword the server may offer, AUTH is withheld per session only
+            capabilities: ClientSession::capabilities(session, tls_enabled, true),
+            metrics: session.metrics.clone(),
+        }
+    }
+
+    pub fn snapshot(&self) -> JsonValue {
+        let capabilities = self.capabilities.iter()
+            .map(|capability| JsonValue::String(capability.clone()))
+            .collect();
+        let metrics = self.metrics.snapshot();
+        JsonValue::Object(HashMap::from([
+            ("capabilities".to_string(), JsonValue::Array(capabilities)),
+            ("active-connections".to_string(), JsonValue::Number(metrics.active_sessions as f64)),
+            ("uptime".to_string(), JsonValue::Number(self.started.elapsed().as_secs() as f64)),
+            ("log-level".to_string(), JsonValue::String(logger::get_logger_level().to_string())),
+        ]))
+    }
+}
+
+// A read-only socket for ops tooling: every client gets one JSON snapshot, then the
+// connection is closed
+pub struct StatusServer {
+    listener: TcpListener,
+    report: StatusReport,
+}
+
+impl StatusServer {
+    pub fn bind(address: SocketAddr, report: StatusReport) -> io::Result<Self> {
+        let listener = TcpListener::bind(address)?;
+        listener.set_nonblocking(true)?;
+        Ok(Self { listener, report })
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    // Serves clients on a thread of its own until the server shuts down
+    pub fn spawn(self, shutdown: ShutdownHandle) -> io::Result<thread::JoinHandle<()>> {
+        thread::Builder::new().name("status".to_string()).spawn(move || {
+            while shutdown.is_running() {
+                match self.listener.accept() {
+                    Ok((mut stream, _)) => {
+                        let result = stream.set_nonblocking(false)
+                            .and_then(|_| stream.write_all(format!("{}\n", self.report.snapshot()).as_bytes()));
+                        if let Err(err) = result {
+                            error!("Could not send status: {}", err);
+                        }
+                    },
+                    Err(err) if err.kind() == io::ErrorKind::WouldBlock => thread::sleep(ACCEPT_POLL_INTERVAL),
+                    Err(err) => error!("Could not accept status connection: {}", err),
+                }
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{io::Read, net::TcpStream};
+    use json_parser::JsonParser;
+    use crate::{access::AccessList, listener::Listener, server::SmtpServer};
+
+    #[test]
+    fn status_socket_test() {
+        let listener = Listener::bind_tcp("127.0.0.1:0".parse().unwrap()).unwrap();
+        let server = SmtpServer::new(listener, None, 5, SessionConfig::default(), AccessList::default()).unwrap();
+        let session = SessionConfig::default();
+        let _active = session.metrics.session_started();
+
+        let status = StatusServer::bind("127.0.0.1:0".parse().unwrap(), StatusReport::new(true, &session)).unwrap();
+        let address = status.local_addr().unwrap();
+        let status_thread = status.spawn(server.shutdown_handle()).unwrap();
+
+        let mut response = String::new();
+        TcpStream::connect(address).unwrap().read_to_string(&mut response).unwrap();
+        let status = JsonParser::default().parse(&response).unwrap();
+        assert_eq!(status["active-connections"].as_number(), Some(1.0));
+        assert!(status["uptime"].as_number().is_some());
+        assert!(status["log-level"].as_str().unwrap().parse::<logger::LogLevel>().is_ok());
+        let capabilities: Vec<String> = status["capabilities"].as_array().unwrap().iter()
+            .filter_map(JsonValue::as_str)
+            .collect();
+        assert_eq!(capabilities, ["STARTTLS", "AUTH PLAIN LOGIN", "SIZE 10485760", "8BITMIME", "PIPELINING", "CHUNKING", "DSN", "ENHANCEDSTATUSCODES", "HELP"]);
+
+        server.shutdown();
+        status_thread.join().unwrap();
+    }
+}
@@ -0,0 +1,263 @@
+use std::{
+    collections::HashMap,
+    io::{Read, Write},
+    net::{SocketAddr, TcpListener, TcpStream},
+    sync::{Arc, Mutex},
+    thread::{self, JoinHandle},
+    time::Duration,
+};
+
+use client_session::SessionConfig;
+use concurrent_runtime::ConcurrentRuntime;
+use mail_database::{IMailDB, MailError, RecipientResults, StoredMail};
+use native_tls::{TlsConnector, TlsStream};
+use smart_stream::TlsAcceptor;
+
+use crate::{access::AccessList, listener::Listener, server::{ShutdownHandle, SmtpServer}};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ReceivedMail {
+    pub sender: String,
+    pub receivers: Vec<String>,
+    pub subject: String,
+    pub body: String,
+}
+
+#[derive(Default)]
+struct MemoryState {
+    users: HashMap<String, String>,
+    mails: Vec<ReceivedMail>,
+}
+
+// In-memory IMailDB shared by every session of a TestServer, without transactions
+#[derive(Default, Clone)]
+pub struct MemoryMailDB {
+    state: Arc<Mutex<MemoryState>>,
+    logged_user: Option<String>,
+}
+
+impl MemoryMailDB {
+    pub fn with_users(users: &[(&str, &str)]) -> Self {
+        let db = Self::default();
+        for (user, pass) in users {
+            db.state.lock().unwrap().users.insert(user.to_string(), pass.to_string());
+        }
+        db
+    }
+
+    pub fn mails(&self) -> Vec<ReceivedMail> {
+        self.state.lock().unwrap().mails.clone()
+    }
+}
+
+impl IMailDB for MemoryMailDB {
+    fn connect(&mut self, _connection_string: &str) -> Result<(), MailError> {
+        Ok(())
+    }
+
+    fn disconnect(&mut self) {}
+
+    fn is_connected(&mut self) -> bool {
+        true
+    }
+
+    fn sign_up(&mut self, user_name: &str, password: &str) -> Result<(), MailError> {
+        let mut state = self.state.lock().unwrap();
+        if state.users.contains_key(user_name) {
+            return Err(MailError::UserAlreadyExist);
+        }
+        state.users.insert(user_name.to_string(), password.to_string());
+        Ok(())
+    }
+
+    fn login(&mut self, user_name: &str, password: &str) -> Result<(), MailError> {
+        if !self.verify_credentials(user_name, password)? {
+            return Err(MailError::UserAuthError);
+        }
+        self.logged_user = Some(user_name.to_string());
+        Ok(())
+    }
+
+    fn verify_credentials(&mut self, user_name: &str, password: &str) -> Result<bool, MailError> {
+        Ok(self.state.lock().unwrap().users.get(user_name).is_some_and(|stored| stored == password))
+    }
+
+    fn insert_email(&mut self, receiver: &str, subject: &str, body: &str) -> Result<(), MailError> {
+        self.insert_multiple_emails(vec![receiver], subject, body)
+    }
+
+    fn insert_multiple_emails(&mut self, receivers: Vec<&str>, subject: &str, body: &str) -> Result<(), MailError> {
+        let results = self.deliver_to_recipients(receivers, subject, body)?;
+        results.into_iter().try_for_each(|(_, result)| result.map(|_| ()))
+    }
+
+    fn deliver_to_recipients(&mut self, receivers: Vec<&str>, subject: &str, body: &str)
+        -> Result<RecipientResults, MailError> {
+        let sender = self.logged_user.clone().ok_or(MailError::UserNotLoggedIn)?;
+        if receivers.is_empty() {
+            return Err(MailError::EmptyReceiversError);
+        }
+        let mut state = self.state.lock().unwrap();
+        let message_id = state.mails.len() as i32 + 1;
+        let (known, unknown): (Vec<&str>, Vec<&str>) = receivers.into_iter()
+            .partition(|receiver| state.users.contains_key(*receiver));
+        if !known.is_empty() {
+            state.mails.push(ReceivedMail {
+                sender,
+                receivers: known.iter().map(ToString::to_string).collect(),
+                subject: subject.to_string(),
+                body: body.to_string(),
+            });
+        }
+        Ok(known.into_iter().map(|receiver| (receiver.to_string(), Ok(message_id)))
+            .chain(unknown.into_iter().map(|receiver| (receiver.to_string(), Err(MailError::UserNotFound))))
+            .collect())
+    }
+
+    fn user_exists(&mut self, user_name: &str) -> Result<bool, MailError> {
+        Ok(self.state.lock().unwrap().users.contains_key(user_name))
+    }
+
+    // Stored messages are read through mails() instead
+    fn get_message(&mut self, _user_name: &str, _email_message_id: i32) -> Result<StoredMail, MailError> {
+        Err(MailError::NotFound)
+    }
+}
+
+// The whole server on an ephemeral loopback port: accept loop, runtime, TLS and an in-memory
+// database. Dropping it stops the server.
+pub struct TestServer {
+    address: SocketAddr,
+    handle: ShutdownHandle,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl TestServer {
+    pub fn start(db: MemoryMailDB, config: SessionConfig) -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let server = SmtpServer::new(Listener::Tcp(listener), Some(tls_acceptor()), 5, config, AccessList::default())
+            .unwrap()
+            .with_database(Arc::new(move || Ok(Box::new(db.clone()))));
+        let handle = server.shutdown_handle();
+        let thread = thread::spawn(move || {
+            let mut runtime = ConcurrentRuntime::new(2);
+            runtime.start();
+            server.run(&runtime);
+            runtime.stop();
+        });
+        Self { address, handle, thread: Some(thread) }
+    }
+
+    // A client that has read the greeting
+    pub fn connect(&self) -> TestClient {
+        let stream = TcpStream::connect(self.address).unwrap();
+        stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        let mut client = TestClient { stream: Some(ClientStream::Plain(stream)) };
+        let greeting = client.read_reply();
+        assert!(greeting.starts_with("220"), "unexpected greeting: {}", greeting);
+        client
+    }
+}
+
+impl Drop for TestServer {
+    fn drop(&mut self) {
+        self.handle.shutdown();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+fn tls_acceptor() -> TlsAcceptor {
+    smart_stream::tls_acceptor(
+        include_bytes!("../../crates/client_session/tests/certs/server.crt"),
+        include_bytes!("../../crates/client_session/tests/certs/server.key"),
+    ).unwrap()
+}
+
+enum ClientStream {
+    Plain(TcpStream),
+    Encrypted(TlsStream<TcpStream>),
+}
+
+// SMTP client whose helpers assert the reply codes of a successful exchange
+pub struct TestClient {
+    stream: Option<ClientStream>,
+}
+
+impl TestClient {
+    // Reads a complete (possibly multiline) reply
+    pub fn read_reply(&mut self) -> String {
+        let mut reply = Vec::new();
+        let mut chunk = [0; 1024];
+        loop {
+            let n = match self.stream.as_mut().unwrap() {
+                ClientStream::Plain(stream) => stream.read(&mut chunk),
+                ClientStream::Encrypted(stream) => stream.read(&mut chunk),
+            }.unwrap();
+            if n == 0 {
+                break;
+            }
+            reply.extend_from_slice(&chunk[..n]);
+            let text = String::from_utf8_lossy(&reply);
+            let last_line = text.trim_end().lines().last().unwrap_or("");
+            if text.ends_with("\r\n") && (last_line.len() == 3 || last_line.as_bytes().get(3) == Some(&b' ')) {
+                break;
+            }
+        }
+        String::from_utf8(reply).unwrap()
+    }
+
+    pub fn send(&mut self, command: &str) -> String {
+        let line = format!("{}\r\n", command);
+        match self.stream.as_mut().unwrap() {
+            ClientStream::Plain(stream) => stream.write_all(line.as_bytes()),
+            ClientStream::Encrypted(stream) => stream.write_all(line.as_bytes()),
+        }.unwrap();
+        self.read_reply()
+    }
+
+    fn expect(&mut self, command: &str, code: &str) -> String {
+        let reply = self.send(command);
+        assert!(reply.starts_with(code), "{} got: {}", command, reply);
+        reply
+    }
+
+    pub fn ehlo(&mut self) -> String {
+        self.expect("EHLO client.example.com", "250")
+    }
+
+    pub fn starttls(&mut self) {
+        self.expect("STARTTLS", "220");
+        let Some(ClientStream::Plain(stream)) = self.stream.take() else {
+            panic!("stream is already encrypted");
+        };
+        let connector = TlsConnector::builder().danger_accept_invalid_certs(true).build().unwrap();
+        self.stream = Some(ClientStream::Encrypted(connector.connect("localhost", stream).unwrap()));
+    }
+
+    // The server only offers AUTH over TLS, a plain connection is encrypted first
+    pub fn auth_plain(&mut self, user: &str, password: &str) {
+        if matches!(self.stream, Some(ClientStream::Plain(_))) {
+            self.starttls();
+            self.ehlo();
+        }
+        let credentials = base64::encode(&format!("\0{}\0{}", user, password));
+        self.expect(&format!("AUTH PLAIN {}", credentials), "235");
+    }
+
+    // One message through MAIL FROM, RCPT TO and DATA; returns the final reply
+    pub fn send_mail(&mut self, from: &str, to: &[&str], body: &str) -> String {
+        self.expect(&format!("MAIL FROM:<{}>", from), "250");
+        for recipient in to {
+            self.expect(&format!("RCPT TO:<{}>", recipient), "250");
+        }
+        self.expect("DATA", "354");
+        self.expect(&format!("{}\r\n.", body), "250")
+    }
+
+    pub fn quit(mut self) {
+        self.expect("QUIT", "221");
+    }
+}